@@ -0,0 +1,145 @@
+//! Sidecar index files, written next to a data file as `<file>.idx`, which record how many
+//! records the data file holds and a hash of them. Reading the count from the index saves
+//! decompressing a compressed file to count its records. The length and modification time
+//! of the data file tell whether the index is current, and the hash detects data files
+//! changed since they were indexed in a way that kept both.
+
+use std::ffi::OsString;
+use std::fs::{File, Metadata};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use bytemuck::{Pod, Zeroable};
+
+use crate::util;
+
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+#[repr(C)]
+pub struct Index {
+    magic: [u8; 8],
+    version: util::U16Le,
+    record_size: util::U16Le,
+    _reserved: [u8; 4],
+    records: util::U64Le,
+    file_len: util::U64Le,
+    // The modification time of the data file, in nanoseconds since the Unix epoch
+    modified: util::U64Le,
+    hash: util::U64Le,
+}
+
+impl Index {
+    pub const MAGIC: [u8; 8] = *b"MARLINIX";
+    pub const VERSION: u16 = 2;
+
+    /// An index of `records` records of `record_size` bytes with the given hash, taken from a
+    /// data file with the metadata `file`, as stored on disk, compressed or not.
+    pub fn new(records: u64, record_size: u16, file: &Metadata, hash: u64) -> Self {
+        Index {
+            magic: Self::MAGIC,
+            version: util::U16Le::new(Self::VERSION),
+            record_size: util::U16Le::new(record_size),
+            _reserved: [0; 4],
+            records: util::U64Le::new(records),
+            file_len: util::U64Le::new(file.len()),
+            modified: util::U64Le::new(modified(file)),
+            hash: util::U64Le::new(hash),
+        }
+    }
+
+    /// Reads the index of a data file, if it has one.
+    pub fn read(dataset: &Path) -> Result<Option<Self>> {
+        let mut bytes = [0; core::mem::size_of::<Index>()];
+        match File::open(path(dataset)) {
+            Ok(mut file) => file.read_exact(&mut bytes)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let index: Index = bytemuck::pod_read_unaligned(&bytes);
+        if index.magic != Self::MAGIC || index.version.get() != Self::VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                std::format!(
+                    "{} is not a version {} index",
+                    path(dataset).display(),
+                    Self::VERSION
+                ),
+            ));
+        }
+        Ok(Some(index))
+    }
+
+    /// Reads the index of a data file, if it has one that was written for the file as it is
+    /// now, going by its length and modification time. Checking the hash would mean reading
+    /// the whole file, which the index is there to save.
+    pub fn read_current(dataset: &Path) -> Result<Option<Self>> {
+        let file = std::fs::metadata(dataset)?;
+        Ok(Self::read(dataset)?
+            .filter(|index| index.file_len() == file.len() && index.modified() == modified(&file)))
+    }
+
+    pub fn write(&self, dataset: &Path) -> Result<()> {
+        File::create(path(dataset))?.write_all(bytemuck::bytes_of(self))
+    }
+
+    pub fn records(&self) -> u64 {
+        self.records.get()
+    }
+
+    pub fn record_size(&self) -> u16 {
+        self.record_size.get()
+    }
+
+    pub fn file_len(&self) -> u64 {
+        self.file_len.get()
+    }
+
+    pub fn modified(&self) -> u64 {
+        self.modified.get()
+    }
+
+    pub fn hash(&self) -> u64 {
+        self.hash.get()
+    }
+}
+
+/// The modification time of a file in nanoseconds since the Unix epoch, or 0 where the
+/// platform doesn't record it.
+fn modified(file: &Metadata) -> u64 {
+    file.modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |time| time.as_nanos() as u64)
+}
+
+/// The path of the index of a data file.
+pub fn path(dataset: &Path) -> PathBuf {
+    let mut path = OsString::from(dataset.as_os_str());
+    path.push(".idx");
+    path.into()
+}
+
+/// The 64-bit FNV-1a hash of the records of an index, past any header.
+pub struct Hasher(u64);
+
+impl Hasher {
+    pub fn new() -> Self {
+        Hasher(0xCBF2_9CE4_8422_2325)
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x0000_0100_0000_01B3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Default for Hasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
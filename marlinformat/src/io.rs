@@ -0,0 +1,247 @@
+//! Reading and writing data files through [`std::io`], with the `std` feature.
+
+use std::format;
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::vec;
+use std::vec::Vec;
+
+use crate::{Header, PackedBoard};
+
+const RECORD_SIZE: usize = core::mem::size_of::<PackedBoard>();
+
+/// Iterates the records of a version 1 data file, skipping its header if it has one and
+/// stopping after as many records as the header gives.
+pub struct Reader<R> {
+    reader: R,
+    header: Option<Header>,
+    first: Option<PackedBoard>,
+    remaining: Option<u64>,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut bytes = [0; RECORD_SIZE];
+        let first = match read_record(&mut reader, &mut bytes)? {
+            true => Some(bytes),
+            false => None,
+        };
+        let header = first.and_then(|bytes| Header::parse(&bytes));
+        if let Some(header) = header {
+            if header.version() != Header::VERSION {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unsupported data file version {}", header.version()),
+                ));
+            }
+        }
+        Ok(Reader {
+            reader,
+            header,
+            first: match header {
+                Some(_) => None,
+                None => first.map(|bytes| bytemuck::pod_read_unaligned(&bytes)),
+            },
+            remaining: header.and_then(|header| header.records()),
+        })
+    }
+
+    pub fn header(&self) -> Option<&Header> {
+        self.header.as_ref()
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<PackedBoard>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(first) = self.first.take() {
+            return Some(Ok(first));
+        }
+        if self.remaining == Some(0) {
+            return None;
+        }
+        let mut bytes = [0; RECORD_SIZE];
+        match read_record(&mut self.reader, &mut bytes) {
+            Ok(true) => {
+                if let Some(remaining) = &mut self.remaining {
+                    *remaining -= 1;
+                }
+                Some(Ok(bytemuck::pod_read_unaligned(&bytes)))
+            }
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Reads a whole record, or returns false at the end of the input. A trailing partial record
+/// is an error.
+fn read_record(reader: &mut impl Read, bytes: &mut [u8; RECORD_SIZE]) -> Result<bool> {
+    match fill(reader, bytes)? {
+        0 => Ok(false),
+        RECORD_SIZE => Ok(true),
+        _ => Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "data file ends in a partial record",
+        )),
+    }
+}
+
+/// Writes records to a version 1 data file through a buffer.
+pub struct Writer<W: Write> {
+    writer: BufWriter<W>,
+    flags: Option<u16>,
+    records: u64,
+}
+
+impl<W: Write> Writer<W> {
+    /// A writer for a headerless file.
+    pub fn new(writer: W) -> Self {
+        Writer {
+            writer: BufWriter::new(writer),
+            flags: None,
+            records: 0,
+        }
+    }
+
+    /// A writer for a file with a header. The record count is left unknown unless the file is
+    /// finished with [`Writer::finish_with_count`].
+    pub fn with_header(writer: W, flags: u16) -> Result<Self> {
+        let mut writer = BufWriter::new(writer);
+        writer.write_all(bytemuck::bytes_of(&Header::new(0, flags)))?;
+        Ok(Writer {
+            writer,
+            flags: Some(flags),
+            records: 0,
+        })
+    }
+
+    pub fn write(&mut self, record: &PackedBoard) -> Result<()> {
+        self.writer.write_all(bytemuck::bytes_of(record))?;
+        self.records += 1;
+        Ok(())
+    }
+
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Flushes the buffer and returns the underlying writer.
+    pub fn finish(self) -> Result<W> {
+        self.writer.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+impl<W: Write + Seek> Writer<W> {
+    /// Flushes the buffer and fills in the record count of the header, if there is one. The
+    /// writer must have started at the beginning of the file.
+    pub fn finish_with_count(self) -> Result<W> {
+        let (flags, records) = (self.flags, self.records);
+        let mut writer = self.finish()?;
+        if let Some(flags) = flags {
+            let end = writer.stream_position()?;
+            writer.seek(SeekFrom::Start(0))?;
+            writer.write_all(bytemuck::bytes_of(&Header::new(records, flags)))?;
+            writer.seek(SeekFrom::Start(end))?;
+        }
+        Ok(writer)
+    }
+}
+
+/// Reads ahead of the consumer on a background thread, keeping up to `blocks` blocks of
+/// `block_size` bytes ready, so that reading a large file overlaps with processing it rather
+/// than stalling on every synchronous read.
+pub struct ReadAhead {
+    blocks: Receiver<Result<Vec<u8>>>,
+    block: Vec<u8>,
+    position: usize,
+    done: bool,
+}
+
+impl ReadAhead {
+    pub fn new<R: Read + Send + 'static>(mut reader: R, block_size: usize, blocks: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(blocks.max(1));
+        // The thread stops at the end of the input, or once the reader is dropped.
+        std::thread::spawn(move || loop {
+            let mut block = vec![0; block_size.max(1)];
+            let block = fill(&mut reader, &mut block).map(|len| {
+                block.truncate(len);
+                block
+            });
+            let last = !matches!(&block, Ok(block) if !block.is_empty());
+            if sender.send(block).is_err() || last {
+                break;
+            }
+        });
+        ReadAhead {
+            blocks: receiver,
+            block: vec![],
+            position: 0,
+            done: false,
+        }
+    }
+}
+
+impl Read for ReadAhead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        while self.position == self.block.len() {
+            if self.done {
+                return Ok(0);
+            }
+            match self.blocks.recv() {
+                Ok(Ok(block)) => {
+                    self.done = block.is_empty();
+                    self.block = block;
+                    self.position = 0;
+                }
+                Ok(Err(e)) => {
+                    self.done = true;
+                    return Err(e);
+                }
+                Err(_) => {
+                    self.done = true;
+                    return Err(Error::other("read ahead thread panicked"));
+                }
+            }
+        }
+        let len = buf.len().min(self.block.len() - self.position);
+        buf[..len].copy_from_slice(&self.block[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// Reads until the buffer is full or the input ends, returning how much was read, for inputs
+/// of fixed-size records that may end mid-record.
+pub fn fill(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Tells the kernel that the file will be read from start to end, which on Linux widens its
+/// readahead window. Elsewhere this does nothing.
+pub fn advise_sequential(file: &File) {
+    #[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        extern "C" {
+            fn posix_fadvise(fd: i32, offset: i64, len: i64, advice: i32) -> i32;
+        }
+        const POSIX_FADV_SEQUENTIAL: i32 = 2;
+        // Only a hint, so failure doesn't matter.
+        unsafe { posix_fadvise(file.as_raw_fd(), 0, 0, POSIX_FADV_SEQUENTIAL) };
+    }
+    #[cfg(not(all(target_os = "linux", target_pointer_width = "64")))]
+    let _ = file;
+}
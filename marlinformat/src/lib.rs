@@ -1,10 +1,180 @@
 #![no_std]
 
+#[cfg(feature = "std")]
+extern crate std;
+
+use core::fmt;
+
 use bytemuck::{Pod, Zeroable};
-use cozy_chess::{BitBoard, Board, BoardBuilder, Color, Piece, Rank, Square};
+use cozy_chess::{BitBoard, Board, BoardBuilder, Color, Move, Piece, Rank, Square};
+
+#[cfg(feature = "std")]
+pub mod index;
+#[cfg(feature = "std")]
+pub mod io;
+#[cfg(feature = "std")]
+pub mod text;
 
 const UNMOVED_ROOK: u8 = Piece::NUM as u8;
 
+/// Reads a per-position sample weight from the `extra` byte, stored in units of 1/64.
+/// Zero, as written by tools that know nothing about weights, means the default weight of 1.
+pub fn weight_from_extra(extra: u8) -> f32 {
+    match extra {
+        0 => 1.0,
+        _ => extra as f32 / 64.0,
+    }
+}
+
+/// Encodes a sample weight into the `extra` byte, saturating to the representable range.
+pub fn extra_from_weight(weight: f32) -> u8 {
+    (weight * 64.0 + 0.5).clamp(1.0, u8::MAX as f32) as u8
+}
+
+/// The `wdl` byte that stands for a win for white in files with [`Header::FLAG_SOFT_WDL`].
+pub const SOFT_WDL_ONE: u8 = 200;
+
+/// Encodes white's expected score, such as one derived from the search score when a game was
+/// adjudicated, into the `wdl` byte of a file with soft results, in units of 1 / [`SOFT_WDL_ONE`].
+pub fn soft_wdl_from_float(score: f32) -> u8 {
+    (score.clamp(0.0, 1.0) * SOFT_WDL_ONE as f32).round() as u8
+}
+
+/// Reads the `wdl` byte as white's expected score from 0 to 1, given whether the file holds
+/// soft results.
+pub fn wdl_to_float(wdl: u8, soft: bool) -> f32 {
+    match soft {
+        true => wdl as f32 / SOFT_WDL_ONE as f32,
+        false => wdl as f32 / 2.0,
+    }
+}
+
+/// The `extra` byte read as flags set by the data pipeline, with the high four bits left for
+/// users. Files with [`Header::FLAG_WEIGHTS`] hold sample weights in the byte instead.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Extra(u8);
+
+impl Extra {
+    /// The side to move was in check.
+    pub const IN_CHECK: u8 = 1 << 0;
+    /// The eval or result was replaced by a tablebase probe.
+    pub const TB_RESCORED: u8 = 1 << 1;
+    /// The position passed a quiescence filter.
+    pub const FILTERED_QUIET: u8 = 1 << 2;
+    pub const USER_MASK: u8 = 0xF0;
+
+    pub fn new(bits: u8) -> Self {
+        Extra(bits)
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn in_check(self) -> bool {
+        self.get(Self::IN_CHECK)
+    }
+
+    pub fn set_in_check(&mut self, value: bool) {
+        self.set(Self::IN_CHECK, value);
+    }
+
+    pub fn tb_rescored(self) -> bool {
+        self.get(Self::TB_RESCORED)
+    }
+
+    pub fn set_tb_rescored(&mut self, value: bool) {
+        self.set(Self::TB_RESCORED, value);
+    }
+
+    pub fn filtered_quiet(self) -> bool {
+        self.get(Self::FILTERED_QUIET)
+    }
+
+    pub fn set_filtered_quiet(&mut self, value: bool) {
+        self.set(Self::FILTERED_QUIET, value);
+    }
+
+    /// The user bits, from 0 to 15.
+    pub fn user(self) -> u8 {
+        self.0 >> 4
+    }
+
+    /// Sets the user bits, keeping the low four bits of `value`.
+    pub fn set_user(&mut self, value: u8) {
+        self.0 = self.0 & !Self::USER_MASK | value << 4;
+    }
+
+    pub fn get(self, flag: u8) -> bool {
+        self.0 & flag != 0
+    }
+
+    pub fn set(&mut self, flag: u8, value: bool) {
+        match value {
+            true => self.0 |= flag,
+            false => self.0 &= !flag,
+        }
+    }
+}
+
+/// Mates are scored as this minus the number of plies to mate, as most engines do.
+pub const MATE_SCORE: i16 = 32000;
+
+/// Mate distances are stored up to one less than this, and longer mates are saturated.
+pub const MAX_MATE_PLIES: i16 = 1000;
+
+/// The meaning of the 16-bit eval field, from white's point of view.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Eval {
+    Centipawns(i16),
+    Mate { winner: Color, plies: u16 },
+}
+
+impl Eval {
+    /// The largest centipawn eval, just short of the mate scores.
+    pub const MAX_CENTIPAWNS: i16 = MATE_SCORE - MAX_MATE_PLIES;
+
+    /// A centipawn eval, saturated so that it cannot be mistaken for a mate.
+    pub fn centipawns(cp: i64) -> Self {
+        let max = Self::MAX_CENTIPAWNS as i64;
+        Eval::Centipawns(cp.clamp(-max, max) as i16)
+    }
+
+    /// Decodes an eval field of a file with mate scores, as [`Header::FLAG_MATE_SCORES`] marks,
+    /// or of one without, whose evals are all centipawns. Values beyond the mate scores, such
+    /// as the `i16` limits that older converters saturated out of range evals to, are read as
+    /// saturated centipawns.
+    pub fn decode(eval: i16, mates: bool) -> Self {
+        let distance = MATE_SCORE as i32 - (eval as i32).abs();
+        match distance {
+            _ if !mates => Eval::Centipawns(eval),
+            _ if distance < 0 => Self::centipawns(eval as i64),
+            _ if distance < MAX_MATE_PLIES as i32 => Eval::Mate {
+                winner: match eval > 0 {
+                    true => Color::White,
+                    false => Color::Black,
+                },
+                plies: distance as u16,
+            },
+            _ => Eval::Centipawns(eval),
+        }
+    }
+
+    /// Encodes an eval field, saturating centipawns and mate distances that are out of range.
+    pub fn encode(self) -> i16 {
+        match self {
+            Eval::Centipawns(cp) => cp.clamp(-Self::MAX_CENTIPAWNS, Self::MAX_CENTIPAWNS),
+            Eval::Mate { winner, plies } => {
+                let score = MATE_SCORE - plies.min(MAX_MATE_PLIES as u16 - 1) as i16;
+                match winner {
+                    Color::White => score,
+                    Color::Black => -score,
+                }
+            }
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Pod, Zeroable)]
 #[repr(C)]
 pub struct PackedBoard {
@@ -87,6 +257,276 @@ impl PackedBoard {
 
         Some((builder.build().ok()?, self.eval.get(), self.wdl, self.extra))
     }
+
+    pub fn set_extra(&mut self, extra: u8) {
+        self.extra = extra;
+    }
+
+    /// The eval field, see [`Eval::decode`].
+    pub fn eval(&self) -> i16 {
+        self.eval.get()
+    }
+
+    pub fn set_eval(&mut self, eval: i16) {
+        self.eval = util::I16Le::new(eval);
+    }
+
+    pub fn extra_flags(&self) -> Extra {
+        Extra(self.extra)
+    }
+
+    pub fn set_extra_flags(&mut self, flags: Extra) {
+        self.extra = flags.0;
+    }
+}
+
+/// An optional header at the start of a marlinformat file. It is the same size as a record,
+/// so records stay aligned to their size after it.
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+#[repr(C)]
+pub struct Header {
+    magic: [u8; 8],
+    version: util::U16Le,
+    flags: util::U16Le,
+    _reserved: [u8; 4],
+    records: util::U64Le,
+    _reserved2: [u8; 8],
+}
+
+impl Header {
+    pub const MAGIC: [u8; 8] = *b"MARLINFM";
+    /// Version 1 files hold [`PackedBoard`] records.
+    pub const VERSION: u16 = 1;
+    /// Version 2 files hold [`PackedBoardV2`] records.
+    pub const VERSION_V2: u16 = 2;
+
+    /// The `extra` byte of every record holds a sample weight.
+    pub const FLAG_WEIGHTS: u16 = 1 << 0;
+    /// The `wdl` byte of every record holds a soft result, see [`soft_wdl_from_float`].
+    pub const FLAG_SOFT_WDL: u16 = 1 << 1;
+    /// Evals close to [`MATE_SCORE`] are mate scores, see [`Eval::decode`]. Files without it
+    /// hold centipawns only, as those written before mates were encoded do.
+    pub const FLAG_MATE_SCORES: u16 = 1 << 2;
+
+    /// A header for a file of `records` records, where 0 means the count is unknown.
+    pub fn new(records: u64, flags: u16) -> Self {
+        Self::with_version(Self::VERSION, records, flags)
+    }
+
+    /// A header for a version 2 file of `records` records.
+    pub fn new_v2(records: u64, flags: u16) -> Self {
+        Self::with_version(Self::VERSION_V2, records, flags)
+    }
+
+    fn with_version(version: u16, records: u64, flags: u16) -> Self {
+        Header {
+            magic: Self::MAGIC,
+            version: util::U16Le::new(version),
+            flags: util::U16Le::new(flags),
+            _reserved: [0; 4],
+            records: util::U64Le::new(records),
+            _reserved2: [0; 8],
+        }
+    }
+
+    /// Reads a header from the start of a file, if it has one.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let bytes = bytes.get(..core::mem::size_of::<Header>())?;
+        let header: Header = bytemuck::pod_read_unaligned(bytes);
+        (header.magic == Self::MAGIC).then_some(header)
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version.get()
+    }
+
+    pub fn flags(&self) -> u16 {
+        self.flags.get()
+    }
+
+    /// The number of records following the header, if known.
+    pub fn records(&self) -> Option<u64> {
+        match self.records.get() {
+            0 => None,
+            records => Some(records),
+        }
+    }
+}
+
+/// Views the bytes of a version 1 data file, such as a memory map, as its header and records.
+/// Returns `None` if the file is of another version, its length is not a whole number of
+/// records, or the bytes are not aligned to 8 bytes.
+pub fn records(bytes: &[u8]) -> Option<(Option<Header>, &[PackedBoard])> {
+    let header = Header::parse(bytes);
+    let records = match header {
+        Some(header) if header.version() != Header::VERSION => return None,
+        Some(header) => {
+            let records = &bytes[core::mem::size_of::<Header>()..];
+            let len = header.records().map_or(records.len(), |count| {
+                count as usize * core::mem::size_of::<PackedBoard>()
+            });
+            records.get(..len)?
+        }
+        None => bytes,
+    };
+    Some((header, bytemuck::try_cast_slice(records).ok()?))
+}
+
+/// Builds a record from its fields, checking that they are valid.
+#[derive(Clone, Debug)]
+pub struct RecordBuilder {
+    board: Board,
+    eval: Option<Eval>,
+    wdl: Option<u8>,
+    extra: u8,
+    mv: Option<Move>,
+}
+
+impl RecordBuilder {
+    pub fn new(board: Board) -> Self {
+        RecordBuilder {
+            board,
+            eval: None,
+            wdl: None,
+            extra: 0,
+            mv: None,
+        }
+    }
+
+    pub fn eval(mut self, eval: Eval) -> Self {
+        self.eval = Some(eval);
+        self
+    }
+
+    /// The game result: 2 for a white win, 1 for a draw and 0 for a black win.
+    pub fn wdl(mut self, wdl: u8) -> Self {
+        self.wdl = Some(wdl);
+        self
+    }
+
+    pub fn extra(mut self, extra: u8) -> Self {
+        self.extra = extra;
+        self
+    }
+
+    /// Stores a sample weight in the `extra` byte. See [`extra_from_weight`].
+    pub fn weight(self, weight: f32) -> Self {
+        self.extra(extra_from_weight(weight))
+    }
+
+    /// The move played or the engine's best move, only stored by [`RecordBuilder::build_v2`].
+    pub fn mv(mut self, mv: Move) -> Self {
+        self.mv = Some(mv);
+        self
+    }
+
+    pub fn build(&self) -> Result<PackedBoard, RecordError> {
+        let eval = self.eval.ok_or(RecordError::MissingEval)?;
+        let wdl = match self.wdl {
+            None => return Err(RecordError::MissingWdl),
+            Some(wdl) if wdl > 2 => return Err(RecordError::InvalidWdl(wdl)),
+            Some(wdl) => wdl,
+        };
+        Ok(PackedBoard::pack(
+            &self.board,
+            eval.encode(),
+            wdl,
+            self.extra,
+        ))
+    }
+
+    pub fn build_v2(&self) -> Result<PackedBoardV2, RecordError> {
+        let board = self.build()?;
+        match self.mv {
+            Some(mv) if !self.board.is_legal(mv) => Err(RecordError::IllegalMove(mv)),
+            mv => Ok(PackedBoardV2 {
+                board,
+                mv: util::U16Le::new(mv.map_or(0, encode_move)),
+            }),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RecordError {
+    MissingEval,
+    MissingWdl,
+    InvalidWdl(u8),
+    IllegalMove(Move),
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordError::MissingEval => write!(f, "record has no eval"),
+            RecordError::MissingWdl => write!(f, "record has no result"),
+            RecordError::InvalidWdl(wdl) => write!(f, "invalid result {wdl}, expected 0, 1 or 2"),
+            RecordError::IllegalMove(mv) => write!(f, "illegal move {mv}"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for RecordError {}
+
+/// A [`PackedBoard`] followed by the move played in the position or the engine's best move,
+/// as stored in version 2 files.
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+#[repr(C, packed)]
+pub struct PackedBoardV2 {
+    board: PackedBoard,
+    mv: util::U16Le,
+}
+
+impl PackedBoardV2 {
+    pub fn pack(board: &Board, eval: i16, wdl: u8, extra: u8, mv: Option<Move>) -> Self {
+        PackedBoardV2 {
+            board: PackedBoard::pack(board, eval, wdl, extra),
+            mv: util::U16Le::new(mv.map_or(0, encode_move)),
+        }
+    }
+
+    pub fn unpack(&self) -> Option<(Board, i16, u8, u8, Option<Move>)> {
+        let (board, eval, wdl, extra) = self.board().unpack()?;
+        let mv = match self.mv.get() {
+            0 => None,
+            mv => Some(decode_move(mv)?),
+        };
+        Some((board, eval, wdl, extra, mv))
+    }
+
+    /// The record without its move.
+    pub fn board(&self) -> PackedBoard {
+        self.board
+    }
+}
+
+impl From<PackedBoard> for PackedBoardV2 {
+    fn from(board: PackedBoard) -> Self {
+        PackedBoardV2 {
+            board,
+            mv: util::U16Le::new(0),
+        }
+    }
+}
+
+/// Encodes a move as its from square, to square and promotion piece plus one, in bits 0-5,
+/// 6-11 and 12-14. Zero is left for no move, since no move goes from a square to itself.
+fn encode_move(mv: Move) -> u16 {
+    let promotion = mv.promotion.map_or(0, |piece| piece as u16 + 1);
+    mv.from as u16 | (mv.to as u16) << 6 | promotion << 12
+}
+
+fn decode_move(mv: u16) -> Option<Move> {
+    let promotion = match mv >> 12 {
+        0 => None,
+        piece => Some(Piece::try_index(piece as usize - 1)?),
+    };
+    Some(Move {
+        from: Square::index(mv as usize & 0x3F),
+        to: Square::index(mv as usize >> 6 & 0x3F),
+        promotion,
+    })
 }
 
 mod util {
@@ -167,4 +607,106 @@ mod tests {
             assert_eq!(board, unpacked, "{}", sfen);
         }
     }
+
+    #[test]
+    fn header() {
+        assert_eq!(
+            core::mem::size_of::<Header>(),
+            core::mem::size_of::<PackedBoard>()
+        );
+        let header = Header::new(42, Header::FLAG_WEIGHTS);
+        let parsed = Header::parse(bytemuck::bytes_of(&header)).unwrap();
+        assert_eq!(parsed.version(), Header::VERSION);
+        assert_eq!(parsed.flags(), Header::FLAG_WEIGHTS);
+        assert_eq!(parsed.records(), Some(42));
+        assert!(Header::parse(&[0; 32]).is_none());
+    }
+
+    #[test]
+    fn packed_move() {
+        let board = Board::default();
+        let mv: Move = "e7e8q".parse().unwrap();
+        let packed = PackedBoardV2::pack(&board, 35, 2, 0, Some(mv));
+        assert_eq!(core::mem::size_of::<PackedBoardV2>(), 34);
+        assert_eq!(packed.unpack().unwrap().4, Some(mv));
+        let packed = PackedBoardV2::pack(&board, 35, 2, 0, None);
+        assert_eq!(packed.unpack().unwrap().4, None);
+    }
+
+    #[test]
+    fn chess960_castling() {
+        let fen = "rkrbbqnn/pppppppp/8/8/8/8/PPPPPPPP/RKRBBQNN w CAca - 0 1";
+        let board = Board::from_fen(fen, true).unwrap();
+        let (unpacked, ..) = PackedBoard::pack(&board, 0, 1, 0).unpack().unwrap();
+        assert_eq!(board, unpacked);
+    }
+
+    #[test]
+    fn eval_encoding() {
+        let mate = Eval::Mate {
+            winner: Color::Black,
+            plies: 7,
+        };
+        assert_eq!(mate.encode(), -31993);
+        assert_eq!(Eval::decode(-31993, true), mate);
+        assert_eq!(Eval::decode(-31993, false), Eval::Centipawns(-31993));
+        assert_eq!(Eval::decode(150, true), Eval::Centipawns(150));
+        assert_eq!(
+            Eval::decode(i16::MAX, true),
+            Eval::Centipawns(Eval::MAX_CENTIPAWNS)
+        );
+        assert_eq!(
+            Eval::centipawns(100_000),
+            Eval::Centipawns(Eval::MAX_CENTIPAWNS)
+        );
+    }
+
+    #[test]
+    fn record_builder() {
+        let builder = RecordBuilder::new(Board::default()).eval(Eval::Centipawns(20));
+        assert_eq!(builder.build().unwrap_err(), RecordError::MissingWdl);
+        let builder = builder.wdl(1).mv("e2e5".parse().unwrap());
+        assert!(builder.build().is_ok());
+        assert!(matches!(
+            builder.build_v2(),
+            Err(RecordError::IllegalMove(_))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn fen_line() {
+        let line = "rkrbbqnn/pppppppp/8/8/8/8/PPPPPPPP/RKRBBQNN w CAca - 0 1 | -35 | 0.5";
+        let packed = PackedBoard::from_fen_line(line).unwrap();
+        assert_eq!(packed.to_fen_line().unwrap(), line);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn mate_text() {
+        let line = "8/8/8/8/8/5k2/8/5K1q w - - 0 1 | #-0 | 0.0";
+        let packed = PackedBoard::from_fen_line(line).unwrap();
+        assert_eq!(packed.eval(), -MATE_SCORE);
+        assert_eq!(packed.to_fen_line().unwrap(), line);
+        let mate = text::TextEval::parse("#5").unwrap();
+        assert_eq!(text::format_eval(mate.flip().to_eval()), "#-5");
+        assert!(text::TextEval::parse("#--5").is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn soft_result_text() {
+        assert_eq!(text::format_result(wdl_to_float(2, false)), "1.0");
+        assert_eq!(text::format_result(wdl_to_float(147, true)), "0.735");
+    }
+
+    #[test]
+    fn extra_flags() {
+        let mut extra = Extra::new(Extra::IN_CHECK);
+        extra.set_tb_rescored(true);
+        extra.set_user(0x1A);
+        assert!(extra.in_check() && extra.tb_rescored() && !extra.filtered_quiet());
+        assert_eq!(extra.user(), 0xA);
+        assert_eq!(extra.bits(), 0xA3);
+    }
 }
@@ -0,0 +1,184 @@
+//! The canonical text form of a record, `<fen> | <eval> | <wdl>`, with the eval in
+//! centipawns or as a mate score, `#N` when white mates in N plies and `#-N` when black does,
+//! and the result as 1.0, 0.5 or 0.0, or as white's expected score for soft results, both
+//! from white's point of view. Positions
+//! whose castling rights are not those of standard chess are written as Shredder-FENs. The
+//! `extra` byte is not part of the text form. Available with the `std` feature.
+
+use std::format;
+use std::string::{String, ToString};
+
+use cozy_chess::{Board, Color, File, Rank, Square};
+
+use crate::{wdl_to_float, Eval, PackedBoard};
+
+pub const SEPARATOR: &str = " | ";
+
+impl PackedBoard {
+    /// Parses a record from its canonical text form. Evals beyond the centipawn range are
+    /// saturated and fractional evals are truncated, and mate scores are encoded as in files
+    /// with [`crate::Header::FLAG_MATE_SCORES`], which files of these records need.
+    pub fn from_fen_line(line: &str) -> Option<Self> {
+        let (board, eval, wdl) = parse_line(line, SEPARATOR, true)?;
+        Some(PackedBoard::pack(
+            &board,
+            eval.to_eval().encode(),
+            wdl_from_float(wdl),
+            0,
+        ))
+    }
+
+    /// Formats a record of a file with mate scores and without soft results in its canonical
+    /// text form, or returns `None` if it is invalid.
+    pub fn to_fen_line(&self) -> Option<String> {
+        let (board, cp, wdl, _) = self.unpack()?;
+        let eval = Eval::decode(cp, true);
+        Some(format_line(&board, eval, wdl_to_float(wdl, false)))
+    }
+}
+
+/// An eval as written in text: centipawns, which may be fractional or beyond the range of a
+/// record, or a mate score.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TextEval {
+    Centipawns(f32),
+    Mate { winner: Color, plies: u16 },
+}
+
+impl TextEval {
+    /// Parses an eval written in centipawns or as a mate score, `#N` when white mates in N
+    /// plies and `#-N` when black does.
+    pub fn parse(eval: &str) -> Option<Self> {
+        let plies = match eval.strip_prefix('#') {
+            Some(plies) => plies,
+            None => return eval.parse().ok().map(TextEval::Centipawns),
+        };
+        let (winner, plies) = match plies.strip_prefix('-') {
+            Some(plies) => (Color::Black, plies),
+            None => (Color::White, plies),
+        };
+        Some(TextEval::Mate {
+            winner,
+            plies: plies.parse().ok()?,
+        })
+    }
+
+    /// The eval from the other side's point of view.
+    pub fn flip(self) -> Self {
+        match self {
+            TextEval::Centipawns(cp) => TextEval::Centipawns(-cp),
+            TextEval::Mate { winner, plies } => TextEval::Mate {
+                winner: !winner,
+                plies,
+            },
+        }
+    }
+
+    /// The eval as a record holds it, with centipawns truncated and saturated as
+    /// [`Eval::centipawns`] does.
+    pub fn to_eval(self) -> Eval {
+        match self {
+            TextEval::Centipawns(cp) => Eval::centipawns(cp as i64),
+            TextEval::Mate { winner, plies } => Eval::Mate { winner, plies },
+        }
+    }
+}
+
+/// Formats an eval in centipawns or as a mate score, as [`TextEval::parse`] reads it.
+pub fn format_eval(eval: Eval) -> String {
+    match eval {
+        Eval::Centipawns(cp) => cp.to_string(),
+        Eval::Mate {
+            winner: Color::White,
+            plies,
+        } => format!("#{plies}"),
+        Eval::Mate {
+            winner: Color::Black,
+            plies,
+        } => format!("#-{plies}"),
+    }
+}
+
+/// Parses a line in the canonical text form, with its columns split by `separator`, into a
+/// board, an eval and a fractional result. `frc` accepts Shredder-FENs as in [`parse_fen`].
+pub fn parse_line(line: &str, separator: &str, frc: bool) -> Option<(Board, TextEval, f32)> {
+    let mut columns = line.split(separator).map(str::trim);
+    let board = parse_fen(columns.next()?, frc)?;
+    let eval = TextEval::parse(columns.next()?)?;
+    let wdl = parse_result(columns.next()?).filter(|wdl| (0.0..=1.0).contains(wdl))?;
+    if columns.next().is_some() {
+        return None;
+    }
+
+    Some((board, eval, wdl))
+}
+
+/// Formats a record in the canonical text form, given its decoded eval and white's expected
+/// score as read by [`wdl_to_float`].
+pub fn format_line(board: &Board, eval: Eval, wdl: f32) -> String {
+    format!(
+        "{}{SEPARATOR}{}{SEPARATOR}{}",
+        fen(board),
+        format_eval(eval),
+        format_result(wdl)
+    )
+}
+
+/// Formats white's expected score as 1.0, 0.5 or 0.0 for a hard result, or with as many
+/// digits as a soft result needs.
+pub fn format_result(wdl: f32) -> String {
+    match (wdl * 2.0).fract() == 0.0 {
+        true => format!("{wdl:.1}"),
+        false => wdl.to_string(),
+    }
+}
+
+/// Parses a FEN. With `frc`, Shredder-FENs naming the files of the castling rooks, as
+/// needed for some Chess960 positions, are accepted too.
+pub fn parse_fen(fen: &str, frc: bool) -> Option<Board> {
+    match fen.parse() {
+        Ok(board) => Some(board),
+        Err(_) if frc => Board::from_fen(fen, true).ok(),
+        Err(_) => None,
+    }
+}
+
+/// Formats a board as a FEN, or as a Shredder-FEN if its castling rights are not those of
+/// standard chess, which a FEN cannot always express.
+pub fn fen(board: &Board) -> String {
+    match has_standard_castling(board) {
+        true => board.to_string(),
+        false => format!("{board:#}"),
+    }
+}
+
+/// Whether a board's castling rights, if any, are those of standard chess.
+pub fn has_standard_castling(board: &Board) -> bool {
+    Color::ALL.into_iter().all(|color| {
+        let rights = board.castle_rights(color);
+        let back_rank = Rank::First.relative_to(color);
+        rights.short.is_none() && rights.long.is_none()
+            || board.king(color) == Square::new(File::E, back_rank)
+                && rights.short.is_none_or(|file| file == File::H)
+                && rights.long.is_none_or(|file| file == File::A)
+    })
+}
+
+/// Parses a game result written as a number or in PGN notation, optionally quoted.
+pub fn parse_result(result: &str) -> Option<f32> {
+    match result.trim().trim_matches('"') {
+        "1-0" => Some(1.0),
+        "1/2-1/2" => Some(0.5),
+        "0-1" => Some(0.0),
+        result => result.parse().ok(),
+    }
+}
+
+/// Converts a fractional game result into a WDL label.
+pub fn wdl_from_float(wdl: f32) -> u8 {
+    match () {
+        _ if wdl < 0.25 => 0,
+        _ if wdl < 0.75 => 1,
+        _ => 2,
+    }
+}
@@ -1,17 +1,66 @@
+use std::ops::Range;
+use std::sync::Arc;
+
+use cozy_chess::Board;
+
+use crate::output_buckets::{OutputBuckets, SingleBucket};
+use crate::scalars::{self, SCALARS};
+use crate::value_format::ValueFormat;
+
+#[derive(Clone)]
 pub struct Batch {
     // The maximum number of entries
     capacity: usize,
 
     max_features: usize,
 
+    // With two indices per feature, the first half of each buffer holds the entry of each
+    // feature and the second half the feature, until `finish` packs them together
     stm_feature_buffer: Box<[i64]>,
     nstm_feature_buffer: Box<[i64]>,
     values: Box<[f32]>,
     total_features: usize,
     indices_per_feature: usize,
 
+    // The number of inputs of a dense batch, whose features are written as planes of that many
+    // floats per entry instead of indices, or 0 for a sparse batch
+    dense_inputs: usize,
+    stm_dense: Box<[f32]>,
+    nstm_dense: Box<[f32]>,
+
     cp: Box<[f32]>,
     wdl: Box<[f32]>,
+    weight: Box<[f32]>,
+    // `lambda * sigmoid(cp / scale) + (1 - lambda) * wdl` for each entry
+    target: Box<[f32]>,
+    eval_lambda: f32,
+    eval_scale: f32,
+    // How far each result is pulled towards a draw, from 0 for hard results to 1
+    wdl_smoothing: f32,
+    // Whether entries keep the weight stored in their record, rather than starting from 1
+    sample_weights: bool,
+    // The eval scale by which weights fall off with the eval, or 0 to leave them as they are
+    eval_weighting: f32,
+    // The eval, result and target of each entry packed by `finish`, unless the format is `F32`
+    value_format: ValueFormat,
+    packed_cp: Box<[u16]>,
+    packed_wdl: Box<[u16]>,
+    packed_target: Box<[u16]>,
+    // The policy index of the move of each entry, or -1
+    moves: Box<[i64]>,
+    // The output bucket of each entry
+    buckets: Box<[i64]>,
+    output_buckets: Arc<dyn OutputBuckets>,
+    // `SCALARS` scalar inputs for each entry
+    scalars: Box<[f32]>,
+    // The index of the first pawn structure feature, if they follow each entry's features
+    pawn_structure: Option<usize>,
+
+    // The index of the first feature of each entry
+    entry_offsets: Box<[u32]>,
+
+    // Checks the features of each entry, for debugging feature sets
+    validation: Option<Validation>,
 
     // The number of entries actually written
     entries: usize,
@@ -29,23 +78,194 @@ impl Batch {
             total_features: 0,
             indices_per_feature,
             values: vec![1.0; capacity * max_features].into_boxed_slice(),
+            dense_inputs: 0,
+            stm_dense: Box::new([]),
+            nstm_dense: Box::new([]),
             cp: vec![0_f32; capacity].into_boxed_slice(),
             wdl: vec![0_f32; capacity].into_boxed_slice(),
+            weight: vec![1_f32; capacity].into_boxed_slice(),
+            target: vec![0_f32; capacity].into_boxed_slice(),
+            eval_lambda: 0.0,
+            eval_scale: 1.0,
+            wdl_smoothing: 0.0,
+            sample_weights: true,
+            eval_weighting: 0.0,
+            value_format: ValueFormat::F32,
+            packed_cp: Box::new([]),
+            packed_wdl: Box::new([]),
+            packed_target: Box::new([]),
+            moves: vec![-1; capacity].into_boxed_slice(),
+            buckets: vec![0; capacity].into_boxed_slice(),
+            output_buckets: Arc::new(SingleBucket),
+            scalars: vec![0.0; capacity * SCALARS].into_boxed_slice(),
+            pawn_structure: None,
+            entry_offsets: vec![0; capacity].into_boxed_slice(),
+            validation: None,
             entries: 0,
         }
     }
 
-    pub fn make_entry(&mut self, cp: f32, wdl: f32) -> EntryFeatureWriter {
+    /// A batch that holds each entry's features as 0/1 planes of `inputs` floats, for models
+    /// that take dense inputs. Any feature set can be read into it.
+    pub fn new_dense(capacity: usize, inputs: usize) -> Self {
+        Self {
+            dense_inputs: inputs,
+            stm_dense: vec![0.0; capacity * inputs].into_boxed_slice(),
+            nstm_dense: vec![0.0; capacity * inputs].into_boxed_slice(),
+            ..Self::new(capacity, 0, 1)
+        }
+    }
+
+    pub fn set_output_buckets(&mut self, output_buckets: Arc<dyn OutputBuckets>) {
+        self.output_buckets = output_buckets;
+    }
+
+    /// Sets how the training target blends the eval, as a win probability, with the game
+    /// result. The target is the result alone until this is called.
+    pub fn set_target_blend(&mut self, lambda: f32, scale: f32) {
+        self.eval_lambda = lambda;
+        self.eval_scale = scale;
+    }
+
+    /// Smooths the result of each entry to `wdl * (1 - epsilon) + 0.5 * epsilon` before it is
+    /// written or blended into the target, so that hard results don't push the network towards
+    /// certainty.
+    pub fn set_wdl_smoothing(&mut self, epsilon: f32) {
+        self.wdl_smoothing = epsilon;
+    }
+
+    /// Checks that the features of each entry are below `num_inputs` and that none is written
+    /// twice, panicking otherwise, to catch buggy feature sets before they train a network on
+    /// wrong inputs. This slows reading down, so it is meant for debugging.
+    pub fn set_validation(&mut self, num_inputs: usize) {
+        self.validation = Some(Validation::new(num_inputs));
+    }
+
+    /// Whether each entry's weight starts from the sample weight of its record, as it does by
+    /// default, or from 1, which leaves only the eval weighting.
+    pub fn set_sample_weights(&mut self, enabled: bool) {
+        self.sample_weights = enabled;
+    }
+
+    /// Scales the weight of each entry by `4 * p * (1 - p)`, where `p = sigmoid(cp / scale)`,
+    /// which is 1 for level positions and falls towards 0 as the eval grows, so that decided
+    /// positions count for less. A scale of 0 turns this off.
+    pub fn set_eval_weighting(&mut self, scale: f32) {
+        self.eval_weighting = scale;
+    }
+
+    /// Packs the eval, result and target of each entry into 16 bits when the batch is finished.
+    pub fn set_value_format(&mut self, format: ValueFormat) {
+        let len = match format {
+            ValueFormat::F32 => 0,
+            _ => self.capacity,
+        };
+        self.value_format = format;
+        self.packed_cp = vec![0; len].into_boxed_slice();
+        self.packed_wdl = vec![0; len].into_boxed_slice();
+        self.packed_target = vec![0; len].into_boxed_slice();
+    }
+
+    /// Writes the features of `PawnStructure` alongside those of the feature set, with
+    /// `offset` added to their indices. The batch needs room for 48 more features per entry.
+    pub fn set_pawn_structure(&mut self, offset: usize) {
+        self.pawn_structure = Some(offset);
+    }
+
+    pub fn pawn_structure(&self) -> Option<usize> {
+        self.pawn_structure
+    }
+
+    pub fn make_entry(
+        &mut self,
+        board: &Board,
+        cp: f32,
+        wdl: f32,
+        weight: f32,
+        mv: i64,
+    ) -> EntryFeatureWriter {
         let index_in_batch = self.entries;
         self.entries += 1;
+        if let Some(validation) = &mut self.validation {
+            validation.start_entry();
+        }
+        self.buckets[index_in_batch] = self.output_buckets.bucket(board) as i64;
+        self.scalars[index_in_batch * SCALARS..(index_in_batch + 1) * SCALARS]
+            .copy_from_slice(&scalars::scalars(board));
+        let wdl = wdl * (1.0 - self.wdl_smoothing) + 0.5 * self.wdl_smoothing;
         self.cp[index_in_batch] = cp;
         self.wdl[index_in_batch] = wdl;
+        let weight = match self.sample_weights {
+            true => weight,
+            false => 1.0,
+        };
+        self.weight[index_in_batch] = match self.eval_weighting {
+            scale if scale > 0.0 => {
+                let p = 1.0 / (1.0 + (-cp / scale).exp());
+                weight * 4.0 * p * (1.0 - p)
+            }
+            _ => weight,
+        };
+        let win_probability = 1.0 / (1.0 + (-cp / self.eval_scale).exp());
+        self.target[index_in_batch] =
+            self.eval_lambda * win_probability + (1.0 - self.eval_lambda) * wdl;
+        self.moves[index_in_batch] = mv;
+        self.entry_offsets[index_in_batch] = self.total_features as u32;
+        let row = index_in_batch * self.dense_inputs..(index_in_batch + 1) * self.dense_inputs;
+        self.stm_dense[row.clone()].fill(0.0);
+        self.nstm_dense[row].fill(0.0);
         EntryFeatureWriter {
             batch: self,
             index_in_batch,
+            offset: 0,
+            completes_entry: true,
+        }
+    }
+
+    /// Packs the features written so far, once the batch is filled, so that the sparse indices
+    /// are a `2 x total_features` matrix of entries over features, the layout
+    /// `torch.sparse_coo_tensor` takes.
+    pub fn finish(&mut self) {
+        if self.indices_per_feature == 2 {
+            let half = self.capacity * self.max_features;
+            let features = half..half + self.total_features;
+            self.stm_feature_buffer
+                .copy_within(features.clone(), self.total_features);
+            self.nstm_feature_buffer
+                .copy_within(features, self.total_features);
+        }
+        if self.value_format != ValueFormat::F32 {
+            let format = self.value_format;
+            for entry in 0..self.entries {
+                self.packed_cp[entry] = format.pack_eval(self.cp[entry]);
+                self.packed_wdl[entry] = format.pack_fraction(self.wdl[entry]);
+                self.packed_target[entry] = format.pack_fraction(self.target[entry]);
+            }
         }
     }
 
+    /// The buffers that are copied to the device, for registering them as page-locked memory.
+    /// Their addresses stay the same for the life of the batch.
+    pub fn host_buffers(&self) -> [&[u8]; 15] {
+        [
+            bytemuck::cast_slice(&self.stm_feature_buffer),
+            bytemuck::cast_slice(&self.nstm_feature_buffer),
+            bytemuck::cast_slice(&self.values),
+            bytemuck::cast_slice(&self.stm_dense),
+            bytemuck::cast_slice(&self.nstm_dense),
+            bytemuck::cast_slice(&self.cp),
+            bytemuck::cast_slice(&self.wdl),
+            bytemuck::cast_slice(&self.weight),
+            bytemuck::cast_slice(&self.target),
+            bytemuck::cast_slice(&self.moves),
+            bytemuck::cast_slice(&self.buckets),
+            bytemuck::cast_slice(&self.scalars),
+            bytemuck::cast_slice(&self.packed_cp),
+            bytemuck::cast_slice(&self.packed_wdl),
+            bytemuck::cast_slice(&self.packed_target),
+        ]
+    }
+
     pub fn clear(&mut self) {
         self.entries = 0;
         self.total_features = 0;
@@ -59,16 +279,17 @@ impl Batch {
         self.entries
     }
 
+    // The feature buffers of dense batches are empty
     pub fn stm_feature_buffer_ptr(&self) -> *const i64 {
-        &self.stm_feature_buffer[0]
+        self.stm_feature_buffer.as_ptr()
     }
 
     pub fn nstm_feature_buffer_ptr(&self) -> *const i64 {
-        &self.nstm_feature_buffer[0]
+        self.nstm_feature_buffer.as_ptr()
     }
 
     pub fn values_ptr(&self) -> *const f32 {
-        &self.values[0]
+        self.values.as_ptr()
     }
 
     pub fn total_features(&self) -> usize {
@@ -79,6 +300,18 @@ impl Batch {
         self.indices_per_feature
     }
 
+    pub fn dense_inputs(&self) -> usize {
+        self.dense_inputs
+    }
+
+    pub fn stm_dense_ptr(&self) -> *const f32 {
+        self.stm_dense.as_ptr()
+    }
+
+    pub fn nstm_dense_ptr(&self) -> *const f32 {
+        self.nstm_dense.as_ptr()
+    }
+
     pub fn cp_ptr(&self) -> *const f32 {
         &self.cp[0]
     }
@@ -86,6 +319,98 @@ impl Batch {
     pub fn wdl_ptr(&self) -> *const f32 {
         &self.wdl[0]
     }
+
+    pub fn weight_ptr(&self) -> *const f32 {
+        &self.weight[0]
+    }
+
+    pub fn target_ptr(&self) -> *const f32 {
+        &self.target[0]
+    }
+
+    pub fn value_format(&self) -> ValueFormat {
+        self.value_format
+    }
+
+    // The packed buffers are empty for the `F32` format
+    pub fn packed_cp_ptr(&self) -> *const u16 {
+        self.packed_cp.as_ptr()
+    }
+
+    pub fn packed_wdl_ptr(&self) -> *const u16 {
+        self.packed_wdl.as_ptr()
+    }
+
+    pub fn packed_target_ptr(&self) -> *const u16 {
+        self.packed_target.as_ptr()
+    }
+
+    pub fn moves_ptr(&self) -> *const i64 {
+        &self.moves[0]
+    }
+
+    pub fn buckets_ptr(&self) -> *const i64 {
+        &self.buckets[0]
+    }
+
+    pub fn scalars_ptr(&self) -> *const f32 {
+        &self.scalars[0]
+    }
+
+    pub fn entry_offsets_ptr(&self) -> *const u32 {
+        &self.entry_offsets[0]
+    }
+}
+
+#[derive(Clone)]
+struct Validation {
+    num_inputs: usize,
+    // The features of the current entry from each side's point of view, as bitsets
+    seen: [Vec<u64>; 2],
+    written: Vec<[i64; 2]>,
+}
+
+impl Validation {
+    fn new(num_inputs: usize) -> Self {
+        let words = (num_inputs + 63) / 64;
+        Self {
+            num_inputs,
+            seen: [vec![0; words], vec![0; words]],
+            written: vec![],
+        }
+    }
+
+    fn start_entry(&mut self) {
+        for features in self.written.drain(..) {
+            for (seen, feature) in self.seen.iter_mut().zip(features) {
+                seen[feature as usize / 64] = 0;
+            }
+        }
+    }
+
+    fn check(&mut self, features: [i64; 2]) {
+        for (side, (seen, &feature)) in ["stm", "nstm"]
+            .iter()
+            .zip(self.seen.iter_mut().zip(&features))
+        {
+            assert!(
+                (0..self.num_inputs as i64).contains(&feature),
+                "{} feature {} is out of range for {} inputs",
+                side,
+                feature,
+                self.num_inputs
+            );
+            let (word, bit) = (feature as usize / 64, 1 << (feature % 64));
+            assert!(
+                seen[word] & bit == 0,
+                "{} feature {} is written twice for one entry",
+                side,
+                feature
+            );
+            seen[word] |= bit;
+        }
+        self.written.push(features);
+    }
 }
 
 pub struct SparseBatchWriter<'b> {
@@ -97,30 +422,47 @@ impl SparseBatchWriter<'_> {
         self.entry_feature_writer
             .add_feature_sparse(stm_feature, nstm_feature);
     }
+
+    /// Adds the features pairwise, as one copy into the batch rather than one write each.
+    pub fn add_features(&mut self, stm_features: &[i64], nstm_features: &[i64]) {
+        self.entry_feature_writer
+            .add_features_sparse(stm_features, nstm_features);
+    }
 }
 
 pub struct CudaBatchWriter<'b> {
     entry_feature_writer: EntryFeatureWriter<'b>,
-    count: usize,
 }
 
 impl CudaBatchWriter<'_> {
     pub fn add_feature(&mut self, stm_feature: i64, nstm_feature: i64) {
         self.entry_feature_writer
             .add_feature_cuda(stm_feature, nstm_feature);
-        self.count += 1;
+    }
+
+    /// Adds the features pairwise, as one copy into the batch rather than one write each.
+    pub fn add_features(&mut self, stm_features: &[i64], nstm_features: &[i64]) {
+        self.entry_feature_writer
+            .add_features_cuda(stm_features, nstm_features);
     }
 }
 
 impl<'b> Drop for CudaBatchWriter<'b> {
     fn drop(&mut self) {
-        self.entry_feature_writer.complete_cuda(self.count);
+        let batch = &self.entry_feature_writer.batch;
+        if self.entry_feature_writer.completes_entry && batch.dense_inputs == 0 {
+            self.entry_feature_writer.complete_cuda();
+        }
     }
 }
 
 pub struct EntryFeatureWriter<'b> {
     batch: &'b mut Batch,
     index_in_batch: usize,
+    // Added to every feature written
+    offset: i64,
+    // Whether the entry is padded once this writer is done, which only the last one may do
+    completes_entry: bool,
 }
 
 impl<'b> EntryFeatureWriter<'b> {
@@ -133,26 +475,110 @@ impl<'b> EntryFeatureWriter<'b> {
     pub fn cuda(self) -> CudaBatchWriter<'b> {
         CudaBatchWriter {
             entry_feature_writer: self,
-            count: 0,
+        }
+    }
+
+    /// A writer for more features of the same entry, such as virtual features that factorize
+    /// the main ones, with `offset` added to their indices. It must be used up before this
+    /// writer is.
+    pub fn extra_features(&mut self, offset: usize) -> EntryFeatureWriter<'_> {
+        EntryFeatureWriter {
+            batch: self.batch,
+            index_in_batch: self.index_in_batch,
+            offset: self.offset + offset as i64,
+            completes_entry: false,
         }
     }
 
     fn add_feature_sparse(&mut self, stm_feature: i64, nstm_feature: i64) {
+        let (stm_feature, nstm_feature) = (stm_feature + self.offset, nstm_feature + self.offset);
+        self.validate(stm_feature, nstm_feature);
+        if self.batch.dense_inputs > 0 {
+            return self.add_feature_dense(stm_feature, nstm_feature);
+        }
         let index = self.batch.total_features;
-        self.batch.stm_feature_buffer[index * 2] = self.index_in_batch as i64;
-        self.batch.nstm_feature_buffer[index * 2] = self.index_in_batch as i64;
-        self.batch.stm_feature_buffer[index * 2 + 1] = stm_feature;
-        self.batch.nstm_feature_buffer[index * 2 + 1] = nstm_feature;
+        let half = self.batch.capacity * self.batch.max_features;
+        self.batch.stm_feature_buffer[index] = self.index_in_batch as i64;
+        self.batch.nstm_feature_buffer[index] = self.index_in_batch as i64;
+        self.batch.stm_feature_buffer[half + index] = stm_feature;
+        self.batch.nstm_feature_buffer[half + index] = nstm_feature;
         self.batch.total_features += 1;
     }
 
     fn add_feature_cuda(&mut self, stm_feature: i64, nstm_feature: i64) {
+        let (stm_feature, nstm_feature) = (stm_feature + self.offset, nstm_feature + self.offset);
+        self.validate(stm_feature, nstm_feature);
+        if self.batch.dense_inputs > 0 {
+            return self.add_feature_dense(stm_feature, nstm_feature);
+        }
         self.batch.stm_feature_buffer[self.batch.total_features] = stm_feature;
         self.batch.nstm_feature_buffer[self.batch.total_features] = nstm_feature;
         self.batch.total_features += 1;
     }
 
-    fn complete_cuda(&mut self, count: usize) {
+    fn add_features_sparse(&mut self, stm_features: &[i64], nstm_features: &[i64]) {
+        if self.batch.validation.is_some() || self.batch.dense_inputs > 0 {
+            for (&stm_feature, &nstm_feature) in stm_features.iter().zip(nstm_features) {
+                self.add_feature_sparse(stm_feature, nstm_feature);
+            }
+            return;
+        }
+        let start = self.batch.total_features;
+        let features = start..start + stm_features.len();
+        let half = self.batch.capacity * self.batch.max_features;
+        let entry = self.index_in_batch as i64;
+        self.batch.stm_feature_buffer[features.clone()].fill(entry);
+        self.batch.nstm_feature_buffer[features.clone()].fill(entry);
+        let features = half + features.start..half + features.end;
+        self.write_features(features, stm_features, nstm_features);
+    }
+
+    fn add_features_cuda(&mut self, stm_features: &[i64], nstm_features: &[i64]) {
+        if self.batch.validation.is_some() || self.batch.dense_inputs > 0 {
+            for (&stm_feature, &nstm_feature) in stm_features.iter().zip(nstm_features) {
+                self.add_feature_cuda(stm_feature, nstm_feature);
+            }
+            return;
+        }
+        let start = self.batch.total_features;
+        self.write_features(
+            start..start + stm_features.len(),
+            stm_features,
+            nstm_features,
+        );
+    }
+
+    /// Copies the features to `range` of the feature buffers, adding the offset, and counts
+    /// them as written.
+    fn write_features(&mut self, range: Range<usize>, stm_features: &[i64], nstm_features: &[i64]) {
+        debug_assert_eq!(stm_features.len(), nstm_features.len());
+        let stm = &mut self.batch.stm_feature_buffer[range.clone()];
+        let nstm = &mut self.batch.nstm_feature_buffer[range];
+        stm.copy_from_slice(stm_features);
+        nstm.copy_from_slice(nstm_features);
+        if self.offset != 0 {
+            for feature in stm.iter_mut().chain(nstm.iter_mut()) {
+                *feature += self.offset;
+            }
+        }
+        self.batch.total_features += stm_features.len();
+    }
+
+    fn validate(&mut self, stm_feature: i64, nstm_feature: i64) {
+        if let Some(validation) = &mut self.batch.validation {
+            validation.check([stm_feature, nstm_feature]);
+        }
+    }
+
+    fn add_feature_dense(&mut self, stm_feature: i64, nstm_feature: i64) {
+        let row = self.index_in_batch * self.batch.dense_inputs;
+        self.batch.stm_dense[row + stm_feature as usize] = 1.0;
+        self.batch.nstm_dense[row + nstm_feature as usize] = 1.0;
+    }
+
+    fn complete_cuda(&mut self) {
+        let count =
+            self.batch.total_features - self.batch.entry_offsets[self.index_in_batch] as usize;
         let left_to_fill = self.batch.max_features - count;
         for _ in 0..left_to_fill {
             self.batch.stm_feature_buffer[self.batch.total_features] = -1;
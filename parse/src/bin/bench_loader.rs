@@ -0,0 +1,131 @@
+//! Measures how fast the loader fills batches of each feature set from a data file, through
+//! the same functions the trainer calls, to make regressions in the hot path visible and to
+//! pick a thread count for training.
+
+use std::ffi::{CStr, CString};
+use std::path::PathBuf;
+use std::time::Instant;
+
+use structopt::StructOpt;
+
+use parse::{
+    batch_drop, batch_new, feature_set_names, file_reader_new, file_reader_set_shuffle_buffer_size,
+    input_feature_set_from_name, input_feature_set_get_indices_per_feature,
+    input_feature_set_get_max_features, parse_last_error, prefetcher_drop, prefetcher_new,
+    prefetcher_next_batch, InputFeatureSetType,
+};
+
+/// Benchmark the loader, printing positions and batches per second for each feature set.
+#[derive(StructOpt)]
+struct Options {
+    /// The data file to read, which should be in the page cache to measure the loader rather
+    /// than the disk.
+    dataset: PathBuf,
+
+    /// Feature sets to measure, by name, or all of them if none are given.
+    #[structopt(long = "feature-set")]
+    feature_sets: Vec<String>,
+
+    #[structopt(long, default_value = "16384")]
+    batch_size: u32,
+
+    /// Threads filling batches in the background.
+    #[structopt(long, default_value = "1")]
+    threads: u32,
+
+    /// Batches to read for each feature set, after one to warm up. Fewer are read if the
+    /// file runs out.
+    #[structopt(long, default_value = "100")]
+    batches: u32,
+
+    #[structopt(long, default_value = "0")]
+    shuffle_buffer_size: u64,
+}
+
+fn main() {
+    let options = Options::from_args();
+    let names = match options.feature_sets.is_empty() {
+        true => feature_set_names().map(String::from).collect(),
+        false => options.feature_sets.clone(),
+    };
+
+    println!(
+        "{:<28} {:>8} {:>14} {:>12}",
+        "feature set", "batches", "positions/s", "batches/s"
+    );
+    for name in &names {
+        match bench(&options, name) {
+            Ok((batches, seconds)) => {
+                let batches_per_second = batches as f64 / seconds;
+                println!(
+                    "{:<28} {:>8} {:>14.0} {:>12.2}",
+                    name,
+                    batches,
+                    batches_per_second * options.batch_size as f64,
+                    batches_per_second
+                );
+            }
+            Err(error) => {
+                eprintln!("{}: {}", name, error);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// Reads up to `options.batches` batches of the feature set, returning how many were read and
+/// how long it took in seconds.
+fn bench(options: &Options, name: &str) -> Result<(u32, f64), String> {
+    let name = CString::new(name).map_err(|e| e.to_string())?;
+    let path =
+        CString::new(options.dataset.to_string_lossy().as_bytes()).map_err(|e| e.to_string())?;
+    unsafe {
+        let feature_set = input_feature_set_from_name(name.as_ptr());
+        if feature_set < 0 {
+            return Err(last_error());
+        }
+        let feature_set: InputFeatureSetType = std::mem::transmute(feature_set);
+
+        let reader = file_reader_new(path.as_ptr());
+        if reader.is_null() {
+            return Err(last_error());
+        }
+        file_reader_set_shuffle_buffer_size(reader, options.shuffle_buffer_size);
+        let template = batch_new(
+            options.batch_size,
+            input_feature_set_get_max_features(feature_set),
+            input_feature_set_get_indices_per_feature(feature_set),
+        );
+        let prefetcher = prefetcher_new(reader, feature_set, template, options.threads, 4);
+        batch_drop(template);
+
+        let result = match prefetcher_next_batch(prefetcher).is_null() {
+            true => Err(format!(
+                "{} has less than one batch",
+                options.dataset.display()
+            )),
+            false => {
+                let start = Instant::now();
+                let mut batches = 0;
+                while batches < options.batches && !prefetcher_next_batch(prefetcher).is_null() {
+                    batches += 1;
+                }
+                let error = parse_last_error();
+                match error.is_null() {
+                    true => Ok((batches, start.elapsed().as_secs_f64())),
+                    false => Err(last_error()),
+                }
+            }
+        };
+        prefetcher_drop(prefetcher);
+        result
+    }
+}
+
+unsafe fn last_error() -> String {
+    let error = parse_last_error();
+    match error.is_null() {
+        true => "unknown error".to_string(),
+        false => CStr::from_ptr(error).to_string_lossy().into_owned(),
+    }
+}
@@ -1,18 +1,31 @@
-use std::{fs::File, io::Read, path::Path};
+use std::{
+    fs::File,
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+};
 
-use bytemuck::Zeroable;
-use cozy_chess::{Board, Color};
-use marlinformat::PackedBoard;
+use bytemuck::Pod;
+use cozy_chess::{Board, BoardBuilder, Color, Move, Square};
+use marlinformat::index::Index;
+use marlinformat::io::{self as format_io, ReadAhead};
+use marlinformat::{wdl_to_float, Eval, Header, PackedBoard, PackedBoardV2, MATE_SCORE};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
 
-use crate::batch::Batch;
-use crate::input_features::InputFeatureSet;
+use crate::batch::{Batch, EntryFeatureWriter};
+use crate::filters::Filters;
+use crate::input_features::{InputFeatureSet, PawnStructure, PawnStructureCuda};
+use crate::policy::policy_index;
+use crate::random_access::{RandomAccess, RandomOrder};
 
 #[derive(Debug)]
 pub struct AnnotatedBoard {
-    board: Board,
+    pub(crate) board: Board,
     cp: f32,
     wdl: f32,
+    weight: f32,
+    mv: Option<Move>,
 }
 
 impl AnnotatedBoard {
@@ -22,87 +35,604 @@ impl AnnotatedBoard {
             Color::Black => (-self.cp, 1.0 - self.wdl),
         }
     }
+
+    /// The sample weight stored in the record's `extra` byte, or 1 if its file is not flagged
+    /// as holding weights.
+    pub fn weight(&self) -> f32 {
+        self.weight
+    }
+
+    /// The position mirrored across the vertical axis, swapping the a- and h-files, or `None` if
+    /// either side can still castle, which makes the position asymmetric.
+    pub fn mirrored(&self) -> Option<AnnotatedBoard> {
+        let can_castle = |color| {
+            let rights = self.board.castle_rights(color);
+            rights.short.is_some() || rights.long.is_some()
+        };
+        if can_castle(Color::White) || can_castle(Color::Black) {
+            return None;
+        }
+        let original = BoardBuilder::from_board(&self.board);
+        let mut builder = BoardBuilder::from_board(&self.board);
+        for &square in &Square::ALL {
+            builder.board[square.flip_file() as usize] = original.board[square as usize];
+        }
+        builder.en_passant = original.en_passant.map(Square::flip_file);
+        Some(AnnotatedBoard {
+            board: builder.build().ok()?,
+            mv: self.mv.map(|mv| Move {
+                from: mv.from.flip_file(),
+                to: mv.to.flip_file(),
+                promotion: mv.promotion,
+            }),
+            ..*self
+        })
+    }
+
+    /// The policy index of the record's move from the side to move's point of view, see
+    /// `policy_index`, or -1 if it has none.
+    pub fn relative_move(&self) -> i64 {
+        match self.mv {
+            Some(mv) => policy_index(mv, self.board.side_to_move()) as i64,
+            None => -1,
+        }
+    }
 }
 
-pub struct FileReader {
-    file: File,
+struct Source {
+    path: PathBuf,
+    file: Box<dyn Read + Send>,
+    // Set to read the records in a random order rather than in sequence.
+    random: Option<RandomAccess>,
+    // The number of records left to read, if the file's header records it.
+    remaining: Option<u64>,
+    // Whether the file holds version 2 records, which carry a move.
+    v2: bool,
+    // Whether the file holds soft results.
+    soft_wdl: bool,
+    // Whether the file's evals near `MATE_SCORE` are mate scores.
+    mate_scores: bool,
+    // Whether the `extra` byte of the file's records holds sample weights.
+    weights: bool,
+    weight: f64,
     packed_buffer: Vec<PackedBoard>,
+    packed_v2_buffer: Vec<PackedBoardV2>,
     board_buffer: Vec<Option<AnnotatedBoard>>,
 }
 
-impl FileReader {
-    pub fn new(path: impl AsRef<Path>) -> std::io::Result<Self> {
-        let file = File::open(path)?;
+impl Source {
+    fn new(
+        path: impl AsRef<Path>,
+        weight: f64,
+        random: Option<(RandomOrder, u64)>,
+        readahead: usize,
+    ) -> std::io::Result<Self> {
+        let mut file = open(path.as_ref())?;
+        let mut first = vec![];
+        (&mut file)
+            .take(std::mem::size_of::<PackedBoard>() as u64)
+            .read_to_end(&mut first)?;
+        let header = Header::parse(&first);
+        let file: Box<dyn Read + Send> = match header {
+            Some(header) if header.version() > Header::VERSION_V2 => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "unsupported marlinformat version",
+                ))
+            }
+            Some(_) => file,
+            // The first record is data, so put it back.
+            None => Box::new(Cursor::new(first).chain(file)),
+        };
+        let file: Box<dyn Read + Send> = match readahead {
+            0 => file,
+            _ if random.is_some() => file,
+            _ => Box::new(ReadAhead::new(
+                file,
+                (readahead / READAHEAD_BLOCKS).max(1 << 16),
+                READAHEAD_BLOCKS,
+            )),
+        };
+        // A current index gives the record count of files whose header does not, including
+        // compressed ones. An index that can't be read is treated as missing.
+        let indexed = Index::read_current(path.as_ref())
+            .ok()
+            .flatten()
+            .map(|index| index.records());
+        let records = header.and_then(|header| header.records()).or(indexed);
+        let random = match random {
+            Some((order, seed)) => Some(open_random_access(
+                path.as_ref(),
+                header,
+                records,
+                order,
+                seed,
+            )?),
+            None => None,
+        };
         Ok(Self {
+            path: path.as_ref().to_owned(),
             file,
+            remaining: match random {
+                Some(_) => None,
+                None => records,
+            },
+            random,
+            v2: header.is_some_and(|header| header.version() == Header::VERSION_V2),
+            soft_wdl: header.is_some_and(|header| header.flags() & Header::FLAG_SOFT_WDL != 0),
+            mate_scores: header
+                .is_some_and(|header| header.flags() & Header::FLAG_MATE_SCORES != 0),
+            weights: header.is_some_and(|header| header.flags() & Header::FLAG_WEIGHTS != 0),
+            weight,
             packed_buffer: vec![],
+            packed_v2_buffer: vec![],
             board_buffer: vec![],
         })
     }
 
-    fn try_fill_buffer(&mut self, chunk_size: usize) -> bool {
-        self.packed_buffer.resize(chunk_size, PackedBoard::zeroed());
-        let buffer = bytemuck::cast_slice_mut(&mut self.packed_buffer);
-        let mut bytes_read = 0;
-        loop {
-            match self.file.read(&mut buffer[bytes_read..]) {
-                Ok(0) => break,
-                Ok(some) => bytes_read += some,
-                Err(_) => break,
+    fn try_fill_buffer(&mut self, chunk_size: usize) -> std::io::Result<bool> {
+        let chunk_size = self
+            .remaining
+            .map_or(chunk_size, |remaining| chunk_size.min(remaining as usize));
+        let (soft_wdl, mate_scores) = (self.soft_wdl, self.mate_scores);
+        let cp = move |eval| centipawns(Eval::decode(eval, mate_scores));
+        // Only files flagged as holding weights use the `extra` byte for them.
+        let weights = self.weights;
+        let weight = move |extra| match weights {
+            true => marlinformat::weight_from_extra(extra),
+            false => 1.0,
+        };
+        let elems = match self.v2 {
+            true => {
+                let elems = read_chunk(
+                    &mut self.file,
+                    &mut self.random,
+                    &mut self.packed_v2_buffer,
+                    chunk_size,
+                )?;
+                self.packed_v2_buffer
+                    .par_iter()
+                    .map(|packed| {
+                        let (board, eval, wdl, extra, mv) = packed.unpack()?;
+                        annotate(
+                            board,
+                            cp(eval),
+                            wdl_to_float(wdl, soft_wdl),
+                            weight(extra),
+                            mv,
+                        )
+                    })
+                    .rev()
+                    .collect_into_vec(&mut self.board_buffer);
+                elems
             }
+            false => {
+                let elems = read_chunk(
+                    &mut self.file,
+                    &mut self.random,
+                    &mut self.packed_buffer,
+                    chunk_size,
+                )?;
+                self.packed_buffer
+                    .par_iter()
+                    .map(|packed| {
+                        let (board, eval, wdl, extra) = packed.unpack()?;
+                        annotate(
+                            board,
+                            cp(eval),
+                            wdl_to_float(wdl, soft_wdl),
+                            weight(extra),
+                            None,
+                        )
+                    })
+                    .rev()
+                    .collect_into_vec(&mut self.board_buffer);
+                elems
+            }
+        };
+        if let Some(remaining) = &mut self.remaining {
+            *remaining -= elems as u64;
         }
-        let elems = bytes_read / std::mem::size_of::<PackedBoard>();
-        self.packed_buffer.truncate(elems);
+        Ok(!self.board_buffer.is_empty())
+    }
 
-        self.packed_buffer
-            .par_iter()
-            .map(|packed| {
-                let (board, cp, wdl, _) = packed.unpack()?;
-                let cp = cp as f32;
-                let wdl = wdl as f32 / 2.0;
+    fn next_from_buffer(&mut self) -> Option<AnnotatedBoard> {
+        while let Some(maybe_board) = self.board_buffer.pop() {
+            if let Some(board) = maybe_board {
+                return Some(board);
+            }
+        }
+        None
+    }
 
-                if cp.abs() > 3000.0 {
-                    return None;
+    /// The next position, or `None` once the file is exhausted. Errors name the file.
+    fn next_board(&mut self) -> std::io::Result<Option<AnnotatedBoard>> {
+        loop {
+            if let Some(board) = self.next_from_buffer() {
+                return Ok(Some(board));
+            }
+            match self.try_fill_buffer(32_000) {
+                Ok(true) => {}
+                Ok(false) => return Ok(None),
+                Err(e) => {
+                    let message = format!("{}: {}", self.path.display(), e);
+                    return Err(std::io::Error::new(e.kind(), message));
                 }
+            }
+        }
+    }
+}
+
+/// The number of blocks the bytes read ahead are split into.
+const READAHEAD_BLOCKS: usize = 4;
+
+/// The zstd frame magic number.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+/// Opens a dataset, decompressing it as it is read if it is compressed with zstd.
+fn open(path: &Path) -> std::io::Result<Box<dyn Read + Send>> {
+    let mut magic = [0; 4];
+    let compressed = File::open(path)?.read_exact(&mut magic).is_ok() && magic == ZSTD_MAGIC;
+    let file = File::open(path)?;
+    format_io::advise_sequential(&file);
+    match compressed {
+        true => Ok(Box::new(zstd::stream::read::Decoder::new(file)?)),
+        false => Ok(Box::new(file)),
+    }
+}
+
+/// Opens a dataset to read its records in a random order, all `records` of them if the count
+/// is known, or as many as its size holds.
+fn open_random_access(
+    path: &Path,
+    header: Option<Header>,
+    records: Option<u64>,
+    order: RandomOrder,
+    seed: u64,
+) -> std::io::Result<RandomAccess> {
+    let file = File::open(path)?;
+    let mut magic = [0; 4];
+    if (&file).read_exact(&mut magic).is_ok() && magic == ZSTD_MAGIC {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "random access needs an uncompressed dataset, decompress it with `zstd -d` first",
+        ));
+    }
+    let start = match header {
+        Some(_) => std::mem::size_of::<Header>() as u64,
+        None => 0,
+    };
+    let record_size = match header.is_some_and(|header| header.version() == Header::VERSION_V2) {
+        true => std::mem::size_of::<PackedBoardV2>(),
+        false => std::mem::size_of::<PackedBoard>(),
+    };
+    let records = records.unwrap_or((file.metadata()?.len() - start) / record_size as u64);
+    Ok(RandomAccess::new(file, start, records, order, seed))
+}
+
+fn read_chunk<T: Pod>(
+    file: &mut impl Read,
+    random: &mut Option<RandomAccess>,
+    buffer: &mut Vec<T>,
+    count: usize,
+) -> std::io::Result<usize> {
+    match random {
+        Some(random) => random.read_records(buffer, count),
+        None => read_records(file, buffer, count),
+    }
+}
+
+/// Fills `buffer` with up to `count` records, returning how many were read.
+fn read_records<T: Pod>(
+    file: &mut impl Read,
+    buffer: &mut Vec<T>,
+    count: usize,
+) -> std::io::Result<usize> {
+    buffer.resize(count, T::zeroed());
+    let bytes_read = format_io::fill(file, bytemuck::cast_slice_mut(buffer))?;
+    let elems = bytes_read / std::mem::size_of::<T>();
+    buffer.truncate(elems);
+    Ok(elems)
+}
+
+/// An eval in centipawns from white's point of view. Mates count as `MATE_SCORE` less the
+/// plies to mate, beyond any centipawn eval, so that they are clamped or filtered like the
+/// largest evals.
+fn centipawns(eval: Eval) -> f32 {
+    match eval {
+        Eval::Centipawns(cp) => cp as f32,
+        Eval::Mate { winner, plies } => {
+            let score = MATE_SCORE as f32 - plies as f32;
+            match winner {
+                Color::White => score,
+                Color::Black => -score,
+            }
+        }
+    }
+}
+
+fn annotate(
+    board: Board,
+    cp: f32,
+    wdl: f32,
+    weight: f32,
+    mv: Option<Move>,
+) -> Option<AnnotatedBoard> {
+    Some(AnnotatedBoard {
+        board,
+        cp,
+        wdl,
+        weight,
+        mv,
+    })
+}
 
-                Some(AnnotatedBoard { board, cp, wdl })
+/// Reads positions from one or more datasets.
+///
+/// With several sources, each position is drawn from a source picked at random
+/// in proportion to its weight, so the datasets are interleaved on the fly.
+/// Sources that run dry drop out of the mix, and the reader is exhausted once
+/// every source is.
+///
+/// With a shuffle buffer, positions are drawn at random from a buffer of that
+/// many positions read ahead, which breaks up runs of positions from the same
+/// game in datasets that haven't been shuffled.
+///
+/// Every random choice, of sources, of positions to skip, of positions to mirror
+/// and of positions to take from the shuffle buffer, comes from one generator seeded with the seed
+/// plus the epoch number, so a run can be repeated exactly.
+///
+/// With a random order, the records of each dataset are read in a random order
+/// instead of in sequence, see `RandomAccess`.
+///
+/// If reading a dataset fails, the reader stops as if it were exhausted, and `take_error`
+/// says why.
+pub struct FileReader {
+    paths: Vec<(PathBuf, f64)>,
+    sources: Vec<Source>,
+    seed: u64,
+    epoch: u64,
+    rng: StdRng,
+    skip_probability: f64,
+    random_order: Option<RandomOrder>,
+    // The bytes of each dataset read ahead on a background thread, or 0 to read in place
+    readahead: usize,
+    mirroring: bool,
+    eval_clamp: f32,
+    filters: Filters,
+    shuffle_buffer: Vec<AnnotatedBoard>,
+    shuffle_buffer_size: usize,
+    // Why reading stopped early, if it did
+    error: Option<std::io::Error>,
+}
+
+impl FileReader {
+    pub fn new(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Self::mixed([(path, 1.0)], 0)
+    }
+
+    pub fn mixed<P: AsRef<Path>>(
+        sources: impl IntoIterator<Item = (P, f64)>,
+        seed: u64,
+    ) -> std::io::Result<Self> {
+        let paths: Vec<_> = sources
+            .into_iter()
+            .map(|(path, weight)| (path.as_ref().to_owned(), weight))
+            .collect();
+        if paths
+            .iter()
+            .any(|&(_, weight)| !(weight.is_finite() && weight > 0.0))
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "dataset weights must be positive",
+            ));
+        }
+        let mut reader = Self {
+            paths,
+            sources: vec![],
+            seed,
+            epoch: 0,
+            rng: StdRng::seed_from_u64(seed),
+            skip_probability: 0.0,
+            random_order: None,
+            readahead: 0,
+            mirroring: false,
+            eval_clamp: f32::INFINITY,
+            filters: Filters::default(),
+            shuffle_buffer: Vec::new(),
+            shuffle_buffer_size: 0,
+            error: None,
+        };
+        reader.open_sources()?;
+        Ok(reader)
+    }
+
+    /// Rewinds every dataset to start the next epoch.
+    pub fn reset(&mut self) -> std::io::Result<()> {
+        self.epoch += 1;
+        self.open_sources()
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Restarts the random choices of the current epoch from `seed`.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = StdRng::seed_from_u64(seed.wrapping_add(self.epoch));
+    }
+
+    /// Skips each position with this probability, to train on a random subset.
+    pub fn set_random_skip_probability(&mut self, probability: f64) {
+        self.skip_probability = probability.clamp(0.0, 1.0);
+    }
+
+    /// Mirrors each position across the vertical axis with probability 0.5, to augment the
+    /// data with the board's symmetry. Positions with castling rights are left as they are.
+    pub fn set_mirroring(&mut self, enabled: bool) {
+        self.mirroring = enabled;
+    }
+
+    pub fn set_filters(&mut self, filters: Filters) {
+        self.filters = filters;
+    }
+
+    /// Clamps evals to `bound` centipawns either way, so that mate and tablebase scores don't
+    /// saturate the target. Evals are clamped after filtering, so the default
+    /// `Filters::max_eval` has to be raised for the clamp to keep the positions it drops.
+    pub fn set_eval_clamp(&mut self, bound: f32) {
+        self.eval_clamp = bound;
+    }
+
+    /// Reads the records of each dataset in a random order, starting over from the
+    /// beginning of the epoch.
+    pub fn set_random_order(&mut self, order: RandomOrder) -> std::io::Result<()> {
+        self.random_order = Some(order);
+        self.open_sources()
+    }
+
+    /// Reads up to `bytes` of each dataset ahead on a background thread, so that reading
+    /// overlaps with parsing, starting over from the beginning of the epoch. Datasets read in
+    /// a random order aren't read ahead.
+    pub fn set_readahead(&mut self, bytes: usize) -> std::io::Result<()> {
+        self.readahead = bytes;
+        self.open_sources()
+    }
+
+    fn open_sources(&mut self) -> std::io::Result<()> {
+        self.rng = StdRng::seed_from_u64(self.seed.wrapping_add(self.epoch));
+        let (random_order, readahead) = (self.random_order, self.readahead);
+        let rng = &mut self.rng;
+        self.sources = self
+            .paths
+            .iter()
+            .map(|(path, weight)| {
+                let random = random_order.map(|order| (order, rng.gen()));
+                Source::new(path, *weight, random, readahead)
             })
-            .rev()
-            .collect_into_vec(&mut self.board_buffer);
-        !self.board_buffer.is_empty()
+            .collect::<std::io::Result<_>>()?;
+        self.shuffle_buffer.clear();
+        self.error = None;
+        Ok(())
     }
 
-    fn next_from_buffer(&mut self) -> Option<AnnotatedBoard> {
-        while let Some(maybe_board) = self.board_buffer.pop() {
-            if let Some(board) = maybe_board {
-                return Some(board);
+    pub fn set_shuffle_buffer_size(&mut self, size: usize) {
+        self.shuffle_buffer_size = size;
+        self.shuffle_buffer.reserve(size);
+    }
+
+    /// Why the reader stopped before the end of the epoch, if reading a dataset failed.
+    pub fn take_error(&mut self) -> Option<std::io::Error> {
+        self.error.take()
+    }
+
+    fn next_from_sources(&mut self) -> Option<AnnotatedBoard> {
+        while !self.sources.is_empty() {
+            let index = self.pick_source();
+            match self.sources[index].next_board() {
+                Ok(Some(board)) if !self.filters.keep(&board) => {}
+                Ok(Some(_))
+                    if self.skip_probability > 0.0 && self.rng.gen_bool(self.skip_probability) => {}
+                Ok(Some(board)) => return Some(self.transform(board)),
+                Ok(None) => {
+                    self.sources.swap_remove(index);
+                }
+                Err(e) => {
+                    // Carrying on with the other sources would quietly skew the mix.
+                    self.sources.clear();
+                    self.shuffle_buffer.clear();
+                    self.error = Some(e);
+                }
             }
         }
         None
     }
+
+    fn transform(&mut self, mut board: AnnotatedBoard) -> AnnotatedBoard {
+        board.cp = board.cp.clamp(-self.eval_clamp, self.eval_clamp);
+        if self.mirroring && self.rng.gen_bool(0.5) {
+            return board.mirrored().unwrap_or(board);
+        }
+        board
+    }
+
+    fn pick_source(&mut self) -> usize {
+        if self.sources.len() == 1 {
+            return 0;
+        }
+        let total: f64 = self.sources.iter().map(|source| source.weight).sum();
+        let mut spot = self.rng.gen_range(0.0..total);
+        for (index, source) in self.sources.iter().enumerate() {
+            if spot < source.weight {
+                return index;
+            }
+            spot -= source.weight;
+        }
+        self.sources.len() - 1
+    }
 }
 
 impl Iterator for FileReader {
     type Item = AnnotatedBoard;
 
     fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            if let Some(board) = self.next_from_buffer() {
-                return Some(board);
-            }
-            if !self.try_fill_buffer(32_000) {
-                return None;
+        while self.shuffle_buffer.len() < self.shuffle_buffer_size {
+            match self.next_from_sources() {
+                Some(board) => self.shuffle_buffer.push(board),
+                None => break,
             }
         }
+        if self.shuffle_buffer.is_empty() {
+            return self.next_from_sources();
+        }
+        let index = self.rng.gen_range(0..self.shuffle_buffer.len());
+        Some(self.shuffle_buffer.swap_remove(index))
     }
 }
 
-pub fn read_batch_into<F: InputFeatureSet>(reader: &mut FileReader, batch: &mut Batch) -> bool {
+/// Fills the batch from the reader, returning whether it could be filled entirely, or the
+/// error that stopped the reader.
+pub fn read_batch_with(
+    reader: &mut FileReader,
+    batch: &mut Batch,
+    add_features: impl Fn(Board, EntryFeatureWriter),
+) -> std::io::Result<bool> {
+    let capacity = batch.capacity();
+    fill_batch(reader.by_ref().take(capacity), batch, add_features);
+    match reader.take_error() {
+        Some(e) => Err(e),
+        None => Ok(batch.capacity() == batch.len()),
+    }
+}
+
+/// Clears the batch and writes the given positions to it.
+pub fn fill_batch(
+    boards: impl IntoIterator<Item = AnnotatedBoard>,
+    batch: &mut Batch,
+    add_features: impl Fn(Board, EntryFeatureWriter),
+) {
     batch.clear();
-    for annotated in reader.take(batch.capacity()) {
+    for annotated in boards {
         let (cp, wdl) = annotated.relative_value();
-        let entry = batch.make_entry(cp, wdl);
-        F::add_features(annotated.board, entry);
+        let pawn_structure = batch.pawn_structure();
+        let cuda = batch.indices_per_feature() == 1;
+        let mut entry = batch.make_entry(
+            &annotated.board,
+            cp,
+            wdl,
+            annotated.weight(),
+            annotated.relative_move(),
+        );
+        if let Some(offset) = pawn_structure {
+            let board = annotated.board.clone();
+            match cuda {
+                true => PawnStructureCuda::add_features(board, entry.extra_features(offset)),
+                false => PawnStructure::add_features(board, entry.extra_features(offset)),
+            }
+        }
+        add_features(annotated.board, entry);
     }
-    batch.capacity() == batch.len()
+    batch.finish();
 }
@@ -0,0 +1,46 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Runs the body of an FFI function, returning `failed` if it fails or panics, and records
+/// why for `last_error`, since a panic must not unwind into the caller. The error recorded
+/// by any earlier call on this thread is cleared first.
+pub fn guard<T>(failed: T, f: impl FnOnce() -> Result<T, String>) -> T {
+    set_last_error(None);
+    let error = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => return value,
+        Ok(Err(error)) => error,
+        Err(payload) => format!("panicked: {}", panic_message(&*payload)),
+    };
+    set_last_error(Some(error));
+    failed
+}
+
+/// The error recorded by the last failed call on this thread, or null if the last call
+/// succeeded. The string stays valid until the next guarded call on this thread.
+pub fn last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(error) => error.as_ptr(),
+        None => std::ptr::null(),
+    })
+}
+
+fn set_last_error(error: Option<String>) {
+    let error = error.map(|error| CString::new(error.replace('\0', "")).unwrap());
+    LAST_ERROR.with(|last| *last.borrow_mut() = error);
+}
+
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload
+            .downcast_ref::<String>()
+            .map_or("unknown panic", String::as_str),
+    }
+}
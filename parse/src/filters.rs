@@ -0,0 +1,62 @@
+use cozy_chess::Color;
+
+use crate::data_loader::AnnotatedBoard;
+
+/// Positions to leave out while reading, so that quick experiments don't need a filtered copy
+/// of a dataset. The defaults keep everything but evals beyond `DEFAULT_MAX_EVAL`.
+#[derive(Clone)]
+pub struct Filters {
+    /// The largest eval kept, in centipawns either way. Mate scores are beyond any centipawn
+    /// eval.
+    pub max_eval: f32,
+    /// The largest eval kept in favour of the side that went on to lose the game.
+    pub max_incongruent_eval: f32,
+    /// The range of pieces on the board kept, kings included.
+    pub min_pieces: u32,
+    pub max_pieces: u32,
+    /// The range of plies since the start of the game kept, by the fullmove number.
+    pub min_ply: u32,
+    pub max_ply: u32,
+    pub skip_in_check: bool,
+}
+
+/// The largest eval kept by default, past which evals are mostly mates and won endgames that
+/// say little about the position.
+pub const DEFAULT_MAX_EVAL: f32 = 3000.0;
+
+impl Default for Filters {
+    fn default() -> Self {
+        Self {
+            max_eval: DEFAULT_MAX_EVAL,
+            max_incongruent_eval: f32::INFINITY,
+            min_pieces: 0,
+            max_pieces: u32::MAX,
+            min_ply: 0,
+            max_ply: u32::MAX,
+            skip_in_check: false,
+        }
+    }
+}
+
+impl Filters {
+    pub fn keep(&self, annotated: &AnnotatedBoard) -> bool {
+        let board = &annotated.board;
+        let (cp, wdl) = annotated.relative_value();
+        // The eval in favour of the side that lost, or 0 for draws and eval in favour of the
+        // winner.
+        let incongruence = match wdl {
+            wdl if wdl < 0.5 => cp,
+            wdl if wdl > 0.5 => -cp,
+            _ => 0.0,
+        };
+        let pieces = board.occupied().len();
+        let ply = (board.fullmove_number() as u32).saturating_sub(1) * 2
+            + (board.side_to_move() == Color::Black) as u32;
+
+        cp.abs() <= self.max_eval
+            && incongruence <= self.max_incongruent_eval
+            && (self.min_pieces..=self.max_pieces).contains(&pieces)
+            && (self.min_ply..=self.max_ply).contains(&ply)
+            && (!self.skip_in_check || board.checkers().is_empty())
+    }
+}
@@ -11,44 +11,26 @@ pub struct Board768Cuda;
 impl InputFeatureSet for Board768 {
     const MAX_FEATURES: usize = 32;
     const INDICES_PER_FEATURE: usize = 2;
+    const NUM_INPUTS: usize = 768;
 
     fn add_features(board: Board, entry: EntryFeatureWriter) {
-        let mut sparse_entry = entry.sparse();
-        let stm = board.side_to_move();
-
-        for &color in &Color::ALL {
-            for &piece in &Piece::ALL {
-                for square in board.pieces(piece) & board.colors(color) {
-                    let stm_feature = feature(stm, color, piece, square);
-                    let nstm_feature = feature(!stm, color, piece, square);
-                    sparse_entry.add_feature(stm_feature as i64, nstm_feature as i64);
-                }
-            }
-        }
+        let features = PieceFeatures::new(&board, [0, 0]);
+        entry.sparse().add_features(features.stm(), features.nstm());
     }
 }
 
 impl InputFeatureSet for Board768Cuda {
     const MAX_FEATURES: usize = 32;
     const INDICES_PER_FEATURE: usize = 1;
+    const NUM_INPUTS: usize = 768;
 
     fn add_features(board: Board, entry: EntryFeatureWriter) {
-        let mut cuda_entry = entry.cuda();
-        let stm = board.side_to_move();
-
-        for &color in &Color::ALL {
-            for &piece in &Piece::ALL {
-                for square in board.pieces(piece) & board.colors(color) {
-                    let stm_feature = feature(stm, color, piece, square);
-                    let nstm_feature = feature(!stm, color, piece, square);
-                    cuda_entry.add_feature(stm_feature as i64, nstm_feature as i64);
-                }
-            }
-        }
+        let features = PieceFeatures::new(&board, [0, 0]);
+        entry.cuda().add_features(features.stm(), features.nstm());
     }
 }
 
-fn feature(perspective: Color, color: Color, piece: Piece, square: Square) -> usize {
+pub(super) fn feature(perspective: Color, color: Color, piece: Piece, square: Square) -> usize {
     let (square, color) = match perspective {
         Color::White => (square, color),
         Color::Black => (square.flip_rank(), !color),
@@ -59,3 +41,60 @@ fn feature(perspective: Color, color: Color, piece: Piece, square: Square) -> us
     index = index * Square::NUM + square as usize;
     index
 }
+
+/// The `Board768` features of every piece from both sides' points of view, computed a plane
+/// of pieces at a time, plus an offset for each side, for feature sets that are `Board768`
+/// repeated per king square.
+pub(super) struct PieceFeatures {
+    features: [[i64; 32]; 2],
+    len: usize,
+}
+
+impl PieceFeatures {
+    pub fn new(board: &Board, offsets: [usize; 2]) -> Self {
+        let stm = board.side_to_move();
+        let mut features = Self {
+            features: [[0; 32]; 2],
+            len: 0,
+        };
+        let stm_flip = flip_mask(stm);
+        let nstm_flip = flip_mask(!stm);
+        for &color in &Color::ALL {
+            for &piece in &Piece::ALL {
+                let pieces = board.pieces(piece) & board.colors(color);
+                // The feature of the piece on the square each side sees as a1
+                let stm_plane = plane(stm, color, piece, offsets[0]);
+                let nstm_plane = plane(!stm, color, piece, offsets[1]);
+                for square in pieces {
+                    let square = square as i64;
+                    features.features[0][features.len] = stm_plane + (square ^ stm_flip);
+                    features.features[1][features.len] = nstm_plane + (square ^ nstm_flip);
+                    features.len += 1;
+                }
+            }
+        }
+        features
+    }
+
+    pub fn stm(&self) -> &[i64] {
+        &self.features[0][..self.len]
+    }
+
+    pub fn nstm(&self) -> &[i64] {
+        &self.features[1][..self.len]
+    }
+}
+
+fn plane(perspective: Color, color: Color, piece: Piece, offset: usize) -> i64 {
+    let a1 = Square::index(flip_mask(perspective) as usize);
+    (offset + feature(perspective, color, piece, a1)) as i64
+}
+
+/// The mask that flips the rank of a square index when xored with it, if `perspective` is
+/// black.
+pub(super) fn flip_mask(perspective: Color) -> i64 {
+    match perspective {
+        Color::White => 0,
+        Color::Black => 56,
+    }
+}
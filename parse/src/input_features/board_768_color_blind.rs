@@ -0,0 +1,52 @@
+use cozy_chess::{Board, Color, Piece};
+
+use crate::batch::EntryFeatureWriter;
+
+use super::board_768::feature;
+use super::InputFeatureSet;
+
+/// `Board768` blind to the side to move: both perspectives see the board from white's side,
+/// so the stm and nstm features are the same, for networks with a single accumulator that
+/// leave the side to move to an output bucket or not at all.
+pub struct Board768ColorBlind;
+pub struct Board768ColorBlindCuda;
+
+impl InputFeatureSet for Board768ColorBlind {
+    const MAX_FEATURES: usize = 32;
+    const INDICES_PER_FEATURE: usize = 2;
+    const NUM_INPUTS: usize = 768;
+    const PERSPECTIVE: bool = false;
+
+    fn add_features(board: Board, entry: EntryFeatureWriter) {
+        let mut sparse_entry = entry.sparse();
+
+        for &color in &Color::ALL {
+            for &piece in &Piece::ALL {
+                for square in board.pieces(piece) & board.colors(color) {
+                    let feature = feature(Color::White, color, piece, square);
+                    sparse_entry.add_feature(feature as i64, feature as i64);
+                }
+            }
+        }
+    }
+}
+
+impl InputFeatureSet for Board768ColorBlindCuda {
+    const MAX_FEATURES: usize = 32;
+    const INDICES_PER_FEATURE: usize = 1;
+    const NUM_INPUTS: usize = 768;
+    const PERSPECTIVE: bool = false;
+
+    fn add_features(board: Board, entry: EntryFeatureWriter) {
+        let mut cuda_entry = entry.cuda();
+
+        for &color in &Color::ALL {
+            for &piece in &Piece::ALL {
+                for square in board.pieces(piece) & board.colors(color) {
+                    let feature = feature(Color::White, color, piece, square);
+                    cuda_entry.add_feature(feature as i64, feature as i64);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use cozy_chess::{Board, Color, File, Piece, Square};
+
+use crate::batch::EntryFeatureWriter;
+
+use super::board_768::feature;
+use super::InputFeatureSet;
+
+/// `Board768` with the board mirrored so that each perspective's king is on files a-d, a
+/// cheap way to share weights between the two halves of the board.
+pub struct Board768Mirrored;
+pub struct Board768MirroredCuda;
+
+impl InputFeatureSet for Board768Mirrored {
+    const MAX_FEATURES: usize = 32;
+    const INDICES_PER_FEATURE: usize = 2;
+    const NUM_INPUTS: usize = 768;
+
+    fn add_features(board: Board, entry: EntryFeatureWriter) {
+        let mut sparse_entry = entry.sparse();
+        let stm = board.side_to_move();
+
+        let stm_mirrored = board.king(stm).file() >= File::E;
+        let nstm_mirrored = board.king(!stm).file() >= File::E;
+
+        for &color in &Color::ALL {
+            for &piece in &Piece::ALL {
+                for square in board.pieces(piece) & board.colors(color) {
+                    let stm_feature = mirrored_feature(stm, stm_mirrored, color, piece, square);
+                    let nstm_feature = mirrored_feature(!stm, nstm_mirrored, color, piece, square);
+                    sparse_entry.add_feature(stm_feature as i64, nstm_feature as i64);
+                }
+            }
+        }
+    }
+}
+
+impl InputFeatureSet for Board768MirroredCuda {
+    const MAX_FEATURES: usize = 32;
+    const INDICES_PER_FEATURE: usize = 1;
+    const NUM_INPUTS: usize = 768;
+
+    fn add_features(board: Board, entry: EntryFeatureWriter) {
+        let mut cuda_entry = entry.cuda();
+        let stm = board.side_to_move();
+
+        let stm_mirrored = board.king(stm).file() >= File::E;
+        let nstm_mirrored = board.king(!stm).file() >= File::E;
+
+        for &color in &Color::ALL {
+            for &piece in &Piece::ALL {
+                for square in board.pieces(piece) & board.colors(color) {
+                    let stm_feature = mirrored_feature(stm, stm_mirrored, color, piece, square);
+                    let nstm_feature = mirrored_feature(!stm, nstm_mirrored, color, piece, square);
+                    cuda_entry.add_feature(stm_feature as i64, nstm_feature as i64);
+                }
+            }
+        }
+    }
+}
+
+fn mirrored_feature(
+    perspective: Color,
+    mirrored: bool,
+    color: Color,
+    piece: Piece,
+    square: Square,
+) -> usize {
+    let square = match mirrored {
+        true => square.flip_file(),
+        false => square,
+    };
+    feature(perspective, color, piece, square)
+}
@@ -0,0 +1,26 @@
+use std::marker::PhantomData;
+
+use cozy_chess::Board;
+
+use crate::batch::EntryFeatureWriter;
+
+use super::InputFeatureSet;
+
+/// The features of `F` together with virtual features of `V`, which are placed after those of
+/// `F` so that one feature transformer can learn both. `V` is usually a coarser view of the
+/// board than `F`, such as `Board768` for `HalfKa`, whose weights are shared by every king
+/// square and can be folded into the main weights once training is done.
+pub struct Factorized<F, V>(PhantomData<(F, V)>);
+
+impl<F: InputFeatureSet, V: InputFeatureSet> InputFeatureSet for Factorized<F, V> {
+    const MAX_FEATURES: usize = F::MAX_FEATURES + V::MAX_FEATURES;
+    const INDICES_PER_FEATURE: usize = F::INDICES_PER_FEATURE;
+    const NUM_INPUTS: usize = F::NUM_INPUTS + V::NUM_INPUTS;
+    const PERSPECTIVE: bool = F::PERSPECTIVE && V::PERSPECTIVE;
+
+    fn add_features(board: Board, mut entry: EntryFeatureWriter) {
+        debug_assert_eq!(F::INDICES_PER_FEATURE, V::INDICES_PER_FEATURE);
+        V::add_features(board.clone(), entry.extra_features(F::NUM_INPUTS));
+        F::add_features(board, entry);
+    }
+}
@@ -2,6 +2,7 @@ use cozy_chess::{Board, Color, Piece, Square};
 
 use crate::batch::EntryFeatureWriter;
 
+use super::board_768::{flip_mask, PieceFeatures};
 use super::InputFeatureSet;
 
 pub struct HalfKa;
@@ -10,58 +11,31 @@ pub struct HalfKaCuda;
 impl InputFeatureSet for HalfKa {
     const MAX_FEATURES: usize = 32;
     const INDICES_PER_FEATURE: usize = 2;
+    const NUM_INPUTS: usize = 49152;
 
     fn add_features(board: Board, entry: EntryFeatureWriter) {
-        let mut sparse_entry = entry.sparse();
-        let stm = board.side_to_move();
-
-        let stm_king = board.king(stm);
-        let nstm_king = board.king(!stm);
-
-        for &color in &Color::ALL {
-            for &piece in &Piece::ALL {
-                for square in board.pieces(piece) & board.colors(color) {
-                    let stm_feature = feature(stm, stm_king, color, piece, square);
-                    let nstm_feature = feature(!stm, nstm_king, color, piece, square);
-                    sparse_entry.add_feature(stm_feature as i64, nstm_feature as i64);
-                }
-            }
-        }
+        let features = PieceFeatures::new(&board, king_offsets(&board));
+        entry.sparse().add_features(features.stm(), features.nstm());
     }
 }
 
 impl InputFeatureSet for HalfKaCuda {
     const MAX_FEATURES: usize = 32;
     const INDICES_PER_FEATURE: usize = 1;
+    const NUM_INPUTS: usize = 49152;
 
     fn add_features(board: Board, entry: EntryFeatureWriter) {
-        let mut cuda_entry = entry.cuda();
-        let stm = board.side_to_move();
-
-        let stm_king = board.king(stm);
-        let nstm_king = board.king(!stm);
-
-        for &color in &Color::ALL {
-            for &piece in &Piece::ALL {
-                for square in board.pieces(piece) & board.colors(color) {
-                    let stm_feature = feature(stm, stm_king, color, piece, square);
-                    let nstm_feature = feature(!stm, nstm_king, color, piece, square);
-                    cuda_entry.add_feature(stm_feature as i64, nstm_feature as i64);
-                }
-            }
-        }
+        let features = PieceFeatures::new(&board, king_offsets(&board));
+        entry.cuda().add_features(features.stm(), features.nstm());
     }
 }
 
-fn feature(perspective: Color, king: Square, color: Color, piece: Piece, square: Square) -> usize {
-    let (king, square, color) = match perspective {
-        Color::White => (king, square, color),
-        Color::Black => (king.flip_rank(), square.flip_rank(), !color),
+/// The offset of the features of each side's king square, from that side's point of view.
+fn king_offsets(board: &Board) -> [usize; 2] {
+    let stm = board.side_to_move();
+    let king_offset = |perspective| {
+        let king = board.king(perspective) as i64 ^ flip_mask(perspective);
+        king as usize * Color::NUM * Piece::NUM * Square::NUM
     };
-    let mut index = 0;
-    index = index * Square::NUM + king as usize;
-    index = index * Color::NUM + color as usize;
-    index = index * Piece::NUM + piece as usize;
-    index = index * Square::NUM + square as usize;
-    index
+    [king_offset(stm), king_offset(!stm)]
 }
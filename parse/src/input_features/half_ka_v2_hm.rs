@@ -0,0 +1,80 @@
+use cozy_chess::{Board, Color, File, Piece, Square};
+
+use crate::batch::EntryFeatureWriter;
+
+use super::InputFeatureSet;
+
+/// HalfKA with the board mirrored so that each perspective's king is on files a-d, giving
+/// 32 king buckets. Both kings share a piece plane, since a king's own position is already
+/// given by the bucket.
+pub struct HalfKaV2Hm;
+pub struct HalfKaV2HmCuda;
+
+impl InputFeatureSet for HalfKaV2Hm {
+    const MAX_FEATURES: usize = 32;
+    const INDICES_PER_FEATURE: usize = 2;
+    const NUM_INPUTS: usize = 22528;
+
+    fn add_features(board: Board, entry: EntryFeatureWriter) {
+        let mut sparse_entry = entry.sparse();
+        let stm = board.side_to_move();
+
+        let stm_king = board.king(stm);
+        let nstm_king = board.king(!stm);
+
+        for &color in &Color::ALL {
+            for &piece in &Piece::ALL {
+                for square in board.pieces(piece) & board.colors(color) {
+                    let stm_feature = feature(stm, stm_king, color, piece, square);
+                    let nstm_feature = feature(!stm, nstm_king, color, piece, square);
+                    sparse_entry.add_feature(stm_feature as i64, nstm_feature as i64);
+                }
+            }
+        }
+    }
+}
+
+impl InputFeatureSet for HalfKaV2HmCuda {
+    const MAX_FEATURES: usize = 32;
+    const INDICES_PER_FEATURE: usize = 1;
+    const NUM_INPUTS: usize = 22528;
+
+    fn add_features(board: Board, entry: EntryFeatureWriter) {
+        let mut cuda_entry = entry.cuda();
+        let stm = board.side_to_move();
+
+        let stm_king = board.king(stm);
+        let nstm_king = board.king(!stm);
+
+        for &color in &Color::ALL {
+            for &piece in &Piece::ALL {
+                for square in board.pieces(piece) & board.colors(color) {
+                    let stm_feature = feature(stm, stm_king, color, piece, square);
+                    let nstm_feature = feature(!stm, nstm_king, color, piece, square);
+                    cuda_entry.add_feature(stm_feature as i64, nstm_feature as i64);
+                }
+            }
+        }
+    }
+}
+
+fn feature(perspective: Color, king: Square, color: Color, piece: Piece, square: Square) -> usize {
+    let (king, square, color) = match perspective {
+        Color::White => (king, square, color),
+        Color::Black => (king.flip_rank(), square.flip_rank(), !color),
+    };
+    let (king, square) = match king.file() >= File::E {
+        true => (king.flip_file(), square.flip_file()),
+        false => (king, square),
+    };
+    let plane = match piece {
+        Piece::King => Color::NUM * (Piece::NUM - 1),
+        _ => color as usize * (Piece::NUM - 1) + piece as usize,
+    };
+    let bucket = king.rank() as usize * 4 + king.file() as usize;
+    let mut index = 0;
+    index = index * (Square::NUM / 2) + bucket;
+    index = index * (Color::NUM * (Piece::NUM - 1) + 1) + plane;
+    index = index * Square::NUM + square as usize;
+    index
+}
@@ -11,6 +11,7 @@ pub struct HalfKpCuda;
 impl InputFeatureSet for HalfKp {
     const MAX_FEATURES: usize = 30;
     const INDICES_PER_FEATURE: usize = 2;
+    const NUM_INPUTS: usize = 40960;
 
     fn add_features(board: Board, entry: EntryFeatureWriter) {
         let mut sparse_entry = entry.sparse();
@@ -37,6 +38,7 @@ impl InputFeatureSet for HalfKp {
 impl InputFeatureSet for HalfKpCuda {
     const MAX_FEATURES: usize = 30;
     const INDICES_PER_FEATURE: usize = 1;
+    const NUM_INPUTS: usize = 40960;
 
     fn add_features(board: Board, entry: EntryFeatureWriter) {
         let mut cuda_entry = entry.cuda();
@@ -0,0 +1,66 @@
+use cozy_chess::{Board, Color, Piece, Square};
+
+use crate::batch::EntryFeatureWriter;
+
+/// HalfKA with king squares grouped into buckets by a map given at runtime, so that bucket
+/// layouts can be tried without adding a feature set for each. The map is indexed by the
+/// square of each perspective's king, seen from that side with its pieces at the bottom.
+pub struct KingBuckets {
+    buckets: [u8; Square::NUM],
+    cuda: bool,
+}
+
+impl KingBuckets {
+    pub fn new(buckets: [u8; Square::NUM], cuda: bool) -> Self {
+        Self { buckets, cuda }
+    }
+
+    pub fn add_features(&self, board: Board, entry: EntryFeatureWriter) {
+        match self.cuda {
+            true => {
+                let mut cuda_entry = entry.cuda();
+                self.for_each_feature(&board, |stm, nstm| cuda_entry.add_feature(stm, nstm));
+            }
+            false => {
+                let mut sparse_entry = entry.sparse();
+                self.for_each_feature(&board, |stm, nstm| sparse_entry.add_feature(stm, nstm));
+            }
+        }
+    }
+
+    fn for_each_feature(&self, board: &Board, mut f: impl FnMut(i64, i64)) {
+        let stm = board.side_to_move();
+
+        let stm_king = board.king(stm);
+        let nstm_king = board.king(!stm);
+
+        for &color in &Color::ALL {
+            for &piece in &Piece::ALL {
+                for square in board.pieces(piece) & board.colors(color) {
+                    let stm_feature = self.feature(stm, stm_king, color, piece, square);
+                    let nstm_feature = self.feature(!stm, nstm_king, color, piece, square);
+                    f(stm_feature as i64, nstm_feature as i64);
+                }
+            }
+        }
+    }
+
+    fn feature(
+        &self,
+        perspective: Color,
+        king: Square,
+        color: Color,
+        piece: Piece,
+        square: Square,
+    ) -> usize {
+        let (king, square, color) = match perspective {
+            Color::White => (king, square, color),
+            Color::Black => (king.flip_rank(), square.flip_rank(), !color),
+        };
+        let mut index = self.buckets[king as usize] as usize;
+        index = index * Color::NUM + color as usize;
+        index = index * Piece::NUM + piece as usize;
+        index = index * Square::NUM + square as usize;
+        index
+    }
+}
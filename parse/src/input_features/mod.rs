@@ -3,19 +3,43 @@ use cozy_chess::Board;
 use crate::batch::EntryFeatureWriter;
 
 mod board_768;
+mod board_768_color_blind;
+mod board_768_mirrored;
+mod factorized;
 mod half_ka;
+mod half_ka_v2_hm;
 mod half_kp;
+mod king_buckets;
+mod pawn_structure;
+mod threats;
 
 pub use board_768::Board768;
 pub use board_768::Board768Cuda;
+pub use board_768_color_blind::Board768ColorBlind;
+pub use board_768_color_blind::Board768ColorBlindCuda;
+pub use board_768_mirrored::Board768Mirrored;
+pub use board_768_mirrored::Board768MirroredCuda;
+pub use factorized::Factorized;
 pub use half_ka::HalfKa;
 pub use half_ka::HalfKaCuda;
+pub use half_ka_v2_hm::HalfKaV2Hm;
+pub use half_ka_v2_hm::HalfKaV2HmCuda;
 pub use half_kp::HalfKp;
 pub use half_kp::HalfKpCuda;
+pub use king_buckets::KingBuckets;
+pub use pawn_structure::PawnStructure;
+pub use pawn_structure::PawnStructureCuda;
+pub use threats::Threats;
+pub use threats::ThreatsCuda;
 
 pub trait InputFeatureSet {
     const INDICES_PER_FEATURE: usize;
     const MAX_FEATURES: usize;
+    /// The number of distinct feature indices, which all lie below it.
+    const NUM_INPUTS: usize;
+    /// Whether the nstm features are the stm features of the board with the colors swapped,
+    /// as they are for any feature set that sees the board from each side's point of view.
+    const PERSPECTIVE: bool = true;
 
     fn add_features(board: Board, entry: EntryFeatureWriter);
 }
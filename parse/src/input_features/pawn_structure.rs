@@ -0,0 +1,91 @@
+use cozy_chess::{BitBoard, Board, Color, File, Piece, Square};
+
+use crate::batch::EntryFeatureWriter;
+
+use super::InputFeatureSet;
+
+/// Passed, isolated and doubled pawns of each side by file, at `(color * 3 + kind) * 8 + file`
+/// with the color relative to the perspective. Meant to be written after another feature set
+/// rather than on its own, see `Batch::set_pawn_structure`.
+pub struct PawnStructure;
+pub struct PawnStructureCuda;
+
+impl InputFeatureSet for PawnStructure {
+    const MAX_FEATURES: usize = 48;
+    const INDICES_PER_FEATURE: usize = 2;
+    const NUM_INPUTS: usize = 48;
+
+    fn add_features(board: Board, entry: EntryFeatureWriter) {
+        let mut sparse_entry = entry.sparse();
+        for_each_feature(&board, |stm, nstm| sparse_entry.add_feature(stm, nstm));
+    }
+}
+
+impl InputFeatureSet for PawnStructureCuda {
+    const MAX_FEATURES: usize = 48;
+    const INDICES_PER_FEATURE: usize = 1;
+    const NUM_INPUTS: usize = 48;
+
+    fn add_features(board: Board, entry: EntryFeatureWriter) {
+        let mut cuda_entry = entry.cuda();
+        for_each_feature(&board, |stm, nstm| cuda_entry.add_feature(stm, nstm));
+    }
+}
+
+const PASSED: usize = 0;
+const ISOLATED: usize = 1;
+const DOUBLED: usize = 2;
+
+fn for_each_feature(board: &Board, mut f: impl FnMut(i64, i64)) {
+    let stm = board.side_to_move();
+
+    for &color in &Color::ALL {
+        let pawns = board.colored_pieces(color, Piece::Pawn);
+        let enemy_pawns = board.colored_pieces(!color, Piece::Pawn);
+        for &file in &File::ALL {
+            let on_file = pawns & file.bitboard();
+            if on_file.is_empty() {
+                continue;
+            }
+            let passed = on_file
+                .iter()
+                .any(|pawn| is_passed(pawn, color, enemy_pawns));
+            let isolated = (pawns & adjacent_files(file)).is_empty();
+            let doubled = on_file.len() > 1;
+
+            for &(kind, present) in &[(PASSED, passed), (ISOLATED, isolated), (DOUBLED, doubled)] {
+                if present {
+                    let stm_feature = feature(stm, color, kind, file);
+                    let nstm_feature = feature(!stm, color, kind, file);
+                    f(stm_feature as i64, nstm_feature as i64);
+                }
+            }
+        }
+    }
+}
+
+fn adjacent_files(file: File) -> BitBoard {
+    File::ALL
+        .iter()
+        .filter(|&&other| (other as i8 - file as i8).abs() == 1)
+        .fold(BitBoard::EMPTY, |files, other| files | other.bitboard())
+}
+
+/// Whether no enemy pawn can stop the pawn on its way to promotion.
+fn is_passed(pawn: Square, color: Color, enemy_pawns: BitBoard) -> bool {
+    enemy_pawns.iter().all(|enemy| {
+        let ahead = match color {
+            Color::White => enemy.rank() as usize > pawn.rank() as usize,
+            Color::Black => (enemy.rank() as usize) < pawn.rank() as usize,
+        };
+        !ahead || (enemy.file() as i8 - pawn.file() as i8).abs() > 1
+    })
+}
+
+fn feature(perspective: Color, color: Color, kind: usize, file: File) -> usize {
+    let color = match perspective {
+        Color::White => color,
+        Color::Black => !color,
+    };
+    (color as usize * 3 + kind) * File::NUM + file as usize
+}
@@ -0,0 +1,97 @@
+use cozy_chess::{
+    get_bishop_moves, get_king_moves, get_knight_moves, get_pawn_attacks, get_rook_moves, BitBoard,
+    Board, Color, Piece, Square,
+};
+
+use crate::batch::EntryFeatureWriter;
+
+use super::InputFeatureSet;
+
+/// Board768 followed by the squares attacked by each side, at `768 + color * 64 + square`
+/// with the color and square relative to the perspective.
+pub struct Threats;
+pub struct ThreatsCuda;
+
+impl InputFeatureSet for Threats {
+    const MAX_FEATURES: usize = 32 + 2 * 64;
+    const INDICES_PER_FEATURE: usize = 2;
+    const NUM_INPUTS: usize = 768 + 2 * 64;
+
+    fn add_features(board: Board, entry: EntryFeatureWriter) {
+        let mut sparse_entry = entry.sparse();
+        for_each_feature(&board, |stm, nstm| sparse_entry.add_feature(stm, nstm));
+    }
+}
+
+impl InputFeatureSet for ThreatsCuda {
+    const MAX_FEATURES: usize = 32 + 2 * 64;
+    const INDICES_PER_FEATURE: usize = 1;
+    const NUM_INPUTS: usize = 768 + 2 * 64;
+
+    fn add_features(board: Board, entry: EntryFeatureWriter) {
+        let mut cuda_entry = entry.cuda();
+        for_each_feature(&board, |stm, nstm| cuda_entry.add_feature(stm, nstm));
+    }
+}
+
+fn for_each_feature(board: &Board, mut f: impl FnMut(i64, i64)) {
+    let stm = board.side_to_move();
+
+    for &color in &Color::ALL {
+        for &piece in &Piece::ALL {
+            for square in board.pieces(piece) & board.colors(color) {
+                let stm_feature = piece_feature(stm, color, piece, square);
+                let nstm_feature = piece_feature(!stm, color, piece, square);
+                f(stm_feature as i64, nstm_feature as i64);
+            }
+        }
+    }
+
+    for &color in &Color::ALL {
+        for square in attacks(board, color) {
+            let stm_feature = attack_feature(stm, color, square);
+            let nstm_feature = attack_feature(!stm, color, square);
+            f(stm_feature as i64, nstm_feature as i64);
+        }
+    }
+}
+
+/// The squares attacked by the pieces of `color`, whether or not moving there is legal.
+fn attacks(board: &Board, color: Color) -> BitBoard {
+    let occupied = board.occupied();
+    let mut attacks = BitBoard::EMPTY;
+    for square in board.colored_pieces(color, Piece::Pawn) {
+        attacks |= get_pawn_attacks(square, color);
+    }
+    for square in board.colored_pieces(color, Piece::Knight) {
+        attacks |= get_knight_moves(square);
+    }
+    let queens = board.pieces(Piece::Queen);
+    for square in (board.pieces(Piece::Bishop) | queens) & board.colors(color) {
+        attacks |= get_bishop_moves(square, occupied);
+    }
+    for square in (board.pieces(Piece::Rook) | queens) & board.colors(color) {
+        attacks |= get_rook_moves(square, occupied);
+    }
+    attacks | get_king_moves(board.king(color))
+}
+
+fn piece_feature(perspective: Color, color: Color, piece: Piece, square: Square) -> usize {
+    let (square, color) = match perspective {
+        Color::White => (square, color),
+        Color::Black => (square.flip_rank(), !color),
+    };
+    let mut index = 0;
+    index = index * Color::NUM + color as usize;
+    index = index * Piece::NUM + piece as usize;
+    index = index * Square::NUM + square as usize;
+    index
+}
+
+fn attack_feature(perspective: Color, color: Color, square: Square) -> usize {
+    let (square, color) = match perspective {
+        Color::White => (square, color),
+        Color::Black => (square.flip_rank(), !color),
+    };
+    Color::NUM * Piece::NUM * Square::NUM + color as usize * Square::NUM + square as usize
+}
@@ -1,15 +1,51 @@
 use std::ffi::CStr;
 use std::os::raw::c_char;
+use std::sync::Arc;
 
-use batch::Batch;
+use batch::{Batch, EntryFeatureWriter};
+use cozy_chess::Board;
 use data_loader::FileReader;
+use filters::Filters;
 use input_features::{
-    Board768, Board768Cuda, HalfKa, HalfKaCuda, HalfKp, HalfKpCuda, InputFeatureSet,
+    Board768, Board768ColorBlind, Board768ColorBlindCuda, Board768Cuda, Board768Mirrored,
+    Board768MirroredCuda, Factorized, HalfKa, HalfKaCuda, HalfKaV2Hm, HalfKaV2HmCuda, HalfKp,
+    HalfKpCuda, InputFeatureSet, KingBuckets, Threats, ThreatsCuda,
 };
+use output_buckets::MaterialBuckets;
+use prefetch::Prefetcher;
+use random_access::RandomOrder;
+use value_format::ValueFormat;
 
 mod batch;
 mod data_loader;
+mod error;
+mod filters;
 mod input_features;
+mod output_buckets;
+mod policy;
+mod prefetch;
+#[cfg(feature = "python")]
+mod python;
+mod random_access;
+mod scalars;
+mod value_format;
+mod verify;
+
+/// The version of the functions and types exported by this library, which goes up with every
+/// change that breaks callers built against an earlier version.
+pub const PARSE_ABI_VERSION: u32 = 1;
+
+#[no_mangle]
+pub extern "C" fn parse_abi_version() -> u32 {
+    PARSE_ABI_VERSION
+}
+
+/// Why the last call on this thread that reports failure failed, or null if it succeeded.
+/// The string stays valid until the next such call on this thread.
+#[no_mangle]
+pub extern "C" fn parse_last_error() -> *const c_char {
+    error::last_error()
+}
 
 #[no_mangle]
 pub unsafe extern "C" fn batch_new(
@@ -25,6 +61,13 @@ pub unsafe extern "C" fn batch_new(
     Box::into_raw(Box::new(batch))
 }
 
+/// Creates a batch that holds the features of each entry as dense planes of `inputs` floats.
+#[no_mangle]
+pub unsafe extern "C" fn batch_new_dense(batch_size: u32, inputs: u32) -> *mut Batch {
+    let batch = Batch::new_dense(batch_size as usize, inputs as usize);
+    Box::into_raw(Box::new(batch))
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn batch_drop(batch: *mut Batch) {
     drop(Box::from_raw(batch));
@@ -35,6 +78,71 @@ pub unsafe extern "C" fn batch_clear(batch: *mut Batch) {
     batch.as_mut().unwrap().clear();
 }
 
+/// Buckets the entries of the batch by piece count into `count` output buckets.
+#[no_mangle]
+pub unsafe extern "C" fn batch_set_material_output_buckets(batch: *mut Batch, count: u32) {
+    let output_buckets = MaterialBuckets::new(count as usize);
+    batch
+        .as_mut()
+        .unwrap()
+        .set_output_buckets(Arc::new(output_buckets));
+}
+
+/// Blends the target of each entry as `lambda * sigmoid(cp / scale) + (1 - lambda) * wdl`.
+#[no_mangle]
+pub unsafe extern "C" fn batch_set_target_blend(batch: *mut Batch, lambda: f32, scale: f32) {
+    batch.as_mut().unwrap().set_target_blend(lambda, scale);
+}
+
+/// Pulls the result of each entry towards a draw, see `Batch::set_wdl_smoothing`.
+#[no_mangle]
+pub unsafe extern "C" fn batch_set_wdl_smoothing(batch: *mut Batch, epsilon: f32) {
+    batch.as_mut().unwrap().set_wdl_smoothing(epsilon);
+}
+
+/// Panics, reported through `parse_last_error`, if a feature set writes a feature of an entry
+/// twice or one at or above `num_inputs`. For debugging feature sets.
+#[no_mangle]
+pub unsafe extern "C" fn batch_set_validation(batch: *mut Batch, num_inputs: u32) {
+    batch.as_mut().unwrap().set_validation(num_inputs as usize);
+}
+
+/// Whether entries keep the sample weights of their records, see `Batch::set_sample_weights`.
+#[no_mangle]
+pub unsafe extern "C" fn batch_set_sample_weights(batch: *mut Batch, enabled: bool) {
+    batch.as_mut().unwrap().set_sample_weights(enabled);
+}
+
+/// Scales the weight of each entry down as its eval grows, see `Batch::set_eval_weighting`.
+#[no_mangle]
+pub unsafe extern "C" fn batch_set_eval_weighting(batch: *mut Batch, scale: f32) {
+    batch.as_mut().unwrap().set_eval_weighting(scale);
+}
+
+/// Packs the eval, result and target of each entry as 16-bit values, see `ValueFormat`.
+#[no_mangle]
+pub unsafe extern "C" fn batch_set_value_format(batch: *mut Batch, format: ValueFormat) {
+    batch.as_mut().unwrap().set_value_format(format);
+}
+
+/// Writes pawn structure features after those of the feature set, from index `offset`.
+#[no_mangle]
+pub unsafe extern "C" fn batch_set_pawn_structure(batch: *mut Batch, offset: u32) {
+    batch.as_mut().unwrap().set_pawn_structure(offset as usize);
+}
+
+/// The number of scalar inputs of each entry in `batch_get_scalars_ptr`.
+#[no_mangle]
+pub extern "C" fn batch_scalars() -> u32 {
+    scalars::SCALARS as u32
+}
+
+/// The size of the policy index space of the moves in `batch_get_moves_ptr`.
+#[no_mangle]
+pub extern "C" fn batch_policy_moves() -> u32 {
+    policy::POLICY_MOVES as u32
+}
+
 macro_rules! export_batch_getters {
     ($($getter:ident $(as $cast_type:ty)?: $exported:ident -> $type:ty,)*) => {$(
         #[no_mangle]
@@ -51,22 +159,205 @@ export_batch_getters! {
     values_ptr                      : batch_get_values_ptr -> *const f32,
     total_features as u32           : batch_get_total_features -> u32,
     indices_per_feature as u32      : batch_get_indices_per_feature -> u32,
+    dense_inputs as u32             : batch_get_dense_inputs -> u32,
+    stm_dense_ptr                   : batch_get_stm_dense_ptr -> *const f32,
+    nstm_dense_ptr                  : batch_get_nstm_dense_ptr -> *const f32,
     cp_ptr                          : batch_get_cp_ptr -> *const f32,
     wdl_ptr                         : batch_get_wdl_ptr -> *const f32,
+    weight_ptr                      : batch_get_weight_ptr -> *const f32,
+    target_ptr                      : batch_get_target_ptr -> *const f32,
+    value_format as u32             : batch_get_value_format -> u32,
+    packed_cp_ptr                   : batch_get_packed_cp_ptr -> *const u16,
+    packed_wdl_ptr                  : batch_get_packed_wdl_ptr -> *const u16,
+    packed_target_ptr               : batch_get_packed_target_ptr -> *const u16,
+    moves_ptr                       : batch_get_moves_ptr -> *const i64,
+    buckets_ptr                     : batch_get_buckets_ptr -> *const i64,
+    scalars_ptr                     : batch_get_scalars_ptr -> *const f32,
+    entry_offsets_ptr               : batch_get_entry_offsets_ptr -> *const u32,
 }
 
+/// The address of the batch buffer at `index` that is copied to the device, writing its size
+/// in bytes to `size`, or null past the last buffer. Buffers may be empty.
 #[no_mangle]
-pub unsafe extern "C" fn file_reader_new(path: *const c_char) -> *mut FileReader {
-    pub unsafe fn try_new_file_reader(path: *const c_char) -> Option<FileReader> {
-        let path = CStr::from_ptr(path).to_str().ok()?;
-        let reader = FileReader::new(path).ok()?;
-        Some(reader)
+pub unsafe extern "C" fn batch_get_host_buffer(
+    batch: *mut Batch,
+    index: u32,
+    size: *mut u64,
+) -> *const u8 {
+    match batch.as_mut().unwrap().host_buffers().get(index as usize) {
+        Some(buffer) => {
+            *size = buffer.len() as u64;
+            buffer.as_ptr()
+        }
+        None => std::ptr::null(),
     }
-    if let Some(reader) = try_new_file_reader(path) {
-        Box::into_raw(Box::new(reader))
-    } else {
-        std::ptr::null_mut()
+}
+
+/// Opens a reader, or returns null, see `parse_last_error`.
+#[no_mangle]
+pub unsafe extern "C" fn file_reader_new(path: *const c_char) -> *mut FileReader {
+    error::guard(std::ptr::null_mut(), || {
+        let path = path_str(path)?;
+        let reader = FileReader::new(path).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Box::into_raw(Box::new(reader)))
+    })
+}
+
+/// Opens a reader that mixes several files, or returns null, see `parse_last_error`.
+#[no_mangle]
+pub unsafe extern "C" fn file_reader_new_mixed(
+    paths: *const *const c_char,
+    weights: *const f32,
+    count: u32,
+    seed: u64,
+) -> *mut FileReader {
+    error::guard(std::ptr::null_mut(), || {
+        if count == 0 {
+            return Err("a mixed reader needs at least one file".to_string());
+        }
+        if paths.is_null() || weights.is_null() {
+            return Err("the paths and weights of a mixed reader can't be null".to_string());
+        }
+        let paths = std::slice::from_raw_parts(paths, count as usize);
+        let weights = std::slice::from_raw_parts(weights, count as usize);
+        let sources = paths
+            .iter()
+            .zip(weights)
+            .map(|(&path, &weight)| Ok((path_str(path)?, weight as f64)))
+            .collect::<Result<Vec<_>, String>>()?;
+        let reader = FileReader::mixed(sources, seed).map_err(|e| e.to_string())?;
+        Ok(Box::into_raw(Box::new(reader)))
+    })
+}
+
+unsafe fn path_str<'a>(path: *const c_char) -> Result<&'a str, String> {
+    c_str(path, "path")
+}
+
+/// Reads a string passed from C, described as `what` in errors.
+unsafe fn c_str<'a>(s: *const c_char, what: &str) -> Result<&'a str, String> {
+    if s.is_null() {
+        return Err(format!("the {} can't be null", what));
     }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| format!("{} is not valid UTF-8", CStr::from_ptr(s).to_string_lossy()))
+}
+
+/// Draws positions at random from a buffer of `size` positions read ahead, or in order for 0.
+#[no_mangle]
+pub unsafe extern "C" fn file_reader_set_shuffle_buffer_size(reader: *mut FileReader, size: u64) {
+    reader
+        .as_mut()
+        .unwrap()
+        .set_shuffle_buffer_size(size as usize);
+}
+
+/// Restarts the random choices of the reader's current epoch from `seed`.
+#[no_mangle]
+pub unsafe extern "C" fn file_reader_set_seed(reader: *mut FileReader, seed: u64) {
+    reader.as_mut().unwrap().set_seed(seed);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn file_reader_set_random_skip_probability(
+    reader: *mut FileReader,
+    probability: f64,
+) {
+    reader
+        .as_mut()
+        .unwrap()
+        .set_random_skip_probability(probability);
+}
+
+/// Leaves out positions while reading, see `Filters`, replacing the default, which drops evals
+/// beyond 3000 centipawns. Pass infinity and `u32::MAX` for no upper limits.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn file_reader_set_filters(
+    reader: *mut FileReader,
+    max_eval: f32,
+    max_incongruent_eval: f32,
+    min_pieces: u32,
+    max_pieces: u32,
+    min_ply: u32,
+    max_ply: u32,
+    skip_in_check: bool,
+) {
+    reader.as_mut().unwrap().set_filters(Filters {
+        max_eval,
+        max_incongruent_eval,
+        min_pieces,
+        max_pieces,
+        min_ply,
+        max_ply,
+        skip_in_check,
+    });
+}
+
+/// Reads the records of each dataset in a random order, every record once per epoch for a
+/// permutation or picked with replacement otherwise. Returns whether the datasets could be
+/// reopened, which fails for compressed ones, see `parse_last_error`.
+#[no_mangle]
+pub unsafe extern "C" fn file_reader_set_random_order(
+    reader: *mut FileReader,
+    permutation: bool,
+) -> bool {
+    let order = match permutation {
+        true => RandomOrder::Permutation,
+        false => RandomOrder::Sampled,
+    };
+    error::guard(false, || {
+        let reader = reader.as_mut().unwrap();
+        reader.set_random_order(order).map_err(|e| e.to_string())?;
+        Ok(true)
+    })
+}
+
+/// Reads up to `bytes` of each dataset ahead on a background thread, or reads in place for
+/// 0, starting over from the beginning of the epoch. Returns false if the datasets can't be
+/// reopened.
+#[no_mangle]
+pub unsafe extern "C" fn file_reader_set_readahead(reader: *mut FileReader, bytes: u64) -> bool {
+    error::guard(false, || {
+        let reader = reader.as_mut().unwrap();
+        reader
+            .set_readahead(bytes as usize)
+            .map_err(|e| e.to_string())?;
+        Ok(true)
+    })
+}
+
+/// Clamps evals to `bound` centipawns either way, for mate and tablebase scores.
+#[no_mangle]
+pub unsafe extern "C" fn file_reader_set_eval_clamp(reader: *mut FileReader, bound: f32) {
+    reader.as_mut().unwrap().set_eval_clamp(bound);
+}
+
+/// Mirrors each position across the vertical axis with probability 0.5, unless it has castling
+/// rights.
+#[no_mangle]
+pub unsafe extern "C" fn file_reader_set_mirroring(reader: *mut FileReader, enabled: bool) {
+    reader.as_mut().unwrap().set_mirroring(enabled);
+}
+
+/// Rewinds the reader to start its next epoch, returning whether its files could be reopened,
+/// see `parse_last_error`.
+#[no_mangle]
+pub unsafe extern "C" fn file_reader_reset(reader: *mut FileReader) -> bool {
+    error::guard(false, || {
+        reader
+            .as_mut()
+            .unwrap()
+            .reset()
+            .map_err(|e| e.to_string())?;
+        Ok(true)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn file_reader_get_epoch(reader: *mut FileReader) -> u64 {
+    reader.as_mut().unwrap().epoch()
 }
 
 #[no_mangle]
@@ -75,6 +366,8 @@ pub unsafe extern "C" fn file_reader_drop(reader: *mut FileReader) {
 }
 
 #[repr(C)]
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "python", pyo3::pyclass(name = "InputFeatureSet"))]
 pub enum InputFeatureSetType {
     Board768,
     HalfKp,
@@ -82,8 +375,79 @@ pub enum InputFeatureSetType {
     Board768Cuda,
     HalfKpCuda,
     HalfKaCuda,
+    HalfKaV2Hm,
+    HalfKaV2HmCuda,
+    HalfKaFactorized,
+    HalfKaFactorizedCuda,
+    Threats,
+    ThreatsCuda,
+    Board768Mirrored,
+    Board768MirroredCuda,
+    Board768ColorBlind,
+    Board768ColorBlindCuda,
+}
+
+/// The feature sets by name, for choosing one at runtime with `input_feature_set_from_name`.
+const FEATURE_SET_NAMES: [(&str, InputFeatureSetType); 16] = [
+    ("board768", InputFeatureSetType::Board768),
+    ("halfkp", InputFeatureSetType::HalfKp),
+    ("halfka", InputFeatureSetType::HalfKa),
+    ("board768_cuda", InputFeatureSetType::Board768Cuda),
+    ("halfkp_cuda", InputFeatureSetType::HalfKpCuda),
+    ("halfka_cuda", InputFeatureSetType::HalfKaCuda),
+    ("halfkav2_hm", InputFeatureSetType::HalfKaV2Hm),
+    ("halfkav2_hm_cuda", InputFeatureSetType::HalfKaV2HmCuda),
+    ("halfka_factorized", InputFeatureSetType::HalfKaFactorized),
+    (
+        "halfka_factorized_cuda",
+        InputFeatureSetType::HalfKaFactorizedCuda,
+    ),
+    ("threats", InputFeatureSetType::Threats),
+    ("threats_cuda", InputFeatureSetType::ThreatsCuda),
+    ("board768_mirrored", InputFeatureSetType::Board768Mirrored),
+    (
+        "board768_mirrored_cuda",
+        InputFeatureSetType::Board768MirroredCuda,
+    ),
+    (
+        "board768_color_blind",
+        InputFeatureSetType::Board768ColorBlind,
+    ),
+    (
+        "board768_color_blind_cuda",
+        InputFeatureSetType::Board768ColorBlindCuda,
+    ),
+];
+
+/// The names of the feature sets, in the order of `InputFeatureSetType`.
+pub fn feature_set_names() -> impl Iterator<Item = &'static str> {
+    FEATURE_SET_NAMES.iter().map(|&(name, _)| name)
+}
+
+/// The feature set called `name`, as its `InputFeatureSetType` value, or -1 if there is none,
+/// see `parse_last_error`.
+#[no_mangle]
+pub unsafe extern "C" fn input_feature_set_from_name(name: *const c_char) -> i32 {
+    error::guard(-1, || {
+        let name = c_str(name, "feature set name")?;
+        match FEATURE_SET_NAMES.iter().find(|&&(n, _)| n == name) {
+            Some(&(_, feature_set)) => Ok(feature_set as i32),
+            None => {
+                let names: Vec<_> = FEATURE_SET_NAMES.iter().map(|&(n, _)| n).collect();
+                Err(format!(
+                    "unknown feature set {}, expected one of {}",
+                    name,
+                    names.join(", ")
+                ))
+            }
+        }
+    })
 }
 
+/// HalfKA with `Board768` as virtual features, at indices from 49152.
+type HalfKaFactorized = Factorized<HalfKa, Board768>;
+type HalfKaFactorizedCuda = Factorized<HalfKaCuda, Board768Cuda>;
+
 #[no_mangle]
 pub unsafe extern "C" fn input_feature_set_get_max_features(
     feature_set: InputFeatureSetType,
@@ -95,6 +459,16 @@ pub unsafe extern "C" fn input_feature_set_get_max_features(
         InputFeatureSetType::Board768Cuda => Board768Cuda::MAX_FEATURES,
         InputFeatureSetType::HalfKpCuda => HalfKpCuda::MAX_FEATURES,
         InputFeatureSetType::HalfKaCuda => HalfKaCuda::MAX_FEATURES,
+        InputFeatureSetType::HalfKaV2Hm => HalfKaV2Hm::MAX_FEATURES,
+        InputFeatureSetType::HalfKaV2HmCuda => HalfKaV2HmCuda::MAX_FEATURES,
+        InputFeatureSetType::HalfKaFactorized => HalfKaFactorized::MAX_FEATURES,
+        InputFeatureSetType::HalfKaFactorizedCuda => HalfKaFactorizedCuda::MAX_FEATURES,
+        InputFeatureSetType::Threats => Threats::MAX_FEATURES,
+        InputFeatureSetType::ThreatsCuda => ThreatsCuda::MAX_FEATURES,
+        InputFeatureSetType::Board768Mirrored => Board768Mirrored::MAX_FEATURES,
+        InputFeatureSetType::Board768MirroredCuda => Board768MirroredCuda::MAX_FEATURES,
+        InputFeatureSetType::Board768ColorBlind => Board768ColorBlind::MAX_FEATURES,
+        InputFeatureSetType::Board768ColorBlindCuda => Board768ColorBlindCuda::MAX_FEATURES,
     };
     max_features as u32
 }
@@ -110,30 +484,190 @@ pub unsafe extern "C" fn input_feature_set_get_indices_per_feature(
         InputFeatureSetType::Board768Cuda => Board768Cuda::INDICES_PER_FEATURE,
         InputFeatureSetType::HalfKpCuda => HalfKpCuda::INDICES_PER_FEATURE,
         InputFeatureSetType::HalfKaCuda => HalfKaCuda::INDICES_PER_FEATURE,
+        InputFeatureSetType::HalfKaV2Hm => HalfKaV2Hm::INDICES_PER_FEATURE,
+        InputFeatureSetType::HalfKaV2HmCuda => HalfKaV2HmCuda::INDICES_PER_FEATURE,
+        InputFeatureSetType::HalfKaFactorized => HalfKaFactorized::INDICES_PER_FEATURE,
+        InputFeatureSetType::HalfKaFactorizedCuda => HalfKaFactorizedCuda::INDICES_PER_FEATURE,
+        InputFeatureSetType::Threats => Threats::INDICES_PER_FEATURE,
+        InputFeatureSetType::ThreatsCuda => ThreatsCuda::INDICES_PER_FEATURE,
+        InputFeatureSetType::Board768Mirrored => Board768Mirrored::INDICES_PER_FEATURE,
+        InputFeatureSetType::Board768MirroredCuda => Board768MirroredCuda::INDICES_PER_FEATURE,
+        InputFeatureSetType::Board768ColorBlind => Board768ColorBlind::INDICES_PER_FEATURE,
+        InputFeatureSetType::Board768ColorBlindCuda => Board768ColorBlindCuda::INDICES_PER_FEATURE,
     };
     indices_per_feature as u32
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn input_feature_set_get_num_inputs(feature_set: InputFeatureSetType) -> u32 {
+    let num_inputs = match feature_set {
+        InputFeatureSetType::Board768 => Board768::NUM_INPUTS,
+        InputFeatureSetType::HalfKp => HalfKp::NUM_INPUTS,
+        InputFeatureSetType::HalfKa => HalfKa::NUM_INPUTS,
+        InputFeatureSetType::Board768Cuda => Board768Cuda::NUM_INPUTS,
+        InputFeatureSetType::HalfKpCuda => HalfKpCuda::NUM_INPUTS,
+        InputFeatureSetType::HalfKaCuda => HalfKaCuda::NUM_INPUTS,
+        InputFeatureSetType::HalfKaV2Hm => HalfKaV2Hm::NUM_INPUTS,
+        InputFeatureSetType::HalfKaV2HmCuda => HalfKaV2HmCuda::NUM_INPUTS,
+        InputFeatureSetType::HalfKaFactorized => HalfKaFactorized::NUM_INPUTS,
+        InputFeatureSetType::HalfKaFactorizedCuda => HalfKaFactorizedCuda::NUM_INPUTS,
+        InputFeatureSetType::Threats => Threats::NUM_INPUTS,
+        InputFeatureSetType::ThreatsCuda => ThreatsCuda::NUM_INPUTS,
+        InputFeatureSetType::Board768Mirrored => Board768Mirrored::NUM_INPUTS,
+        InputFeatureSetType::Board768MirroredCuda => Board768MirroredCuda::NUM_INPUTS,
+        InputFeatureSetType::Board768ColorBlind => Board768ColorBlind::NUM_INPUTS,
+        InputFeatureSetType::Board768ColorBlindCuda => Board768ColorBlindCuda::NUM_INPUTS,
+    };
+    num_inputs as u32
+}
+
+fn is_perspective(feature_set: InputFeatureSetType) -> bool {
+    match feature_set {
+        InputFeatureSetType::Board768 => Board768::PERSPECTIVE,
+        InputFeatureSetType::HalfKp => HalfKp::PERSPECTIVE,
+        InputFeatureSetType::HalfKa => HalfKa::PERSPECTIVE,
+        InputFeatureSetType::Board768Cuda => Board768Cuda::PERSPECTIVE,
+        InputFeatureSetType::HalfKpCuda => HalfKpCuda::PERSPECTIVE,
+        InputFeatureSetType::HalfKaCuda => HalfKaCuda::PERSPECTIVE,
+        InputFeatureSetType::HalfKaV2Hm => HalfKaV2Hm::PERSPECTIVE,
+        InputFeatureSetType::HalfKaV2HmCuda => HalfKaV2HmCuda::PERSPECTIVE,
+        InputFeatureSetType::HalfKaFactorized => HalfKaFactorized::PERSPECTIVE,
+        InputFeatureSetType::HalfKaFactorizedCuda => HalfKaFactorizedCuda::PERSPECTIVE,
+        InputFeatureSetType::Threats => Threats::PERSPECTIVE,
+        InputFeatureSetType::ThreatsCuda => ThreatsCuda::PERSPECTIVE,
+        InputFeatureSetType::Board768Mirrored => Board768Mirrored::PERSPECTIVE,
+        InputFeatureSetType::Board768MirroredCuda => Board768MirroredCuda::PERSPECTIVE,
+        InputFeatureSetType::Board768ColorBlind => Board768ColorBlind::PERSPECTIVE,
+        InputFeatureSetType::Board768ColorBlindCuda => Board768ColorBlindCuda::PERSPECTIVE,
+    }
+}
+
+/// Checks the feature set against the invariants the trainer relies on, see
+/// `verify::verify_feature_set`, returning whether it keeps them. If it doesn't,
+/// `parse_last_error` says how.
+#[no_mangle]
+pub unsafe extern "C" fn input_feature_set_verify(feature_set: InputFeatureSetType) -> bool {
+    error::guard(false, || {
+        verify::verify_feature_set(feature_set).map(|()| true)
+    })
+}
+
+fn add_features_fn(feature_set: InputFeatureSetType) -> fn(Board, EntryFeatureWriter) {
+    match feature_set {
+        InputFeatureSetType::Board768 => Board768::add_features,
+        InputFeatureSetType::HalfKp => HalfKp::add_features,
+        InputFeatureSetType::HalfKa => HalfKa::add_features,
+        InputFeatureSetType::Board768Cuda => Board768Cuda::add_features,
+        InputFeatureSetType::HalfKpCuda => HalfKpCuda::add_features,
+        InputFeatureSetType::HalfKaCuda => HalfKaCuda::add_features,
+        InputFeatureSetType::HalfKaV2Hm => HalfKaV2Hm::add_features,
+        InputFeatureSetType::HalfKaV2HmCuda => HalfKaV2HmCuda::add_features,
+        InputFeatureSetType::HalfKaFactorized => HalfKaFactorized::add_features,
+        InputFeatureSetType::HalfKaFactorizedCuda => HalfKaFactorizedCuda::add_features,
+        InputFeatureSetType::Threats => Threats::add_features,
+        InputFeatureSetType::ThreatsCuda => ThreatsCuda::add_features,
+        InputFeatureSetType::Board768Mirrored => Board768Mirrored::add_features,
+        InputFeatureSetType::Board768MirroredCuda => Board768MirroredCuda::add_features,
+        InputFeatureSetType::Board768ColorBlind => Board768ColorBlind::add_features,
+        InputFeatureSetType::Board768ColorBlindCuda => Board768ColorBlindCuda::add_features,
+    }
+}
+
+/// Fills the batch, returning whether it was filled. It isn't once the reader is exhausted, or
+/// if reading failed, in which case `parse_last_error` says why.
 #[no_mangle]
 pub unsafe extern "C" fn read_batch_into(
     reader: *mut FileReader,
     feature_set: InputFeatureSetType,
     batch: *mut Batch,
 ) -> bool {
-    let reader = reader.as_mut().unwrap();
-    let batch = batch.as_mut().unwrap();
-    match feature_set {
-        InputFeatureSetType::Board768 => data_loader::read_batch_into::<Board768>(reader, batch),
-        InputFeatureSetType::HalfKp => data_loader::read_batch_into::<HalfKp>(reader, batch),
-        InputFeatureSetType::HalfKa => data_loader::read_batch_into::<HalfKa>(reader, batch),
-        InputFeatureSetType::Board768Cuda => {
-            data_loader::read_batch_into::<Board768Cuda>(reader, batch)
-        }
-        InputFeatureSetType::HalfKpCuda => {
-            data_loader::read_batch_into::<HalfKpCuda>(reader, batch)
-        }
-        InputFeatureSetType::HalfKaCuda => {
-            data_loader::read_batch_into::<HalfKaCuda>(reader, batch)
+    error::guard(false, || {
+        let reader = reader.as_mut().unwrap();
+        let batch = batch.as_mut().unwrap();
+        data_loader::read_batch_with(reader, batch, add_features_fn(feature_set))
+            .map_err(|e| e.to_string())
+    })
+}
+
+/// Reads a batch of king bucketed features, given a map of 64 buckets indexed by king square.
+#[no_mangle]
+pub unsafe extern "C" fn read_batch_into_king_buckets(
+    reader: *mut FileReader,
+    buckets: *const u8,
+    cuda: bool,
+    batch: *mut Batch,
+) -> bool {
+    error::guard(false, || {
+        let reader = reader.as_mut().unwrap();
+        let batch = batch.as_mut().unwrap();
+        let buckets = *(buckets as *const [u8; 64]);
+        let feature_set = KingBuckets::new(buckets, cuda);
+        data_loader::read_batch_with(reader, batch, |board, entry| {
+            feature_set.add_features(board, entry)
+        })
+        .map_err(|e| e.to_string())
+    })
+}
+
+/// Starts filling batches like `template` in the background with `threads` threads, keeping
+/// up to `queue` batches ready. Takes ownership of the reader.
+#[no_mangle]
+pub unsafe extern "C" fn prefetcher_new(
+    reader: *mut FileReader,
+    feature_set: InputFeatureSetType,
+    template: *const Batch,
+    threads: u32,
+    queue: u32,
+) -> *mut Prefetcher {
+    let prefetcher = Prefetcher::new(
+        *Box::from_raw(reader),
+        template.as_ref().unwrap(),
+        Arc::new(add_features_fn(feature_set)),
+        threads as usize,
+        queue as usize,
+    );
+    Box::into_raw(Box::new(prefetcher))
+}
+
+/// Like `prefetcher_new`, for king bucketed features.
+#[no_mangle]
+pub unsafe extern "C" fn prefetcher_new_king_buckets(
+    reader: *mut FileReader,
+    buckets: *const u8,
+    cuda: bool,
+    template: *const Batch,
+    threads: u32,
+    queue: u32,
+) -> *mut Prefetcher {
+    let buckets = *(buckets as *const [u8; 64]);
+    let feature_set = KingBuckets::new(buckets, cuda);
+    let prefetcher = Prefetcher::new(
+        *Box::from_raw(reader),
+        template.as_ref().unwrap(),
+        Arc::new(move |board, entry| feature_set.add_features(board, entry)),
+        threads as usize,
+        queue as usize,
+    );
+    Box::into_raw(Box::new(prefetcher))
+}
+
+/// The next batch, which stays valid until the next call, or null once the reader is
+/// exhausted or if filling a batch failed, in which case `parse_last_error` says why.
+#[no_mangle]
+pub unsafe extern "C" fn prefetcher_next_batch(prefetcher: *mut Prefetcher) -> *const Batch {
+    error::guard(std::ptr::null(), || {
+        let prefetcher = prefetcher.as_mut().unwrap();
+        let batch = prefetcher
+            .next()
+            .map_or(std::ptr::null(), |batch| batch as *const _);
+        match prefetcher.failure() {
+            Some(error) => Err(error),
+            None => Ok(batch),
         }
-    }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn prefetcher_drop(prefetcher: *mut Prefetcher) {
+    drop(Box::from_raw(prefetcher));
 }
@@ -0,0 +1,41 @@
+use cozy_chess::Board;
+
+/// Picks which of several output layers each position is trained through, so that trainers
+/// get the bucket as a tensor instead of deriving it from the sparse features.
+pub trait OutputBuckets: Send + Sync {
+    fn bucket(&self, board: &Board) -> usize;
+}
+
+/// A single output layer, the default.
+pub struct SingleBucket;
+
+impl OutputBuckets for SingleBucket {
+    fn bucket(&self, _board: &Board) -> usize {
+        0
+    }
+}
+
+/// Buckets of equal width by the number of pieces on the board, kings included, with the
+/// fewest pieces in bucket 0.
+pub struct MaterialBuckets {
+    count: usize,
+    pieces_per_bucket: usize,
+}
+
+impl MaterialBuckets {
+    pub fn new(count: usize) -> Self {
+        // The kings are always there, which leaves 30 pieces to split up.
+        let count = count.max(1);
+        Self {
+            count,
+            pieces_per_bucket: (30 + count) / count,
+        }
+    }
+}
+
+impl OutputBuckets for MaterialBuckets {
+    fn bucket(&self, board: &Board) -> usize {
+        let bucket = (board.occupied().len() as usize).saturating_sub(2) / self.pieces_per_bucket;
+        bucket.min(self.count - 1)
+    }
+}
@@ -0,0 +1,28 @@
+use cozy_chess::{Color, Move, Piece};
+
+/// The size of the policy index space: `from * 64 + to` for every move, queen promotions
+/// included, then the underpromotions.
+pub const POLICY_MOVES: usize = 64 * 64 + UNDERPROMOTIONS;
+
+// A knight, bishop or rook promotion from each file, capturing left, pushing or capturing right
+const UNDERPROMOTIONS: usize = 3 * 8 * 3;
+
+/// The policy index of `mv` from the point of view of `stm`, with the board flipped for black.
+/// Underpromotions come after the other moves, at `4096 + (piece * 8 + file) * 3 + direction`,
+/// where piece is 0 for knights, 1 for bishops and 2 for rooks, and direction is 0 for
+/// captures towards the a-file, 1 for pushes and 2 for captures towards the h-file.
+pub fn policy_index(mv: Move, stm: Color) -> usize {
+    let (from, to) = match stm {
+        Color::White => (mv.from, mv.to),
+        Color::Black => (mv.from.flip_rank(), mv.to.flip_rank()),
+    };
+    let piece = match mv.promotion {
+        Some(Piece::Knight) => 0,
+        Some(Piece::Bishop) => 1,
+        Some(Piece::Rook) => 2,
+        _ => return from as usize * 64 + to as usize,
+    };
+    let file = from.file() as usize;
+    let direction = to.file() as usize + 1 - file;
+    64 * 64 + (piece * 8 + file) * 3 + direction
+}
@@ -0,0 +1,158 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use cozy_chess::Board;
+
+use crate::batch::{Batch, EntryFeatureWriter};
+use crate::data_loader::{self, AnnotatedBoard, FileReader};
+use crate::error;
+
+pub type AddFeatures = dyn Fn(Board, EntryFeatureWriter) + Send + Sync;
+
+/// Fills batches in the background so that feature extraction doesn't hold up training.
+///
+/// One thread reads the positions of each batch from the reader, and a pool of threads
+/// writes their features to batches, which wait in a queue of ready batches until they are
+/// taken with `next`. Batches are built concurrently, so they may come out of order, and the
+/// positions left over at the end of the reader that don't fill a batch are dropped, as they
+/// are by `read_batch_with`.
+pub struct Prefetcher {
+    queues: Option<Queues>,
+    // The batch last handed out, which is recycled on the next call to `next`
+    current: Option<Batch>,
+    threads: Vec<JoinHandle<()>>,
+    // Why a thread panicked or reading failed, if either did
+    failure: Arc<Mutex<Option<String>>>,
+}
+
+struct Queues {
+    ready: Receiver<Batch>,
+    free: Sender<Batch>,
+}
+
+impl Prefetcher {
+    /// Starts `threads` threads that write batches configured like `template`, keeping up to
+    /// `queue` batches ready.
+    pub fn new(
+        mut reader: FileReader,
+        template: &Batch,
+        add_features: Arc<AddFeatures>,
+        threads: usize,
+        queue: usize,
+    ) -> Self {
+        let threads = threads.max(1);
+        let queue = queue.max(1);
+        let capacity = template.capacity();
+
+        let (positions_sender, positions) = mpsc::sync_channel::<Vec<AnnotatedBoard>>(queue);
+        let (ready_sender, ready) = mpsc::sync_channel(queue);
+        let (free, free_batches) = mpsc::channel();
+        // Enough batches for every queued and in-flight batch, and the one handed out.
+        for _ in 0..queue + threads + 1 {
+            free.send(template.clone()).unwrap();
+        }
+
+        let failure = Arc::new(Mutex::new(None));
+        let read_failure = Arc::clone(&failure);
+        let mut handles = vec![spawn(&failure, move || loop {
+            let positions: Vec<_> = reader.by_ref().take(capacity).collect();
+            if let Some(e) = reader.take_error() {
+                read_failure.lock().unwrap().get_or_insert(e.to_string());
+                break;
+            }
+            if positions.len() < capacity || positions_sender.send(positions).is_err() {
+                break;
+            }
+        })];
+        let positions = Arc::new(Mutex::new(positions));
+        let free_batches = Arc::new(Mutex::new(free_batches));
+        for _ in 0..threads {
+            let positions = Arc::clone(&positions);
+            let free_batches = Arc::clone(&free_batches);
+            let ready_sender = ready_sender.clone();
+            let add_features = Arc::clone(&add_features);
+            handles.push(spawn(&failure, move || {
+                work(&positions, &free_batches, &ready_sender, &*add_features)
+            }));
+        }
+
+        Self {
+            queues: Some(Queues { ready, free }),
+            current: None,
+            threads: handles,
+            failure,
+        }
+    }
+
+    /// The next ready batch, or `None` once the reader is exhausted. The batch stays valid
+    /// until the next call.
+    pub fn next(&mut self) -> Option<&Batch> {
+        let queues = self.queues.as_ref()?;
+        if let Some(batch) = self.current.take() {
+            // The workers may all have stopped, in which case the batch isn't needed.
+            let _ = queues.free.send(batch);
+        }
+        self.current = queues.ready.recv().ok();
+        if self.current.is_none() {
+            // Every thread has stopped or is about to, so wait for any failure to be recorded.
+            self.join();
+        }
+        self.current.as_ref()
+    }
+
+    /// Why filling batches failed, if a thread panicked or reading a dataset failed.
+    pub fn failure(&self) -> Option<String> {
+        self.failure.lock().unwrap().clone()
+    }
+
+    fn join(&mut self) {
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        // Closing the queues stops every thread at its next send or receive.
+        self.queues = None;
+        self.join();
+    }
+}
+
+fn spawn(
+    failure: &Arc<Mutex<Option<String>>>,
+    f: impl FnOnce() + Send + 'static,
+) -> JoinHandle<()> {
+    let failure = Arc::clone(failure);
+    thread::spawn(move || {
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(f)) {
+            let message = format!("panicked: {}", error::panic_message(&*payload));
+            failure.lock().unwrap().get_or_insert(message);
+        }
+    })
+}
+
+fn work(
+    positions: &Mutex<Receiver<Vec<AnnotatedBoard>>>,
+    free_batches: &Mutex<Receiver<Batch>>,
+    ready: &SyncSender<Batch>,
+    add_features: &AddFeatures,
+) {
+    loop {
+        let positions = match positions.lock().unwrap().recv() {
+            Ok(positions) => positions,
+            Err(_) => return,
+        };
+        let mut batch = match free_batches.lock().unwrap().recv() {
+            Ok(batch) => batch,
+            Err(_) => return,
+        };
+        data_loader::fill_batch(positions, &mut batch, add_features);
+        if ready.send(batch).is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Python bindings of the loader, built with `--features python`, as an alternative to
+//! driving the C functions through ctypes. Batches come out as dicts of numpy arrays, named
+//! and shaped like the fields of `dataloader.Batch`.
+
+use numpy::PyArray1;
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use crate::batch::Batch;
+use crate::data_loader::{self, FileReader};
+use crate::scalars::SCALARS;
+use crate::verify;
+use crate::{
+    add_features_fn, input_feature_set_get_indices_per_feature, input_feature_set_get_max_features,
+    input_feature_set_get_num_inputs, InputFeatureSetType,
+};
+
+#[pymethods]
+impl InputFeatureSetType {
+    fn max_features(&self) -> u32 {
+        unsafe { input_feature_set_get_max_features(*self) }
+    }
+
+    fn indices_per_feature(&self) -> u32 {
+        unsafe { input_feature_set_get_indices_per_feature(*self) }
+    }
+
+    fn num_inputs(&self) -> u32 {
+        unsafe { input_feature_set_get_num_inputs(*self) }
+    }
+
+    /// Raises `ValueError` if the feature set breaks an invariant the trainer relies on.
+    fn verify(&self) -> PyResult<()> {
+        verify::verify_feature_set(*self).map_err(PyValueError::new_err)
+    }
+}
+
+/// Positions read from one or more data files, drawn from in proportion to `weights` when
+/// there are several.
+#[pyclass]
+struct Dataset {
+    // Taken by the `BatchLoader` that reads the dataset
+    reader: Option<FileReader>,
+}
+
+#[pymethods]
+impl Dataset {
+    #[new]
+    #[pyo3(signature = (paths, weights = None, seed = 0))]
+    fn new(paths: Vec<String>, weights: Option<Vec<f64>>, seed: u64) -> PyResult<Self> {
+        let weights = weights.unwrap_or_else(|| vec![1.0; paths.len()]);
+        if weights.len() != paths.len() {
+            return Err(PyValueError::new_err("there must be one weight per path"));
+        }
+        let reader = FileReader::mixed(paths.into_iter().zip(weights), seed)
+            .map_err(|e| PyIOError::new_err(e.to_string()))?;
+        Ok(Self {
+            reader: Some(reader),
+        })
+    }
+
+    fn set_seed(&mut self, seed: u64) -> PyResult<()> {
+        self.reader()?.set_seed(seed);
+        Ok(())
+    }
+
+    fn set_shuffle_buffer_size(&mut self, size: usize) -> PyResult<()> {
+        self.reader()?.set_shuffle_buffer_size(size);
+        Ok(())
+    }
+
+    fn set_random_skip_probability(&mut self, probability: f64) -> PyResult<()> {
+        self.reader()?.set_random_skip_probability(probability);
+        Ok(())
+    }
+
+    fn set_mirroring(&mut self, enabled: bool) -> PyResult<()> {
+        self.reader()?.set_mirroring(enabled);
+        Ok(())
+    }
+}
+
+impl Dataset {
+    fn reader(&mut self) -> PyResult<&mut FileReader> {
+        self.reader.as_mut().ok_or_else(already_read)
+    }
+}
+
+fn already_read() -> PyErr {
+    PyValueError::new_err("the dataset is already being read")
+}
+
+/// Reads batches of `batch_size` positions from a dataset, which it takes over.
+#[pyclass]
+struct BatchLoader {
+    reader: FileReader,
+    feature_set: InputFeatureSetType,
+    batch: Batch,
+}
+
+#[pymethods]
+impl BatchLoader {
+    #[new]
+    fn new(
+        mut dataset: PyRefMut<Dataset>,
+        feature_set: InputFeatureSetType,
+        batch_size: usize,
+    ) -> PyResult<Self> {
+        let reader = dataset.reader.take().ok_or_else(already_read)?;
+        let batch = Batch::new(
+            batch_size,
+            feature_set.max_features() as usize,
+            feature_set.indices_per_feature() as usize,
+        );
+        Ok(Self {
+            reader,
+            feature_set,
+            batch,
+        })
+    }
+
+    /// Rewinds the dataset to start its next epoch.
+    fn reset(&mut self) -> PyResult<()> {
+        self.reader
+            .reset()
+            .map_err(|e| PyIOError::new_err(e.to_string()))
+    }
+
+    fn epoch(&self) -> u64 {
+        self.reader.epoch()
+    }
+
+    /// The next full batch, or `None` once the epoch is over.
+    fn next_batch<'py>(&mut self, py: Python<'py>) -> PyResult<Option<&'py PyDict>> {
+        let (reader, batch) = (&mut self.reader, &mut self.batch);
+        let add_features = add_features_fn(self.feature_set);
+        let filled = py
+            .allow_threads(|| data_loader::read_batch_with(reader, batch, add_features))
+            .map_err(|e| PyIOError::new_err(e.to_string()))?;
+        if !filled {
+            return Ok(None);
+        }
+        batch_dict(py, &self.batch).map(Some)
+    }
+}
+
+fn batch_dict<'py>(py: Python<'py>, batch: &Batch) -> PyResult<&'py PyDict> {
+    let len = batch.len();
+    let features = batch.total_features();
+    let indices = features * batch.indices_per_feature();
+    // Safety: the buffers hold at least this many initialized values, and are copied into
+    // the arrays before the batch can change.
+    let (stm, nstm, values, cp, wdl, weight, target, moves, buckets, scalars) = unsafe {
+        (
+            std::slice::from_raw_parts(batch.stm_feature_buffer_ptr(), indices),
+            std::slice::from_raw_parts(batch.nstm_feature_buffer_ptr(), indices),
+            std::slice::from_raw_parts(batch.values_ptr(), features),
+            std::slice::from_raw_parts(batch.cp_ptr(), len),
+            std::slice::from_raw_parts(batch.wdl_ptr(), len),
+            std::slice::from_raw_parts(batch.weight_ptr(), len),
+            std::slice::from_raw_parts(batch.target_ptr(), len),
+            std::slice::from_raw_parts(batch.moves_ptr(), len),
+            std::slice::from_raw_parts(batch.buckets_ptr(), len),
+            std::slice::from_raw_parts(batch.scalars_ptr(), len * SCALARS),
+        )
+    };
+
+    let dict = PyDict::new(py);
+    let (stm, nstm) = (
+        PyArray1::from_slice(py, stm),
+        PyArray1::from_slice(py, nstm),
+    );
+    match batch.indices_per_feature() {
+        2 => {
+            dict.set_item("stm_indices", stm.reshape([2, features])?)?;
+            dict.set_item("nstm_indices", nstm.reshape([2, features])?)?;
+        }
+        _ => {
+            dict.set_item("stm_indices", stm)?;
+            dict.set_item("nstm_indices", nstm)?;
+        }
+    }
+    dict.set_item("values", PyArray1::from_slice(py, values))?;
+    dict.set_item("cp", PyArray1::from_slice(py, cp).reshape([len, 1])?)?;
+    dict.set_item("wdl", PyArray1::from_slice(py, wdl).reshape([len, 1])?)?;
+    dict.set_item(
+        "weight",
+        PyArray1::from_slice(py, weight).reshape([len, 1])?,
+    )?;
+    dict.set_item(
+        "target",
+        PyArray1::from_slice(py, target).reshape([len, 1])?,
+    )?;
+    dict.set_item("moves", PyArray1::from_slice(py, moves))?;
+    dict.set_item("buckets", PyArray1::from_slice(py, buckets))?;
+    dict.set_item(
+        "scalars",
+        PyArray1::from_slice(py, scalars).reshape([len, SCALARS])?,
+    )?;
+    dict.set_item("size", len)?;
+    Ok(dict)
+}
+
+#[pymodule]
+fn parse(_py: Python, module: &PyModule) -> PyResult<()> {
+    module.add_class::<InputFeatureSetType>()?;
+    module.add_class::<Dataset>()?;
+    module.add_class::<BatchLoader>()?;
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use std::fs::File;
+
+use bytemuck::Pod;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+/// How a dataset read with random access picks its records.
+#[derive(Clone, Copy)]
+pub enum RandomOrder {
+    /// Every record once per epoch, in an order shuffled up front, which takes 8 bytes of
+    /// memory per record.
+    Permutation,
+    /// As many records per epoch as there are in the dataset, each picked at random, so some
+    /// are seen more than once and others not at all.
+    Sampled,
+}
+
+/// Reads the records of an uncompressed dataset in a random order, which gives a global
+/// shuffle every epoch without shuffling the dataset on disk. Each record is a positional
+/// read, which is cheap once the dataset is in the page cache.
+pub struct RandomAccess {
+    file: File,
+    // The offset of the first record, past any header
+    start: u64,
+    records: u64,
+    order: Order,
+    taken: u64,
+}
+
+enum Order {
+    Permutation(Vec<u64>),
+    Sampled(StdRng),
+}
+
+impl RandomAccess {
+    pub fn new(file: File, start: u64, records: u64, order: RandomOrder, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let order = match order {
+            RandomOrder::Permutation => {
+                let mut permutation: Vec<_> = (0..records).collect();
+                permutation.shuffle(&mut rng);
+                Order::Permutation(permutation)
+            }
+            RandomOrder::Sampled => Order::Sampled(rng),
+        };
+        Self {
+            file,
+            start,
+            records,
+            order,
+            taken: 0,
+        }
+    }
+
+    /// Fills `buffer` with up to `count` records, returning how many were read.
+    pub fn read_records<T: Pod>(
+        &mut self,
+        buffer: &mut Vec<T>,
+        count: usize,
+    ) -> std::io::Result<usize> {
+        buffer.clear();
+        while buffer.len() < count {
+            let index = match self.next_index() {
+                Some(index) => index,
+                None => break,
+            };
+            let mut record = T::zeroed();
+            let offset = self.start + index * std::mem::size_of::<T>() as u64;
+            read_exact_at(&self.file, bytemuck::bytes_of_mut(&mut record), offset)?;
+            buffer.push(record);
+        }
+        Ok(buffer.len())
+    }
+
+    fn next_index(&mut self) -> Option<u64> {
+        if self.taken == self.records {
+            return None;
+        }
+        let index = match &mut self.order {
+            Order::Permutation(permutation) => permutation[self.taken as usize],
+            Order::Sampled(rng) => rng.gen_range(0..self.records),
+        };
+        self.taken += 1;
+        Some(index)
+    }
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buffer: &mut [u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buffer, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buffer: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+    use std::io::{Error, ErrorKind};
+    use std::os::windows::fs::FileExt;
+
+    while !buffer.is_empty() {
+        match file.seek_read(buffer, offset) {
+            Ok(0) => return Err(Error::from(ErrorKind::UnexpectedEof)),
+            Ok(n) => {
+                buffer = &mut std::mem::take(&mut buffer)[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,34 @@
+use cozy_chess::{Board, Piece};
+
+/// The number of scalar inputs of each entry.
+pub const SCALARS: usize = 7;
+
+const MATERIAL: [u32; Piece::NUM] = [1, 3, 3, 5, 9, 0];
+const PHASE: [u32; Piece::NUM] = [0, 1, 1, 2, 4, 0];
+const MAX_PHASE: u32 = 24;
+
+/// Scalar facts about a position that trainers can condition on, in order: the material of
+/// both sides in pawns, the game phase from 0 for pawn endings to 1 for the starting
+/// material, the halfmove clock, and whether the side to move and then the other side can
+/// castle short and long.
+pub fn scalars(board: &Board) -> [f32; SCALARS] {
+    let mut material = 0;
+    let mut phase = 0;
+    for &piece in &Piece::ALL {
+        let count = board.pieces(piece).len();
+        material += count * MATERIAL[piece as usize];
+        phase += count * PHASE[piece as usize];
+    }
+
+    let stm = board.side_to_move();
+    let rights = [board.castle_rights(stm), board.castle_rights(!stm)];
+    [
+        material as f32,
+        phase.min(MAX_PHASE) as f32 / MAX_PHASE as f32,
+        board.halfmove_clock() as f32,
+        rights[0].short.is_some() as u8 as f32,
+        rights[0].long.is_some() as u8 as f32,
+        rights[1].short.is_some() as u8 as f32,
+        rights[1].long.is_some() as u8 as f32,
+    ]
+}
@@ -0,0 +1,70 @@
+/// The number that stands for 1 in the `I16` format, in which the result and target of each
+/// entry are fixed point.
+pub const I16_ONE: f32 = 16384.0;
+
+/// How the eval, result and target of each entry are packed for transfer to the device, to
+/// cut the bytes copied for large batches. Packed values are 16 bits wide: half precision
+/// floats for `F16`, and for `I16`, the eval in whole centipawns and the result and target
+/// in units of `1 / I16_ONE`.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ValueFormat {
+    F32,
+    F16,
+    I16,
+}
+
+impl ValueFormat {
+    pub fn pack_eval(self, cp: f32) -> u16 {
+        match self {
+            ValueFormat::F32 => unreachable!("F32 values aren't packed"),
+            ValueFormat::F16 => f16_bits(cp),
+            ValueFormat::I16 => cp.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16 as u16,
+        }
+    }
+
+    pub fn pack_fraction(self, value: f32) -> u16 {
+        match self {
+            ValueFormat::F32 => unreachable!("F32 values aren't packed"),
+            ValueFormat::F16 => f16_bits(value),
+            ValueFormat::I16 => (value * I16_ONE).round() as i16 as u16,
+        }
+    }
+}
+
+/// The bits of the half precision float nearest to `value`, rounding ties to even.
+fn f16_bits(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent == 0xff {
+        // Infinity stays infinity, and NaN stays NaN.
+        return sign | 0x7c00 | ((mantissa != 0) as u16) << 9;
+    }
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if exponent <= 0 {
+        // Subnormal, or too small to represent at all.
+        if exponent < -10 {
+            return sign;
+        }
+        let shift = (14 - exponent) as u32;
+        return sign | round_shift(mantissa | 0x80_0000, shift) as u16;
+    }
+    // A carry out of the mantissa rounds up into the exponent, as it should.
+    sign | (((exponent as u32) << 10) + round_shift(mantissa, 13)) as u16
+}
+
+/// `value >> shift`, rounded to nearest with ties to even.
+fn round_shift(value: u32, shift: u32) -> u32 {
+    let truncated = value >> shift;
+    let rest = value & ((1 << shift) - 1);
+    let half = 1 << (shift - 1);
+    match rest > half || (rest == half && truncated & 1 == 1) {
+        true => truncated + 1,
+        false => truncated,
+    }
+}
@@ -0,0 +1,117 @@
+use cozy_chess::{Board, BoardBuilder, Square};
+
+use crate::batch::Batch;
+use crate::{
+    add_features_fn, input_feature_set_get_max_features, input_feature_set_get_num_inputs,
+    is_perspective, InputFeatureSetType,
+};
+
+// Between them, kings on both wings, castling rights, en passant, promoted pieces and bare
+// kings.
+const POSITIONS: [&str; 6] = [
+    "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+    "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+    "r1bq1rk1/pp2bppp/2n1pn2/2pp4/3P4/2PBPN2/PP1N1PPP/R2QK2R b KQ c6 0 8",
+    "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+    "2Q5/5k2/8/3n4/8/1B6/6NN/K7 b - - 0 60",
+    "8/8/8/4k3/8/8/8/3K4 w - - 0 1",
+];
+
+/// Checks that a feature set keeps the invariants that a mistake in it would silently break,
+/// on a few positions and on each of them with the colors swapped: every feature is below
+/// `NUM_INPUTS` and written at most once per entry, no entry has more than `MAX_FEATURES`, and
+/// swapping the colors swaps the stm and nstm features, or for feature sets that don't see
+/// the board from each side's point of view, the stm and nstm features are the same.
+pub fn verify_feature_set(feature_set: InputFeatureSetType) -> Result<(), String> {
+    for fen in POSITIONS {
+        let board = Board::from_fen(fen, false).unwrap();
+        let [stm, nstm] = features(feature_set, &board).map_err(|e| format!("{} in {}", e, fen))?;
+        let [swapped_stm, swapped_nstm] = features(feature_set, &swap_colors(&board))
+            .map_err(|e| format!("{} in {} with the colors swapped", e, fen))?;
+        if !is_perspective(feature_set) {
+            if stm != nstm {
+                return Err(format!("the stm and nstm features differ in {}", fen));
+            }
+            continue;
+        }
+        if stm != swapped_nstm {
+            return Err(format!(
+                "the stm features of {} aren't the nstm features with the colors swapped",
+                fen
+            ));
+        }
+        if nstm != swapped_stm {
+            return Err(format!(
+                "the nstm features of {} aren't the stm features with the colors swapped",
+                fen
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The sorted stm and nstm features of the board, which panics on features out of range or
+/// written twice.
+fn features(feature_set: InputFeatureSetType, board: &Board) -> Result<[Vec<usize>; 2], String> {
+    let num_inputs = unsafe { input_feature_set_get_num_inputs(feature_set) } as usize;
+    let max_features = unsafe { input_feature_set_get_max_features(feature_set) } as usize;
+    // A dense batch, so that too many features can't overrun the sparse buffers
+    let mut batch = Batch::new_dense(1, num_inputs);
+    batch.set_validation(num_inputs);
+    let entry = batch.make_entry(board, 0.0, 0.5, 1.0, -1);
+    add_features_fn(feature_set)(board.clone(), entry);
+
+    // Safety: a dense batch of one entry holds `num_inputs` floats per side.
+    let planes = unsafe {
+        [
+            std::slice::from_raw_parts(batch.stm_dense_ptr(), num_inputs),
+            std::slice::from_raw_parts(batch.nstm_dense_ptr(), num_inputs),
+        ]
+    };
+    let features = planes.map(|plane| {
+        (0..num_inputs)
+            .filter(|&feature| plane[feature] != 0.0)
+            .collect::<Vec<_>>()
+    });
+    for (side, features) in ["stm", "nstm"].iter().zip(&features) {
+        if features.len() > max_features {
+            return Err(format!(
+                "{} {} features, more than the {} allowed",
+                features.len(),
+                side,
+                max_features
+            ));
+        }
+    }
+    Ok(features)
+}
+
+/// The board with the colors of the pieces and the side to move swapped, and the ranks
+/// flipped, which is the same position from the other side's point of view.
+fn swap_colors(board: &Board) -> Board {
+    let original = BoardBuilder::from_board(board);
+    let mut builder = BoardBuilder::from_board(board);
+    for &square in &Square::ALL {
+        builder.board[square.flip_rank() as usize] =
+            original.board[square as usize].map(|(piece, color)| (piece, !color));
+    }
+    builder.side_to_move = !original.side_to_move;
+    builder.castle_rights.reverse();
+    builder.en_passant = original.en_passant.map(Square::flip_rank);
+    builder.build().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::verify_feature_set;
+    use crate::FEATURE_SET_NAMES;
+
+    #[test]
+    fn feature_sets_keep_their_invariants() {
+        for &(name, feature_set) in FEATURE_SET_NAMES.iter() {
+            if let Err(error) = verify_feature_set(feature_set) {
+                panic!("{}: {}", name, error);
+            }
+        }
+    }
+}
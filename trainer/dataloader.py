@@ -4,34 +4,93 @@ from dataclasses import dataclass
 from enum import IntEnum
 
 import ctypes
+import math
 import os
 
 import numpy as np
 import torch
 
 
+# The version of the parse library's functions and types that this module is written for
+PARSE_ABI_VERSION = 1
+
+
 def _load_parse_lib():
     path = "./libparse.dll" if os.name == "nt" else "./libparse.so"
     lib = ctypes.cdll.LoadLibrary(path)
 
+    lib.parse_abi_version.restype = ctypes.c_uint32
+    version = lib.parse_abi_version()
+    if version != PARSE_ABI_VERSION:
+        raise Exception(
+            f"{path} has ABI version {version}, expected {PARSE_ABI_VERSION}, "
+            "rebuild it"
+        )
+    lib.parse_last_error.restype = ctypes.c_char_p
+
     lib.batch_new.restype = ctypes.c_void_p
+    lib.batch_new_dense.restype = ctypes.c_void_p
     lib.batch_drop.restype = None
+    lib.batch_set_material_output_buckets.restype = None
+    lib.batch_set_pawn_structure.restype = None
+    lib.batch_set_target_blend.restype = None
+    lib.batch_set_value_format.restype = None
+    lib.batch_set_sample_weights.restype = None
+    lib.batch_set_eval_weighting.restype = None
+    lib.batch_set_wdl_smoothing.restype = None
+    lib.batch_set_validation.restype = None
     lib.batch_get_capacity.restype = ctypes.c_uint32
     lib.batch_get_len.restype = ctypes.c_uint32
     lib.batch_get_stm_feature_buffer_ptr.restype = ctypes.POINTER(ctypes.c_int64)
     lib.batch_get_nstm_feature_buffer_ptr.restype = ctypes.POINTER(ctypes.c_int64)
     lib.batch_get_values_ptr.restype = ctypes.POINTER(ctypes.c_float)
     lib.batch_get_total_features.restype = ctypes.c_uint32
+    lib.batch_get_dense_inputs.restype = ctypes.c_uint32
+    lib.batch_get_stm_dense_ptr.restype = ctypes.POINTER(ctypes.c_float)
+    lib.batch_get_nstm_dense_ptr.restype = ctypes.POINTER(ctypes.c_float)
     lib.batch_get_cp_ptr.restype = ctypes.POINTER(ctypes.c_float)
     lib.batch_get_wdl_ptr.restype = ctypes.POINTER(ctypes.c_float)
+    lib.batch_get_weight_ptr.restype = ctypes.POINTER(ctypes.c_float)
+    lib.batch_get_target_ptr.restype = ctypes.POINTER(ctypes.c_float)
+    lib.batch_get_value_format.restype = ctypes.c_uint32
+    lib.batch_get_packed_cp_ptr.restype = ctypes.POINTER(ctypes.c_uint16)
+    lib.batch_get_packed_wdl_ptr.restype = ctypes.POINTER(ctypes.c_uint16)
+    lib.batch_get_packed_target_ptr.restype = ctypes.POINTER(ctypes.c_uint16)
+    lib.batch_get_moves_ptr.restype = ctypes.POINTER(ctypes.c_int64)
+    lib.batch_get_buckets_ptr.restype = ctypes.POINTER(ctypes.c_int64)
+    lib.batch_get_scalars_ptr.restype = ctypes.POINTER(ctypes.c_float)
+    lib.batch_scalars.restype = ctypes.c_uint32
+    lib.batch_policy_moves.restype = ctypes.c_uint32
+    lib.batch_get_entry_offsets_ptr.restype = ctypes.POINTER(ctypes.c_uint32)
+    lib.batch_get_host_buffer.restype = ctypes.c_void_p
 
     lib.file_reader_new.restype = ctypes.c_void_p
+    lib.file_reader_new_mixed.restype = ctypes.c_void_p
+    lib.file_reader_set_shuffle_buffer_size.restype = None
+    lib.file_reader_set_seed.restype = None
+    lib.file_reader_set_random_skip_probability.restype = None
+    lib.file_reader_set_filters.restype = None
+    lib.file_reader_set_random_order.restype = ctypes.c_bool
+    lib.file_reader_set_mirroring.restype = None
+    lib.file_reader_set_eval_clamp.restype = None
+    lib.file_reader_set_readahead.restype = ctypes.c_bool
+    lib.file_reader_reset.restype = ctypes.c_bool
+    lib.file_reader_get_epoch.restype = ctypes.c_uint64
     lib.file_reader_drop.restype = None
 
     lib.input_feature_set_get_max_features.restype = ctypes.c_uint32
     lib.input_feature_set_get_indices_per_feature.restype = ctypes.c_uint32
+    lib.input_feature_set_get_num_inputs.restype = ctypes.c_uint32
+    lib.input_feature_set_from_name.restype = ctypes.c_int32
+    lib.input_feature_set_verify.restype = ctypes.c_bool
 
     lib.read_batch_into.restype = ctypes.c_bool
+    lib.read_batch_into_king_buckets.restype = ctypes.c_bool
+
+    lib.prefetcher_new.restype = ctypes.c_void_p
+    lib.prefetcher_new_king_buckets.restype = ctypes.c_void_p
+    lib.prefetcher_next_batch.restype = ctypes.c_void_p
+    lib.prefetcher_drop.restype = None
 
     return lib
 
@@ -39,6 +98,30 @@ def _load_parse_lib():
 PARSE_LIB = _load_parse_lib()
 
 
+def _last_error() -> str:
+    error = PARSE_LIB.parse_last_error()
+    return "unknown error" if error is None else error.decode()
+
+
+def _check_error() -> None:
+    """Raises the error of the last call that reported one, for calls whose failure
+    looks like running out of data."""
+    error = PARSE_LIB.parse_last_error()
+    if error is not None:
+        raise Exception(error.decode())
+
+# Passed, isolated and doubled pawns of each side by file.
+PAWN_STRUCTURE_INPUTS = 48
+
+# Material in pawns, phase from 0 to 1, halfmove clock, then castling rights of the side
+# to move (short, long) and of the other side (short, long).
+SCALARS = PARSE_LIB.batch_scalars()
+
+# The size of the policy index space of Batch.moves: from * 64 + to for the side to
+# move, queen promotions included, then 72 underpromotions.
+POLICY_MOVES = PARSE_LIB.batch_policy_moves()
+
+
 class InputFeatureSet(IntEnum):
     BOARD_768 = 0
     HALF_KP = 1
@@ -46,6 +129,25 @@ class InputFeatureSet(IntEnum):
     BOARD_768_CUDA = 3
     HALF_KP_CUDA = 4
     HALF_KA_CUDA = 5
+    HALF_KA_V2_HM = 6
+    HALF_KA_V2_HM_CUDA = 7
+    HALF_KA_FACTORIZED = 8
+    HALF_KA_FACTORIZED_CUDA = 9
+    THREATS = 10
+    THREATS_CUDA = 11
+    BOARD_768_MIRRORED = 12
+    BOARD_768_MIRRORED_CUDA = 13
+    BOARD_768_COLOR_BLIND = 14
+    BOARD_768_COLOR_BLIND_CUDA = 15
+
+    @classmethod
+    def from_name(cls, name: str) -> InputFeatureSet:
+        """The feature set called `name` by the parse library, such as "halfka" or
+        "halfkav2_hm_cuda"."""
+        value = PARSE_LIB.input_feature_set_from_name(bytes(name, "utf-8"))
+        if value < 0:
+            raise ValueError(_last_error())
+        return cls(value)
 
     def max_features(self) -> int:
         return PARSE_LIB.input_feature_set_get_max_features(self)
@@ -53,14 +155,105 @@ class InputFeatureSet(IntEnum):
     def indices_per_feature(self) -> int:
         return PARSE_LIB.input_feature_set_get_indices_per_feature(self)
 
+    def num_inputs(self) -> int:
+        return PARSE_LIB.input_feature_set_get_num_inputs(self)
+
+    def verify(self) -> None:
+        """Raises ValueError if the feature set writes features out of range, more than
+        max_features of them, or features that don't swap between the two sides when
+        the colors are swapped."""
+        if not PARSE_LIB.input_feature_set_verify(self):
+            raise ValueError(_last_error())
+
+
+class KingBuckets:
+    """HalfKA features with king squares grouped by a map of 64 buckets, indexed by the
+    square of each side's king as seen from that side (a1 = 0, h8 = 63)."""
+
+    def __init__(self, buckets: list[int], cuda: bool = False) -> None:
+        assert len(buckets) == 64
+        assert all(0 <= bucket < 256 for bucket in buckets)
+        self.buckets = buckets
+        self.cuda = cuda
+
+    def max_features(self) -> int:
+        return 32
+
+    def indices_per_feature(self) -> int:
+        return 1 if self.cuda else 2
+
+    def num_inputs(self) -> int:
+        return (max(self.buckets) + 1) * 768
+
+
+class ValueFormat(IntEnum):
+    """How Batch.cp, wdl and target are copied to the device, where they are turned
+    back into floats. F16 and I16 halve the bytes copied: I16 holds the eval in whole
+    centipawns and the result and target in units of 1 / I16_ONE."""
+
+    F32 = 0
+    F16 = 1
+    I16 = 2
+
+
+I16_ONE = 16384
+
+
+@dataclass
+class Filters:
+    """Positions to leave out while reading. The defaults keep everything but evals
+    beyond 3000 centipawns, which are mostly mates and won endgames."""
+
+    # The largest eval kept, in centipawns either way. Mate scores are beyond any
+    # centipawn eval.
+    max_eval: float = 3000.0
+    # The largest eval kept in favour of the side that went on to lose the game.
+    max_incongruent_eval: float = math.inf
+    # Pieces on the board, kings included.
+    min_pieces: int = 0
+    max_pieces: int = 2**32 - 1
+    # Plies since the start of the game, by the fullmove number.
+    min_ply: int = 0
+    max_ply: int = 2**32 - 1
+    skip_in_check: bool = False
+
+
+def _to_pytorch(
+    array: np.ndarray, device: torch.device, pinned: bool = False
+) -> torch.Tensor:
+    tch_array = torch.from_numpy(array)
+    if torch.cuda.is_available() and not pinned:
+        tch_array = tch_array.pin_memory()
+    return tch_array.to(device, non_blocking=True)
+
+
+def _unpack_fraction(tensor: torch.Tensor, value_format: ValueFormat) -> torch.Tensor:
+    tensor = tensor.float()
+    if value_format == ValueFormat.I16:
+        tensor /= I16_ONE
+    return tensor
+
 
 @dataclass
 class Batch:
+    # A (2, features) matrix of entries over features for sparse feature sets, as
+    # torch.sparse_coo_tensor takes, or max_features per entry, padded with -1, for
+    # cuda ones.
     stm_indices: torch.Tensor
     nstm_indices: torch.Tensor
     values: torch.Tensor
     cp: torch.Tensor
     wdl: torch.Tensor
+    weight: torch.Tensor
+    # lambda * sigmoid(cp / scale) + (1 - lambda) * wdl, see BatchLoader.
+    target: torch.Tensor
+    moves: torch.Tensor
+    buckets: torch.Tensor
+    scalars: torch.Tensor
+    # 0/1 planes of shape (size, inputs) when read densely, in which case the sparse
+    # indices and values are empty.
+    stm_dense: torch.Tensor | None
+    nstm_dense: torch.Tensor | None
     size: int
 
 
@@ -78,6 +271,51 @@ class ParserBatch:
         if self._ptr.value is None:
             raise Exception("Failed to create batch")
 
+    @classmethod
+    def dense(cls, batch_size: int, inputs: int) -> ParserBatch:
+        batch = cls.__new__(cls)
+        batch._ptr = ctypes.c_void_p(
+            PARSE_LIB.batch_new_dense(
+                ctypes.c_uint32(batch_size), ctypes.c_uint32(inputs)
+            )
+        )
+        if batch._ptr.value is None:
+            raise Exception("Failed to create dense batch")
+        return batch
+
+    @classmethod
+    def _borrowed(cls, ptr: int) -> ParserBatch:
+        """A batch owned by the parse library, which must not be dropped."""
+        batch = cls.__new__(cls)
+        batch._ptr = ctypes.c_void_p(ptr)
+        return batch
+
+    def set_material_output_buckets(self, count: int) -> None:
+        PARSE_LIB.batch_set_material_output_buckets(self._ptr, ctypes.c_uint32(count))
+
+    def set_target_blend(self, eval_lambda: float, scale: float) -> None:
+        PARSE_LIB.batch_set_target_blend(
+            self._ptr, ctypes.c_float(eval_lambda), ctypes.c_float(scale)
+        )
+
+    def set_validation(self, num_inputs: int) -> None:
+        PARSE_LIB.batch_set_validation(self._ptr, ctypes.c_uint32(num_inputs))
+
+    def set_sample_weights(self, enabled: bool) -> None:
+        PARSE_LIB.batch_set_sample_weights(self._ptr, ctypes.c_bool(enabled))
+
+    def set_eval_weighting(self, scale: float) -> None:
+        PARSE_LIB.batch_set_eval_weighting(self._ptr, ctypes.c_float(scale))
+
+    def set_wdl_smoothing(self, epsilon: float) -> None:
+        PARSE_LIB.batch_set_wdl_smoothing(self._ptr, ctypes.c_float(epsilon))
+
+    def set_value_format(self, value_format: ValueFormat) -> None:
+        PARSE_LIB.batch_set_value_format(self._ptr, value_format)
+
+    def set_pawn_structure(self, offset: int) -> None:
+        PARSE_LIB.batch_set_pawn_structure(self._ptr, ctypes.c_uint32(offset))
+
     def drop(self) -> None:
         if self._ptr.value is not None:
             PARSE_LIB.batch_drop(self._ptr)
@@ -110,44 +348,161 @@ class ParserBatch:
     def get_indices_per_feature(self) -> int:
         return PARSE_LIB.batch_get_indices_per_feature(self._ptr)
 
+    def get_dense_inputs(self) -> int:
+        return PARSE_LIB.batch_get_dense_inputs(self._ptr)
+
+    def get_stm_dense_ptr(self) -> ctypes.pointer[ctypes.c_float]:
+        return PARSE_LIB.batch_get_stm_dense_ptr(self._ptr)
+
+    def get_nstm_dense_ptr(self) -> ctypes.pointer[ctypes.c_float]:
+        return PARSE_LIB.batch_get_nstm_dense_ptr(self._ptr)
+
     def get_cp_ptr(self) -> ctypes.pointer[ctypes.c_float]:
         return PARSE_LIB.batch_get_cp_ptr(self._ptr)
 
     def get_wdl_ptr(self) -> ctypes.pointer[ctypes.c_float]:
         return PARSE_LIB.batch_get_wdl_ptr(self._ptr)
 
-    def to_pytorch_batch(self, device: torch.device) -> Batch:
-        def to_pytorch(array: np.ndarray) -> torch.Tensor:
-            tch_array = torch.from_numpy(array)
-            if torch.cuda.is_available():
-                tch_array = tch_array.pin_memory()
-            return tch_array.to(device, non_blocking=True)
+    def get_weight_ptr(self) -> ctypes.pointer[ctypes.c_float]:
+        return PARSE_LIB.batch_get_weight_ptr(self._ptr)
+
+    def get_target_ptr(self) -> ctypes.pointer[ctypes.c_float]:
+        return PARSE_LIB.batch_get_target_ptr(self._ptr)
+
+    def get_value_format(self) -> ValueFormat:
+        return ValueFormat(PARSE_LIB.batch_get_value_format(self._ptr))
+
+    def get_packed_cp_ptr(self) -> ctypes.pointer[ctypes.c_uint16]:
+        return PARSE_LIB.batch_get_packed_cp_ptr(self._ptr)
+
+    def get_packed_wdl_ptr(self) -> ctypes.pointer[ctypes.c_uint16]:
+        return PARSE_LIB.batch_get_packed_wdl_ptr(self._ptr)
+
+    def get_packed_target_ptr(self) -> ctypes.pointer[ctypes.c_uint16]:
+        return PARSE_LIB.batch_get_packed_target_ptr(self._ptr)
+
+    def get_moves_ptr(self) -> ctypes.pointer[ctypes.c_int64]:
+        return PARSE_LIB.batch_get_moves_ptr(self._ptr)
+
+    def get_buckets_ptr(self) -> ctypes.pointer[ctypes.c_int64]:
+        return PARSE_LIB.batch_get_buckets_ptr(self._ptr)
+
+    def get_scalars_ptr(self) -> ctypes.pointer[ctypes.c_float]:
+        return PARSE_LIB.batch_get_scalars_ptr(self._ptr)
 
+    def get_entry_offsets_ptr(self) -> ctypes.pointer[ctypes.c_uint32]:
+        return PARSE_LIB.batch_get_entry_offsets_ptr(self._ptr)
+
+    def host_buffers(self) -> list[tuple[int, int]]:
+        """The address and size in bytes of each buffer copied to the device."""
+        buffers = []
+        size = ctypes.c_uint64()
+        while True:
+            ptr = PARSE_LIB.batch_get_host_buffer(
+                self._ptr, ctypes.c_uint32(len(buffers)), ctypes.byref(size)
+            )
+            if ptr is None:
+                return buffers
+            buffers.append((ptr, size.value))
+
+    def to_pytorch_batch(self, device: torch.device, pinned: bool = False) -> Batch:
+        return self.to_pytorch_micro_batches(device, 1, pinned)[0]
+
+    def to_pytorch_micro_batches(
+        self, device: torch.device, count: int, pinned: bool = False
+    ) -> list[Batch]:
+        """Split the batch into `count` micro-batches without re-reading features.
+        With `pinned`, the host buffers are page-locked, see BatchLoader."""
         total_features = self.get_total_features()
         indices_per_feature = self.get_indices_per_feature()
-        boards_stm = to_pytorch(
-            np.ctypeslib.as_array(
-                self.get_stm_feature_buffer_ptr(),
-                shape=(total_features * indices_per_feature,),
-            )
+        if indices_per_feature == 2:
+            shape = (2, total_features)
+        else:
+            shape = (total_features,)
+        boards_stm = np.ctypeslib.as_array(
+            self.get_stm_feature_buffer_ptr(), shape=shape
         )
-        boards_nstm = to_pytorch(
-            np.ctypeslib.as_array(
-                self.get_nstm_feature_buffer_ptr(),
-                shape=(total_features * indices_per_feature,),
-            )
-        )
-        values = to_pytorch(
-            np.ctypeslib.as_array(self.get_values_ptr(), shape=(total_features,))
+        boards_nstm = np.ctypeslib.as_array(
+            self.get_nstm_feature_buffer_ptr(), shape=shape
         )
+        values = np.ctypeslib.as_array(self.get_values_ptr(), shape=(total_features,))
 
         batch_len = self.get_len()
-        cp = to_pytorch(np.ctypeslib.as_array(self.get_cp_ptr(), shape=(batch_len, 1)))
-        wdl = to_pytorch(
-            np.ctypeslib.as_array(self.get_wdl_ptr(), shape=(batch_len, 1))
+        value_format = self.get_value_format()
+        if value_format == ValueFormat.F32:
+            cp_ptr, wdl_ptr = self.get_cp_ptr(), self.get_wdl_ptr()
+            target_ptr = self.get_target_ptr()
+        else:
+            cp_ptr, wdl_ptr = self.get_packed_cp_ptr(), self.get_packed_wdl_ptr()
+            target_ptr = self.get_packed_target_ptr()
+        dtype = {
+            ValueFormat.F32: np.float32,
+            ValueFormat.F16: np.float16,
+            ValueFormat.I16: np.int16,
+        }[value_format]
+        cp = np.ctypeslib.as_array(cp_ptr, shape=(batch_len, 1)).view(dtype)
+        wdl = np.ctypeslib.as_array(wdl_ptr, shape=(batch_len, 1)).view(dtype)
+        weight = np.ctypeslib.as_array(self.get_weight_ptr(), shape=(batch_len, 1))
+        target = np.ctypeslib.as_array(target_ptr, shape=(batch_len, 1)).view(dtype)
+        # The policy index of the move, below POLICY_MOVES, or -1 without one
+        moves = np.ctypeslib.as_array(self.get_moves_ptr(), shape=(batch_len,))
+        buckets = np.ctypeslib.as_array(self.get_buckets_ptr(), shape=(batch_len,))
+        scalars = np.ctypeslib.as_array(
+            self.get_scalars_ptr(), shape=(batch_len, SCALARS)
+        )
+        offsets = np.append(
+            np.ctypeslib.as_array(self.get_entry_offsets_ptr(), shape=(batch_len,)),
+            total_features,
         )
+        dense_inputs = self.get_dense_inputs()
+        if dense_inputs > 0:
+            stm_dense = np.ctypeslib.as_array(
+                self.get_stm_dense_ptr(), shape=(batch_len, dense_inputs)
+            )
+            nstm_dense = np.ctypeslib.as_array(
+                self.get_nstm_dense_ptr(), shape=(batch_len, dense_inputs)
+            )
 
-        return Batch(boards_stm, boards_nstm, values, cp, wdl, batch_len)
+        micro_batches = []
+        bounds = np.linspace(0, batch_len, count + 1, dtype=np.int64)
+        for start, end in zip(bounds[:-1], bounds[1:]):
+            first, last = offsets[start], offsets[end]
+            stm = boards_stm[..., first:last]
+            nstm = boards_nstm[..., first:last]
+            sparse_pinned = pinned
+            if indices_per_feature == 2 and count > 1:
+                # Copy each slice of both rows together, and rebase the entries.
+                stm = stm.copy()
+                nstm = nstm.copy()
+                stm[0] -= start
+                nstm[0] -= start
+                sparse_pinned = False
+            stm_planes = nstm_planes = None
+            if dense_inputs > 0:
+                stm_planes = _to_pytorch(stm_dense[start:end], device, pinned)
+                nstm_planes = _to_pytorch(nstm_dense[start:end], device, pinned)
+            micro_batches.append(
+                Batch(
+                    _to_pytorch(stm, device, sparse_pinned),
+                    _to_pytorch(nstm, device, sparse_pinned),
+                    _to_pytorch(values[first:last], device, pinned),
+                    _to_pytorch(cp[start:end], device, pinned).float(),
+                    _unpack_fraction(
+                        _to_pytorch(wdl[start:end], device, pinned), value_format
+                    ),
+                    _to_pytorch(weight[start:end], device, pinned),
+                    _unpack_fraction(
+                        _to_pytorch(target[start:end], device, pinned), value_format
+                    ),
+                    _to_pytorch(moves[start:end], device, pinned),
+                    _to_pytorch(buckets[start:end], device, pinned),
+                    _to_pytorch(scalars[start:end], device, pinned),
+                    stm_planes,
+                    nstm_planes,
+                    int(end - start),
+                )
+            )
+        return micro_batches
 
 
 class ParserFileReader:
@@ -156,7 +511,84 @@ class ParserFileReader:
             PARSE_LIB.file_reader_new(ctypes.create_string_buffer(bytes(path, "ascii")))
         )
         if self._ptr.value is None:
-            raise Exception("Failed to create file reader")
+            raise Exception(f"Failed to create file reader: {_last_error()}")
+
+    @classmethod
+    def mixed(
+        cls, paths: list[str], weights: list[float], seed: int
+    ) -> ParserFileReader:
+        assert len(paths) == len(weights)
+        c_paths = (ctypes.c_char_p * len(paths))(
+            *(bytes(path, "ascii") for path in paths)
+        )
+        reader = cls.__new__(cls)
+        reader._ptr = ctypes.c_void_p(
+            PARSE_LIB.file_reader_new_mixed(
+                c_paths,
+                (ctypes.c_float * len(weights))(*weights),
+                ctypes.c_uint32(len(paths)),
+                ctypes.c_uint64(seed),
+            )
+        )
+        if reader._ptr.value is None:
+            raise Exception(f"Failed to create mixed file reader: {_last_error()}")
+        return reader
+
+    def set_shuffle_buffer_size(self, size: int) -> None:
+        PARSE_LIB.file_reader_set_shuffle_buffer_size(self._ptr, ctypes.c_uint64(size))
+
+    def set_seed(self, seed: int) -> None:
+        """Seeds the mixing, skipping and shuffle buffer draws of the current epoch,
+        which are seeded with `seed + epoch` in later ones."""
+        PARSE_LIB.file_reader_set_seed(self._ptr, ctypes.c_uint64(seed))
+
+    def set_random_skip_probability(self, probability: float) -> None:
+        PARSE_LIB.file_reader_set_random_skip_probability(
+            self._ptr, ctypes.c_double(probability)
+        )
+
+    def set_mirroring(self, enabled: bool) -> None:
+        PARSE_LIB.file_reader_set_mirroring(self._ptr, ctypes.c_bool(enabled))
+
+    def set_eval_clamp(self, bound: float) -> None:
+        PARSE_LIB.file_reader_set_eval_clamp(self._ptr, ctypes.c_float(bound))
+
+    def set_readahead(self, size: int) -> None:
+        """Reads up to `size` bytes of each dataset ahead on a background thread,
+        and starts the epoch over."""
+        if not PARSE_LIB.file_reader_set_readahead(self._ptr, ctypes.c_uint64(size)):
+            raise Exception(f"Failed to reopen datasets: {_last_error()}")
+
+    def set_filters(self, filters: Filters) -> None:
+        PARSE_LIB.file_reader_set_filters(
+            self._ptr,
+            ctypes.c_float(filters.max_eval),
+            ctypes.c_float(filters.max_incongruent_eval),
+            ctypes.c_uint32(filters.min_pieces),
+            ctypes.c_uint32(filters.max_pieces),
+            ctypes.c_uint32(filters.min_ply),
+            ctypes.c_uint32(filters.max_ply),
+            ctypes.c_bool(filters.skip_in_check),
+        )
+
+    def set_random_order(self, permutation: bool) -> None:
+        """Reads the records of each dataset in a random order, every record once
+        per epoch for a permutation or picked with replacement otherwise. Needs
+        uncompressed datasets, and starts the epoch over."""
+        if not PARSE_LIB.file_reader_set_random_order(
+            self._ptr, ctypes.c_bool(permutation)
+        ):
+            raise Exception(
+                f"Failed to read datasets in a random order: {_last_error()}"
+            )
+
+    def reset(self) -> None:
+        """Rewinds the reader to start its next epoch."""
+        if not PARSE_LIB.file_reader_reset(self._ptr):
+            raise Exception(f"Failed to reset file reader: {_last_error()}")
+
+    def epoch(self) -> int:
+        return PARSE_LIB.file_reader_get_epoch(self._ptr)
 
     def drop(self) -> None:
         if self._ptr.value is not None:
@@ -171,35 +603,301 @@ class ParserFileReader:
 
 
 def read_batch_into(
-    reader: ParserFileReader, feature_set: InputFeatureSet, parser_batch: ParserBatch
+    reader: ParserFileReader,
+    feature_set: InputFeatureSet | KingBuckets,
+    parser_batch: ParserBatch,
 ) -> bool:
-    return PARSE_LIB.read_batch_into(reader._ptr, feature_set, parser_batch._ptr)
+    if isinstance(feature_set, KingBuckets):
+        filled = PARSE_LIB.read_batch_into_king_buckets(
+            reader._ptr,
+            (ctypes.c_uint8 * 64)(*feature_set.buckets),
+            ctypes.c_bool(feature_set.cuda),
+            parser_batch._ptr,
+        )
+    else:
+        filled = PARSE_LIB.read_batch_into(reader._ptr, feature_set, parser_batch._ptr)
+    if not filled:
+        _check_error()
+    return filled
+
+
+class ParserPrefetcher:
+    """Fills batches like `template` on `threads` background threads, keeping up to
+    `queue` batches ready. Takes ownership of the reader."""
+
+    def __init__(
+        self,
+        reader: ParserFileReader,
+        feature_set: InputFeatureSet | KingBuckets,
+        template: ParserBatch,
+        threads: int,
+        queue: int,
+    ) -> None:
+        if isinstance(feature_set, KingBuckets):
+            ptr = PARSE_LIB.prefetcher_new_king_buckets(
+                reader._ptr,
+                (ctypes.c_uint8 * 64)(*feature_set.buckets),
+                ctypes.c_bool(feature_set.cuda),
+                template._ptr,
+                ctypes.c_uint32(threads),
+                ctypes.c_uint32(queue),
+            )
+        else:
+            ptr = PARSE_LIB.prefetcher_new(
+                reader._ptr,
+                feature_set,
+                template._ptr,
+                ctypes.c_uint32(threads),
+                ctypes.c_uint32(queue),
+            )
+        reader._ptr.value = None
+        self._ptr = ctypes.c_void_p(ptr)
+
+    def next_batch(self) -> ParserBatch | None:
+        """The next full batch, valid until the next call, or None at the end of the
+        reader."""
+        ptr = PARSE_LIB.prefetcher_next_batch(self._ptr)
+        if ptr is None:
+            _check_error()
+            return None
+        return ParserBatch._borrowed(ptr)
+
+    def drop(self) -> None:
+        if self._ptr.value is not None:
+            PARSE_LIB.prefetcher_drop(self._ptr)
+            self._ptr.value = None
+
+    def __enter__(self) -> ParserPrefetcher:
+        return self
+
+    def __exit__(self) -> None:
+        self.drop()
 
 
 class BatchLoader:
     def __init__(
-        self, files: list[str], feature_set: InputFeatureSet, batch_size: int
+        self,
+        files: list[str],
+        feature_set: InputFeatureSet | KingBuckets,
+        batch_size: int,
+        weights: list[float] | None = None,
+        seed: int = 0,
+        output_buckets: int = 1,
+        pawn_structure: bool = False,
+        dense: bool = False,
+        threads: int = 0,
+        prefetch: int = 4,
+        shuffle_buffer_size: int = 0,
+        random_skip_probability: float = 0.0,
+        random_order: str | None = None,
+        readahead: int = 0,
+        mirror: bool = False,
+        eval_clamp: float | None = None,
+        filters: Filters | None = None,
+        target_blend: tuple[float, float] | None = None,
+        pin_memory: bool = False,
+        value_format: ValueFormat = ValueFormat.F32,
+        sample_weights: bool = True,
+        eval_weighting: float | None = None,
+        wdl_smoothing: float | None = None,
+        validate: bool = False,
     ) -> None:
+        """With `threads` > 0, batches are filled on that many background threads,
+        keeping up to `prefetch` batches ready, and may come out of order. With
+        `shuffle_buffer_size` > 0, positions are drawn at random from a buffer of
+        that many positions read ahead. Each position is skipped with probability
+        `random_skip_probability`. `random_order` of "permutation" or "sampled"
+        reads the records of each dataset in a random order instead of in sequence,
+        for a global shuffle every epoch of datasets that fit in the page cache.
+        `readahead` > 0 reads that many bytes of each dataset ahead on a background
+        thread, so that reading overlaps with parsing, unless they are read in a
+        random order. With `mirror`, positions without castling rights are mirrored
+        across the vertical axis with probability 0.5. `eval_clamp` clamps evals to
+        that many centipawns either way, for mate and tablebase scores, which
+        `Filters.max_eval` drops instead. Without `filters`, it keeps the evals
+        beyond 3000 centipawns that the default filters drop.
+        `seed` seeds every random choice, so that runs
+        without background threads can be repeated exactly. `target_blend` is the
+        `(lambda, scale)` of Batch.target, which is the game result without it.
+        With `pin_memory`, the batch buffers are page-locked so that they are copied
+        to the GPU without staging, and a batch is only refilled once its copies
+        are done. `value_format` packs the eval, result and target for the copy, for
+        very large batches. Batch.weight starts from the weight stored in each
+        record, or from 1 without `sample_weights`. `eval_weighting` scales it by
+        4 * p * (1 - p), where p = sigmoid(cp / eval_weighting), so that positions
+        count for less the more decided their eval is. `wdl_smoothing` pulls each
+        result towards a draw, as wdl * (1 - wdl_smoothing) + 0.5 * wdl_smoothing.
+        Datasets written with soft results, such as by `txt-to-data --soft-wdl`,
+        give fractional results either way. `validate` raises an error
+        if the feature set writes a feature twice for a position or one out of range,
+        for debugging feature sets."""
         assert files
+        assert weights is None or len(weights) == len(files)
+        assert random_order in (None, "permutation", "sampled")
         self._feature_set = feature_set
         self._files = files
+        self._weights = weights
+        self._seed = seed
         self._file_index = 0
-        self._reader = ParserFileReader(self._files[self._file_index])
-        self._batch = ParserBatch(
-            batch_size, feature_set.max_features(), feature_set.indices_per_feature()
-        )
+        self._epoch = 0
+        self._threads = threads
+        self._prefetch = prefetch
+        self._shuffle_buffer_size = shuffle_buffer_size
+        self._random_skip_probability = random_skip_probability
+        self._random_order = random_order
+        self._readahead = readahead
+        self._mirror = mirror
+        self._eval_clamp = eval_clamp
+        self._filters = filters
+        self._pin_memory = pin_memory and torch.cuda.is_available()
+        # The addresses of the page-locked buffers
+        self._pinned: set[int] = set()
+        self._copied: torch.cuda.Event | None = None
+        max_features = feature_set.max_features()
+        if pawn_structure:
+            max_features += PAWN_STRUCTURE_INPUTS
+        inputs = feature_set.num_inputs()
+        if pawn_structure:
+            inputs += PAWN_STRUCTURE_INPUTS
+        if dense:
+            self._batch = ParserBatch.dense(batch_size, inputs)
+        else:
+            self._batch = ParserBatch(
+                batch_size, max_features, feature_set.indices_per_feature()
+            )
+        if validate:
+            self._batch.set_validation(inputs)
+        # Batch.buckets holds each position's output bucket by piece count.
+        if output_buckets > 1:
+            self._batch.set_material_output_buckets(output_buckets)
+        # Pawn structure features follow those of the feature set, from index
+        # feature_set.num_inputs(), so models need PAWN_STRUCTURE_INPUTS more inputs.
+        if pawn_structure:
+            self._batch.set_pawn_structure(feature_set.num_inputs())
+        if target_blend is not None:
+            self._batch.set_target_blend(*target_blend)
+        if not sample_weights:
+            self._batch.set_sample_weights(False)
+        if eval_weighting is not None:
+            self._batch.set_eval_weighting(eval_weighting)
+        if wdl_smoothing is not None:
+            self._batch.set_wdl_smoothing(wdl_smoothing)
+        if value_format != ValueFormat.F32:
+            self._batch.set_value_format(value_format)
+        self._reader: ParserFileReader | None = None
+        self._prefetcher: ParserPrefetcher | None = None
+        self._start()
+
+    def _start(self) -> None:
+        reader = self._open_reader()
+        if self._threads > 0:
+            self._prefetcher = ParserPrefetcher(
+                reader, self._feature_set, self._batch, self._threads, self._prefetch
+            )
+        else:
+            self._reader = reader
+
+    def _stop(self) -> None:
+        self._wait_for_copies()
+        for ptr in self._pinned:
+            torch.cuda.check_error(torch.cuda.cudart().cudaHostUnregister(ptr))
+        self._pinned.clear()
+        if self._prefetcher is not None:
+            self._prefetcher.drop()
+        if self._reader is not None:
+            self._reader.drop()
+
+    def _pin(self, batch: ParserBatch) -> None:
+        for ptr, size in batch.host_buffers():
+            if size > 0 and ptr not in self._pinned:
+                cudart = torch.cuda.cudart()
+                torch.cuda.check_error(cudart.cudaHostRegister(ptr, size, 0))
+                self._pinned.add(ptr)
+
+    def _wait_for_copies(self) -> None:
+        if self._copied is not None:
+            self._copied.synchronize()
+            self._copied = None
+
+    def _next_batch(self) -> ParserBatch | None:
+        # The batch handed out last is about to be refilled.
+        self._wait_for_copies()
+        if self._prefetcher is not None:
+            return self._prefetcher.next_batch()
+        if read_batch_into(self._reader, self._feature_set, self._batch):
+            return self._batch
+        return None
+
+    def _open_reader(self) -> ParserFileReader:
+        if self._weights is None:
+            reader = ParserFileReader(self._files[self._file_index])
+            reader.set_seed(
+                self._seed + self._epoch * len(self._files) + self._file_index
+            )
+        else:
+            # Mixed datasets are drawn from all at once, so one reader is one epoch.
+            reader = ParserFileReader.mixed(
+                self._files, self._weights, self._seed + self._epoch
+            )
+        if self._random_order is not None:
+            reader.set_random_order(self._random_order == "permutation")
+        elif self._readahead > 0:
+            reader.set_readahead(self._readahead)
+        if self._shuffle_buffer_size > 0:
+            reader.set_shuffle_buffer_size(self._shuffle_buffer_size)
+        if self._random_skip_probability > 0:
+            reader.set_random_skip_probability(self._random_skip_probability)
+        if self._mirror:
+            reader.set_mirroring(True)
+        if self._eval_clamp is not None:
+            reader.set_eval_clamp(self._eval_clamp)
+        if self._filters is not None:
+            reader.set_filters(self._filters)
+        elif self._eval_clamp is not None:
+            # Clamp the evals the default filters would drop instead.
+            reader.set_filters(Filters(max_eval=math.inf))
+        return reader
 
     def read_batch(self, device: torch.device) -> tuple[bool, Batch]:
+        new_epoch, batches = self.read_micro_batches(device, 1)
+        return new_epoch, batches[0]
+
+    def read_micro_batches(
+        self, device: torch.device, count: int
+    ) -> tuple[bool, list[Batch]]:
         new_epoch = False
-        while not read_batch_into(self._reader, self._feature_set, self._batch):
-            self._reader.drop()
-            self._file_index = (self._file_index + 1) % len(self._files)
-            self._reader = ParserFileReader(self._files[self._file_index])
-            new_epoch = self._file_index == 0
-        return new_epoch, self._batch.to_pytorch_batch(device)
+        batch = self._next_batch()
+        while batch is None:
+            self._stop()
+            if self._weights is None:
+                self._file_index = (self._file_index + 1) % len(self._files)
+                new_epoch = self._file_index == 0
+            else:
+                new_epoch = True
+            if new_epoch:
+                self._epoch += 1
+            self._start()
+            batch = self._next_batch()
+        if not self._pin_memory:
+            return new_epoch, batch.to_pytorch_micro_batches(device, count)
+        self._pin(batch)
+        batches = batch.to_pytorch_micro_batches(device, count, pinned=True)
+        self._copied = torch.cuda.Event()
+        self._copied.record()
+        return new_epoch, batches
+
+    def reset(self) -> None:
+        """Starts over from the first epoch, repeating the batches read so far."""
+        self._stop()
+        self._file_index = 0
+        self._epoch = 0
+        self._start()
+
+    def epoch(self) -> int:
+        return self._epoch
 
     def drop(self) -> None:
-        self._reader.drop()
+        self._stop()
         self._batch.drop()
 
     def __enter__(self) -> BatchLoader:
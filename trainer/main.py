@@ -5,14 +5,21 @@ import json
 import os
 import pathlib
 
-from dataloader import BatchLoader
+from dataloader import BatchLoader, Filters
 from model import (
     NnBoard768Cuda,
     NnBoard768,
     NnHalfKA,
     NnHalfKACuda,
+    NnHalfKAFactorized,
+    NnHalfKAFactorizedCuda,
+    NnHalfKAv2Hm,
+    NnHalfKAv2HmCuda,
     NnHalfKP,
     NnHalfKPCuda,
+    NnKingBuckets,
+    NnThreats,
+    NnThreatsCuda,
 )
 from time import time
 
@@ -39,13 +46,12 @@ def train(
     model: torch.nn.Module,
     optimizer: torch.optim.Optimizer,
     dataloader: BatchLoader,
-    wdl: float,
-    scale: float,
     epochs: int,
     save_epochs: int,
     train_id: str,
     lr_drop: int | None = None,
     train_log: TrainLog | None = None,
+    sample_weights: bool = False,
 ) -> None:
     clipper = WeightClipper()
     running_loss = torch.zeros((1,), device=DEVICE)
@@ -88,9 +94,12 @@ def train(
 
         optimizer.zero_grad()
         prediction = model(batch)
-        expected = torch.sigmoid(batch.cp / scale) * (1 - wdl) + batch.wdl * wdl
+        expected = batch.target
 
-        loss = torch.mean((prediction - expected) ** 2)
+        if sample_weights:
+            loss = torch.mean(batch.weight * (prediction - expected) ** 2)
+        else:
+            loss = torch.mean((prediction - expected) ** 2)
         loss.backward()
         optimizer.step()
         model.apply(clipper)
@@ -141,6 +150,65 @@ def main():
         default=None,
         help="The epoch learning rate will be dropped",
     )
+    parser.add_argument(
+        "--sample-weights",
+        action="store_true",
+        help="Scale the loss of each position by the weight stored in its extra byte",
+    )
+    parser.add_argument(
+        "--loader-threads",
+        type=int,
+        default=0,
+        help="Threads to fill batches on in the background, 0 to fill them in turn",
+    )
+    parser.add_argument(
+        "--shuffle-buffer",
+        type=int,
+        default=0,
+        help="Positions to read ahead and draw from at random, for unshuffled data",
+    )
+    parser.add_argument(
+        "--random-order",
+        choices=["permutation", "sampled"],
+        help="Read uncompressed datasets in a random order instead of in sequence",
+    )
+    parser.add_argument(
+        "--readahead",
+        type=int,
+        default=0,
+        help="MiB of each dataset to read ahead on a background thread",
+    )
+    parser.add_argument(
+        "--eval-weighting",
+        type=float,
+        help="Scale down the loss of positions with large evals, with this eval scale",
+    )
+    parser.add_argument(
+        "--wdl-smoothing",
+        type=float,
+        help="Pull game results towards a draw by this fraction, as label smoothing",
+    )
+    parser.add_argument(
+        "--max-eval",
+        type=float,
+        help="Drop positions whose eval is beyond this, such as mate scores "
+        "(default 3000, or no limit with --clamp-eval)",
+    )
+    parser.add_argument(
+        "--clamp-eval",
+        type=float,
+        help="Clamp evals to this many centipawns either way",
+    )
+    parser.add_argument(
+        "--mirror",
+        action="store_true",
+        help="Mirror positions without castling rights across the board at random",
+    )
+    parser.add_argument(
+        "--pin-memory",
+        action="store_true",
+        help="Page-lock the batch buffers for faster copies to the GPU",
+    )
     args = parser.parse_args()
 
     assert args.train_id is not None
@@ -151,8 +219,28 @@ def main():
     model = NnHalfKPCuda(128).to(DEVICE)
 
     data_path = pathlib.Path(args.data_root)
-    paths = list(map(str, data_path.glob("*.bin")))
-    dataloader = BatchLoader(paths, model.input_feature_set(), args.batch_size)
+    paths = [
+        str(path)
+        for pattern in ("*.bin", "*.bin.zst")
+        for path in data_path.glob(pattern)
+    ]
+    dataloader = BatchLoader(
+        paths,
+        model.input_feature_set(),
+        args.batch_size,
+        threads=args.loader_threads,
+        shuffle_buffer_size=args.shuffle_buffer,
+        random_order=args.random_order,
+        readahead=args.readahead << 20,
+        mirror=args.mirror,
+        eval_clamp=args.clamp_eval,
+        filters=None if args.max_eval is None else Filters(max_eval=args.max_eval),
+        sample_weights=args.sample_weights,
+        eval_weighting=args.eval_weighting,
+        wdl_smoothing=args.wdl_smoothing,
+        pin_memory=args.pin_memory,
+        target_blend=(1 - args.wdl, args.scale),
+    )
 
     optimizer = torch.optim.Adam(model.parameters(), lr=args.lr)
 
@@ -160,13 +248,12 @@ def main():
         model,
         optimizer,
         dataloader,
-        args.wdl,
-        args.scale,
         args.epochs,
         args.save_epochs,
         args.train_id,
         lr_drop=args.lr_drop,
         train_log=train_log,
+        sample_weights=args.sample_weights or args.eval_weighting is not None,
     )
 
 
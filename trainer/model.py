@@ -1,6 +1,6 @@
 import torch
 
-from dataloader import Batch, InputFeatureSet
+from dataloader import Batch, InputFeatureSet, KingBuckets
 
 
 class NnBoard768(torch.nn.Module):
@@ -10,8 +10,8 @@ class NnBoard768(torch.nn.Module):
         self.out = torch.nn.Linear(ft_out * 2, 1)
 
     def forward(self, batch: Batch):
-        stm_indices = batch.stm_indices.reshape(-1, 2).T
-        nstm_indices = batch.nstm_indices.reshape(-1, 2).T
+        stm_indices = batch.stm_indices
+        nstm_indices = batch.nstm_indices
         board_stm_sparse = torch.sparse_coo_tensor(
             stm_indices, batch.values, (batch.size, 768)
         ).to_dense()
@@ -30,6 +30,35 @@ class NnBoard768(torch.nn.Module):
         return InputFeatureSet.BOARD_768
 
 
+class NnThreats(torch.nn.Module):
+    """Board768 with the squares attacked by each side, at indices from 768."""
+
+    def __init__(self, ft_out: int):
+        super().__init__()
+        self.ft = torch.nn.Linear(768 + 128, ft_out)
+        self.out = torch.nn.Linear(ft_out * 2, 1)
+
+    def forward(self, batch: Batch):
+        stm_indices = batch.stm_indices
+        nstm_indices = batch.nstm_indices
+        board_stm_sparse = torch.sparse_coo_tensor(
+            stm_indices, batch.values, (batch.size, 768 + 128)
+        ).to_dense()
+        board_nstm_sparse = torch.sparse_coo_tensor(
+            nstm_indices, batch.values, (batch.size, 768 + 128)
+        ).to_dense()
+
+        stm_ft = self.ft(board_stm_sparse)
+        nstm_ft = self.ft(board_nstm_sparse)
+
+        hidden = torch.clamp(torch.cat((stm_ft, nstm_ft), dim=1), 0, 1)
+
+        return torch.sigmoid(self.out(hidden))
+
+    def input_feature_set(self) -> InputFeatureSet:
+        return InputFeatureSet.THREATS
+
+
 class NnHalfKP(torch.nn.Module):
     def __init__(self, ft_out: int):
         super().__init__()
@@ -39,8 +68,8 @@ class NnHalfKP(torch.nn.Module):
 
     def forward(self, batch: Batch):
 
-        stm_indices = batch.stm_indices.reshape(-1, 2).T
-        nstm_indices = batch.nstm_indices.reshape(-1, 2).T
+        stm_indices = batch.stm_indices
+        nstm_indices = batch.nstm_indices
         board_stm_sparse = torch.sparse_coo_tensor(
             stm_indices, batch.values, (batch.size, 40960)
         )
@@ -78,8 +107,8 @@ class NnHalfKA(torch.nn.Module):
         self.out = torch.nn.Linear(ft_out * 2, 1)
 
     def forward(self, batch: Batch):
-        stm_indices = batch.stm_indices.reshape(-1, 2).T
-        nstm_indices = batch.nstm_indices.reshape(-1, 2).T
+        stm_indices = batch.stm_indices
+        nstm_indices = batch.nstm_indices
         board_stm_sparse = torch.sparse_coo_tensor(
             stm_indices, batch.values, (batch.size, 49152)
         )
@@ -109,6 +138,116 @@ class NnHalfKA(torch.nn.Module):
         return InputFeatureSet.HALF_KA
 
 
+class NnHalfKAFactorized(torch.nn.Module):
+    """HalfKA with the Board768 factorizer given by the data loader as virtual
+    features, at indices from 49152."""
+
+    def __init__(self, ft_out: int):
+        super().__init__()
+        self.ft = torch.nn.Linear(49152 + 768, ft_out)
+        self.out = torch.nn.Linear(ft_out * 2, 1)
+
+    def forward(self, batch: Batch):
+        stm_indices = batch.stm_indices
+        nstm_indices = batch.nstm_indices
+        board_stm_sparse = torch.sparse_coo_tensor(
+            stm_indices, batch.values, (batch.size, 49152 + 768)
+        )
+        board_nstm_sparse = torch.sparse_coo_tensor(
+            nstm_indices, batch.values, (batch.size, 49152 + 768)
+        )
+
+        stm_ft = self.ft(board_stm_sparse)
+        nstm_ft = self.ft(board_nstm_sparse)
+
+        hidden = torch.clamp(torch.cat((stm_ft, nstm_ft), dim=1), 0, 1)
+
+        return torch.sigmoid(self.out(hidden))
+
+    def input_feature_set(self) -> InputFeatureSet:
+        return InputFeatureSet.HALF_KA_FACTORIZED
+
+
+class NnHalfKAv2Hm(torch.nn.Module):
+    def __init__(self, ft_out: int):
+        super().__init__()
+        self.ft = torch.nn.Linear(22528, ft_out)
+        self.fft = torch.nn.Linear(704, ft_out)
+        self.out = torch.nn.Linear(ft_out * 2, 1)
+
+    def forward(self, batch: Batch):
+        stm_indices = batch.stm_indices
+        nstm_indices = batch.nstm_indices
+        board_stm_sparse = torch.sparse_coo_tensor(
+            stm_indices, batch.values, (batch.size, 22528)
+        )
+        board_nstm_sparse = torch.sparse_coo_tensor(
+            nstm_indices, batch.values, (batch.size, 22528)
+        )
+
+        v_stm_indices = torch.clone(stm_indices)
+        v_nstm_indices = torch.clone(nstm_indices)
+        v_stm_indices[1][:] %= 704
+        v_nstm_indices[1][:] %= 704
+        v_board_stm_sparse = torch.sparse_coo_tensor(
+            v_stm_indices, batch.values, (batch.size, 704)
+        ).to_dense()
+        v_board_nstm_sparse = torch.sparse_coo_tensor(
+            v_nstm_indices, batch.values, (batch.size, 704)
+        ).to_dense()
+
+        stm_ft = self.ft(board_stm_sparse) + self.fft(v_board_stm_sparse)
+        nstm_ft = self.ft(board_nstm_sparse) + self.fft(v_board_nstm_sparse)
+
+        hidden = torch.clamp(torch.cat((stm_ft, nstm_ft), dim=1), 0, 1)
+
+        return torch.sigmoid(self.out(hidden))
+
+    def input_feature_set(self) -> InputFeatureSet:
+        return InputFeatureSet.HALF_KA_V2_HM
+
+
+class NnKingBuckets(torch.nn.Module):
+    def __init__(self, ft_out: int, buckets: list[int]):
+        super().__init__()
+        self.feature_set = KingBuckets(buckets)
+        self.inputs = self.feature_set.num_inputs()
+        self.ft = torch.nn.Linear(self.inputs, ft_out)
+        self.fft = torch.nn.Linear(768, ft_out)
+        self.out = torch.nn.Linear(ft_out * 2, 1)
+
+    def forward(self, batch: Batch):
+        stm_indices = batch.stm_indices
+        nstm_indices = batch.nstm_indices
+        board_stm_sparse = torch.sparse_coo_tensor(
+            stm_indices, batch.values, (batch.size, self.inputs)
+        )
+        board_nstm_sparse = torch.sparse_coo_tensor(
+            nstm_indices, batch.values, (batch.size, self.inputs)
+        )
+
+        v_stm_indices = torch.clone(stm_indices)
+        v_nstm_indices = torch.clone(nstm_indices)
+        v_stm_indices[1][:] %= 768
+        v_nstm_indices[1][:] %= 768
+        v_board_stm_sparse = torch.sparse_coo_tensor(
+            v_stm_indices, batch.values, (batch.size, 768)
+        ).to_dense()
+        v_board_nstm_sparse = torch.sparse_coo_tensor(
+            v_nstm_indices, batch.values, (batch.size, 768)
+        ).to_dense()
+
+        stm_ft = self.ft(board_stm_sparse) + self.fft(v_board_stm_sparse)
+        nstm_ft = self.ft(board_nstm_sparse) + self.fft(v_board_nstm_sparse)
+
+        hidden = torch.clamp(torch.cat((stm_ft, nstm_ft), dim=1), 0, 1)
+
+        return torch.sigmoid(self.out(hidden))
+
+    def input_feature_set(self) -> KingBuckets:
+        return self.feature_set
+
+
 class NnBoard768Cuda(torch.nn.Module):
     def __init__(self, ft_out: int):
         from cudasparse import DoubleFeatureTransformerSlice
@@ -141,6 +280,38 @@ class NnBoard768Cuda(torch.nn.Module):
         return InputFeatureSet.BOARD_768_CUDA
 
 
+class NnThreatsCuda(torch.nn.Module):
+    def __init__(self, ft_out: int):
+        from cudasparse import DoubleFeatureTransformerSlice
+
+        super().__init__()
+        self.max_features = InputFeatureSet.THREATS_CUDA.max_features()
+        self.ft = DoubleFeatureTransformerSlice(768 + 128, ft_out)
+        self.out = torch.nn.Linear(ft_out * 2, 1)
+
+    def forward(self, batch: Batch):
+        values = batch.values.reshape(-1, self.max_features)
+        stm_indices = batch.stm_indices.reshape(-1, self.max_features).type(
+            dtype=torch.int32
+        )
+        nstm_indices = batch.nstm_indices.reshape(-1, self.max_features).type(
+            dtype=torch.int32
+        )
+        stm_ft, nstm_ft = self.ft(
+            stm_indices,
+            values,
+            nstm_indices,
+            values,
+        )
+
+        hidden = torch.clamp(torch.cat((stm_ft, nstm_ft), dim=1), 0, 1)
+
+        return torch.sigmoid(self.out(hidden))
+
+    def input_feature_set(self) -> InputFeatureSet:
+        return InputFeatureSet.THREATS_CUDA
+
+
 class NnHalfKPCuda(torch.nn.Module):
     def __init__(self, ft_out: int):
         super().__init__()
@@ -215,3 +386,75 @@ class NnHalfKACuda(torch.nn.Module):
 
     def input_feature_set(self) -> InputFeatureSet:
         return InputFeatureSet.HALF_KA_CUDA
+
+
+class NnHalfKAFactorizedCuda(torch.nn.Module):
+    def __init__(self, ft_out: int):
+        super().__init__()
+        from cudasparse import DoubleFeatureTransformerSlice
+
+        self.max_features = (
+            InputFeatureSet.HALF_KA_FACTORIZED_CUDA.max_features()
+        )
+        self.ft = DoubleFeatureTransformerSlice(49152 + 768, ft_out)
+        self.out = torch.nn.Linear(ft_out * 2, 1)
+
+    def forward(self, batch: Batch):
+        values = batch.values.reshape(-1, self.max_features)
+        stm_indices = batch.stm_indices.reshape(-1, self.max_features).type(
+            dtype=torch.int32
+        )
+        nstm_indices = batch.nstm_indices.reshape(-1, self.max_features).type(
+            dtype=torch.int32
+        )
+        stm_ft, nstm_ft = self.ft(
+            stm_indices,
+            values,
+            nstm_indices,
+            values,
+        )
+
+        hidden = torch.clamp(torch.cat((stm_ft, nstm_ft), dim=1), 0, 1)
+
+        return torch.sigmoid(self.out(hidden))
+
+    def input_feature_set(self) -> InputFeatureSet:
+        return InputFeatureSet.HALF_KA_FACTORIZED_CUDA
+
+
+class NnHalfKAv2HmCuda(torch.nn.Module):
+    def __init__(self, ft_out: int):
+        super().__init__()
+        from cudasparse import DoubleFeatureTransformerSlice
+
+        self.max_features = InputFeatureSet.HALF_KA_V2_HM_CUDA.max_features()
+        self.ft = DoubleFeatureTransformerSlice(22528, ft_out)
+        self.fft = DoubleFeatureTransformerSlice(704, ft_out)
+        self.out = torch.nn.Linear(ft_out * 2, 1)
+
+    def forward(self, batch: Batch):
+        values = batch.values.reshape(-1, self.max_features)
+        stm_indices = batch.stm_indices.reshape(-1, self.max_features).type(
+            dtype=torch.int32
+        )
+        nstm_indices = batch.nstm_indices.reshape(-1, self.max_features).type(
+            dtype=torch.int32
+        )
+        stm_ft, nstm_ft = self.ft(
+            stm_indices,
+            values,
+            nstm_indices,
+            values,
+        )
+        v_stm_ft, v_nstm_ft = self.fft(
+            stm_indices.fmod(704), values, nstm_indices.fmod(704), values
+        )
+
+        hidden = torch.clamp(
+            torch.cat((stm_ft + v_stm_ft, nstm_ft + v_nstm_ft), dim=1), 0, 1
+        )
+
+        return torch.sigmoid(self.out(hidden))
+
+    def input_feature_set(self) -> InputFeatureSet:
+        return InputFeatureSet.HALF_KA_V2_HM_CUDA
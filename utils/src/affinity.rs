@@ -0,0 +1,131 @@
+//! Pinning worker threads to CPUs, for machines with more than one NUMA node, where a
+//! worker whose memory sits on another node runs at a fraction of the speed.
+
+use std::str::FromStr;
+
+/// How the workers of a parallel subcommand are placed on the machine's CPUs.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Affinity {
+    /// Leave the placement to the scheduler.
+    None,
+    /// Pin each worker to its own CPU, in order.
+    Cores,
+    /// Spread the workers evenly over the NUMA nodes, in order, each pinned to the CPUs of
+    /// its node. As workers take contiguous ranges of the dataset, each node reads its own
+    /// part of the file into its own memory.
+    Numa,
+}
+
+impl FromStr for Affinity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "none" => Ok(Affinity::None),
+            "cores" => Ok(Affinity::Cores),
+            "numa" => Ok(Affinity::Numa),
+            _ => Err(format!(
+                "unknown affinity {s}, expected none, cores or numa"
+            )),
+        }
+    }
+}
+
+impl Affinity {
+    /// Pins the calling thread, worker `worker` of `workers`, as this affinity says. Does
+    /// nothing outside Linux or if the CPUs can't be determined.
+    pub fn pin(self, worker: usize, workers: usize) {
+        let allowed = allowed_cpus();
+        if allowed.is_empty() {
+            return;
+        }
+        let cpus = match self {
+            Affinity::None => return,
+            Affinity::Cores => vec![allowed[worker % allowed.len()]],
+            Affinity::Numa => {
+                let nodes: Vec<_> = numa_nodes()
+                    .into_iter()
+                    .map(|node| -> Vec<_> {
+                        node.into_iter()
+                            .filter(|cpu| allowed.contains(cpu))
+                            .collect()
+                    })
+                    .filter(|node| !node.is_empty())
+                    .collect();
+                match nodes.len() {
+                    0 => return,
+                    len => nodes[worker * len / workers.max(1)].clone(),
+                }
+            }
+        };
+        set_cpus(&cpus);
+    }
+}
+
+/// The CPUs of each NUMA node, from sysfs.
+fn numa_nodes() -> Vec<Vec<usize>> {
+    let mut nodes = vec![];
+    for node in 0.. {
+        let path = format!("/sys/devices/system/node/node{node}/cpulist");
+        match std::fs::read_to_string(path) {
+            Ok(list) => nodes.push(parse_cpu_list(list.trim())),
+            Err(_) => break,
+        }
+    }
+    nodes
+}
+
+/// Parses a CPU list such as `0-7,16-23`.
+fn parse_cpu_list(list: &str) -> Vec<usize> {
+    let mut cpus = vec![];
+    for part in list.split(',').filter(|part| !part.is_empty()) {
+        let (first, last) = part.split_once('-').unwrap_or((part, part));
+        if let (Ok(first), Ok(last)) = (first.parse::<usize>(), last.parse::<usize>()) {
+            cpus.extend(first..=last);
+        }
+    }
+    cpus
+}
+
+// The size of glibc's `cpu_set_t`, which covers CPUs 0 to 1023
+#[cfg(target_os = "linux")]
+const CPU_SET_WORDS: usize = 1024 / 64;
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn sched_getaffinity(pid: i32, size: usize, mask: *mut u64) -> i32;
+    fn sched_setaffinity(pid: i32, size: usize, mask: *const u64) -> i32;
+}
+
+/// The CPUs the process may run on.
+#[cfg(target_os = "linux")]
+fn allowed_cpus() -> Vec<usize> {
+    let mut mask = [0_u64; CPU_SET_WORDS];
+    // Safety: the mask is as large as the size passed.
+    if unsafe { sched_getaffinity(0, std::mem::size_of_val(&mask), mask.as_mut_ptr()) } != 0 {
+        return vec![];
+    }
+    (0..CPU_SET_WORDS * 64)
+        .filter(|&cpu| mask[cpu / 64] & 1 << (cpu % 64) != 0)
+        .collect()
+}
+
+/// Restricts the calling thread to the given CPUs.
+#[cfg(target_os = "linux")]
+fn set_cpus(cpus: &[usize]) {
+    let mut mask = [0_u64; CPU_SET_WORDS];
+    for &cpu in cpus.iter().filter(|&&cpu| cpu < CPU_SET_WORDS * 64) {
+        mask[cpu / 64] |= 1 << (cpu % 64);
+    }
+    // Safety: the mask is as large as the size passed. Pinning is only an optimization, so
+    // failure doesn't matter.
+    unsafe { sched_setaffinity(0, std::mem::size_of_val(&mask), mask.as_ptr()) };
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allowed_cpus() -> Vec<usize> {
+    vec![]
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_cpus(_cpus: &[usize]) {}
@@ -0,0 +1,497 @@
+//! Conversions between any two position formats: every input format has a [`Reader`] and
+//! every output format a [`Writer`], so a format gains conversion to and from all the others
+//! as soon as it has either.
+
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Lines, Read, Result, Write};
+use std::path::{Path, PathBuf};
+
+use cozy_chess::Board;
+use marlinformat::{io as format_io, soft_wdl_from_float, wdl_to_float, Eval, Header, PackedBoard};
+
+use super::{viriformat, Options};
+use crate::formats::bullet::BulletBoard;
+use crate::formats::{self, Format, TextFormat};
+use crate::progress::Progress;
+use crate::txt_to_data::Malformed;
+use crate::{dataset, export_pgn, inputs};
+
+/// A labelled position: the board, the eval from white's point of view as in a marlinformat
+/// record with the flags the output is created with, white's expected score as
+/// `wdl_to_float` reads it, and the `extra` byte.
+pub type Position = (Board, i16, f32, u8);
+
+pub trait Reader {
+    /// Reads the next position, or returns `None` at the end of the input. Records that can't
+    /// be read are skipped.
+    fn read(&mut self) -> Result<Option<Position>>;
+
+    /// The number of records skipped so far.
+    fn skipped(&self) -> u64;
+
+    /// The header of the input, for formats that have one.
+    fn header(&self) -> Option<Header> {
+        None
+    }
+
+    /// Whether the input holds soft results.
+    fn soft_wdl(&self) -> bool {
+        self.header()
+            .is_some_and(|header| header.flags() & Header::FLAG_SOFT_WDL != 0)
+    }
+}
+
+pub trait Writer {
+    fn write(&mut self, position: &Position) -> Result<()>;
+
+    /// Whether the output can hold soft results rather than only a loss, draw or win.
+    fn soft_results(&self) -> bool {
+        false
+    }
+
+    /// Flushes the output and fills in anything only known at the end, such as the record
+    /// count of a header.
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+pub fn run(inputs: &[PathBuf], from: &str, to: &str, options: &Options) -> Result<()> {
+    let progress = Progress::new("convert", 0);
+    // Each input appends its malformed lines to the log.
+    if let Some(error_log) = &options.error_log {
+        std::fs::File::create(error_log)?;
+    }
+    match &options.suffix {
+        Some(suffix) => {
+            for input in inputs {
+                let output = inputs::with_suffix(input, suffix);
+                convert_files(
+                    std::slice::from_ref(input),
+                    &output,
+                    from,
+                    to,
+                    options,
+                    &progress,
+                )?;
+            }
+        }
+        None => {
+            let output = options.output.as_ref().unwrap();
+            convert_files(inputs, output, from, to, options, &progress)?
+        }
+    }
+    progress.finish();
+
+    Ok(())
+}
+
+fn convert_files(
+    inputs: &[PathBuf],
+    output: &Path,
+    from: &str,
+    to: &str,
+    options: &Options,
+    progress: &Progress,
+) -> Result<()> {
+    // The first input is opened before the output, as it may be stdin, whose header can only
+    // be read once. Any others are files, whose headers are read ahead to merge their flags.
+    let mut first = Some(open_reader(from, &inputs[0], options)?);
+    let mut headers: Vec<_> = first
+        .as_ref()
+        .and_then(|reader| reader.header())
+        .into_iter()
+        .collect();
+    match from {
+        "marlin" => {
+            for input in &inputs[1..] {
+                headers.extend(dataset::peek_header(input)?);
+            }
+        }
+        "bullet" | "viriformat" => {}
+        // Text evals are mate scores or centipawns saturated short of them, which read like
+        // the evals of files with mate scores.
+        _ => headers = vec![Header::new(0, Header::FLAG_MATE_SCORES); inputs.len()],
+    }
+    let flags = dataset::merge_flags(&headers, inputs.len())?;
+    let mut writer = create_writer(to, output, flags)?;
+    let mut skipped = 0;
+    for input in inputs {
+        let mut reader = match first.take() {
+            Some(reader) => reader,
+            None => open_reader(from, input, options)?,
+        };
+        if !writer.soft_results() {
+            dataset::check_hard_wdl(input, reader.soft_wdl(), &format!("`{to}`"))?;
+        }
+        let mut unreported = 0;
+        while let Some(position) = reader.read()? {
+            writer.write(&position)?;
+            unreported += 1;
+            if unreported == 1 << 12 {
+                progress.advance(unreported);
+                unreported = 0;
+            }
+        }
+        progress.advance(unreported);
+        skipped += reader.skipped();
+    }
+    writer.finish()?;
+    if skipped > 0 {
+        eprintln!("Skipped {skipped} records that could not be read.");
+    }
+    Ok(())
+}
+
+/// Accepts `legacy-txt` as another name of the legacy text format.
+fn text_name(format: &str) -> &str {
+    match format {
+        "legacy-txt" => "legacy",
+        format => format,
+    }
+}
+
+/// Extends the error of an unknown text format with the binary formats.
+fn unknown_format(text_error: String, binary: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("{text_error}, or {binary}"),
+    )
+}
+
+fn unsupported(message: &str) -> Error {
+    Error::new(ErrorKind::Unsupported, message)
+}
+
+pub fn open_reader<'a>(
+    format: &str,
+    input: &Path,
+    options: &'a Options,
+) -> Result<Box<dyn Reader + 'a>> {
+    let text_format = match format {
+        "marlin" | "bullet" | "viriformat" => None,
+        "binpack" => return Err(unsupported("Stockfish binpacks are not supported")),
+        format => Some(
+            text_name(format)
+                .parse::<TextFormat>()
+                .map_err(|e| unknown_format(e, "`marlin`, `bullet` or `viriformat`"))?,
+        ),
+    };
+    let file = BufReader::new(inputs::open(input)?);
+    Ok(match (format, text_format) {
+        (_, Some(format)) => Box::new(TextReader {
+            lines: file.lines(),
+            path: input.to_owned(),
+            line_number: 0,
+            format,
+            options,
+            malformed: Malformed::new(options.on_error, options.error_log.as_deref())?,
+        }),
+        ("marlin", _) => Box::new(MarlinReader {
+            records: format_io::Reader::new(file)?,
+            skipped: 0,
+        }),
+        ("bullet", _) => Box::new(BulletReader { file, skipped: 0 }),
+        _ => Box::new(ViriformatReader {
+            file,
+            path: input.to_owned(),
+            options,
+            positions: VecDeque::new(),
+            skipped: 0,
+        }),
+    })
+}
+
+/// Creates a writer of `format`. A data file gets a header with `flags`, and holds soft results
+/// if they include `Header::FLAG_SOFT_WDL`. The other formats decode evals as they say, writing
+/// mate scores if they include `Header::FLAG_MATE_SCORES`.
+pub fn create_writer(format: &str, output: &Path, flags: u16) -> Result<Box<dyn Writer>> {
+    let mates = flags & Header::FLAG_MATE_SCORES != 0;
+    let text_format = match format {
+        "marlin" | "bullet" | "pgn" => None,
+        "binpack" => return Err(unsupported("Stockfish binpacks are not supported")),
+        "viriformat" => {
+            return Err(unsupported(
+                "viriformat holds whole games, so it can be read but not written",
+            ))
+        }
+        format => Some(
+            text_name(format)
+                .parse::<Box<dyn Format>>()
+                .map_err(|e| unknown_format(e, "`marlin`, `bullet` or `pgn`"))?,
+        ),
+    };
+    let mut file = BufWriter::new(inputs::create(output)?);
+    Ok(match (format, text_format) {
+        (_, Some(format)) => {
+            if let Some(header) = format.header() {
+                writeln!(file, "{header}")?;
+            }
+            Box::new(TextWriter {
+                file,
+                format,
+                mates,
+            })
+        }
+        ("marlin", _) => {
+            file.write_all(bytemuck::bytes_of(&Header::new(0, flags)))?;
+            Box::new(MarlinWriter {
+                file,
+                path: output.to_owned(),
+                flags,
+                records: 0,
+            })
+        }
+        ("bullet", _) => Box::new(BulletWriter { file }),
+        _ => Box::new(PgnWriter {
+            file,
+            index: 0,
+            mates,
+        }),
+    })
+}
+
+struct MarlinReader<R: Read> {
+    records: format_io::Reader<R>,
+    skipped: u64,
+}
+
+impl<R: Read> Reader for MarlinReader<R> {
+    fn read(&mut self) -> Result<Option<Position>> {
+        let soft = self.soft_wdl();
+        for packed in &mut self.records {
+            match packed?.unpack() {
+                Some((board, cp, wdl, extra)) => {
+                    return Ok(Some((board, cp, wdl_to_float(wdl, soft), extra)))
+                }
+                None => self.skipped += 1,
+            }
+        }
+        Ok(None)
+    }
+
+    fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    fn header(&self) -> Option<Header> {
+        self.records.header().copied()
+    }
+}
+
+struct BulletReader<R: Read> {
+    file: R,
+    skipped: u64,
+}
+
+impl<R: Read> Reader for BulletReader<R> {
+    fn read(&mut self) -> Result<Option<Position>> {
+        loop {
+            let mut record: BulletBoard = bytemuck::Zeroable::zeroed();
+            match format_io::fill(&mut self.file, bytemuck::bytes_of_mut(&mut record))? {
+                0 => return Ok(None),
+                read if read < std::mem::size_of::<BulletBoard>() => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "the input ends with a partial bulletformat record",
+                    ))
+                }
+                _ => {}
+            }
+            match record.unpack() {
+                Some((board, cp, wdl)) => {
+                    return Ok(Some((board, cp, wdl_to_float(wdl, false), 0)))
+                }
+                None => self.skipped += 1,
+            }
+        }
+    }
+
+    fn skipped(&self) -> u64 {
+        self.skipped
+    }
+}
+
+/// Replays the games of a viriformat file, reading the positions that pass the game filters
+/// of [`Options`]. Games are cut short at their first illegal move, which counts as a
+/// skipped record.
+struct ViriformatReader<'a, R: Read> {
+    file: R,
+    path: PathBuf,
+    options: &'a Options,
+    positions: VecDeque<Position>,
+    skipped: u64,
+}
+
+impl<R: Read> Reader for ViriformatReader<'_, R> {
+    fn read(&mut self) -> Result<Option<Position>> {
+        while self.positions.is_empty() {
+            let Some(game) = viriformat::read_game(&mut self.file, &self.path)? else {
+                return Ok(None);
+            };
+            let positions = &mut self.positions;
+            let (_, legal) =
+                viriformat::replay(&game, self.options, |packed| {
+                    positions.extend(packed.unpack().map(|(board, cp, wdl, extra)| {
+                        (board, cp, wdl_to_float(wdl, false), extra)
+                    }));
+                    Ok(())
+                })?;
+            if !legal {
+                self.skipped += 1;
+            }
+        }
+        Ok(self.positions.pop_front())
+    }
+
+    fn skipped(&self) -> u64 {
+        self.skipped
+    }
+}
+
+/// Reads a text format, skipping blank lines and dealing with malformed ones as `--on-error`
+/// says, as `txt-to-data` does.
+struct TextReader<'a, R> {
+    lines: Lines<R>,
+    path: PathBuf,
+    // The number of the last line read, counting from 1
+    line_number: u64,
+    format: TextFormat,
+    options: &'a Options,
+    malformed: Malformed,
+}
+
+impl<R: BufRead> Reader for TextReader<'_, R> {
+    fn read(&mut self) -> Result<Option<Position>> {
+        for line in &mut self.lines {
+            let line = line?;
+            self.line_number += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let parsed = self
+                .format
+                .parse_line(&line, &self.options.separator, self.options.frc);
+            match parsed {
+                Some((board, eval, wdl, extra)) => {
+                    return Ok(Some((board, eval.to_eval().encode(), wdl, extra)))
+                }
+                None => self.malformed.report(&self.path, self.line_number, &line)?,
+            }
+        }
+        self.malformed.flush()?;
+        Ok(None)
+    }
+
+    fn skipped(&self) -> u64 {
+        self.malformed.count()
+    }
+}
+
+/// Writes a data file with a header, whose record count is filled in at the end unless it
+/// goes to stdout.
+struct MarlinWriter<W: Write> {
+    file: BufWriter<W>,
+    path: PathBuf,
+    // The header flags, carried over from the inputs
+    flags: u16,
+    records: u64,
+}
+
+impl<W: Write> Writer for MarlinWriter<W> {
+    fn write(&mut self, (board, cp, wdl, extra): &Position) -> Result<()> {
+        let wdl = match self.soft_results() {
+            true => soft_wdl_from_float(*wdl),
+            false => formats::wdl_from_float(*wdl),
+        };
+        let packed = PackedBoard::pack(board, *cp, wdl, *extra);
+        self.records += 1;
+        self.file.write_all(bytemuck::bytes_of(&packed))
+    }
+
+    fn soft_results(&self) -> bool {
+        self.flags & Header::FLAG_SOFT_WDL != 0
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        let MarlinWriter {
+            mut file,
+            path,
+            flags,
+            records,
+        } = *self;
+        file.flush()?;
+        drop(file);
+        if !inputs::is_stdio(&path) {
+            dataset::set_header(&path, &Header::new(records, flags))?;
+        }
+        Ok(())
+    }
+}
+
+struct BulletWriter<W: Write> {
+    file: BufWriter<W>,
+}
+
+impl<W: Write> Writer for BulletWriter<W> {
+    fn write(&mut self, (board, cp, wdl, _): &Position) -> Result<()> {
+        let wdl = formats::wdl_from_float(*wdl);
+        let packed = BulletBoard::pack(board, *cp, wdl).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                "position cannot be packed for bullet",
+            )
+        })?;
+        self.file.write_all(bytemuck::bytes_of(&packed))
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Writes each position as a game without moves, as `export-pgn` does.
+struct PgnWriter<W: Write> {
+    file: BufWriter<W>,
+    index: u64,
+    // Whether evals close to the mate score are mates
+    mates: bool,
+}
+
+impl<W: Write> Writer for PgnWriter<W> {
+    fn write(&mut self, (board, cp, wdl, extra): &Position) -> Result<()> {
+        let eval = Eval::decode(*cp, self.mates);
+        export_pgn::write_position(&mut self.file, self.index, "?", board, eval, *wdl, *extra)?;
+        self.index += 1;
+        Ok(())
+    }
+
+    fn soft_results(&self) -> bool {
+        true
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.file.flush()
+    }
+}
+
+struct TextWriter<W: Write> {
+    file: BufWriter<W>,
+    format: Box<dyn Format>,
+    // Whether evals close to the mate score are mates
+    mates: bool,
+}
+
+impl<W: Write> Writer for TextWriter<W> {
+    fn write(&mut self, (board, cp, wdl, extra): &Position) -> Result<()> {
+        let eval = Eval::decode(*cp, self.mates);
+        let line = self.format.format_line(board, eval, *wdl, *extra);
+        writeln!(self.file, "{line}")
+    }
+
+    fn soft_results(&self) -> bool {
+        self.format.soft_results()
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.file.flush()
+    }
+}
@@ -1,24 +1,114 @@
 mod halfkp;
+mod matrix;
 mod utils;
+mod viriformat;
 
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
 use halfkp::HalfKp;
 use structopt::StructOpt;
 
+use crate::inputs;
+use crate::txt_to_data::OnError;
+
 #[derive(StructOpt)]
-/// Convert JSON neural network file into BlackMarlin NNUE format
+/// Convert JSON neural network file into BlackMarlin NNUE format, or positions between any
+/// two formats with `--from` and `--to`
 pub struct Options {
-    /// Path to the JSON file cont
+    /// Path to the input file, a directory or glob pattern of input files, or `-` for stdin
     path: PathBuf,
-    #[structopt(long, short = "o", default_value = "nnue.bin")]
-    output: PathBuf,
+    /// Output file, or `-` for stdout. Defaults to `nnue.bin` for a network, and is needed to
+    /// convert positions unless `--suffix` is given
+    #[structopt(long, short = "o")]
+    output: Option<PathBuf>,
+    /// Convert every input to its own file, named after the input with its extension
+    /// replaced by this suffix
+    #[structopt(long)]
+    suffix: Option<String>,
+    /// Input format: `json` for a network, `viriformat` for game records, which are replayed
+    /// into a record per position, `marlin` or `bullet` for data files, or a text format:
+    /// `legacy` (or `legacy-txt`), `cudad`, `zurichess`, `viri` or `epd`
+    #[structopt(long, default_value = "json")]
+    from: String,
+    /// Output format: `marlin` for a data file, the default for game records, which also get a
+    /// game index, `bullet`, `pgn`, or a text format: `legacy`, `cudad`, `viri`, `fens`,
+    /// `csv` or `epd`
+    #[structopt(long)]
+    to: Option<String>,
+    /// Column separator of the legacy and viri input formats
+    #[structopt(long, default_value = " | ")]
+    separator: String,
+    /// Also accept Shredder-FENs in text input, for Chess960 and DFRC data
+    #[structopt(long)]
+    frc: bool,
+    /// Skip the first plies of each game. Book moves are not recorded in viriformat, so these
+    /// count from the end of the book
+    #[structopt(long, default_value = "0")]
+    skip_plies: usize,
+    /// Skip positions whose move captures or promotes
+    #[structopt(long)]
+    skip_noisy: bool,
+    /// Skip positions where the side to move is in check
+    #[structopt(long)]
+    skip_check: bool,
+    /// Also write the sidecar index of each data file written from game records (see `index`)
+    #[structopt(long)]
+    index: bool,
+    /// What to do with text lines that can't be parsed: `skip` them, warning about the first,
+    /// `fail` at the first one, or `log` each of them to `--error-log` and carry on
+    #[structopt(long, default_value = "skip")]
+    on_error: OnError,
+    /// The file that `--on-error log` writes malformed lines to, each as
+    /// `<file>:<line number>: <line>`
+    #[structopt(long, required_if("on-error", "log"))]
+    error_log: Option<PathBuf>,
 }
 
 pub fn run(options: Options) {
-    let content = std::fs::read(options.path).unwrap();
+    let inputs = inputs::expand(&options.path).unwrap();
+    if options.from != "json" || options.to.is_some() {
+        assert!(
+            options.output.is_some() || options.suffix.is_some(),
+            "--output or --suffix is needed to convert positions"
+        );
+    }
+    match (options.from.as_str(), options.to.as_deref()) {
+        ("json", None) => {}
+        ("json", Some(_)) => panic!("a network converts only to BlackMarlin's format"),
+        ("viriformat", None | Some("marlin")) => {
+            return viriformat::run(&inputs, &options).unwrap();
+        }
+        (from, Some(to)) => return matrix::run(&inputs, from, to, &options).unwrap(),
+        (from, None) => panic!("--to is needed to convert from {from}"),
+    }
+    match &options.suffix {
+        Some(suffix) => {
+            for input in &inputs {
+                convert(input, &inputs::with_suffix(input, suffix));
+            }
+        }
+        None => {
+            assert!(
+                inputs.len() == 1,
+                "{} matches several files, use --suffix to convert each of them",
+                options.path.display()
+            );
+            let output = options.output.as_deref().unwrap_or(Path::new("nnue.bin"));
+            convert(&inputs[0], output);
+        }
+    }
+}
+
+fn convert(input: &Path, output: &Path) {
+    let mut content = vec![];
+    inputs::open(input)
+        .and_then(|mut input| input.read_to_end(&mut content))
+        .unwrap();
 
     let arch = HalfKp::from(&content);
     let bin = arch.to_bin(255.0, 64.0);
-    std::fs::write(options.output, bin).unwrap();
+    inputs::create(output)
+        .and_then(|mut output| output.write_all(&bin))
+        .unwrap();
 }
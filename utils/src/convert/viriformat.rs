@@ -0,0 +1,168 @@
+//! Game records in viriformat: a marlinformat record of the position each game starts from,
+//! whose result is that of the game, followed by the little-endian `u16` move and the
+//! white-relative `i16` eval of each position of the game, and four zero bytes.
+//!
+//! Moves hold the from square in bits 0-5 and the to square in bits 6-11, with castling
+//! written as the king capturing its rook. Bits 14-15 hold a flag, which is `0b11` for
+//! promotions, whose piece, counting from a knight, is in bits 12-13.
+
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+
+use cozy_chess::{Board, Move, Piece, Square};
+use marlinformat::{Header, PackedBoard};
+
+use super::Options;
+use crate::progress::Progress;
+use crate::{dataset, games, index, inputs};
+
+const PROMOTION: u16 = 0b11 << 14;
+
+pub struct Game {
+    start: PackedBoard,
+    moves: Vec<(u16, i16)>,
+}
+
+pub fn run(inputs: &[PathBuf], options: &Options) -> Result<()> {
+    let mut bytes = 0;
+    for input in inputs {
+        bytes += inputs::len(input)?;
+    }
+    let progress = Progress::new("convert", bytes);
+    match &options.suffix {
+        Some(suffix) => {
+            for input in inputs {
+                let output = inputs::with_suffix(input, suffix);
+                convert_files(std::slice::from_ref(input), &output, options, &progress)?;
+            }
+        }
+        None => convert_files(inputs, options.output.as_ref().unwrap(), options, &progress)?,
+    }
+    progress.finish();
+
+    Ok(())
+}
+
+/// Converts the games of `inputs` into one data file, along with its game index unless it is
+/// written to stdout.
+fn convert_files(
+    inputs: &[PathBuf],
+    output_path: &Path,
+    options: &Options,
+    progress: &Progress,
+) -> Result<()> {
+    let mut output = BufWriter::new(inputs::create(output_path)?);
+    output.write_all(bytemuck::bytes_of(&Header::new(0, 0)))?;
+    let mut hashing = index::Hashing::new(&mut output);
+    let mut starts = vec![];
+    let mut records = 0;
+    let mut illegal = 0;
+    for input in inputs {
+        let mut games = BufReader::new(inputs::open(input)?);
+        while let Some(game) = read_game(&mut games, input)? {
+            let bytes = (std::mem::size_of::<PackedBoard>() + 4 * (game.moves.len() + 1)) as u64;
+            let (positions, legal) = replay(&game, options, |packed| {
+                hashing.write_all(bytemuck::bytes_of(packed))
+            })?;
+            if !legal {
+                illegal += 1;
+            }
+            if positions > 0 {
+                starts.push(records);
+                records += positions;
+            }
+            progress.advance_work(positions, bytes);
+        }
+    }
+    let hashed = hashing.finish();
+    output.flush()?;
+    drop(output);
+    if illegal > 0 {
+        eprintln!("Cut {illegal} games short at an illegal move.");
+    }
+    if !inputs::is_stdio(output_path) {
+        dataset::set_header(output_path, &Header::new(records, 0))?;
+        games::write_index(output_path, &starts)?;
+    }
+    if options.index {
+        index::write_for(output_path, &hashed, std::mem::size_of::<PackedBoard>())?;
+    }
+    Ok(())
+}
+
+/// Reads the next game, or `None` at the end of the input.
+pub fn read_game(input: &mut impl Read, path: &Path) -> Result<Option<Game>> {
+    let invalid_data = |message: &str| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("{}: {message}", path.display()),
+        )
+    };
+    let mut start: PackedBoard = bytemuck::Zeroable::zeroed();
+    match marlinformat::io::fill(input, bytemuck::bytes_of_mut(&mut start))? {
+        0 => return Ok(None),
+        read if read < std::mem::size_of::<PackedBoard>() => {
+            return Err(invalid_data("ends partway through a game"))
+        }
+        _ => {}
+    }
+    let mut moves = vec![];
+    loop {
+        let mut entry = [0; 4];
+        if marlinformat::io::fill(input, &mut entry)? < entry.len() {
+            return Err(invalid_data("ends partway through a game"));
+        }
+        let mv = u16::from_le_bytes([entry[0], entry[1]]);
+        let eval = i16::from_le_bytes([entry[2], entry[3]]);
+        if mv == 0 && eval == 0 {
+            return Ok(Some(Game { start, moves }));
+        }
+        moves.push((mv, eval));
+    }
+}
+
+/// Replays a game, writing a record for each position that passes the filters of `options`.
+/// Returns the number of records written and whether every move was legal; the game is cut
+/// short at the first illegal one.
+pub fn replay(
+    game: &Game,
+    options: &Options,
+    mut write: impl FnMut(&PackedBoard) -> Result<()>,
+) -> Result<(u64, bool)> {
+    let Some((mut board, _, wdl, _)) = game.start.unpack() else {
+        return Ok((0, false));
+    };
+    let mut positions = 0;
+    for (ply, &(mv, eval)) in game.moves.iter().enumerate() {
+        let mv = decode_move(mv);
+        if !board.is_legal(mv) {
+            return Ok((positions, false));
+        }
+        let keep = ply >= options.skip_plies
+            && (!options.skip_noisy || !is_noisy(&board, mv))
+            && (!options.skip_check || board.checkers().is_empty());
+        if keep {
+            write(&PackedBoard::pack(&board, eval, wdl, 0))?;
+            positions += 1;
+        }
+        board.play_unchecked(mv);
+    }
+    Ok((positions, true))
+}
+
+fn decode_move(mv: u16) -> Move {
+    Move {
+        from: Square::index(mv as usize & 0x3F),
+        to: Square::index(mv as usize >> 6 & 0x3F),
+        promotion: match mv & PROMOTION {
+            PROMOTION => Some(Piece::index((mv as usize >> 12 & 0b11) + 1)),
+            _ => None,
+        },
+    }
+}
+
+/// Whether a move captures, including en passant, or promotes.
+fn is_noisy(board: &Board, mv: Move) -> bool {
+    let en_passant = board.piece_on(mv.from) == Some(Piece::Pawn) && mv.from.file() != mv.to.file();
+    board.color_on(mv.to) == Some(!board.side_to_move()) || en_passant || mv.promotion.is_some()
+}
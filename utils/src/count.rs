@@ -0,0 +1,156 @@
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+
+use marlinformat::index::Index;
+use marlinformat::{Header, PackedBoard, PackedBoardV2};
+use structopt::StructOpt;
+
+use crate::inputs;
+
+/// Compressed formats recognised by their magic bytes, with the command that decompresses
+/// them to stdout.
+const COMPRESSED: [(&str, &[u8]); 4] = [
+    ("gzip", &[0x1F, 0x8B]),
+    ("zstd", &[0x28, 0xB5, 0x2F, 0xFD]),
+    ("xz", &[0xFD, b'7', b'z', b'X', b'Z', 0x00]),
+    ("bzip2", b"BZh"),
+];
+
+/// Count the records of data files, from their size or their header. Compressed files are
+/// counted by decompressing them in full with the matching command line tool.
+#[derive(StructOpt)]
+pub struct Options {
+    /// Data files, directories of them, or patterns with `*` and `?` wildcards.
+    #[structopt(required = true)]
+    files: Vec<PathBuf>,
+
+    /// Read the files as legacy files without a header, even if they start with one.
+    #[structopt(long)]
+    headerless: bool,
+}
+
+pub fn run(options: Options) -> Result<()> {
+    let mut files = vec![];
+    for path in &options.files {
+        files.extend(inputs::expand(path)?);
+    }
+
+    let mut total = 0;
+    for path in &files {
+        let (records, compressor) = count(path, options.headerless)?;
+        total += records;
+        match compressor {
+            Some(compressor) => println!("{records:12}  {} ({compressor})", path.display()),
+            None => println!("{records:12}  {}", path.display()),
+        }
+    }
+    if files.len() > 1 {
+        println!("{total:12}  total");
+    }
+
+    Ok(())
+}
+
+/// Counts the records of a file, along with the compressor it was decompressed with.
+fn count(path: &Path, headerless: bool) -> Result<(u64, Option<&'static str>)> {
+    let mut file = File::open(path)?;
+    let mut start = vec![];
+    (&mut file).take(32).read_to_end(&mut start)?;
+    let compressor = compressor(path)?;
+    // An index written for the file as it is saves decompressing it. One that can't be read
+    // is ignored, as if there were none.
+    let index = Index::read_current(path).ok().flatten();
+    if let Some(index) = index.filter(|_| !headerless) {
+        return Ok((index.records(), compressor));
+    }
+
+    let (start, len) = match compressor {
+        Some(compressor) => decompressed_len(path, compressor)?,
+        None => (start, file.metadata()?.len()),
+    };
+
+    let header = Header::parse(&start).filter(|_| !headerless);
+    let (record_size, body) = match header {
+        Some(header) if header.version() == Header::VERSION_V2 => {
+            (std::mem::size_of::<PackedBoardV2>() as u64, len - 32)
+        }
+        Some(header) if header.version() > Header::VERSION_V2 => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{}: unsupported marlinformat version {}",
+                    path.display(),
+                    header.version()
+                ),
+            ))
+        }
+        Some(_) => (std::mem::size_of::<PackedBoard>() as u64, len - 32),
+        None => (std::mem::size_of::<PackedBoard>() as u64, len),
+    };
+
+    let stored = body / record_size;
+    if body % record_size != 0 {
+        eprintln!(
+            "Warning: {} ends in a partial record of {} bytes, and may be truncated.",
+            path.display(),
+            body % record_size
+        );
+    }
+    match header.and_then(|header| header.records()) {
+        Some(records) if records > stored => {
+            eprintln!(
+                "Warning: the header of {} promises {records} records but it holds {stored}.",
+                path.display()
+            );
+            Ok((stored, compressor))
+        }
+        Some(records) => Ok((records, compressor)),
+        None => Ok((stored, compressor)),
+    }
+}
+
+/// The compressor a file was compressed with, going by its magic bytes.
+pub fn compressor(path: &Path) -> Result<Option<&'static str>> {
+    let mut start = vec![];
+    File::open(path)?.take(8).read_to_end(&mut start)?;
+    Ok(COMPRESSED
+        .iter()
+        .find(|(_, magic)| start.starts_with(magic))
+        .map(|&(compressor, _)| compressor))
+}
+
+/// Starts decompressing a file to the child's stdout.
+pub fn decompress(path: &Path, compressor: &str) -> Result<Child> {
+    Command::new(compressor)
+        .arg("-dc")
+        .arg(path)
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!(
+                    "{} is compressed, but {compressor} failed to run: {e}",
+                    path.display()
+                ),
+            )
+        })
+}
+
+/// Decompresses a file, returning its first 32 bytes and its decompressed length.
+fn decompressed_len(path: &Path, compressor: &str) -> Result<(Vec<u8>, u64)> {
+    let mut child = decompress(path, compressor)?;
+    let mut stdout = child.stdout.take().unwrap();
+    let mut start = vec![];
+    (&mut stdout).take(32).read_to_end(&mut start)?;
+    let len = start.len() as u64 + std::io::copy(&mut stdout, &mut std::io::sink())?;
+    if !child.wait()?.success() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{compressor} failed to decompress {}", path.display()),
+        ));
+    }
+    Ok((start, len))
+}
@@ -0,0 +1,245 @@
+use std::io::{BufWriter, Result, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use marlinformat::{wdl_to_float, Eval, Header, PackedBoard, PackedBoardV2};
+use structopt::StructOpt;
+
+use crate::affinity::Affinity;
+use crate::dataset::{self, Dataset, Stream, Subrange};
+use crate::formats::{self, Format};
+use crate::inputs;
+use crate::progress::Progress;
+use crate::uci;
+
+/// Convert marlinformat to a text data format.
+#[derive(StructOpt)]
+pub struct Options {
+    /// Data file, a directory or glob pattern of data files, or `-` for stdin.
+    #[structopt(required_unless("list-formats"))]
+    dataset: Option<PathBuf>,
+
+    /// Output file, which every input is converted into, or `-` for stdout.
+    #[structopt(short, long, required_unless_one(&["suffix", "list-formats"]))]
+    output: Option<PathBuf>,
+
+    /// Convert every input to its own file instead, named after the input with its extension
+    /// replaced by this suffix.
+    #[structopt(long, conflicts_with("output"))]
+    suffix: Option<String>,
+
+    /// Output format: `legacy`, `cudad`, `viri`, `epd`, `fens` for bare FENs without labels,
+    /// `csv` for comma-separated columns of the FEN, eval, result, extra byte, piece count and
+    /// phase under a header row, or any other that `--list-formats` shows as writable.
+    #[structopt(long, default_value = "legacy")]
+    format: Box<dyn Format>,
+
+    /// List the text formats, including any registered by a fork, and exit.
+    #[structopt(long)]
+    list_formats: bool,
+
+    /// Number of workers, each converting its own range of the file into a temporary file.
+    /// Defaults to the number of CPUs.
+    #[structopt(long)]
+    workers: Option<usize>,
+
+    /// Pin workers to CPUs: `cores` for one CPU each, or `numa` to spread them over the NUMA
+    /// nodes with each reading its range into its node's memory.
+    #[structopt(long, default_value = "none")]
+    affinity: Affinity,
+
+    #[structopt(flatten)]
+    range: Subrange,
+}
+
+/// An input read in parallel from a file, or in order from stdin or a version 2 file.
+enum Input {
+    Dataset(Dataset),
+    Stream(PathBuf, Stream),
+}
+
+impl Input {
+    fn path(&self) -> &Path {
+        match self {
+            Input::Dataset(dataset) => dataset.path(),
+            Input::Stream(path, _) => path,
+        }
+    }
+
+    /// The flags of the input's header, or none if it has no header.
+    fn flags(&self) -> u16 {
+        let header = match self {
+            Input::Dataset(dataset) => dataset.header(),
+            Input::Stream(_, stream) => stream.header(),
+        };
+        header.map_or(0, Header::flags)
+    }
+
+    /// Whether the input holds soft results.
+    fn soft_wdl(&self) -> bool {
+        self.flags() & Header::FLAG_SOFT_WDL != 0
+    }
+}
+
+pub fn run(options: Options) -> Result<()> {
+    if options.list_formats {
+        formats::print_list();
+        return Ok(());
+    }
+    let mut inputs = vec![];
+    for path in inputs::expand(options.dataset.as_ref().unwrap())? {
+        if inputs::is_stdio(&path) || dataset::is_v2(&path, &options.range)? {
+            let stream = Stream::open(inputs::open(&path)?, &options.range)?;
+            inputs.push(Input::Stream(path, stream));
+        } else {
+            inputs.push(Input::Dataset(
+                Dataset::open(&path)?
+                    .subrange(&options.range)
+                    .with_affinity(options.affinity),
+            ));
+        }
+    }
+    if !options.format.soft_results() {
+        for input in &inputs {
+            dataset::check_hard_wdl(input.path(), input.soft_wdl(), "the format")?;
+        }
+    }
+    let workers = options.workers.unwrap_or_else(dataset::default_workers);
+
+    // The length of a stream is unknown, and so is the total if there is one.
+    let total = inputs
+        .iter()
+        .map(|input| match input {
+            Input::Dataset(dataset) => Some(dataset.len()),
+            Input::Stream(..) => None,
+        })
+        .sum::<Option<u64>>();
+    let progress = Progress::new("data-to-txt", total.unwrap_or(0));
+    match &options.suffix {
+        Some(suffix) => {
+            for input in inputs {
+                let output = inputs::with_suffix(input.path(), suffix);
+                let mut into = BufWriter::new(inputs::create(&output)?);
+                write_header(&mut into, std::slice::from_ref(&input), &options)?;
+                convert(input, &mut into, &output, workers, &options, &progress)?;
+                into.flush()?;
+            }
+        }
+        None => {
+            let output = options.output.as_ref().unwrap();
+            let mut into = BufWriter::new(inputs::create(output)?);
+            write_header(&mut into, &inputs, &options)?;
+            for input in inputs {
+                convert(input, &mut into, output, workers, &options, &progress)?;
+            }
+            into.flush()?;
+        }
+    }
+    progress.finish();
+
+    Ok(())
+}
+
+/// Writes the header row of the format, if it has one, naming a move column if the first
+/// input holds moves.
+fn write_header(into: &mut impl Write, inputs: &[Input], options: &Options) -> Result<()> {
+    let Some(header) = options.format.header() else {
+        return Ok(());
+    };
+    let moves = matches!(inputs.first(), Some(Input::Stream(_, stream)) if stream.is_v2());
+    match moves {
+        true => writeln!(into, "{header}{}move", options.format.separator()),
+        false => writeln!(into, "{header}"),
+    }
+}
+
+fn convert(
+    input: Input,
+    into: &mut impl Write,
+    output: &Path,
+    workers: usize,
+    options: &Options,
+    progress: &Progress,
+) -> Result<()> {
+    let flags = input.flags();
+    let dataset = match input {
+        Input::Dataset(dataset) => dataset,
+        Input::Stream(_, stream) => {
+            let moves = stream.is_v2();
+            return stream.for_each_chunk(progress, |chunk| {
+                write_v2_chunk(into, chunk, moves, flags, options)
+            });
+        }
+    };
+    let output_dir = match inputs::is_stdio(output) {
+        true => std::env::temp_dir(),
+        false => output
+            .parent()
+            .expect("Could not get nominal parent directory of the output file")
+            .to_owned(),
+    };
+    let parts = dataset.par_chunks(
+        workers,
+        progress,
+        || Ok(BufWriter::new(tempfile::tempfile_in(&output_dir)?)),
+        |part, chunk| write_chunk(part, chunk, flags, options),
+    )?;
+
+    for part in parts {
+        let mut part = part.into_inner()?;
+        part.rewind()?;
+        std::io::copy(&mut part, into)?;
+    }
+
+    Ok(())
+}
+
+/// Writes records with the move in UCI notation as an extra column if `moves` is set, or
+/// `0000` for records without one. `flags` are those of the header of the records' file.
+fn write_v2_chunk(
+    into: &mut impl Write,
+    chunk: &[PackedBoardV2],
+    moves: bool,
+    flags: u16,
+    options: &Options,
+) -> Result<()> {
+    for packed in chunk {
+        if let Some((board, cp, wdl, extra, mv)) = packed.unpack() {
+            let (eval, wdl) = decode(cp, wdl, flags);
+            let line = options.format.format_line(&board, eval, wdl, extra);
+            let separator = options.format.separator();
+            match (moves, mv) {
+                (false, _) => writeln!(into, "{line}")?,
+                (true, Some(mv)) => writeln!(into, "{line}{separator}{}", uci::to_uci(&board, mv))?,
+                (true, None) => writeln!(into, "{line}{separator}0000")?,
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_chunk(
+    into: &mut impl Write,
+    chunk: &[PackedBoard],
+    flags: u16,
+    options: &Options,
+) -> Result<()> {
+    for packed in chunk {
+        if let Some((board, cp, wdl, extra)) = packed.unpack() {
+            let (eval, wdl) = decode(cp, wdl, flags);
+            writeln!(
+                into,
+                "{}",
+                options.format.format_line(&board, eval, wdl, extra)
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Decodes the eval and result of a record of a file with the given header flags.
+fn decode(cp: i16, wdl: u8, flags: u16) -> (Eval, f32) {
+    (
+        Eval::decode(cp, flags & Header::FLAG_MATE_SCORES != 0),
+        wdl_to_float(wdl, flags & Header::FLAG_SOFT_WDL != 0),
+    )
+}
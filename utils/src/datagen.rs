@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+
+use cozy_chess::{Board, Color, GameStatus};
+use cozy_syzygy::{Tablebase, Wdl};
+use marlinformat::{Extra, Header, PackedBoard};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use structopt::StructOpt;
+
+use crate::dataset;
+use crate::games;
+use crate::openings::{OpeningOptions, Openings};
+use crate::progress::Progress;
+use crate::uci::{Engine, EngineOptions};
+
+/// Generate training data by self-play with a UCI engine. Every searched position is written
+/// with the engine's score and the final result of its game, along with the games index
+/// described under `games`.
+#[derive(StructOpt)]
+pub struct Options {
+    #[structopt(short, long)]
+    output: PathBuf,
+
+    /// Number of games to play.
+    #[structopt(long)]
+    games: u64,
+
+    #[structopt(flatten)]
+    engine: EngineOptions,
+
+    /// Number of games played at once, each by its own engine process. Defaults to the
+    /// number of CPUs.
+    #[structopt(long)]
+    concurrency: Option<usize>,
+
+    #[structopt(flatten)]
+    openings: OpeningOptions,
+
+    #[structopt(flatten)]
+    adjudication: Adjudication,
+
+    #[structopt(long, default_value = "0")]
+    seed: u64,
+}
+
+/// Rules for ending games early.
+#[derive(StructOpt)]
+struct Adjudication {
+    /// Adjudicate a win once the score stays at least this far in one side's favour...
+    #[structopt(long, default_value = "1000")]
+    resign_score: i16,
+
+    /// ...for this many plies in a row. 0 disables win adjudication.
+    #[structopt(long, default_value = "6")]
+    resign_plies: u32,
+
+    /// Adjudicate a draw once the score stays within this many centipawns of 0...
+    #[structopt(long, default_value = "10")]
+    draw_score: i16,
+
+    /// ...for this many plies in a row. 0 disables draw adjudication.
+    #[structopt(long, default_value = "10")]
+    draw_plies: u32,
+
+    /// Only adjudicate draws after this many plies of the game.
+    #[structopt(long, default_value = "80")]
+    draw_after: u32,
+
+    /// End games as soon as the Syzygy tables in this directory can be probed, and label
+    /// their positions with the tablebase result, marking them as tablebase rescored.
+    #[structopt(long)]
+    tb_adjudicate: Option<PathBuf>,
+
+    /// With `--tb-adjudicate`, only probe positions with at most this many pieces, kings
+    /// included. Defaults to the largest tables found.
+    #[structopt(long, requires("tb-adjudicate"))]
+    tb_max_pieces: Option<u32>,
+
+    #[structopt(skip)]
+    tablebase: Option<Tablebase>,
+}
+
+impl Adjudication {
+    /// The result of the game from `board` on, if the tablebase knows it.
+    fn probe(&self, board: &Board) -> Option<u8> {
+        let tablebase = self.tablebase.as_ref()?;
+        let max_pieces = self.tb_max_pieces.unwrap_or_else(|| tablebase.max_pieces());
+        if board.occupied().len() > max_pieces {
+            return None;
+        }
+        let (wdl, _) = tablebase.probe_wdl(board)?;
+        let stm = board.side_to_move();
+        Some(match wdl {
+            Wdl::Win => winner_wdl(stm),
+            Wdl::Loss => winner_wdl(!stm),
+            // The fifty-move rule draws cursed wins and blessed losses.
+            Wdl::CursedWin | Wdl::Draw | Wdl::BlessedLoss => 1,
+        })
+    }
+}
+
+pub fn run(mut options: Options) -> Result<()> {
+    options.engine.check_limits()?;
+    if let Some(path) = &options.adjudication.tb_adjudicate {
+        let mut tablebase = Tablebase::new();
+        tablebase.add_directory(path)?;
+        options.adjudication.tablebase = Some(tablebase);
+    }
+    let openings = Openings::new(&options.openings)?;
+    let concurrency = options.concurrency.unwrap_or_else(dataset::default_workers);
+
+    let mut output = BufWriter::new(File::create(&options.output)?);
+    // The record count is filled in once it is known.
+    output.write_all(bytemuck::bytes_of(&Header::new(
+        0,
+        Header::FLAG_MATE_SCORES,
+    )))?;
+
+    let next = AtomicU64::new(0);
+    let progress = Progress::new("datagen", options.games);
+    let mut starts = vec![];
+    let mut records = 0;
+    std::thread::scope(|scope| {
+        let (send, recv) = mpsc::channel();
+        for _ in 0..concurrency {
+            let send = send.clone();
+            let (options, openings, next) = (&options, &openings, &next);
+            scope.spawn(move || {
+                if let Err(e) = work(options, openings, next, &send) {
+                    let _ = send.send(Err(e));
+                }
+            });
+        }
+        drop(send);
+
+        for game in recv {
+            let game = game?;
+            starts.push(records);
+            records += game.len() as u64;
+            output.write_all(bytemuck::cast_slice(&game))?;
+            progress.advance_work(game.len() as u64, 1);
+        }
+        Ok::<_, Error>(())
+    })?;
+    output.flush()?;
+    drop(output);
+    progress.finish();
+
+    dataset::set_header(
+        &options.output,
+        &Header::new(records, Header::FLAG_MATE_SCORES),
+    )?;
+    games::write_index(&options.output, &starts)
+}
+
+/// Plays games and sends their positions until enough games have been started.
+fn work(
+    options: &Options,
+    openings: &Openings,
+    next: &AtomicU64,
+    send: &mpsc::Sender<Result<Vec<PackedBoard>>>,
+) -> Result<()> {
+    let mut engine = Engine::start(&options.engine)?;
+    loop {
+        let game = next.fetch_add(1, Ordering::Relaxed);
+        if game >= options.games {
+            return Ok(());
+        }
+        let mut rng = StdRng::seed_from_u64(options.seed ^ game);
+        let opening = openings.draw(&mut rng);
+        let positions = play(&mut engine, &opening, &options.adjudication)?;
+        // The receiver only hangs up after an error, when the work is abandoned anyway.
+        if send.send(Ok(positions)).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Plays a game from `opening`, returning its positions labelled with the engine's score and
+/// the game's result.
+fn play(engine: &mut Engine, opening: &Board, rules: &Adjudication) -> Result<Vec<PackedBoard>> {
+    engine.new_game()?;
+    let mut board = opening.clone();
+    let mut moves = vec![];
+    let mut positions = vec![];
+    let mut seen = HashMap::new();
+    let (mut winning, mut drawn) = (0, 0);
+    let mut extra = Extra::default();
+    let wdl = loop {
+        let repetitions = seen.entry(board.hash()).or_insert(0);
+        *repetitions += 1;
+        match board.status() {
+            GameStatus::Won => break winner_wdl(!board.side_to_move()),
+            GameStatus::Drawn => break 1,
+            GameStatus::Ongoing if *repetitions == 3 => break 1,
+            GameStatus::Ongoing => {}
+        }
+        if let Some(wdl) = rules.probe(&board) {
+            extra.set_tb_rescored(true);
+            break wdl;
+        }
+
+        let search = engine.search(opening, &moves)?;
+        let (eval, mv) = match (search.eval, search.best_move) {
+            (Some(eval), Some(mv)) => (eval.encode(), mv),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("the engine gave no score or legal move in {board}"),
+                ))
+            }
+        };
+        positions.push((board.clone(), eval));
+
+        winning = match eval.abs() >= rules.resign_score {
+            true if winning * eval.signum() as i32 > 0 => winning + eval.signum() as i32,
+            true => eval.signum() as i32,
+            false => 0,
+        };
+        drawn = match eval.abs() <= rules.draw_score {
+            true => drawn + 1,
+            false => 0,
+        };
+        if rules.resign_plies > 0 && winning.unsigned_abs() >= rules.resign_plies {
+            break match winning > 0 {
+                true => 2,
+                false => 0,
+            };
+        }
+        if rules.draw_plies > 0
+            && drawn >= rules.draw_plies
+            && moves.len() as u32 >= rules.draw_after
+        {
+            break 1;
+        }
+
+        board.play(mv);
+        moves.push(mv);
+    };
+
+    Ok(positions
+        .iter()
+        .map(|(board, eval)| PackedBoard::pack(board, *eval, wdl, extra.bits()))
+        .collect())
+}
+
+fn winner_wdl(winner: Color) -> u8 {
+    match winner {
+        Color::White => 2,
+        Color::Black => 0,
+    }
+}
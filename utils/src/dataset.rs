@@ -0,0 +1,483 @@
+//! Reading and writing datasets of packed boards.
+//!
+//! Parallel work splits a dataset into contiguous ranges of records, each handled by a
+//! worker with its own file handle using positioned reads. Unlike sharing one
+//! mapping between threads, this performs well on network filesystems, and a range can
+//! just as well be handed to another machine with `--skip` and `--limit`.
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::{Cursor, Error, ErrorKind, Read, Result, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use bytemuck::{Pod, Zeroable};
+use marlinformat::{wdl_to_float, Header, PackedBoard, PackedBoardV2};
+use structopt::StructOpt;
+
+use crate::affinity::Affinity;
+use crate::progress::Progress;
+
+/// How many records are read at a time.
+const CHUNK_RECORDS: usize = 1 << 16;
+
+const RECORD_SIZE: u64 = std::mem::size_of::<PackedBoard>() as u64;
+
+/// Restricts a subcommand to a range of a dataset's records.
+#[derive(StructOpt)]
+pub struct Subrange {
+    /// Skip this many records at the start of the dataset.
+    #[structopt(long, default_value = "0")]
+    skip: u64,
+
+    /// Read at most this many records.
+    #[structopt(long)]
+    limit: Option<u64>,
+
+    /// Read the file as a legacy file without a header, even if it starts with one.
+    #[structopt(long)]
+    headerless: bool,
+}
+
+impl Subrange {
+    /// Returns the selected records of a dataset holding `records` records.
+    fn of(&self, records: u64) -> Range<u64> {
+        let start = self.skip.min(records);
+        let end = self
+            .limit
+            .map_or(records, |limit| start.saturating_add(limit).min(records));
+        start..end
+    }
+}
+
+pub fn default_workers() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// A data file read as version 1 records. The records of a version 2 file are read without
+/// their moves.
+pub struct Dataset {
+    path: PathBuf,
+    file: File,
+    header: Option<Header>,
+    // The selected records, counted from the first record after any header
+    records: Range<u64>,
+    record_size: u64,
+    affinity: Affinity,
+}
+
+impl Dataset {
+    /// Opens a dataset, skipping its header if it has one.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let (header, records) = read_header(&file)?;
+        Ok(Dataset {
+            path: path.to_owned(),
+            file,
+            header,
+            records: 0..records,
+            record_size: record_size(header.as_ref()),
+            affinity: Affinity::None,
+        })
+    }
+
+    /// Restricts the dataset to the records selected by `--skip` and `--limit`, after
+    /// treating it as headerless if asked to.
+    pub fn subrange(mut self, range: &Subrange) -> Self {
+        if range.headerless && self.header.take().is_some() {
+            self.records = 0..self
+                .file
+                .metadata()
+                .map_or(0, |meta| meta.len() / RECORD_SIZE);
+            self.record_size = RECORD_SIZE;
+        }
+        let selected = range.of(self.len());
+        self.records = self.records.start + selected.start..self.records.start + selected.end;
+        self
+    }
+
+    /// Places the workers of `par_chunks` on the CPUs as `affinity` says.
+    pub fn with_affinity(mut self, affinity: Affinity) -> Self {
+        self.affinity = affinity;
+        self
+    }
+
+    pub fn header(&self) -> Option<&Header> {
+        self.header.as_ref()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> u64 {
+        self.records.end - self.records.start
+    }
+
+    /// The index of the first record of the dataset among all the records of the file.
+    pub fn start(&self) -> u64 {
+        self.records.start
+    }
+
+    /// Reads the records with the given indices, relative to the start of the dataset.
+    pub fn read_chunk(&self, records: Range<u64>) -> Result<Vec<PackedBoard>> {
+        let mut chunk = vec![PackedBoard::zeroed(); (records.end - records.start) as usize];
+        let first = self.records.start + records.start;
+        self.read_records_at(&self.file, &mut chunk, first, &mut vec![])?;
+        Ok(chunk)
+    }
+
+    /// Fills `chunk` with the records of `file` from record `first` of the file on, reading
+    /// version 2 records through `v2_chunk` to drop their moves.
+    fn read_records_at(
+        &self,
+        file: &File,
+        chunk: &mut [PackedBoard],
+        first: u64,
+        v2_chunk: &mut Vec<PackedBoardV2>,
+    ) -> Result<()> {
+        let offset = self.header.map_or(0, |_| RECORD_SIZE) + first * self.record_size;
+        if self.record_size == RECORD_SIZE {
+            return read_exact_at(file, bytemuck::cast_slice_mut(chunk), offset);
+        }
+        v2_chunk.resize(chunk.len(), PackedBoardV2::zeroed());
+        read_exact_at(file, bytemuck::cast_slice_mut(v2_chunk), offset)?;
+        for (record, v2) in chunk.iter_mut().zip(v2_chunk.iter()) {
+            *record = v2.board();
+        }
+        Ok(())
+    }
+
+    pub fn read(&self, index: u64) -> Result<PackedBoard> {
+        Ok(self.read_chunk(index..index + 1)?[0])
+    }
+
+    /// Iterates over the records in order.
+    pub fn iter(&self) -> Records<'_> {
+        Records {
+            dataset: self,
+            next: 0,
+            chunk: Vec::new(),
+            chunk_pos: 0,
+        }
+    }
+
+    /// Splits the dataset into one contiguous range per worker thread. Each worker creates its
+    /// state with `init` and passes it to `f` along with each chunk of its range in turn.
+    /// Returns the states in range order. Workers are pinned, as set by `with_affinity`,
+    /// before they allocate anything, so that their memory is local to their CPUs.
+    pub fn par_chunks<S: Send>(
+        &self,
+        workers: usize,
+        progress: &Progress,
+        init: impl Fn() -> Result<S> + Sync,
+        f: impl Fn(&mut S, &[PackedBoard]) -> Result<()> + Sync,
+    ) -> Result<Vec<S>> {
+        let (init, f) = (&init, &f);
+        std::thread::scope(|scope| {
+            let ranges = split(self.records.clone(), workers);
+            let count = ranges.len();
+            let handles: Vec<_> = ranges
+                .into_iter()
+                .enumerate()
+                .map(|(worker, range)| {
+                    scope.spawn(move || {
+                        self.affinity.pin(worker, count);
+                        let file = File::open(&self.path)?;
+                        let mut state = init()?;
+                        let mut chunk = vec![PackedBoard::zeroed(); CHUNK_RECORDS];
+                        let mut v2_chunk = vec![];
+                        let mut next = range.start;
+                        while next < range.end {
+                            let count = ((range.end - next) as usize).min(CHUNK_RECORDS);
+                            let chunk = &mut chunk[..count];
+                            self.read_records_at(&file, chunk, next, &mut v2_chunk)?;
+                            f(&mut state, chunk)?;
+                            progress.advance(count as u64);
+                            next += count as u64;
+                        }
+                        Ok(state)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        })
+    }
+}
+
+/// Reads the header at the start of a data file, if it has one, and returns it along with
+/// the range of record slots holding its records. Version 2 records don't fit the slots, so
+/// files holding them are refused.
+pub fn detect_header(file: &File) -> Result<(Option<Header>, Range<u64>)> {
+    let (header, records) = read_header(file)?;
+    match header {
+        Some(header) if header.version() == Header::VERSION_V2 => Err(Error::new(
+            ErrorKind::InvalidData,
+            "the file holds version 2 records, which this tool can't copy",
+        )),
+        Some(header) => Ok((Some(header), 1..1 + records)),
+        None => Ok((None, 0..records)),
+    }
+}
+
+/// Reads the header at the start of a data file, if it has one, and returns it along with
+/// the number of records after it.
+fn read_header(file: &File) -> Result<(Option<Header>, u64)> {
+    let len = file.metadata()?.len();
+    let mut first = [0; RECORD_SIZE as usize];
+    if len < RECORD_SIZE {
+        return Ok((None, 0));
+    }
+    read_exact_at(file, &mut first, 0)?;
+    let header = match Header::parse(&first) {
+        Some(header) => header,
+        None => return Ok((None, len / RECORD_SIZE)),
+    };
+    check_version(&header)?;
+    let slots = (len - RECORD_SIZE) / record_size(Some(&header));
+    let records = match header.records() {
+        Some(records) if records > slots => {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("the header promises {records} records but the file holds {slots}"),
+            ))
+        }
+        Some(records) => records,
+        None => slots,
+    };
+    Ok((Some(header), records))
+}
+
+/// The size of the records of a file with the given header.
+fn record_size(header: Option<&Header>) -> u64 {
+    match header.is_some_and(|header| header.version() == Header::VERSION_V2) {
+        true => std::mem::size_of::<PackedBoardV2>() as u64,
+        false => RECORD_SIZE,
+    }
+}
+
+fn check_version(header: &Header) -> Result<()> {
+    if header.version() > Header::VERSION_V2 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "unsupported marlinformat version {}, pass --headerless to read the file as \
+                 legacy data",
+                header.version()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// The flags that every header agrees on, for a file merged from `inputs` files, of which those
+/// with a header have `headers`. A file without a header holds plain records, so it vouches for
+/// no flags at all. Soft and hard results can't be mixed, as nothing in a record says which it
+/// holds.
+pub fn merge_flags(headers: &[Header], inputs: usize) -> Result<u16> {
+    let soft = headers
+        .iter()
+        .filter(|header| header.flags() & Header::FLAG_SOFT_WDL != 0)
+        .count();
+    if soft > 0 && soft < inputs {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "can't merge files with soft results with files with hard ones",
+        ));
+    }
+    if headers.len() < inputs {
+        return Ok(0);
+    }
+    Ok(headers
+        .iter()
+        .fold(!0, |flags, header| flags & header.flags()))
+}
+
+/// A record's `wdl` byte as a loss, draw or win for white, 0, 1 or 2, counting soft results as
+/// the nearest of the three.
+pub fn hard_wdl(wdl: u8, soft: bool) -> u8 {
+    marlinformat::text::wdl_from_float(wdl_to_float(wdl, soft))
+}
+
+/// Fails if the file at `path` holds soft results, which `what` can only hold as a loss, draw or
+/// win.
+pub fn check_hard_wdl(path: &Path, soft: bool, what: &str) -> Result<()> {
+    match soft {
+        true => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} holds soft results, which {what} can only hold as a loss, draw or win",
+                path.display()
+            ),
+        )),
+        false => Ok(()),
+    }
+}
+
+/// Writes a header to the start of a data file that already has room for it, as reserved by
+/// writing a header with an unknown record count before the records.
+pub fn set_header(path: &Path, header: &Header) -> Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.write_all(bytemuck::bytes_of(header))
+}
+
+/// The header of a data file, if it starts with one.
+pub fn peek_header(path: &Path) -> Result<Option<Header>> {
+    let mut first = [0; RECORD_SIZE as usize];
+    let filled = marlinformat::io::fill(&mut File::open(path)?, &mut first)?;
+    Ok(Header::parse(&first[..filled]))
+}
+
+/// Whether a data file holds version 2 records, going by its header, unless `range` says to
+/// read it as headerless.
+pub fn is_v2(path: &Path, range: &Subrange) -> Result<bool> {
+    Ok(peek_header(path)?
+        .is_some_and(|header| !range.headerless && header.version() == Header::VERSION_V2))
+}
+
+/// A stream of records such as stdin, which unlike a [`Dataset`] cannot be split between
+/// workers and is read in order.
+pub struct Stream {
+    reader: Box<dyn Read>,
+    header: Option<Header>,
+    selected: Range<u64>,
+}
+
+impl Stream {
+    /// Reads the stream's header, if it has one, and selects the records given by `range`.
+    pub fn open(mut reader: impl Read + 'static, range: &Subrange) -> Result<Self> {
+        let mut first = [0; RECORD_SIZE as usize];
+        let filled = marlinformat::io::fill(&mut reader, &mut first)?;
+        let header = Header::parse(&first[..filled]).filter(|_| !range.headerless);
+        let reader: Box<dyn Read> = match &header {
+            Some(header) => {
+                check_version(header)?;
+                Box::new(reader)
+            }
+            None => Box::new(Cursor::new(first[..filled].to_vec()).chain(reader)),
+        };
+        let records = header.and_then(|header| header.records());
+        Ok(Stream {
+            reader,
+            header,
+            selected: range.of(records.unwrap_or(u64::MAX)),
+        })
+    }
+
+    pub fn header(&self) -> Option<&Header> {
+        self.header.as_ref()
+    }
+
+    /// Whether the stream holds version 2 records, which carry a move.
+    pub fn is_v2(&self) -> bool {
+        self.header
+            .is_some_and(|header| header.version() == Header::VERSION_V2)
+    }
+
+    /// Passes the selected records to `f` a chunk at a time, with version 1 records widened
+    /// to version 2 records without a move.
+    pub fn for_each_chunk(
+        mut self,
+        progress: &Progress,
+        mut f: impl FnMut(&[PackedBoardV2]) -> Result<()>,
+    ) -> Result<()> {
+        let v2 = self.is_v2();
+        let mut v1_chunk = vec![PackedBoard::zeroed(); CHUNK_RECORDS];
+        let mut chunk = vec![PackedBoardV2::zeroed(); CHUNK_RECORDS];
+        let mut next = 0;
+        while next < self.selected.end {
+            let count = match v2 {
+                true => read_records(&mut self.reader, &mut chunk)?,
+                false => {
+                    let count = read_records(&mut self.reader, &mut v1_chunk)?;
+                    for (record, &v1) in chunk.iter_mut().zip(&v1_chunk[..count]) {
+                        *record = PackedBoardV2::from(v1);
+                    }
+                    count
+                }
+            };
+            if count == 0 {
+                break;
+            }
+            let count = count as u64;
+            let start = self.selected.start.clamp(next, next + count) - next;
+            let end = self.selected.end.clamp(next, next + count) - next;
+            f(&chunk[start as usize..end as usize])?;
+            progress.advance(end - start);
+            next += count;
+        }
+        Ok(())
+    }
+}
+
+/// Fills `chunk` from `reader`, returning the number of whole records read.
+fn read_records<T: Pod>(reader: &mut impl Read, chunk: &mut [T]) -> Result<usize> {
+    let filled = marlinformat::io::fill(reader, bytemuck::cast_slice_mut(chunk))?;
+    Ok(filled / std::mem::size_of::<T>())
+}
+
+/// An iterator over a dataset's records, reading a chunk at a time.
+pub struct Records<'a> {
+    dataset: &'a Dataset,
+    next: u64,
+    chunk: Vec<PackedBoard>,
+    chunk_pos: usize,
+}
+
+impl Iterator for Records<'_> {
+    type Item = Result<PackedBoard>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.chunk_pos == self.chunk.len() {
+            let count = (self.dataset.len() - self.next).min(CHUNK_RECORDS as u64);
+            if count == 0 {
+                return None;
+            }
+            self.chunk = match self.dataset.read_chunk(self.next..self.next + count) {
+                Ok(chunk) => chunk,
+                Err(e) => return Some(Err(e)),
+            };
+            self.chunk_pos = 0;
+            self.next += count;
+        }
+        self.chunk_pos += 1;
+        Some(Ok(self.chunk[self.chunk_pos - 1]))
+    }
+}
+
+/// Splits a range of records into at most `parts` contiguous ranges of near-equal size.
+fn split(records: Range<u64>, parts: usize) -> Vec<Range<u64>> {
+    let len = records.end - records.start;
+    let parts = (parts as u64).clamp(1, len.max(1));
+    (0..parts)
+        .map(|i| records.start + len * i / parts..records.start + len * (i + 1) / parts)
+        .collect()
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buffer: &mut [u8], offset: u64) -> Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buffer, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buffer: &mut [u8], mut offset: u64) -> Result<()> {
+    use std::io::{Error, ErrorKind};
+    use std::os::windows::fs::FileExt;
+
+    while !buffer.is_empty() {
+        match file.seek_read(buffer, offset) {
+            Ok(0) => return Err(Error::from(ErrorKind::UnexpectedEof)),
+            Ok(n) => {
+                buffer = &mut std::mem::take(&mut buffer)[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
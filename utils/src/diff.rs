@@ -0,0 +1,200 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::Result;
+use std::path::PathBuf;
+
+use cozy_chess::Board;
+use marlinformat::{wdl_to_float, Header, PackedBoard};
+use structopt::StructOpt;
+
+use crate::dataset::Dataset;
+use crate::formats;
+
+/// Compare two datasets and report positions and labels that differ.
+#[derive(StructOpt)]
+pub struct Options {
+    old: PathBuf,
+    new: PathBuf,
+
+    /// Match positions by hash instead of comparing the files record by record. A position
+    /// that appears several times in a file is matched occurrence by occurrence, in file order.
+    #[structopt(long)]
+    by_hash: bool,
+
+    /// Print up to this many differing records.
+    #[structopt(long, default_value = "0")]
+    print: u64,
+}
+
+#[derive(Default)]
+struct Report {
+    old_records: u64,
+    new_records: u64,
+    only_old: u64,
+    only_new: u64,
+    eval_changed: u64,
+    wdl_changes: [[u64; 3]; 3],
+    printed: u64,
+}
+
+/// The eval of a record and white's expected score, which soft results are compared by.
+type Labels = (i16, f32);
+
+impl Report {
+    fn compare(&mut self, board: &Board, old: Labels, new: Labels, print: u64) {
+        let (old_cp, old_wdl) = old;
+        let (new_cp, new_wdl) = new;
+        if old_cp != new_cp {
+            self.eval_changed += 1;
+        }
+        let (old_result, new_result) = (
+            formats::wdl_from_float(old_wdl),
+            formats::wdl_from_float(new_wdl),
+        );
+        self.wdl_changes[old_result as usize][new_result as usize] += 1;
+        if old != new && self.printed < print {
+            self.printed += 1;
+            println!(
+                "{board}: {old_cp} | {} -> {new_cp} | {}",
+                formats::format_result(old_wdl),
+                formats::format_result(new_wdl)
+            );
+        }
+    }
+
+    fn missing(&mut self, board: &Board, side: &str, print: u64) {
+        if self.printed < print {
+            self.printed += 1;
+            println!("{board}: only in {side}");
+        }
+    }
+
+    fn print_summary(&self) {
+        println!("old records:     {:12}", self.old_records);
+        println!("new records:     {:12}", self.new_records);
+        println!("only in old:     {:12}", self.only_old);
+        println!("only in new:     {:12}", self.only_new);
+        println!("eval changed:    {:12}", self.eval_changed);
+        println!("wdl changes (old -> new):");
+        let labels = ["loss", "draw", "win"];
+        for (old, row) in self.wdl_changes.iter().enumerate() {
+            for (new, &count) in row.iter().enumerate() {
+                if old != new {
+                    println!("  {:>4} -> {:<4}  {count:12}", labels[old], labels[new]);
+                }
+            }
+        }
+    }
+}
+
+pub fn run(options: Options) -> Result<()> {
+    let mut report = Report::default();
+    let old = Dataset::open(&options.old)?;
+    let new = Dataset::open(&options.new)?;
+    let (old_count, new_count) = (old.len(), new.len());
+    report.old_records = old_count;
+    report.new_records = new_count;
+    let soft = |dataset: &Dataset| {
+        dataset
+            .header()
+            .is_some_and(|header| header.flags() & Header::FLAG_SOFT_WDL != 0)
+    };
+    let soft = [soft(&old), soft(&new)];
+
+    if options.by_hash {
+        compare_by_hash(&mut report, old.iter(), new.iter(), soft, options.print)?;
+    } else {
+        for (old_packed, new_packed) in old.iter().zip(new.iter()) {
+            let old_unpacked = unpack(&old_packed?, soft[0]);
+            let new_unpacked = unpack(&new_packed?, soft[1]);
+            let ((old_board, old), (new_board, new)) = match (old_unpacked, new_unpacked) {
+                (Some(old), Some(new)) => (old, new),
+                _ => continue,
+            };
+            if old_board == new_board {
+                report.compare(&old_board, old, new, options.print);
+            } else {
+                report.only_old += 1;
+                report.only_new += 1;
+                report.missing(&old_board, "old", options.print);
+                report.missing(&new_board, "new", options.print);
+            }
+        }
+        report.only_old += old_count.saturating_sub(new_count);
+        report.only_new += new_count.saturating_sub(old_count);
+    }
+
+    report.print_summary();
+
+    Ok(())
+}
+
+/// Unpacks a record of a file with soft results if `soft` is set.
+fn unpack(packed: &PackedBoard, soft: bool) -> Option<(Board, Labels)> {
+    let (board, cp, wdl, _) = packed.unpack()?;
+    Some((board, (cp, wdl_to_float(wdl, soft))))
+}
+
+/// Matches the positions of `new` to those of `old` by hash, pairing repeated positions in the
+/// order they appear. `soft` says whether each of them holds soft results.
+fn compare_by_hash(
+    report: &mut Report,
+    old: impl Iterator<Item = Result<PackedBoard>>,
+    new: impl Iterator<Item = Result<PackedBoard>>,
+    soft: [bool; 2],
+    print: u64,
+) -> Result<()> {
+    let mut seen: HashMap<u64, VecDeque<Labels>> = HashMap::new();
+    for packed in old {
+        if let Some((board, labels)) = unpack(&packed?, soft[0]) {
+            seen.entry(board.hash()).or_default().push_back(labels);
+        }
+    }
+    for packed in new {
+        let (board, labels) = match unpack(&packed?, soft[1]) {
+            Some(unpacked) => unpacked,
+            None => continue,
+        };
+        match seen.get_mut(&board.hash()).and_then(VecDeque::pop_front) {
+            Some(old) => report.compare(&board, old, labels, print),
+            None => {
+                report.only_new += 1;
+                report.missing(&board, "new", print);
+            }
+        }
+    }
+    report.only_old = seen.values().map(|labels| labels.len() as u64).sum();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(positions: &[(&str, i16, u8)]) -> impl Iterator<Item = Result<PackedBoard>> {
+        positions
+            .iter()
+            .map(|&(fen, cp, wdl)| {
+                let board = Board::from_fen(fen, false).unwrap();
+                Ok(PackedBoard::pack(&board, cp, wdl, 0))
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn by_hash_compares_every_occurrence() {
+        let start = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+        let e4 = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1";
+        let d4 = "rnbqkbnr/pppppppp/8/8/3P4/8/PPP1PPPP/RNBQKBNR b KQkq - 0 1";
+        let old = records(&[(start, 10, 1), (start, 20, 1), (e4, 0, 2)]);
+        let new = records(&[(start, 10, 1), (start, 30, 0), (d4, 0, 1)]);
+
+        let mut report = Report::default();
+        compare_by_hash(&mut report, old, new, [false; 2], 0).unwrap();
+        assert_eq!(report.eval_changed, 1);
+        assert_eq!(report.wdl_changes[1][0], 1);
+        assert_eq!(report.wdl_changes[1][1], 1);
+        assert_eq!(report.only_old, 1);
+        assert_eq!(report.only_new, 1);
+    }
+}
@@ -0,0 +1,107 @@
+use std::fs::File;
+use std::io::{Error, Result};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use arrow_array::{ArrayRef, Int16Array, RecordBatch, StringArray, UInt8Array};
+use arrow_schema::{DataType, Field, Schema};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use structopt::StructOpt;
+
+use crate::dataset::{Dataset, Subrange};
+use crate::formats;
+use crate::progress::Progress;
+
+/// Export a dataset as a Parquet file with the columns of `data-to-txt --format csv`, for
+/// pandas, polars and other dataframe tools.
+#[derive(StructOpt)]
+pub struct Options {
+    dataset: PathBuf,
+
+    #[structopt(short, long)]
+    output: PathBuf,
+
+    /// Number of positions in each row group.
+    #[structopt(long, default_value = "65536")]
+    row_group: usize,
+
+    #[structopt(flatten)]
+    range: Subrange,
+}
+
+/// The columns of a row group, filled a position at a time.
+#[derive(Default)]
+struct Columns {
+    fen: Vec<String>,
+    eval: Vec<i16>,
+    wdl: Vec<u8>,
+    extra: Vec<u8>,
+    piece_count: Vec<u8>,
+    phase: Vec<u8>,
+}
+
+impl Columns {
+    fn len(&self) -> usize {
+        self.fen.len()
+    }
+
+    fn into_batch(self, schema: &Arc<Schema>) -> Result<RecordBatch> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from(self.fen)),
+            Arc::new(Int16Array::from(self.eval)),
+            Arc::new(UInt8Array::from(self.wdl)),
+            Arc::new(UInt8Array::from(self.extra)),
+            Arc::new(UInt8Array::from(self.piece_count)),
+            Arc::new(UInt8Array::from(self.phase)),
+        ];
+        RecordBatch::try_new(schema.clone(), columns).map_err(Error::other)
+    }
+}
+
+pub fn run(options: Options) -> Result<()> {
+    let dataset = Dataset::open(&options.dataset)?.subrange(&options.range);
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("fen", DataType::Utf8, false),
+        Field::new("eval", DataType::Int16, false),
+        Field::new("wdl", DataType::UInt8, false),
+        Field::new("extra", DataType::UInt8, false),
+        Field::new("piece_count", DataType::UInt8, false),
+        Field::new("phase", DataType::UInt8, false),
+    ]));
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .set_max_row_group_size(options.row_group)
+        .build();
+    let file = File::create(&options.output)?;
+    let mut writer =
+        ArrowWriter::try_new(file, schema.clone(), Some(properties)).map_err(Error::other)?;
+
+    let progress = Progress::new("export-parquet", dataset.len());
+    let mut columns = Columns::default();
+    for packed in dataset.iter() {
+        if let Some((board, cp, wdl, extra)) = packed?.unpack() {
+            columns.fen.push(formats::fen(&board));
+            columns.eval.push(cp);
+            columns.wdl.push(wdl);
+            columns.extra.push(extra);
+            columns.piece_count.push(board.occupied().len() as u8);
+            columns.phase.push(formats::phase(&board) as u8);
+        }
+        if columns.len() == options.row_group {
+            let batch = std::mem::take(&mut columns).into_batch(&schema)?;
+            writer.write(&batch).map_err(Error::other)?;
+        }
+        progress.advance(1);
+    }
+    if columns.len() > 0 {
+        writer
+            .write(&columns.into_batch(&schema)?)
+            .map_err(Error::other)?;
+    }
+    writer.close().map_err(Error::other)?;
+    progress.finish();
+
+    Ok(())
+}
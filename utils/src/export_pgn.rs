@@ -0,0 +1,106 @@
+use std::fs::File;
+use std::io::{BufWriter, Result, Write};
+use std::path::PathBuf;
+
+use cozy_chess::{Board, Color};
+use marlinformat::{wdl_to_float, Eval, Header};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use structopt::StructOpt;
+
+use crate::dataset::{Dataset, Subrange};
+use crate::formats;
+
+/// Export a random sample of positions as PGN with [%eval] annotations, for review in
+/// board viewers such as lichess study import.
+#[derive(StructOpt)]
+pub struct Options {
+    dataset: PathBuf,
+
+    #[structopt(short, long)]
+    output: PathBuf,
+
+    /// Number of positions to sample.
+    #[structopt(long, default_value = "64")]
+    count: usize,
+
+    #[structopt(long, default_value = "0")]
+    seed: u64,
+
+    #[structopt(flatten)]
+    range: Subrange,
+}
+
+pub fn run(options: Options) -> Result<()> {
+    let dataset = Dataset::open(&options.dataset)?.subrange(&options.range);
+    let positions = dataset.len();
+    let flags = dataset.header().map_or(0, Header::flags);
+    let soft = flags & Header::FLAG_SOFT_WDL != 0;
+    let mates = flags & Header::FLAG_MATE_SCORES != 0;
+
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let count = options.count.min(positions as usize);
+    let mut indices = rand::seq::index::sample(&mut rng, positions as usize, count).into_vec();
+    indices.sort_unstable();
+
+    let mut output = BufWriter::new(File::create(options.output)?);
+    let site = options.dataset.display().to_string().replace('"', "'");
+    for index in indices {
+        let packed = dataset.read(index as u64)?;
+        let (board, cp, wdl, extra) = match packed.unpack() {
+            Some(unpacked) => unpacked,
+            None => continue,
+        };
+        let eval = Eval::decode(cp, mates);
+        let wdl = wdl_to_float(wdl, soft);
+        write_position(&mut output, index as u64, &site, &board, eval, wdl, extra)?;
+    }
+    output.flush()?;
+
+    Ok(())
+}
+
+/// Writes a position as a game without moves that starts from it, with its eval and `extra`
+/// byte in a comment. Mates are annotated as `#N` for white mating in N moves and `#-N` for
+/// black, as lichess does. `wdl` is white's expected score, which a soft result is rounded to
+/// the nearest game result for, and also written in the comment.
+pub fn write_position(
+    output: &mut impl Write,
+    index: u64,
+    site: &str,
+    board: &Board,
+    eval: Eval,
+    wdl: f32,
+    extra: u8,
+) -> Result<()> {
+    let result = match formats::wdl_from_float(wdl) {
+        0 => "0-1",
+        1 => "1/2-1/2",
+        _ => "1-0",
+    };
+    let eval = match eval {
+        Eval::Centipawns(cp) => format!("{:.2}", cp as f32 / 100.0),
+        Eval::Mate { winner, plies } => {
+            let sign = if winner == Color::White { "" } else { "-" };
+            format!("#{sign}{}", (plies + 1) / 2)
+        }
+    };
+    let score = match (wdl * 2.0).fract() == 0.0 {
+        true => String::new(),
+        false => format!(" score: {}", formats::format_result(wdl)),
+    };
+    writeln!(output, "[Event \"Position {index}\"]")?;
+    writeln!(output, "[Site \"{site}\"]")?;
+    writeln!(output, "[Result \"{result}\"]")?;
+    writeln!(output, "[SetUp \"1\"]")?;
+    if !formats::has_standard_castling(board) {
+        writeln!(output, "[Variant \"Chess960\"]")?;
+    }
+    writeln!(output, "[FEN \"{}\"]", formats::fen(board))?;
+    writeln!(output)?;
+    writeln!(
+        output,
+        "{{ [%eval {eval}] extra: {extra}{score} }} {result}"
+    )?;
+    writeln!(output)
+}
@@ -0,0 +1,426 @@
+use std::fs::File;
+use std::io::{BufWriter, Result, Seek, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use cozy_chess::{
+    get_bishop_moves, get_king_moves, get_knight_moves, get_pawn_attacks, get_rook_moves, BitBoard,
+    Board, Color, Piece, Square,
+};
+use cozy_syzygy::Tablebase;
+use marlinformat::Header;
+use structopt::StructOpt;
+
+use crate::affinity::Affinity;
+use crate::dataset::{self, Dataset, Subrange};
+use crate::formats;
+use crate::grep::parse_piece;
+use crate::progress::Progress;
+
+/// Write the positions of a dataset that pass the given filters to a new file.
+#[derive(StructOpt)]
+pub struct Options {
+    dataset: PathBuf,
+
+    #[structopt(short, long)]
+    output: PathBuf,
+
+    /// Drop positions where the side to move is in check, can promote, or has a capture
+    /// that wins material by static exchange evaluation. The positions kept are marked as
+    /// filtered quiet in the `extra` byte, unless the file holds sample weights there.
+    #[structopt(long)]
+    quiet: bool,
+
+    /// Keep positions whose material balance is within this many pawns of equality, counting
+    /// pawns as 1, minor pieces as 3, rooks as 5 and queens as 9.
+    #[structopt(long)]
+    max_imbalance: Option<i32>,
+
+    /// Keep positions whose material balance is at least this many pawns from equality.
+    #[structopt(long)]
+    min_imbalance: Option<i32>,
+
+    /// Keep positions whose game phase is at least this, from 0 for kings and pawns up to 24
+    /// for the starting material, counting minor pieces as 1, rooks as 2 and queens as 4.
+    #[structopt(long)]
+    min_phase: Option<u64>,
+
+    /// Keep positions whose game phase is at most this.
+    #[structopt(long)]
+    max_phase: Option<u64>,
+
+    /// A common cut for finetuning sets: `endgames` (phase at most 6), `middlegames` (phase 7
+    /// to 20), or `pawn-endings` (kings and pawns only, with at least one pawn).
+    #[structopt(long)]
+    preset: Option<Preset>,
+
+    /// Keep positions with this piece imbalance, ignoring pawns, held by either side, e.g.
+    /// `QvR` for a queen against a rook or `v` for equal pieces. May be repeated.
+    #[structopt(long = "imbalance")]
+    imbalances: Vec<Imbalance>,
+
+    /// Drop positions with this piece imbalance. May be repeated.
+    #[structopt(long = "exclude-imbalance")]
+    excluded_imbalances: Vec<Imbalance>,
+
+    /// Keep positions with the white king on one of these squares, e.g. `g1,h1`.
+    #[structopt(long)]
+    white_king: Option<Squares>,
+
+    /// Keep positions with the black king on one of these squares.
+    #[structopt(long)]
+    black_king: Option<Squares>,
+
+    /// Keep positions with the side to move's king on one of these squares, seen from its own
+    /// side of the board as king buckets are, so `g1` also matches a black king on g8.
+    #[structopt(long)]
+    stm_king: Option<Squares>,
+
+    /// Drop positions before this move number where either king is still on the d or e file.
+    #[structopt(long)]
+    drop_central_kings_before: Option<u16>,
+
+    /// Drop positions with at most this many pieces, kings included, for engines that leave
+    /// them to tablebases at runtime. Defaults to 7, the largest Syzygy tables.
+    #[structopt(long)]
+    drop_tb_positions: Option<Option<u32>>,
+
+    /// With `--drop-tb-positions`, only drop positions that the Syzygy tables in this
+    /// directory can be probed for, leaving those with castling rights or missing tables.
+    #[structopt(long, requires("drop-tb-positions"))]
+    syzygy: Option<PathBuf>,
+
+    #[structopt(skip)]
+    tablebase: Option<Tablebase>,
+
+    /// Number of workers, each filtering its own range of the file into a temporary file.
+    /// Defaults to the number of CPUs.
+    #[structopt(long)]
+    workers: Option<usize>,
+
+    /// Pin workers to CPUs: `cores` for one CPU each, or `numa` to spread them over the NUMA
+    /// nodes with each reading its range into its node's memory.
+    #[structopt(long, default_value = "none")]
+    affinity: Affinity,
+
+    #[structopt(flatten)]
+    range: Subrange,
+}
+
+impl Options {
+    fn keep(&self, board: &Board) -> bool {
+        let balance = material_balance(board).abs();
+        let phase = formats::phase(board);
+        (!self.quiet || is_quiet(board))
+            && self.min_phase.is_none_or(|min| phase >= min)
+            && self.max_phase.is_none_or(|max| phase <= max)
+            && self.preset.is_none_or(|preset| preset.matches(board))
+            && self.max_imbalance.is_none_or(|max| balance <= max)
+            && self.min_imbalance.is_none_or(|min| balance >= min)
+            && (self.imbalances.is_empty() || self.imbalances.iter().any(|i| i.matches(board)))
+            && !self.excluded_imbalances.iter().any(|i| i.matches(board))
+            && self.keep_kings(board)
+            && !self.in_tablebase(board)
+    }
+
+    fn in_tablebase(&self, board: &Board) -> bool {
+        let Some(max_pieces) = self.drop_tb_positions else {
+            return false;
+        };
+        board.occupied().len() <= max_pieces.unwrap_or(7)
+            && self
+                .tablebase
+                .as_ref()
+                .is_none_or(|tablebase| tablebase.probe_wdl(board).is_some())
+    }
+
+    fn keep_kings(&self, board: &Board) -> bool {
+        let on = |squares: &Option<Squares>, square| squares.as_ref().is_none_or(|s| s.has(square));
+        let stm = board.side_to_move();
+        let stm_king = match stm {
+            Color::White => board.king(stm),
+            Color::Black => board.king(stm).flip_rank(),
+        };
+        let central = (cozy_chess::File::D.bitboard() | cozy_chess::File::E.bitboard())
+            & board.pieces(Piece::King);
+        on(&self.white_king, board.king(Color::White))
+            && on(&self.black_king, board.king(Color::Black))
+            && on(&self.stm_king, stm_king)
+            && !self
+                .drop_central_kings_before
+                .is_some_and(|before| board.fullmove_number() < before && !central.is_empty())
+    }
+}
+
+#[derive(Default)]
+struct Part {
+    kept: u64,
+    dropped: u64,
+}
+
+pub fn run(mut options: Options) -> Result<()> {
+    if let Some(path) = &options.syzygy {
+        let mut tablebase = Tablebase::new();
+        tablebase.add_directory(path)?;
+        options.tablebase = Some(tablebase);
+    }
+    let output_dir = options
+        .output
+        .parent()
+        .expect("Could not get nominal parent directory of the output file");
+    let dataset = Dataset::open(&options.dataset)?
+        .subrange(&options.range)
+        .with_affinity(options.affinity);
+    let workers = options.workers.unwrap_or_else(dataset::default_workers);
+
+    let mark_quiet = options.quiet
+        && dataset
+            .header()
+            .is_none_or(|header| header.flags() & Header::FLAG_WEIGHTS == 0);
+
+    let progress = Progress::new("filter", dataset.len());
+    let parts = dataset.par_chunks(
+        workers,
+        &progress,
+        || {
+            let file = BufWriter::new(tempfile::tempfile_in(output_dir)?);
+            Ok((file, Part::default()))
+        },
+        |(file, part), chunk| {
+            for packed in chunk.iter() {
+                match packed.unpack() {
+                    Some((board, ..)) if options.keep(&board) => {
+                        let mut packed = *packed;
+                        if mark_quiet {
+                            let mut extra = packed.extra_flags();
+                            extra.set_filtered_quiet(true);
+                            packed.set_extra_flags(extra);
+                        }
+                        file.write_all(bytemuck::bytes_of(&packed))?;
+                        part.kept += 1;
+                    }
+                    _ => part.dropped += 1,
+                }
+            }
+            Ok(())
+        },
+    )?;
+    progress.finish();
+
+    let kept = parts.iter().map(|(_, part)| part.kept).sum();
+    let dropped: u64 = parts.iter().map(|(_, part)| part.dropped).sum();
+    let mut output = File::create(&options.output)?;
+    if let Some(header) = dataset.header() {
+        output.write_all(bytemuck::bytes_of(&Header::new(kept, header.flags())))?;
+    }
+    for (file, _) in parts {
+        let mut file = file.into_inner()?;
+        file.rewind()?;
+        std::io::copy(&mut file, &mut output)?;
+    }
+    println!("kept:    {kept:12}");
+    println!("dropped: {dropped:12}");
+
+    Ok(())
+}
+
+fn is_quiet(board: &Board) -> bool {
+    if !board.checkers().is_empty() {
+        return false;
+    }
+    let them = board.colors(!board.side_to_move());
+    // The move generator stops as soon as the listener returns true.
+    !board.generate_moves(|moves| {
+        moves.into_iter().any(|mv| {
+            let en_passant = moves.piece == Piece::Pawn && mv.from.file() != mv.to.file();
+            let capture = them.has(mv.to) || en_passant;
+            mv.promotion.is_some() || (capture && see(board, mv.from, mv.to) > 0)
+        })
+    })
+}
+
+/// White's material minus black's, in pawns.
+fn material_balance(board: &Board) -> i32 {
+    let values = [
+        (Piece::Pawn, 1),
+        (Piece::Knight, 3),
+        (Piece::Bishop, 3),
+        (Piece::Rook, 5),
+        (Piece::Queen, 9),
+    ];
+    values
+        .iter()
+        .map(|&(piece, value)| {
+            let white = board.colored_pieces(Color::White, piece).len() as i32;
+            let black = board.colored_pieces(Color::Black, piece).len() as i32;
+            (white - black) * value
+        })
+        .sum()
+}
+
+#[derive(Clone, Copy)]
+pub enum Preset {
+    Endgames,
+    Middlegames,
+    PawnEndings,
+}
+
+impl Preset {
+    fn matches(self, board: &Board) -> bool {
+        let phase = formats::phase(board);
+        match self {
+            Preset::Endgames => phase <= 6,
+            Preset::Middlegames => (7..=20).contains(&phase),
+            Preset::PawnEndings => phase == 0 && !board.pieces(Piece::Pawn).is_empty(),
+        }
+    }
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "endgames" => Ok(Preset::Endgames),
+            "middlegames" => Ok(Preset::Middlegames),
+            "pawn-endings" => Ok(Preset::PawnEndings),
+            _ => Err(format!(
+                "unknown preset {s:?}, expected `endgames`, `middlegames` or `pawn-endings`"
+            )),
+        }
+    }
+}
+
+/// A set of squares, written as a list such as `g1,h1`.
+pub struct Squares(BitBoard);
+
+impl Squares {
+    fn has(&self, square: Square) -> bool {
+        self.0.has(square)
+    }
+}
+
+impl FromStr for Squares {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut squares = BitBoard::EMPTY;
+        for square in s.split([',', '/']) {
+            let square: Square = square
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid square {square:?}"))?;
+            squares |= square.bitboard();
+        }
+        Ok(Squares(squares))
+    }
+}
+
+/// The pieces, other than pawns and kings, that one side has in excess of the other, as
+/// in `QvR`.
+pub struct Imbalance {
+    excess: [[u32; Piece::NUM]; Color::NUM],
+}
+
+impl Imbalance {
+    fn matches(&self, board: &Board) -> bool {
+        let mut excess = [[0; Piece::NUM]; Color::NUM];
+        for piece in [Piece::Knight, Piece::Bishop, Piece::Rook, Piece::Queen] {
+            let white = board.colored_pieces(Color::White, piece).len();
+            let black = board.colored_pieces(Color::Black, piece).len();
+            excess[Color::White as usize][piece as usize] = white.saturating_sub(black);
+            excess[Color::Black as usize][piece as usize] = black.saturating_sub(white);
+        }
+        let [white, black] = self.excess;
+        excess == [white, black] || excess == [black, white]
+    }
+}
+
+impl FromStr for Imbalance {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (first, second) = s
+            .split_once(['v', 'V'])
+            .ok_or_else(|| format!("imbalance {s:?} is missing a `v`"))?;
+        let mut excess = [[0; Piece::NUM]; Color::NUM];
+        for (side, pieces) in [first, second].into_iter().enumerate() {
+            for c in pieces.chars() {
+                match parse_piece(c) {
+                    Some((Piece::Pawn | Piece::King, _)) | None => {
+                        return Err(format!("invalid piece {c:?} in imbalance"))
+                    }
+                    Some((piece, _)) => excess[side][piece as usize] += 1,
+                }
+            }
+        }
+        for piece in Piece::ALL {
+            let common = excess[0][piece as usize].min(excess[1][piece as usize]);
+            excess[0][piece as usize] -= common;
+            excess[1][piece as usize] -= common;
+        }
+        Ok(Imbalance { excess })
+    }
+}
+
+fn piece_value(piece: Piece) -> i32 {
+    match piece {
+        Piece::Pawn => 100,
+        Piece::Knight => 300,
+        Piece::Bishop => 300,
+        Piece::Rook => 500,
+        Piece::Queen => 900,
+        Piece::King => 20000,
+    }
+}
+
+fn attackers(board: &Board, square: Square, occupied: BitBoard) -> BitBoard {
+    let rooks = board.pieces(Piece::Rook) | board.pieces(Piece::Queen);
+    let bishops = board.pieces(Piece::Bishop) | board.pieces(Piece::Queen);
+    let attackers = get_rook_moves(square, occupied) & rooks
+        | get_bishop_moves(square, occupied) & bishops
+        | get_knight_moves(square) & board.pieces(Piece::Knight)
+        | get_king_moves(square) & board.pieces(Piece::King)
+        | get_pawn_attacks(square, Color::White) & board.colored_pieces(Color::Black, Piece::Pawn)
+        | get_pawn_attacks(square, Color::Black) & board.colored_pieces(Color::White, Piece::Pawn);
+    attackers & occupied
+}
+
+/// Static exchange evaluation of the capture `from`x`to`, in centipawns for the side to move.
+fn see(board: &Board, from: Square, to: Square) -> i32 {
+    let mut gain = [0; 32];
+    let mut depth = 0;
+    let mut occupied = board.occupied();
+    let mut side = board.side_to_move();
+    let mut attacker = board.piece_on(from).unwrap();
+    gain[0] = board
+        .piece_on(to)
+        .map_or(piece_value(Piece::Pawn), piece_value);
+    occupied ^= from.bitboard();
+    loop {
+        depth += 1;
+        gain[depth] = piece_value(attacker) - gain[depth - 1];
+        if (-gain[depth - 1]).max(gain[depth]) < 0 || depth == gain.len() - 1 {
+            break;
+        }
+        side = !side;
+        let ours = attackers(board, to, occupied) & board.colors(side);
+        let next = Piece::ALL.iter().find_map(|&piece| {
+            (ours & board.pieces(piece))
+                .next_square()
+                .map(|square| (piece, square))
+        });
+        match next {
+            Some((piece, square)) => {
+                attacker = piece;
+                occupied ^= square.bitboard();
+            }
+            None => break,
+        }
+    }
+    while depth > 1 {
+        depth -= 1;
+        gain[depth - 1] = -(-gain[depth - 1]).max(gain[depth]);
+    }
+    gain[0]
+}
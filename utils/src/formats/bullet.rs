@@ -0,0 +1,90 @@
+//! The 32-byte `ChessBoard` record used by the bullet trainer.
+//!
+//! Positions are stored from the side to move's point of view: boards with black to move
+//! are flipped vertically with colours swapped, and the score and result are relative to
+//! the side to move. Castling rights, the en passant square, and move counters are not
+//! stored.
+
+use bytemuck::{Pod, Zeroable};
+use cozy_chess::{Board, BoardBuilder, Color, Piece, Square};
+
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+#[repr(C)]
+pub struct BulletBoard {
+    occupancy: u64,
+    pieces: [u8; 16],
+    score: i16,
+    result: u8,
+    king_square: u8,
+    opponent_king_square: u8,
+    extra: [u8; 3],
+}
+
+/// Returns the board as seen by the side to move, with the labels made relative to it.
+pub fn relative_to_stm(board: &Board, cp: i16, wdl: u8) -> Option<(Board, i16, u8)> {
+    let stm = board.side_to_move();
+    if stm == Color::White {
+        let mut builder = BoardBuilder::from_board(board);
+        builder.castle_rights = Default::default();
+        builder.en_passant = None;
+        builder.halfmove_clock = 0;
+        builder.fullmove_number = core::num::NonZeroU16::new(1).unwrap();
+        return Some((builder.build().ok()?, cp, wdl));
+    }
+
+    let mut builder = BoardBuilder::empty();
+    for sq in board.occupied() {
+        let piece = board.piece_on(sq)?;
+        let color = board.color_on(sq)?;
+        builder.board[sq.flip_rank() as usize] = Some((piece, !color));
+    }
+    builder.side_to_move = Color::White;
+    Some((
+        builder.build().ok()?,
+        cp.saturating_neg(),
+        2u8.saturating_sub(wdl),
+    ))
+}
+
+impl BulletBoard {
+    pub fn pack(board: &Board, cp: i16, wdl: u8) -> Option<Self> {
+        let (board, cp, wdl) = relative_to_stm(board, cp, wdl)?;
+
+        let occupancy = board.occupied();
+        let mut pieces = [0; 16];
+        for (i, sq) in occupancy.into_iter().enumerate() {
+            let piece = board.piece_on(sq)? as u8;
+            let color = board.color_on(sq)? as u8;
+            pieces[i / 2] |= (piece | color << 3) << ((i % 2) * 4);
+        }
+
+        Some(BulletBoard {
+            occupancy: occupancy.0.to_le(),
+            pieces,
+            score: cp.to_le(),
+            result: wdl,
+            king_square: board.king(Color::White) as u8,
+            opponent_king_square: board.king(Color::Black).flip_rank() as u8,
+            extra: [0; 3],
+        })
+    }
+
+    /// Unpacks the position, which always has white (the side to move) to move.
+    pub fn unpack(&self) -> Option<(Board, i16, u8)> {
+        let mut builder = BoardBuilder::empty();
+        let occupancy = cozy_chess::BitBoard(u64::from_le(self.occupancy));
+        for (i, sq) in occupancy.into_iter().enumerate() {
+            let code = (self.pieces[i / 2] >> ((i % 2) * 4)) & 0xF;
+            let piece = Piece::try_index(code as usize & 0b0111)?;
+            let color = Color::try_index(code as usize >> 3)?;
+            builder.board[sq as usize] = Some((piece, color));
+        }
+        builder.side_to_move = Color::White;
+        let board = builder.build().ok()?;
+
+        if board.king(Color::White) != Square::try_index(self.king_square as usize)? {
+            return None;
+        }
+        Some((board, i16::from_le(self.score), self.result))
+    }
+}
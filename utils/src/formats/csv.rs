@@ -0,0 +1,33 @@
+//! Comma-separated values with a header row, for loading data into pandas, polars and the
+//! like. The result and extra byte are written as in marlinformat and the eval as in the
+//! legacy text format, followed by the piece count and game phase of the position.
+
+use cozy_chess::Board;
+
+use super::Eval;
+
+pub const HEADER: &str = "fen,eval,wdl,extra,piece_count,phase";
+
+pub fn format_line(board: &Board, eval: Eval, wdl: u8, extra: u8) -> String {
+    let fen = super::fen(board);
+    let eval = super::format_eval(eval);
+    let pieces = board.occupied().len();
+    let phase = super::phase(board);
+    format!("{fen},{eval},{wdl},{extra},{pieces},{phase}")
+}
+
+pub struct Csv;
+
+impl super::Format for Csv {
+    fn format_line(&self, board: &Board, eval: Eval, wdl: f32, extra: u8) -> String {
+        format_line(board, eval, super::wdl_from_float(wdl), extra)
+    }
+
+    fn header(&self) -> Option<&str> {
+        Some(HEADER)
+    }
+
+    fn separator(&self) -> &str {
+        ","
+    }
+}
@@ -0,0 +1,39 @@
+//! The CudAD `<fen> [<wdl>] <eval>` text format, with evals in centipawns or as mate scores
+//! and results as 1.0, 0.5, or 0.0, or as white's expected score for soft results, all from
+//! white's point of view.
+
+use cozy_chess::Board;
+
+use super::{Eval, TextEval};
+
+pub fn parse_line(line: &str, frc: bool) -> Option<(Board, TextEval, f32)> {
+    let (board, annotation) = line.split_once(" [")?;
+    let (wdl, eval) = annotation.split_once(']')?;
+
+    let board = super::parse_fen(board.trim(), frc)?;
+    let wdl = super::parse_result(wdl)?;
+    let eval = TextEval::parse(eval.trim())?;
+
+    Some((board, eval, wdl))
+}
+
+pub fn format_line(board: &Board, eval: Eval, wdl: f32) -> String {
+    format!(
+        "{} [{}] {}",
+        super::fen(board),
+        super::format_result(wdl),
+        super::format_eval(eval)
+    )
+}
+
+pub struct Cudad;
+
+impl super::Format for Cudad {
+    fn format_line(&self, board: &Board, eval: Eval, wdl: f32, _extra: u8) -> String {
+        format_line(board, eval, wdl)
+    }
+
+    fn soft_results(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,72 @@
+//! EPD with the `ce` opcode for the eval, in centipawns from the side to move's point of
+//! view as the standard defines it or as a mate score from the same point of view, and the
+//! `c9` opcode for the result, as `1-0`, `1/2-1/2` or `0-1`. The move counters are kept in
+//! the `hmvc` and `fmvn` opcodes.
+
+use cozy_chess::{Board, Color};
+
+use super::{Eval, TextEval};
+
+pub fn parse_line(line: &str, frc: bool) -> Option<(Board, TextEval, f32)> {
+    let fields: Vec<_> = line.splitn(5, ' ').collect();
+    let [placement, stm, castling, en_passant, opcodes] = fields[..] else {
+        return None;
+    };
+    let (mut eval, mut wdl, mut halfmove, mut fullmove) = (None, None, "0", "1");
+    for opcode in opcodes
+        .split(';')
+        .map(str::trim)
+        .filter(|op| !op.is_empty())
+    {
+        let (name, operand) = opcode.split_once(' ')?;
+        let operand = operand.trim().trim_matches('"');
+        match name {
+            "ce" => eval = Some(TextEval::parse(operand)?),
+            "c9" => wdl = Some(super::parse_result(operand)?),
+            "hmvc" => halfmove = operand,
+            "fmvn" => fullmove = operand,
+            _ => {}
+        }
+    }
+    let fen = format!("{placement} {stm} {castling} {en_passant} {halfmove} {fullmove}");
+    let board = super::parse_fen(&fen, frc)?;
+    let eval = match board.side_to_move() {
+        Color::White => eval?,
+        Color::Black => eval?.flip(),
+    };
+
+    Some((board, eval, wdl?))
+}
+
+pub fn format_line(board: &Board, eval: Eval, wdl: u8) -> String {
+    let fen = super::fen(board);
+    let fields: Vec<_> = fen.split(' ').collect();
+    let eval = match (board.side_to_move(), eval) {
+        (Color::White, eval) => eval,
+        (Color::Black, Eval::Centipawns(cp)) => Eval::Centipawns(cp.saturating_neg()),
+        (Color::Black, Eval::Mate { winner, plies }) => Eval::Mate {
+            winner: !winner,
+            plies,
+        },
+    };
+    let eval = super::format_eval(eval);
+    let result = match wdl {
+        0 => "0-1",
+        1 => "1/2-1/2",
+        _ => "1-0",
+    };
+    format!(
+        "{} ce {eval}; c9 \"{result}\"; hmvc {}; fmvn {};",
+        fields[..4].join(" "),
+        fields[4],
+        fields[5]
+    )
+}
+
+pub struct Epd;
+
+impl super::Format for Epd {
+    fn format_line(&self, board: &Board, eval: Eval, wdl: f32, _extra: u8) -> String {
+        format_line(board, eval, super::wdl_from_float(wdl))
+    }
+}
@@ -0,0 +1,17 @@
+//! Bare FEN strings without labels, for feeding positions to other tools.
+
+use cozy_chess::Board;
+
+use super::Eval;
+
+pub struct Fens;
+
+impl super::Format for Fens {
+    fn format_line(&self, board: &Board, _eval: Eval, _wdl: f32, _extra: u8) -> String {
+        super::fen(board)
+    }
+
+    fn soft_results(&self) -> bool {
+        true
+    }
+}
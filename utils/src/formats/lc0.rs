@@ -0,0 +1,99 @@
+//! Version 6 training records written by Leela Chess Zero, as found in its training chunks.
+//!
+//! Each record is 8356 little-endian bytes holding the policy, the 104 input planes of the
+//! last 8 positions, and the search statistics. Positions are stored from the side to move's
+//! point of view: boards with black to move are flipped vertically, and the first six planes
+//! are always the pieces of the side to move. Q values are relative to the side to move and
+//! range from -1 to 1.
+
+use cozy_chess::{BitBoard, Board, BoardBuilder, CastleRights, Color, File, Piece};
+
+pub const RECORD_SIZE: usize = 8356;
+
+pub const VERSION: u32 = 6;
+
+const PLANES: usize = 8 + 1858 * 4;
+const CASTLING: usize = PLANES + 104 * 8;
+const SIDE_TO_MOVE: usize = CASTLING + 4;
+const RULE50: usize = SIDE_TO_MOVE + 1;
+const INVARIANCE: usize = SIDE_TO_MOVE + 2;
+const BEST_Q: usize = SIDE_TO_MOVE + 8;
+const RESULT_Q: usize = SIDE_TO_MOVE + 32;
+
+/// The input format of the classic 112 planes, which stores the side to move in its own byte.
+/// Later formats store it in the top bit of the invariance byte.
+const INPUT_CLASSICAL: u32 = 1;
+
+fn u32_at(record: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(record[offset..offset + 4].try_into().unwrap())
+}
+
+fn f32_at(record: &[u8], offset: usize) -> f32 {
+    f32::from_le_bytes(record[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn version(record: &[u8]) -> u32 {
+    u32_at(record, 0)
+}
+
+/// Converts a Q value to centipawns with the formula Leela used for its centipawn scores.
+pub fn q_to_centipawns(q: f32) -> f64 {
+    90.0 * (1.5637541897 * q as f64).tan()
+}
+
+/// Unpacks the current position of a record, along with the white-relative best Q and the
+/// white-relative game result from 0 to 1. The en passant square is not recovered, and boards
+/// that Leela transformed into a canonical orientation are returned as they are stored, which
+/// is an equivalent position.
+pub fn unpack(record: &[u8]) -> Option<(Board, f32, f32)> {
+    let input_format = u32_at(record, 4);
+    let stm = match input_format {
+        INPUT_CLASSICAL => record[SIDE_TO_MOVE] != 0,
+        _ => record[INVARIANCE] & 0x80 != 0,
+    };
+    let stm = match stm {
+        true => Color::Black,
+        false => Color::White,
+    };
+
+    let mut builder = BoardBuilder::empty();
+    for (plane, bytes) in record[PLANES..].chunks_exact(8).take(12).enumerate() {
+        // Leela reverses the bits of each byte when it writes a plane.
+        let bytes: [u8; 8] = bytes.try_into().unwrap();
+        let mask = BitBoard(u64::from_le_bytes(bytes.map(u8::reverse_bits)));
+        let piece = Piece::index(plane % 6);
+        let color = match plane < 6 {
+            true => stm,
+            false => !stm,
+        };
+        for square in mask {
+            let square = match stm {
+                Color::White => square,
+                Color::Black => square.flip_rank(),
+            };
+            builder.board[square as usize] = Some((piece, color));
+        }
+    }
+    let rights = |long: u8, short: u8| CastleRights {
+        short: (short != 0).then_some(File::H),
+        long: (long != 0).then_some(File::A),
+    };
+    let ours = rights(record[CASTLING], record[CASTLING + 1]);
+    let theirs = rights(record[CASTLING + 2], record[CASTLING + 3]);
+    builder.castle_rights[stm as usize] = ours;
+    builder.castle_rights[!stm as usize] = theirs;
+    builder.side_to_move = stm;
+    builder.halfmove_clock = record[RULE50];
+    let board = builder.build().ok()?;
+
+    let best_q = f32_at(record, BEST_Q);
+    let result_q = f32_at(record, RESULT_Q);
+    if !best_q.is_finite() || !result_q.is_finite() {
+        return None;
+    }
+    let (best_q, result_q) = match stm {
+        Color::White => (best_q, result_q),
+        Color::Black => (-best_q, -result_q),
+    };
+    Some((board, best_q, (result_q + 1.0) / 2.0))
+}
@@ -0,0 +1,26 @@
+//! The legacy `<fen> | <eval> | <wdl>` text format, with evals in centipawns or as mate
+//! scores and results as 1.0, 0.5, or 0.0, all from white's point of view. This is the
+//! canonical text form of [`marlinformat::text`], which implements it.
+
+use cozy_chess::Board;
+
+use super::{Eval, TextEval};
+
+pub use marlinformat::text::{format_line, parse_line as parse_line_with, SEPARATOR};
+
+/// Parses a line as written by [`format_line`].
+pub fn parse_line(line: &str) -> Option<(Board, TextEval, f32)> {
+    parse_line_with(line, SEPARATOR, true)
+}
+
+pub struct Legacy;
+
+impl super::Format for Legacy {
+    fn format_line(&self, board: &Board, eval: Eval, wdl: f32, _extra: u8) -> String {
+        format_line(board, eval, wdl)
+    }
+
+    fn soft_results(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,254 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use cozy_chess::{Board, Piece};
+
+pub mod bullet;
+pub mod csv;
+pub mod cudad;
+pub mod epd;
+pub mod fens;
+pub mod lc0;
+pub mod legacy;
+pub mod viri;
+pub mod zurichess;
+
+pub use marlinformat::text::{
+    fen, format_eval, format_result, has_standard_castling, parse_fen, parse_result,
+    wdl_from_float, TextEval,
+};
+pub use marlinformat::Eval;
+
+/// A text format that positions can be written in.
+pub trait Format: Sync {
+    /// Formats a position, given its decoded eval and white's expected score as
+    /// `wdl_to_float` reads it. Formats write mate scores as [`format_eval`] does.
+    fn format_line(&self, board: &Board, eval: Eval, wdl: f32, extra: u8) -> String;
+
+    /// Whether the format can hold soft results. Formats that can't hold results other than a
+    /// loss, draw or win get them rounded, so files with soft results aren't written in them.
+    fn soft_results(&self) -> bool {
+        false
+    }
+
+    /// A line naming the columns, written once at the start of the output.
+    fn header(&self) -> Option<&str> {
+        None
+    }
+
+    /// The separator before any column appended to a line, such as the move of a version 2
+    /// record.
+    fn separator(&self) -> &str {
+        legacy::SEPARATOR
+    }
+}
+
+/// The game phase of a position, from 0 for bare kings and pawns up to 24 for the starting
+/// material, counting minor pieces as 1, rooks as 2 and queens as 4.
+pub fn phase(board: &Board) -> u64 {
+    let phase = |piece, weight| board.pieces(piece).len() as u64 * weight;
+    (phase(Piece::Knight, 1)
+        + phase(Piece::Bishop, 1)
+        + phase(Piece::Rook, 2)
+        + phase(Piece::Queen, 4))
+    .min(24)
+}
+
+/// Parses a line into a board, a white-relative eval, a white-relative result and the extra
+/// byte. Takes the column separator of formats that let it be chosen, and whether to accept
+/// Shredder-FENs as described in [`parse_fen`]. Evals are read with [`TextEval::parse`].
+pub type ParseLine =
+    fn(line: &str, separator: &str, frc: bool) -> Option<(Board, TextEval, f32, u8)>;
+
+/// A text format known by name to the subcommands that read or write text.
+#[derive(Clone, Copy)]
+pub struct Registration {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Makes a writer of the format, if positions can be written in it.
+    pub writer: Option<fn() -> Box<dyn Format>>,
+    /// Parses a line of the format, if positions can be read from it.
+    pub parser: Option<ParseLine>,
+}
+
+static REGISTRY: RwLock<Vec<Registration>> = RwLock::new(Vec::new());
+
+/// Registers a text format. Formats must be registered before the arguments are parsed, as
+/// `main` does with the built-in ones, for their names to be accepted. A format registered
+/// under the name of an earlier one replaces it.
+pub fn register(registration: Registration) {
+    let mut registry = REGISTRY.write().unwrap();
+    registry.retain(|existing| existing.name != registration.name);
+    registry.push(registration);
+}
+
+/// Adds the `extra` byte, which most formats do not carry.
+fn with_extra((board, eval, wdl): (Board, TextEval, f32)) -> (Board, TextEval, f32, u8) {
+    (board, eval, wdl, 0)
+}
+
+pub fn register_builtins() {
+    register(Registration {
+        name: "legacy",
+        description: "<fen> | <eval> | <result>, with the result as 1.0, 0.5 or 0.0",
+        writer: Some(|| Box::new(legacy::Legacy)),
+        parser: Some(|line, separator, frc| {
+            legacy::parse_line_with(line, separator, frc).map(with_extra)
+        }),
+    });
+    register(Registration {
+        name: "cudad",
+        description: "<fen> [<result>] <eval>",
+        writer: Some(|| Box::new(cudad::Cudad)),
+        parser: Some(|line, _, frc| cudad::parse_line(line, frc).map(with_extra)),
+    });
+    register(Registration {
+        name: "zurichess",
+        description: "<fen>;<eval>;<result>, read only",
+        writer: None,
+        parser: Some(|line, _, frc| zurichess::parse_line(line, frc).map(with_extra)),
+    });
+    register(Registration {
+        name: "viri",
+        description: "<fen> | <eval> | <wdl> [| <extra>], with the WDL as 2, 1 or 0",
+        writer: Some(|| Box::new(viri::Viri)),
+        parser: Some(viri::parse_line),
+    });
+    register(Registration {
+        name: "epd",
+        description: "EPD with ce, c9, hmvc and fmvn opcodes",
+        writer: Some(|| Box::new(epd::Epd)),
+        parser: Some(|line, _, frc| epd::parse_line(line, frc).map(with_extra)),
+    });
+    register(Registration {
+        name: "fens",
+        description: "bare FENs without labels, write only",
+        writer: Some(|| Box::new(fens::Fens)),
+        parser: None,
+    });
+    register(Registration {
+        name: "csv",
+        description: "comma-separated columns under a header row, write only",
+        writer: Some(|| Box::new(csv::Csv)),
+        parser: None,
+    });
+}
+
+/// The registered text formats, in the order they were registered.
+pub fn registered() -> Vec<Registration> {
+    REGISTRY.read().unwrap().clone()
+}
+
+pub fn lookup(name: &str) -> Option<Registration> {
+    registered()
+        .into_iter()
+        .find(|registration| registration.name == name)
+}
+
+/// The names of the registered formats that can be read or written, for error messages.
+fn names(filter: impl Fn(&Registration) -> bool) -> String {
+    let names: Vec<_> = registered()
+        .into_iter()
+        .filter(filter)
+        .map(|registration| format!("`{}`", registration.name))
+        .collect();
+    names.join(", ")
+}
+
+/// Prints the registered text formats, for `--list-formats`.
+pub fn print_list() {
+    for registration in registered() {
+        let modes = match (registration.parser, registration.writer) {
+            (Some(_), Some(_)) => "read, write",
+            (Some(_), None) => "read",
+            (None, _) => "write",
+        };
+        println!(
+            "{:12} {:12} {}",
+            registration.name, modes, registration.description
+        );
+    }
+}
+
+impl FromStr for Box<dyn Format> {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match lookup(s).and_then(|registration| registration.writer) {
+            Some(writer) => Ok(writer()),
+            None => Err(format!(
+                "unknown format {s:?}, expected one of {}",
+                names(|registration| registration.writer.is_some())
+            )),
+        }
+    }
+}
+
+/// A text format that positions can be read from.
+#[derive(Clone, Copy)]
+pub struct TextFormat {
+    name: &'static str,
+    parser: ParseLine,
+}
+
+impl TextFormat {
+    /// Parses a line into a board, a white-relative eval, a white-relative result and the
+    /// extra byte. `separator` is the column separator used by the legacy and viri formats,
+    /// and `frc` accepts Shredder-FENs as described in [`parse_fen`].
+    pub fn parse_line(
+        self,
+        line: &str,
+        separator: &str,
+        frc: bool,
+    ) -> Option<(Board, TextEval, f32, u8)> {
+        (self.parser)(line, separator, frc)
+    }
+
+    /// Returns the only readable format that parses every given line.
+    pub fn detect(lines: &[&str], separator: &str, frc: bool) -> Result<TextFormat, String> {
+        let candidates: Vec<_> = registered()
+            .into_iter()
+            .filter_map(|registration| {
+                Some(TextFormat {
+                    name: registration.name,
+                    parser: registration.parser?,
+                })
+            })
+            .filter(|format| {
+                lines
+                    .iter()
+                    .all(|line| format.parse_line(line, separator, frc).is_some())
+            })
+            .collect();
+        match candidates[..] {
+            [format] => Ok(format),
+            [] => Err("no known format matches the input".to_string()),
+            _ => Err(format!("the input is ambiguous between {candidates:?}")),
+        }
+    }
+}
+
+impl fmt::Debug for TextFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+impl FromStr for TextFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let registration = lookup(s).filter(|registration| registration.parser.is_some());
+        match registration {
+            Some(registration) => Ok(TextFormat {
+                name: registration.name,
+                parser: registration.parser.unwrap(),
+            }),
+            None => Err(format!(
+                "unknown format {s:?}, expected one of {}",
+                names(|registration| registration.parser.is_some())
+            )),
+        }
+    }
+}
@@ -0,0 +1,41 @@
+//! The `<fen> | <eval> | <wdl> [| <extra>]` text format, with evals in centipawns or as
+//! mate scores and results as the integers 2, 1, or 0, all from white's point of view. The
+//! extra byte is only written when it is non-zero, which keeps the format lossless for
+//! marlinformat.
+
+use cozy_chess::Board;
+
+use super::{Eval, TextEval};
+
+pub fn parse_line(line: &str, separator: &str, frc: bool) -> Option<(Board, TextEval, f32, u8)> {
+    let mut columns = line.split(separator).map(str::trim);
+    let board = super::parse_fen(columns.next()?, frc)?;
+    let eval = TextEval::parse(columns.next()?)?;
+    let wdl: u8 = columns.next()?.parse().ok().filter(|&wdl| wdl <= 2)?;
+    let extra: u8 = match columns.next() {
+        Some(extra) => extra.parse().ok()?,
+        None => 0,
+    };
+    if columns.next().is_some() {
+        return None;
+    }
+
+    Some((board, eval, wdl as f32 / 2.0, extra))
+}
+
+pub fn format_line(board: &Board, eval: Eval, wdl: u8, extra: u8) -> String {
+    let fen = super::fen(board);
+    let eval = super::format_eval(eval);
+    match extra {
+        0 => format!("{fen} | {eval} | {wdl}"),
+        _ => format!("{fen} | {eval} | {wdl} | {extra}"),
+    }
+}
+
+pub struct Viri;
+
+impl super::Format for Viri {
+    fn format_line(&self, board: &Board, eval: Eval, wdl: f32, extra: u8) -> String {
+        format_line(board, eval, super::wdl_from_float(wdl), extra)
+    }
+}
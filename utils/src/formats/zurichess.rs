@@ -0,0 +1,11 @@
+//! The Zurichess-style `<fen>;<eval>;<result>` CSV format, with evals in centipawns and
+//! results written either as `1-0`, `1/2-1/2`, `0-1` or as 1.0, 0.5, 0.0, all from
+//! white's point of view.
+
+use cozy_chess::Board;
+
+use super::TextEval;
+
+pub fn parse_line(line: &str, frc: bool) -> Option<(Board, TextEval, f32)> {
+    super::legacy::parse_line_with(line.trim_end_matches(';'), ";", frc)
+}
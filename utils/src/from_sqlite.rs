@@ -0,0 +1,100 @@
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+use std::path::PathBuf;
+
+use marlinformat::{Header, PackedBoard};
+use rusqlite::{params_from_iter, Connection, OpenFlags};
+use structopt::StructOpt;
+
+use crate::dataset;
+use crate::formats;
+use crate::to_sqlite::{self, check_table};
+
+/// Write the positions of an SQLite table made by `to-sqlite` back into a data file, or count
+/// them, optionally only those matching an SQL condition or a position.
+#[derive(StructOpt)]
+pub struct Options {
+    database: PathBuf,
+
+    /// Write the matching positions here. Without it, they are only counted.
+    #[structopt(short, long)]
+    output: Option<PathBuf>,
+
+    #[structopt(long, default_value = "positions")]
+    table: String,
+
+    /// An SQL condition on the columns `hash`, `fen`, `eval`, `wdl`, `extra` and `material`,
+    /// e.g. `"material = 'KRPvKR' AND abs(eval) < 200"`.
+    #[structopt(long = "where")]
+    condition: Option<String>,
+
+    /// Only positions that are the same as this FEN, through the hash index, regardless of
+    /// move counters.
+    #[structopt(long)]
+    fen: Option<String>,
+}
+
+pub fn run(options: Options) -> Result<()> {
+    let table = &options.table;
+    check_table(table)?;
+    let db = Connection::open_with_flags(&options.database, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(Error::other)?;
+    // Databases written before the flags were stored hold plain positions.
+    let flags = to_sqlite::read_flags(&db, table)?.unwrap_or(0);
+
+    let mut conditions = vec![];
+    let mut hashes = vec![];
+    if let Some(condition) = &options.condition {
+        conditions.push(format!("({condition})"));
+    }
+    if let Some(fen) = &options.fen {
+        let board = formats::parse_fen(fen, true)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("invalid FEN {fen:?}")))?;
+        conditions.push("hash = ?1".to_string());
+        hashes.push(board.hash() as i64);
+    }
+    let mut query = format!("SELECT fen, eval, wdl, extra FROM {table}");
+    if !conditions.is_empty() {
+        query += &format!(" WHERE {}", conditions.join(" AND "));
+    }
+    let mut select = db.prepare(&query).map_err(Error::other)?;
+    let mut rows = select
+        .query(params_from_iter(hashes))
+        .map_err(Error::other)?;
+
+    let mut output = match &options.output {
+        Some(path) => Some(BufWriter::new(File::create(path)?)),
+        None => None,
+    };
+    if let Some(output) = &mut output {
+        output.write_all(bytemuck::bytes_of(&Header::new(0, flags)))?;
+    }
+    let mut matches = 0;
+    while let Some(row) = rows.next().map_err(Error::other)? {
+        let fen: String = row.get(0).map_err(Error::other)?;
+        let eval: i16 = row.get(1).map_err(Error::other)?;
+        let wdl: u8 = row.get(2).map_err(Error::other)?;
+        let extra: u8 = row.get(3).map_err(Error::other)?;
+        let board = formats::parse_fen(&fen, true).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid FEN {fen:?} in {table}"),
+            )
+        })?;
+        matches += 1;
+        if let Some(output) = &mut output {
+            let packed = PackedBoard::pack(&board, eval, wdl, extra);
+            output.write_all(bytemuck::bytes_of(&packed))?;
+        }
+    }
+
+    if let Some(mut output) = output {
+        output.flush()?;
+        drop(output);
+        let path = options.output.as_ref().unwrap();
+        dataset::set_header(path, &Header::new(matches, flags))?;
+    }
+    eprintln!("{matches} matching positions.");
+
+    Ok(())
+}
@@ -0,0 +1,285 @@
+//! Game-level operations on datasets whose positions are stored game by game.
+//!
+//! The games of a dataset are recorded in an index file next to it, named after the dataset
+//! with `.games` appended, which holds the little-endian `u64` index of the first record of
+//! each game in order. Data generators can write it alongside their output, and `--infer`
+//! reconstructs it for datasets written without one.
+
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use marlinformat::{Header, PackedBoard};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use structopt::StructOpt;
+
+use crate::dataset::{self, Dataset};
+use crate::progress::Progress;
+
+/// Upper bounds of the game length histogram buckets, in positions.
+pub const LENGTH_BUCKETS: [u64; 6] = [16, 32, 64, 128, 256, u64::MAX];
+
+/// The bucket of `LENGTH_BUCKETS` that a game of `length` positions falls into.
+pub fn length_bucket(length: u64) -> usize {
+    LENGTH_BUCKETS
+        .iter()
+        .position(|&max| length <= max)
+        .unwrap()
+}
+
+/// Prints a histogram of game lengths, counted by `length_bucket`.
+pub fn print_lengths(lengths: &[u64; LENGTH_BUCKETS.len()]) {
+    let mut lower = 1;
+    for (&max, &count) in LENGTH_BUCKETS.iter().zip(lengths) {
+        match max {
+            u64::MAX => println!("  {lower:>4}+     positions: {count:12}"),
+            _ => println!("  {lower:>4}-{max:<4} positions: {count:12}"),
+        }
+        lower = max + 1;
+    }
+}
+
+/// Print game statistics of a dataset, and split it or drop games by game.
+#[derive(StructOpt)]
+pub struct Options {
+    dataset: PathBuf,
+
+    /// Infer where games start and write the index, for datasets written without one. A new
+    /// game is assumed wherever the fullmove number goes down or pieces are added to the board.
+    #[structopt(long)]
+    infer: bool,
+
+    /// Write the games that are kept here, along with their index.
+    #[structopt(short, long)]
+    output: Option<PathBuf>,
+
+    /// Write a random fraction of the games here instead of to `--output`, along with their
+    /// index, for a validation set that shares no games with the training set.
+    #[structopt(long, requires("output"))]
+    val: Option<PathBuf>,
+
+    /// Fraction of games that go to `--val`.
+    #[structopt(long, default_value = "0.05")]
+    val_fraction: f64,
+
+    /// Drop games with fewer positions than this.
+    #[structopt(long, default_value = "0")]
+    min_positions: u64,
+
+    #[structopt(long, default_value = "0")]
+    seed: u64,
+}
+
+pub fn run(options: Options) -> Result<()> {
+    if !(0.0..=1.0).contains(&options.val_fraction) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--val-fraction must be between 0 and 1",
+        ));
+    }
+
+    let dataset = Dataset::open(&options.dataset)?;
+    let starts = match options.infer {
+        true => {
+            let starts = infer(&dataset)?;
+            write_index(&options.dataset, &starts)?;
+            starts
+        }
+        false => read_index(&options.dataset, dataset.len())?,
+    };
+    let games = ranges(&starts, dataset.len());
+
+    let flags = dataset.header().map(Header::flags);
+    let soft = flags.is_some_and(|flags| flags & Header::FLAG_SOFT_WDL != 0);
+    let mut output = options
+        .output
+        .as_deref()
+        .map(|path| GameWriter::create(path, flags))
+        .transpose()?;
+    let mut val = options
+        .val
+        .as_deref()
+        .map(|path| GameWriter::create(path, flags))
+        .transpose()?;
+    let mut rng = StdRng::seed_from_u64(options.seed);
+
+    let progress = Progress::new("games", dataset.len());
+    let mut lengths = [0u64; LENGTH_BUCKETS.len()];
+    let mut results = [0u64; 3];
+    let mut dropped = 0;
+    let mut records = dataset.iter();
+    let mut game = Vec::new();
+    for range in &games {
+        game.clear();
+        for _ in range.clone() {
+            game.push(records.next().unwrap()?);
+        }
+        progress.advance(game.len() as u64);
+
+        let length = game.len() as u64;
+        lengths[length_bucket(length)] += 1;
+        if let Some((.., wdl, _)) = game[0].unpack() {
+            results[dataset::hard_wdl(wdl, soft) as usize] += 1;
+        }
+
+        if length < options.min_positions {
+            dropped += 1;
+            continue;
+        }
+        let to_val = val.is_some() && rng.gen_bool(options.val_fraction);
+        let writer = match to_val {
+            true => val.as_mut(),
+            false => output.as_mut(),
+        };
+        if let Some(writer) = writer {
+            writer.write_game(&game)?;
+        }
+    }
+    progress.finish();
+
+    println!("games:     {:12}", games.len());
+    println!(
+        "positions: {:12} ({:.1} per game)",
+        dataset.len(),
+        dataset.len() as f64 / games.len().max(1) as f64
+    );
+    println!(
+        "results:   {:12} white wins, {} draws, {} black wins",
+        results[2], results[1], results[0]
+    );
+    print_lengths(&lengths);
+    if options.min_positions > 0 {
+        println!(
+            "dropped {dropped} games shorter than {}.",
+            options.min_positions
+        );
+    }
+
+    for writer in output.into_iter().chain(val) {
+        writer.finish()?;
+    }
+
+    Ok(())
+}
+
+/// The index file of a dataset.
+pub fn index_path(dataset: &Path) -> PathBuf {
+    let mut path = dataset.as_os_str().to_owned();
+    path.push(".games");
+    path.into()
+}
+
+/// Reads the index of a dataset of `records` records.
+pub fn read_index(dataset: &Path, records: u64) -> Result<Vec<u64>> {
+    let path = index_path(dataset);
+    let bytes = std::fs::read(&path).map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!(
+                "could not read {}: {e}, pass --infer to create it",
+                path.display()
+            ),
+        )
+    })?;
+    let starts: Vec<_> = bytes
+        .chunks_exact(8)
+        .map(|start| u64::from_le_bytes(start.try_into().unwrap()))
+        .collect();
+    let valid = starts.first().is_none_or(|&first| first == 0)
+        && starts.windows(2).all(|pair| pair[0] < pair[1])
+        && starts.last().is_none_or(|&last| last < records);
+    if bytes.len() % 8 != 0 || !valid {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} is not a valid index of the dataset", path.display()),
+        ));
+    }
+    Ok(starts)
+}
+
+pub fn write_index(dataset: &Path, starts: &[u64]) -> Result<()> {
+    let mut index = BufWriter::new(File::create(index_path(dataset))?);
+    for start in starts {
+        index.write_all(&start.to_le_bytes())?;
+    }
+    index.flush()
+}
+
+/// The records of each game, given the records each game starts at.
+pub fn ranges(starts: &[u64], records: u64) -> Vec<Range<u64>> {
+    starts
+        .iter()
+        .zip(starts.iter().skip(1).chain([&records]))
+        .map(|(&start, &end)| start..end)
+        .collect()
+}
+
+fn infer(dataset: &Dataset) -> Result<Vec<u64>> {
+    let progress = Progress::new("infer games", dataset.len());
+    let mut starts = vec![];
+    let mut previous = None;
+    for (index, packed) in dataset.iter().enumerate() {
+        let current = packed?
+            .unpack()
+            .map(|(board, ..)| (board.fullmove_number(), board.occupied().len()));
+        let new_game = match (previous, current) {
+            (_, None) => false,
+            (None, Some(_)) => true,
+            (Some((moves, pieces)), Some((next_moves, next_pieces))) => {
+                next_moves < moves || next_pieces > pieces
+            }
+        };
+        if new_game || index == 0 {
+            starts.push(index as u64);
+        }
+        previous = current.or(previous);
+        progress.advance(1);
+    }
+    progress.finish();
+    Ok(starts)
+}
+
+/// Writes whole games to a dataset and its index.
+struct GameWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+    flags: Option<u16>,
+    starts: Vec<u64>,
+    records: u64,
+}
+
+impl GameWriter {
+    /// Creates a dataset, with a header if `flags` is given.
+    fn create(path: &Path, flags: Option<u16>) -> Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        // The record count is filled in once it is known.
+        if let Some(flags) = flags {
+            file.write_all(bytemuck::bytes_of(&Header::new(0, flags)))?;
+        }
+        Ok(GameWriter {
+            path: path.to_owned(),
+            file,
+            flags,
+            starts: vec![],
+            records: 0,
+        })
+    }
+
+    fn write_game(&mut self, game: &[PackedBoard]) -> Result<()> {
+        self.starts.push(self.records);
+        self.file.write_all(bytemuck::cast_slice(game))?;
+        self.records += game.len() as u64;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.file.flush()?;
+        drop(self.file);
+        if let Some(flags) = self.flags {
+            dataset::set_header(&self.path, &Header::new(self.records, flags))?;
+        }
+        write_index(&self.path, &self.starts)
+    }
+}
@@ -0,0 +1,126 @@
+use std::io::Result;
+use std::path::{Path, PathBuf};
+
+use marlinformat::index;
+use structopt::StructOpt;
+
+use crate::dataset::{self, Dataset};
+use crate::progress::Progress;
+use crate::{games, interleave, stats};
+
+/// Move shards that fail health checks into a `rejected/` directory.
+#[derive(StructOpt)]
+pub struct Options {
+    #[structopt(required = true)]
+    shards: Vec<PathBuf>,
+
+    /// Directory to move rejected shards into. Defaults to `rejected/` beside each shard.
+    #[structopt(long)]
+    rejected_dir: Option<PathBuf>,
+
+    /// Maximum fraction of records that fail to unpack.
+    #[structopt(long, default_value = "0")]
+    max_invalid: f64,
+
+    /// Maximum fraction of positions whose eval strongly contradicts the game result.
+    #[structopt(long)]
+    max_incongruent: Option<f64>,
+
+    /// Maximum fraction of evals saturated at the limits of the centipawn range.
+    #[structopt(long)]
+    max_saturated: Option<f64>,
+
+    /// Maximum fraction of drawn positions.
+    #[structopt(long)]
+    max_draws: Option<f64>,
+
+    /// Minimum number of positions in a shard.
+    #[structopt(long, default_value = "1")]
+    min_positions: u64,
+
+    /// Report failing shards without moving them.
+    #[structopt(long)]
+    dry_run: bool,
+}
+
+pub fn run(options: Options) -> Result<()> {
+    let mut rejected = 0;
+    for shard in &options.shards {
+        let stats = stats::compute(
+            &Dataset::open(shard)?,
+            dataset::default_workers(),
+            None,
+            &Progress::hidden(),
+        )?;
+
+        let mut failures = vec![];
+        if stats.positions() < options.min_positions {
+            failures.push(format!("{} positions", stats.positions()));
+        }
+        let mut check = |name: &str, value: f64, max: Option<f64>| {
+            if let Some(max) = max.filter(|&max| value > max) {
+                failures.push(format!(
+                    "{name} {:.2}% > {:.2}%",
+                    value * 100.0,
+                    max * 100.0
+                ));
+            }
+        };
+        check(
+            "invalid",
+            stats.invalid_fraction(),
+            Some(options.max_invalid),
+        );
+        check(
+            "incongruent",
+            stats.incongruent_fraction(),
+            options.max_incongruent,
+        );
+        check(
+            "saturated",
+            stats.saturated_fraction(),
+            options.max_saturated,
+        );
+        check("draws", stats.draw_fraction(), options.max_draws);
+
+        if failures.is_empty() {
+            println!("ok       {}", shard.display());
+            continue;
+        }
+
+        rejected += 1;
+        println!("rejected {}: {}", shard.display(), failures.join(", "));
+        if options.dry_run {
+            continue;
+        }
+        let rejected_dir = match &options.rejected_dir {
+            Some(dir) => dir.clone(),
+            None => shard
+                .parent()
+                .expect("Could not get nominal parent directory of the shard")
+                .join("rejected"),
+        };
+        std::fs::create_dir_all(&rejected_dir)?;
+        quarantine(shard, &rejected_dir)?;
+    }
+    println!("{rejected}/{} shards rejected.", options.shards.len());
+
+    Ok(())
+}
+
+/// Moves a shard into `rejected_dir` along with the sidecar files written next to it, so that
+/// none are left behind describing a file that is gone.
+fn quarantine(shard: &Path, rejected_dir: &Path) -> Result<()> {
+    let into =
+        |path: &Path| rejected_dir.join(path.file_name().expect("Shard path has no file name"));
+    std::fs::rename(shard, into(shard))?;
+    let sidecars = [
+        index::path(shard),
+        games::index_path(shard),
+        interleave::sources_path(shard),
+    ];
+    for sidecar in sidecars.iter().filter(|sidecar| sidecar.exists()) {
+        std::fs::rename(sidecar, into(sidecar))?;
+    }
+    Ok(())
+}
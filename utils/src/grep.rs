@@ -0,0 +1,252 @@
+use std::fs::File;
+use std::io::{BufWriter, Result, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use cozy_chess::{Board, Color, Piece, Square};
+use marlinformat::{wdl_to_float, Eval, Header};
+use structopt::StructOpt;
+
+use crate::dataset::{self, Dataset, Subrange};
+use crate::formats::legacy;
+
+/// Extract positions matching a pattern.
+#[derive(StructOpt)]
+pub struct Options {
+    dataset: PathBuf,
+
+    /// Material signature, e.g. `KRPvKR`. Matches either side holding either half.
+    #[structopt(long)]
+    material: Option<Material>,
+
+    /// Piece placement in FEN notation, where `?` matches any square, e.g. `6k1/5ppp/8/8/8/8/8/????K???`.
+    #[structopt(long)]
+    placement: Option<Placement>,
+
+    /// Require a piece on a square, e.g. `Pe4` for a white pawn on e4 or `qd8` for a black queen on d8.
+    #[structopt(long = "piece")]
+    pieces: Vec<PieceOnSquare>,
+
+    /// Write matching positions to this file.
+    #[structopt(short, long)]
+    output: Option<PathBuf>,
+
+    /// Print matching positions in the legacy text format.
+    #[structopt(long)]
+    print: bool,
+
+    /// Stop after this many matches.
+    #[structopt(long)]
+    max_matches: Option<u64>,
+
+    #[structopt(flatten)]
+    range: Subrange,
+}
+
+pub fn run(options: Options) -> Result<()> {
+    let dataset = Dataset::open(&options.dataset)?.subrange(&options.range);
+
+    let mut output = match &options.output {
+        Some(path) => Some(BufWriter::new(File::create(path)?)),
+        None => None,
+    };
+    // The record count is filled in once it is known.
+    let flags = dataset.header().map(Header::flags);
+    let soft = flags.is_some_and(|flags| flags & Header::FLAG_SOFT_WDL != 0);
+    let mates = flags.is_some_and(|flags| flags & Header::FLAG_MATE_SCORES != 0);
+    if let (Some(output), Some(flags)) = (&mut output, flags) {
+        output.write_all(bytemuck::bytes_of(&Header::new(0, flags)))?;
+    }
+
+    let mut matches = 0;
+    for packed in dataset.iter() {
+        if options.max_matches.is_some_and(|max| matches >= max) {
+            break;
+        }
+
+        let packed = packed?;
+        let (board, cp, wdl, _) = match packed.unpack() {
+            Some(unpacked) => unpacked,
+            None => continue,
+        };
+
+        let matched = options
+            .material
+            .as_ref()
+            .is_none_or(|material| material.matches(&board))
+            && options
+                .placement
+                .as_ref()
+                .is_none_or(|placement| placement.matches(&board))
+            && options.pieces.iter().all(|piece| piece.matches(&board));
+        if !matched {
+            continue;
+        }
+
+        matches += 1;
+        if let Some(output) = &mut output {
+            output.write_all(bytemuck::bytes_of(&packed))?;
+        }
+        if options.print {
+            println!(
+                "{}",
+                legacy::format_line(&board, Eval::decode(cp, mates), wdl_to_float(wdl, soft))
+            );
+        }
+    }
+
+    if let Some(mut output) = output {
+        output.flush()?;
+        drop(output);
+        if let (Some(path), Some(flags)) = (&options.output, flags) {
+            dataset::set_header(path, &Header::new(matches, flags))?;
+        }
+    }
+    eprintln!("{matches} matching positions.");
+
+    Ok(())
+}
+
+pub fn parse_piece(c: char) -> Option<(Piece, Color)> {
+    let piece = match c.to_ascii_lowercase() {
+        'p' => Piece::Pawn,
+        'n' => Piece::Knight,
+        'b' => Piece::Bishop,
+        'r' => Piece::Rook,
+        'q' => Piece::Queen,
+        'k' => Piece::King,
+        _ => return None,
+    };
+    let color = match c.is_ascii_uppercase() {
+        true => Color::White,
+        false => Color::Black,
+    };
+    Some((piece, color))
+}
+
+/// Piece counts for each side, as in `KRPvKR`.
+pub struct Material {
+    counts: [[u32; Piece::NUM]; Color::NUM],
+}
+
+impl Material {
+    fn matches(&self, board: &Board) -> bool {
+        let side_matches = |us: Color, them: Color| {
+            Piece::ALL.iter().all(|&piece| {
+                board.colored_pieces(Color::White, piece).len()
+                    == self.counts[us as usize][piece as usize]
+                    && board.colored_pieces(Color::Black, piece).len()
+                        == self.counts[them as usize][piece as usize]
+            })
+        };
+        side_matches(Color::White, Color::Black) || side_matches(Color::Black, Color::White)
+    }
+}
+
+impl FromStr for Material {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (white, black) = s
+            .split_once(['v', 'V'])
+            .ok_or_else(|| format!("material signature {s:?} is missing a `v`"))?;
+        let mut counts = [[0; Piece::NUM]; Color::NUM];
+        for (color, side) in [(Color::White, white), (Color::Black, black)] {
+            for c in side.chars() {
+                let (piece, _) = parse_piece(c)
+                    .ok_or_else(|| format!("invalid piece {c:?} in material signature"))?;
+                counts[color as usize][piece as usize] += 1;
+            }
+        }
+        Ok(Material { counts })
+    }
+}
+
+/// A FEN piece placement where each square is constrained to a piece, empty, or anything.
+pub struct Placement {
+    squares: [Option<Option<(Piece, Color)>>; Square::NUM],
+}
+
+impl Placement {
+    fn matches(&self, board: &Board) -> bool {
+        Square::ALL
+            .iter()
+            .all(|&sq| match self.squares[sq as usize] {
+                None => true,
+                Some(expected) => expected == board.piece_on(sq).zip(board.color_on(sq)),
+            })
+    }
+}
+
+impl FromStr for Placement {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let ranks: Vec<_> = s.split('/').collect();
+        if ranks.len() != 8 {
+            return Err(format!("placement {s:?} does not have 8 ranks"));
+        }
+        let mut squares = [None; Square::NUM];
+        for (rank_index, rank) in ranks.iter().rev().enumerate() {
+            let mut file_index = 0;
+            for c in rank.chars() {
+                let (count, constraint) = match c {
+                    '1'..='8' => (c as usize - '0' as usize, Some(None)),
+                    '?' => (1, None),
+                    _ => {
+                        let piece = parse_piece(c)
+                            .ok_or_else(|| format!("invalid character {c:?} in placement"))?;
+                        (1, Some(Some(piece)))
+                    }
+                };
+                if file_index + count > 8 {
+                    return Err(format!("rank {rank:?} has more than 8 squares"));
+                }
+                for _ in 0..count {
+                    squares[rank_index * 8 + file_index] = constraint;
+                    file_index += 1;
+                }
+            }
+            if file_index != 8 {
+                return Err(format!("rank {rank:?} has fewer than 8 squares"));
+            }
+        }
+        Ok(Placement { squares })
+    }
+}
+
+/// A piece on a specific square, as in `Pe4`.
+pub struct PieceOnSquare {
+    piece: Piece,
+    color: Color,
+    square: Square,
+}
+
+impl PieceOnSquare {
+    fn matches(&self, board: &Board) -> bool {
+        board
+            .colored_pieces(self.color, self.piece)
+            .has(self.square)
+    }
+}
+
+impl FromStr for PieceOnSquare {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut chars = s.chars();
+        let (piece, color) = chars
+            .next()
+            .and_then(parse_piece)
+            .ok_or_else(|| format!("{s:?} does not start with a piece"))?;
+        let square = chars
+            .as_str()
+            .parse()
+            .map_err(|_| format!("{s:?} does not name a square"))?;
+        Ok(PieceOnSquare {
+            piece,
+            color,
+            square,
+        })
+    }
+}
@@ -0,0 +1,200 @@
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+
+use marlinformat::index::{Hasher, Index};
+use marlinformat::{Header, PackedBoard, PackedBoardV2};
+use structopt::StructOpt;
+
+use crate::{count, inputs};
+
+/// Write the sidecar index `<file>.idx` of data files, which records their record count and
+/// a hash of their records, so that the count of a compressed file is known without
+/// decompressing it. Compressed files are decompressed once with the matching tool. Records
+/// have a fixed size, so the index holds no offsets of them.
+#[derive(StructOpt)]
+pub struct Options {
+    /// Data files, directories of them, or patterns with `*` and `?` wildcards.
+    #[structopt(required = true)]
+    files: Vec<PathBuf>,
+
+    /// Check the existing indices against the files instead of writing them.
+    #[structopt(long)]
+    check: bool,
+}
+
+pub fn run(options: Options) -> Result<()> {
+    let mut files = vec![];
+    for path in &options.files {
+        files.extend(inputs::expand(path)?);
+    }
+
+    let mut mismatched = 0;
+    for path in &files {
+        let index = build(path)?;
+        if !options.check {
+            index.write(path)?;
+            println!("{:12}  {}", index.records(), path.display());
+            continue;
+        }
+        let problem = match Index::read(path)? {
+            None => Some("has no index"),
+            Some(stored) if stored.file_len() != index.file_len() => Some("changed in length"),
+            Some(stored) if stored.records() != index.records() => Some("changed in length"),
+            Some(stored) if stored.hash() != index.hash() => Some("changed in content"),
+            Some(_) => None,
+        };
+        match problem {
+            Some(problem) => {
+                println!("{}: {problem} since it was indexed", path.display());
+                mismatched += 1;
+            }
+            None => println!("{}: ok", path.display()),
+        }
+    }
+    if mismatched > 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{mismatched} of {} files do not match their index",
+                files.len()
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Indexes a data file by reading all of its records, decompressing it if it is compressed.
+pub fn build(path: &Path) -> Result<Index> {
+    let metadata = std::fs::metadata(path)?;
+    let (mut child, reader): (_, Box<dyn Read>) = match count::compressor(path)? {
+        Some(compressor) => {
+            let mut child = count::decompress(path, compressor)?;
+            let stdout = child.stdout.take().unwrap();
+            (Some(child), Box::new(stdout))
+        }
+        None => (None, Box::new(File::open(path)?)),
+    };
+    let mut reader = BufReader::with_capacity(1 << 20, reader);
+
+    let mut first = [0; std::mem::size_of::<Header>()];
+    let read = marlinformat::io::fill(&mut reader, &mut first)?;
+    let header = Header::parse(&first[..read]);
+    let record_size = match header {
+        Some(header) if header.version() == Header::VERSION_V2 => {
+            std::mem::size_of::<PackedBoardV2>()
+        }
+        Some(header) if header.version() > Header::VERSION_V2 => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{}: unsupported marlinformat version {}",
+                    path.display(),
+                    header.version()
+                ),
+            ))
+        }
+        _ => std::mem::size_of::<PackedBoard>(),
+    };
+
+    let mut hasher = Hasher::new();
+    let mut records = 0;
+    // A file without a header starts with a record.
+    if header.is_none() && read == first.len() {
+        hasher.update(&first);
+        records += 1;
+    }
+    let limit = header.and_then(|header| header.records());
+    let mut record = vec![0; record_size];
+    let mut at_end = false;
+    while limit != Some(records) {
+        if marlinformat::io::fill(&mut reader, &mut record)? < record_size {
+            at_end = true;
+            break;
+        }
+        hasher.update(&record);
+        records += 1;
+    }
+    drop(reader);
+    if let Some(child) = &mut child {
+        // Past the records the header gives, the rest of the output is not needed.
+        if !at_end {
+            let _ = child.kill();
+        }
+        if !child.wait()?.success() && at_end {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("failed to decompress {}", path.display()),
+            ));
+        }
+    }
+
+    Ok(Index::new(
+        records,
+        record_size as u16,
+        &metadata,
+        hasher.finish(),
+    ))
+}
+
+/// Hashes the records a converter writes on their way to its output, so that the index of
+/// the output doesn't have to be built by reading it back. Any header is written to the
+/// output before it is wrapped.
+pub struct Hashing<W> {
+    output: W,
+    hasher: Hasher,
+    bytes: u64,
+}
+
+/// The hash and length of the records written through [`Hashing`].
+pub struct Hashed {
+    hash: u64,
+    bytes: u64,
+}
+
+impl<W: Write> Hashing<W> {
+    pub fn new(output: W) -> Self {
+        Hashing {
+            output,
+            hasher: Hasher::new(),
+            bytes: 0,
+        }
+    }
+
+    pub fn finish(self) -> Hashed {
+        Hashed {
+            hash: self.hasher.finish(),
+            bytes: self.bytes,
+        }
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let written = self.output.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Writes the index of a data file of `record_size`-byte records just written by a converter,
+/// whose records were hashed on the way, unless it went to stdout. The file must be complete,
+/// header included, as its length and modification time go into the index.
+pub fn write_for(path: &Path, records: &Hashed, record_size: usize) -> Result<()> {
+    if inputs::is_stdio(path) {
+        return Ok(());
+    }
+    let index = Index::new(
+        records.bytes / record_size as u64,
+        record_size as u16,
+        &std::fs::metadata(path)?,
+        records.hash,
+    );
+    index.write(path)
+}
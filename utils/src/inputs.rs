@@ -0,0 +1,95 @@
+//! Input and output paths of the conversion subcommands, where `-` stands for stdin or stdout.
+
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::path::{Path, PathBuf};
+
+use marlinformat::io::{self, ReadAhead};
+
+pub fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// The bytes of each input read ahead on a background thread, in blocks of a quarter of that.
+const READAHEAD: usize = 32 << 20;
+
+/// Opens a file for reading, or stdin for `-`. Files are read ahead on a background thread.
+pub fn open(path: &Path) -> Result<Box<dyn Read>> {
+    match is_stdio(path) {
+        true => Ok(Box::new(std::io::stdin().lock())),
+        false => {
+            let file = File::open(path)?;
+            io::advise_sequential(&file);
+            Ok(Box::new(ReadAhead::new(file, READAHEAD / 4, 4)))
+        }
+    }
+}
+
+/// Creates a file for writing, or stdout for `-`.
+pub fn create(path: &Path) -> Result<Box<dyn Write>> {
+    match is_stdio(path) {
+        true => Ok(Box::new(std::io::stdout().lock())),
+        false => Ok(Box::new(File::create(path)?)),
+    }
+}
+
+/// The size of an input in bytes, or 0 for stdin, whose size is unknown.
+pub fn len(path: &Path) -> Result<u64> {
+    match is_stdio(path) {
+        true => Ok(0),
+        false => Ok(path.metadata()?.len()),
+    }
+}
+
+/// Expands an input argument into the files it names, sorted by path: every file in a
+/// directory, or every file whose name matches a pattern with `*` and `?` wildcards in its
+/// last component. Any other path is returned as is.
+pub fn expand(path: &Path) -> Result<Vec<PathBuf>> {
+    let (dir, pattern) = if path.is_dir() {
+        (path, "*")
+    } else {
+        match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if name.contains(['*', '?']) => {
+                let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+                (dir.unwrap_or(Path::new(".")), name)
+            }
+            _ => return Ok(vec![path.to_owned()]),
+        }
+    };
+
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let matches = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| wildcard_match(pattern.as_bytes(), name.as_bytes()));
+        if matches && entry.file_type()?.is_file() {
+            files.push(entry.path());
+        }
+    }
+    if files.is_empty() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("no files match {}", path.display()),
+        ));
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// The per-file output path for `input`: the input with its extension replaced by `suffix`.
+pub fn with_suffix(input: &Path, suffix: &str) -> PathBuf {
+    let mut output = input.with_extension("").into_os_string();
+    output.push(suffix);
+    output.into()
+}
+
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, _) => name.is_empty(),
+        (Some((b'*', rest)), _) => (0..=name.len()).any(|skip| wildcard_match(rest, &name[skip..])),
+        (Some(_), None) => false,
+        (Some((&p, rest)), Some((&n, name))) => (p == b'?' || p == n) && wildcard_match(rest, name),
+    }
+}
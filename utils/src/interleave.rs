@@ -1,13 +1,18 @@
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Result, Seek, SeekFrom, Write};
-use std::path::PathBuf;
-use std::time::Instant;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
 use bytemuck::Zeroable;
-use marlinformat::PackedBoard;
+use marlinformat::{Header, PackedBoard};
 use rand::{thread_rng, Rng};
 use structopt::StructOpt;
 
+use crate::dataset;
+use crate::progress::Progress;
+
+const RECORD_SIZE: u64 = std::mem::size_of::<PackedBoard>() as u64;
+
 /// Randomly interleave two or more datasets.
 #[derive(StructOpt)]
 pub struct Options {
@@ -16,51 +21,140 @@ pub struct Options {
 
     #[structopt(required = true, min_values = 2)]
     files: Vec<PathBuf>,
+
+    /// Comma-separated sample weights, one per file, stamped into the `extra` byte of every
+    /// position taken from that file so the trainer can scale its loss per sample.
+    #[structopt(long, use_delimiter = true)]
+    use_weights: Vec<f32>,
+
+    /// Stamp the index of the file each position came from into its `extra` byte, replacing
+    /// any flags or weights it held, and list the files in `<output>.sources`, so that `stats
+    /// --buckets source` can break the output down by source. The output is not flagged as
+    /// holding weights, so the dataloader gives every position a weight of 1.
+    #[structopt(long)]
+    tag_sources: bool,
+
+    /// Read the files as legacy files without a header, even if they start with one.
+    #[structopt(long)]
+    headerless: bool,
 }
 
 pub fn run(options: Options) -> Result<()> {
-    let mut files: Vec<_> = options
-        .files
-        .iter()
-        .map(|path| File::open(path))
-        .collect::<Result<_>>()?;
+    let mut files = vec![];
+    let mut headers = vec![];
+    for path in &options.files {
+        let file = File::open(path)?;
+        let (header, records) = match options.headerless {
+            true => (None, whole_file(&file)?),
+            false => dataset::detect_header(&file)?,
+        };
+        files.push((file, records));
+        headers.extend(header);
+    }
 
-    let mut into = File::create(options.output)?;
+    if !options.use_weights.is_empty() && options.use_weights.len() != files.len() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--use-weights needs exactly one weight per file",
+        ));
+    }
+    if options.tag_sources && !options.use_weights.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--tag-sources and --use-weights both use the `extra` byte, pick one",
+        ));
+    }
+    if options.tag_sources && files.len() > 256 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--tag-sources takes at most 256 files",
+        ));
+    }
+    let extras: Vec<_> = match options.tag_sources {
+        true => (0..files.len()).map(|index| index as u8).collect(),
+        false => options
+            .use_weights
+            .iter()
+            .map(|&weight| marlinformat::extra_from_weight(weight))
+            .collect(),
+    };
 
-    let start = Instant::now();
+    let mut into = File::create(&options.output)?;
+    if options.tag_sources {
+        let mut sources = String::new();
+        for path in &options.files {
+            sources += &format!("{}\n", path.display());
+        }
+        std::fs::write(sources_path(&options.output), sources)?;
+    }
 
-    interleave(&mut into, &mut files, |progress, total| {
-        if progress & 0xFFFFF == 0 {
-            let proportion = progress as f64 / total as f64;
-            print!("\r\x1B[K{progress:12}/{total} ({:4.1}%)", proportion * 100.0);
-            let _ = std::io::stdout().flush();
+    let total = files
+        .iter()
+        .map(|(_, records)| records.end - records.start)
+        .sum();
+    // The output gets a header if any input has one, keeping the flags all headers agree on,
+    // or if it holds weights, which the dataloader only reads from files flagged as such.
+    if !headers.is_empty() || !options.use_weights.is_empty() {
+        let mut flags = match headers.is_empty() {
+            true => 0,
+            false => dataset::merge_flags(&headers, files.len())?,
+        };
+        // Weights the inputs held are replaced by the ones given or by the source tags.
+        match (options.use_weights.is_empty(), options.tag_sources) {
+            (false, _) => flags |= Header::FLAG_WEIGHTS,
+            (true, true) => flags &= !Header::FLAG_WEIGHTS,
+            (true, false) => {}
         }
-    })?;
-    println!();
-    println!("Done ({:.1?}).", start.elapsed());
+        into.write_all(bytemuck::bytes_of(&Header::new(total, flags)))?;
+    }
+    let progress = Progress::new("interleave", total);
+    interleave(
+        &mut into,
+        &mut files,
+        (!extras.is_empty()).then_some(&extras[..]),
+        &progress,
+    )?;
+    progress.finish();
 
     Ok(())
 }
 
+/// The path of the list of source files of a dataset interleaved with `--tag-sources`, one
+/// per line in the order of their indices.
+pub fn sources_path(dataset: &Path) -> PathBuf {
+    let mut path = dataset.as_os_str().to_owned();
+    path.push(".sources");
+    path.into()
+}
+
+/// The record slots of a file without a header.
+pub fn whole_file(file: &File) -> Result<Range<u64>> {
+    Ok(0..file.metadata()?.len() / RECORD_SIZE)
+}
+
+/// Interleaves the given ranges of record slots of `files` into `into`, after anything
+/// already written to it. If `extras` is given, the `extra` byte of every record is
+/// overwritten with the value given for the file it came from.
 pub fn interleave(
     into: &mut File,
-    files: &mut [File],
-    mut progress: impl FnMut(u64, u64),
+    files: &mut [(File, Range<u64>)],
+    extras: Option<&[u8]>,
+    progress: &Progress,
 ) -> Result<()> {
     let mut into = BufWriter::new(into);
     let mut streams = Vec::with_capacity(files.len());
     let mut total = 0;
-    for file in files {
-        let size_bytes = file.seek(SeekFrom::End(0))?;
-        file.seek(SeekFrom::Start(0))?;
-        let count = size_bytes / std::mem::size_of::<PackedBoard>() as u64;
+    for (index, (file, records)) in files.iter_mut().enumerate() {
+        let extra = extras.map(|extras| extras[index]);
+        file.seek(SeekFrom::Start(records.start * RECORD_SIZE))?;
+        let count = records.end - records.start;
         if count > 0 {
-            streams.push((count, BufReader::new(file)));
+            streams.push((count, extra, BufReader::new(file)));
             total += count;
         }
     }
 
-    let mut written = 0;
+    let mut unreported = 0;
 
     while total > 0 {
         let mut spot = thread_rng().gen_range(0..total);
@@ -69,10 +163,13 @@ pub fn interleave(
             spot -= streams[index].0;
             index += 1;
         }
-        let (count, reader) = &mut streams[index];
+        let (count, extra, reader) = &mut streams[index];
 
         let mut value = PackedBoard::zeroed();
         reader.read_exact(bytemuck::bytes_of_mut(&mut value))?;
+        if let Some(extra) = *extra {
+            value.set_extra(extra);
+        }
         into.write_all(bytemuck::bytes_of(&value))?;
 
         total -= 1;
@@ -81,9 +178,13 @@ pub fn interleave(
             streams.swap_remove(index);
         }
 
-        written += 1;
-        progress(written, total);
+        unreported += 1;
+        if unreported == 1 << 12 {
+            progress.advance(unreported);
+            unreported = 0;
+        }
     }
+    progress.advance(unreported);
 
     Ok(())
 }
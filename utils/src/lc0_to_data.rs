@@ -0,0 +1,120 @@
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+
+use marlinformat::{Eval, Header, PackedBoard};
+use structopt::StructOpt;
+
+use crate::dataset;
+use crate::formats::{lc0, wdl_from_float};
+use crate::progress::Progress;
+use crate::{index, inputs};
+
+/// Convert Leela Chess Zero training chunks into a data file, labelling each position with the
+/// best Q of its search, converted to centipawns, and the result of its game, to distill NNUE
+/// networks from Leela data. Only version 6 records are read, and chunks must be decompressed
+/// first, for example with `gzip -dc`.
+#[derive(StructOpt)]
+pub struct Options {
+    /// Path to a chunk, a directory or glob pattern of chunks, or `-` for stdin
+    chunks: PathBuf,
+
+    /// Output file, or `-` for stdout
+    #[structopt(short, long)]
+    output: PathBuf,
+
+    /// Write a header with the record count
+    #[structopt(long)]
+    header: bool,
+
+    /// Also write the sidecar index of the output (see `index`)
+    #[structopt(long)]
+    index: bool,
+}
+
+pub fn run(options: Options) -> Result<()> {
+    let inputs = inputs::expand(&options.chunks)?;
+    let mut bytes = 0;
+    for input in &inputs {
+        bytes += inputs::len(input)?;
+    }
+    let progress = Progress::new("lc0-to-data", bytes);
+
+    let mut output = BufWriter::new(inputs::create(&options.output)?);
+    if options.header {
+        output.write_all(bytemuck::bytes_of(&Header::new(0, 0)))?;
+    }
+    let mut records = index::Hashing::new(&mut output);
+    let (mut positions, mut invalid) = (0, 0);
+    for input in &inputs {
+        let (converted, skipped) = convert_chunk(input, &mut records, &progress)?;
+        positions += converted;
+        invalid += skipped;
+    }
+    let records = records.finish();
+    output.flush()?;
+    drop(output);
+    if options.header && !inputs::is_stdio(&options.output) {
+        dataset::set_header(&options.output, &Header::new(positions, 0))?;
+    }
+    if options.index {
+        index::write_for(
+            &options.output,
+            &records,
+            std::mem::size_of::<PackedBoard>(),
+        )?;
+    }
+    progress.finish();
+    if invalid > 0 {
+        eprintln!("Skipped {invalid} records with an invalid position or no Q.");
+    }
+
+    Ok(())
+}
+
+/// Converts the records of a chunk, returning how many were written and how many skipped.
+fn convert_chunk(input: &Path, output: &mut impl Write, progress: &Progress) -> Result<(u64, u64)> {
+    let invalid_data = |message: String| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("{}: {message}", input.display()),
+        )
+    };
+    let mut chunk = inputs::open(input)?;
+    let mut record = vec![0; lc0::RECORD_SIZE];
+    let (mut converted, mut skipped) = (0, 0);
+    loop {
+        let read = marlinformat::io::fill(&mut chunk, &mut record)?;
+        if read == 0 {
+            break;
+        }
+        if converted + skipped == 0 && record.starts_with(&[0x1f, 0x8b]) {
+            return Err(invalid_data(
+                "the chunk is gzip-compressed, decompress it first".to_string(),
+            ));
+        }
+        if read < record.len() {
+            return Err(invalid_data(format!(
+                "ends with a partial record of {read} bytes"
+            )));
+        }
+        if lc0::version(&record) != lc0::VERSION {
+            return Err(invalid_data(format!(
+                "unsupported record version {}, expected {}",
+                lc0::version(&record),
+                lc0::VERSION
+            )));
+        }
+
+        match lc0::unpack(&record) {
+            Some((board, q, result)) => {
+                let cp = Eval::centipawns(lc0::q_to_centipawns(q).round() as i64).encode();
+                let packed = PackedBoard::pack(&board, cp, wdl_from_float(result), 0);
+                output.write_all(bytemuck::bytes_of(&packed))?;
+                converted += 1;
+            }
+            None => skipped += 1,
+        }
+        progress.advance_work(1, record.len() as u64);
+    }
+    Ok((converted, skipped))
+}
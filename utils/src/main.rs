@@ -1,22 +1,102 @@
 use structopt::StructOpt;
 
+mod affinity;
 mod convert;
+mod count;
+mod data_to_txt;
+mod datagen;
+mod dataset;
+mod diff;
+#[cfg(feature = "parquet")]
+mod export_parquet;
+mod export_pgn;
+mod filter;
+mod formats;
+#[cfg(feature = "sqlite")]
+mod from_sqlite;
+mod games;
+mod gate;
+mod grep;
+mod index;
+mod inputs;
 mod interleave;
+mod lc0_to_data;
+mod openings;
+mod prepare;
+mod progress;
+mod rescore_engine;
+mod roundtrip_check;
 mod shuffle;
+mod sort;
+mod stats;
+mod thin;
+#[cfg(feature = "sqlite")]
+mod to_sqlite;
+mod transform;
 mod txt_to_data;
+mod uci;
 
 #[derive(StructOpt)]
 pub enum Options {
     Convert(convert::Options),
+    Count(count::Options),
+    DataToTxt(data_to_txt::Options),
+    Datagen(datagen::Options),
+    Diff(diff::Options),
+    #[cfg(feature = "parquet")]
+    ExportParquet(export_parquet::Options),
+    ExportPgn(export_pgn::Options),
+    Filter(filter::Options),
+    #[cfg(feature = "sqlite")]
+    FromSqlite(from_sqlite::Options),
+    Games(games::Options),
+    Gate(gate::Options),
+    Grep(grep::Options),
+    Index(index::Options),
+    Lc0ToData(lc0_to_data::Options),
+    Prepare(prepare::Options),
+    RescoreEngine(rescore_engine::Options),
+    RoundtripCheck(roundtrip_check::Options),
     Shuffle(shuffle::Options),
+    Sort(sort::Options),
+    Stats(stats::Options),
+    Thin(thin::Options),
+    #[cfg(feature = "sqlite")]
+    ToSqlite(to_sqlite::Options),
+    Transform(transform::Options),
     Interleave(interleave::Options),
     TxtToData(txt_to_data::Options),
 }
 
 fn main() {
+    formats::register_builtins();
     match Options::from_args() {
         Options::Convert(options) => convert::run(options),
+        Options::Count(options) => count::run(options).unwrap(),
+        Options::DataToTxt(options) => data_to_txt::run(options).unwrap(),
+        Options::Datagen(options) => datagen::run(options).unwrap(),
+        Options::Diff(options) => diff::run(options).unwrap(),
+        #[cfg(feature = "parquet")]
+        Options::ExportParquet(options) => export_parquet::run(options).unwrap(),
+        Options::ExportPgn(options) => export_pgn::run(options).unwrap(),
+        Options::Filter(options) => filter::run(options).unwrap(),
+        #[cfg(feature = "sqlite")]
+        Options::FromSqlite(options) => from_sqlite::run(options).unwrap(),
+        Options::Games(options) => games::run(options).unwrap(),
+        Options::Gate(options) => gate::run(options).unwrap(),
+        Options::Grep(options) => grep::run(options).unwrap(),
+        Options::Index(options) => index::run(options).unwrap(),
+        Options::Lc0ToData(options) => lc0_to_data::run(options).unwrap(),
+        Options::Prepare(options) => prepare::run(options).unwrap(),
+        Options::RescoreEngine(options) => rescore_engine::run(options).unwrap(),
+        Options::RoundtripCheck(options) => roundtrip_check::run(options).unwrap(),
         Options::Shuffle(options) => shuffle::run(options).unwrap(),
+        Options::Sort(options) => sort::run(options).unwrap(),
+        Options::Stats(options) => stats::run(options).unwrap(),
+        Options::Thin(options) => thin::run(options).unwrap(),
+        #[cfg(feature = "sqlite")]
+        Options::ToSqlite(options) => to_sqlite::run(options).unwrap(),
+        Options::Transform(options) => transform::run(options).unwrap(),
         Options::Interleave(options) => interleave::run(options).unwrap(),
         Options::TxtToData(options) => txt_to_data::run(options).unwrap(),
     }
@@ -0,0 +1,243 @@
+//! Opening positions for `datagen`: books of FENs, EPDs or PGN games, random plies played
+//! on top of them, and random DFRC start positions.
+
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use cozy_chess::{Board, File, GameStatus, Move, Piece, Rank, Square};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use structopt::StructOpt;
+
+use crate::formats;
+
+/// How many times an opening is drawn again when it was already used by another game.
+const MAX_ATTEMPTS: usize = 100;
+
+#[derive(StructOpt)]
+pub struct OpeningOptions {
+    /// Book of openings: a PGN file (`.pgn`), whose games are played to their end, or a file
+    /// with one FEN or EPD per line. Defaults to the starting position.
+    #[structopt(long)]
+    openings: Option<PathBuf>,
+
+    /// Play this many random legal moves from each opening.
+    #[structopt(long, default_value = "0")]
+    random_plies: u32,
+
+    /// Start from random double Fischer random chess positions instead of a book. Engines need
+    /// `--engine-options UCI_Chess960=true` to play them.
+    #[structopt(long, conflicts_with("openings"))]
+    dfrc: bool,
+}
+
+/// Draws openings, avoiding positions used by earlier games where it can.
+pub struct Openings {
+    book: Vec<Board>,
+    random_plies: u32,
+    dfrc: bool,
+    seen: Mutex<HashSet<u64>>,
+}
+
+impl Openings {
+    pub fn new(options: &OpeningOptions) -> Result<Self> {
+        let book = match &options.openings {
+            Some(path) => read_book(path)?,
+            None => vec![Board::default()],
+        };
+        Ok(Openings {
+            book,
+            random_plies: options.random_plies,
+            dfrc: options.dfrc,
+            seen: Mutex::new(HashSet::new()),
+        })
+    }
+
+    pub fn draw(&self, rng: &mut impl Rng) -> Board {
+        let mut board = self.draw_once(rng);
+        for _ in 1..MAX_ATTEMPTS {
+            if self.seen.lock().unwrap().insert(board.hash()) {
+                break;
+            }
+            board = self.draw_once(rng);
+        }
+        board
+    }
+
+    /// Draws an opening and plays the random plies on it, drawing again if they end the game.
+    /// The book only holds ongoing positions, so after enough tries the opening is returned
+    /// without random plies.
+    fn draw_once(&self, rng: &mut impl Rng) -> Board {
+        let mut opening = self.draw_opening(rng);
+        for _ in 0..MAX_ATTEMPTS {
+            let mut board = opening.clone();
+            for _ in 0..self.random_plies {
+                match legal_moves(&board).choose(rng) {
+                    Some(&mv) => board.play_unchecked(mv),
+                    None => break,
+                }
+            }
+            if board.status() == GameStatus::Ongoing {
+                return board;
+            }
+            opening = self.draw_opening(rng);
+        }
+        opening
+    }
+
+    fn draw_opening(&self, rng: &mut impl Rng) -> Board {
+        match self.dfrc {
+            true => Board::double_chess960_startpos(rng.gen_range(0..960), rng.gen_range(0..960)),
+            false => self.book.choose(rng).unwrap().clone(),
+        }
+    }
+}
+
+fn legal_moves(board: &Board) -> Vec<Move> {
+    let mut moves = vec![];
+    board.generate_moves(|piece_moves| {
+        moves.extend(piece_moves);
+        false
+    });
+    moves
+}
+
+/// Reads a book of openings from a PGN file or a file of FENs or EPDs, leaving out positions
+/// where the game is already over, such as the final positions of decisive PGN games.
+pub fn read_book(path: &Path) -> Result<Vec<Board>> {
+    let text = std::fs::read_to_string(path)?;
+    let invalid = |what: String| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("{what} in {}", path.display()),
+        )
+    };
+    let book = match path.extension().is_some_and(|ext| ext == "pgn") {
+        true => read_pgn(&text).map_err(invalid)?,
+        false => {
+            let mut book = vec![];
+            for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+                book.push(
+                    parse_epd(line).ok_or_else(|| invalid(format!("invalid opening {line:?}")))?,
+                );
+            }
+            book
+        }
+    };
+    if book.is_empty() {
+        return Err(invalid("no openings".to_string()));
+    }
+    let book: Vec<_> = book
+        .into_iter()
+        .filter(|board| board.status() == GameStatus::Ongoing)
+        .collect();
+    if book.is_empty() {
+        return Err(invalid(
+            "no openings where the game is still going".to_string(),
+        ));
+    }
+    Ok(book)
+}
+
+/// Parses a FEN, or an EPD, whose move counters default to `0 1`.
+fn parse_epd(line: &str) -> Option<Board> {
+    formats::parse_fen(line, true).or_else(|| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        formats::parse_fen(&format!("{} 0 1", fields.get(..4)?.join(" ")), true)
+    })
+}
+
+/// Reads the final position of every game of a PGN file, starting from its `FEN` tag if it
+/// has one.
+fn read_pgn(text: &str) -> std::result::Result<Vec<Board>, String> {
+    let mut games = vec![];
+    let mut board = None;
+    let mut depth = 0;
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(tag) = line.strip_prefix('[') {
+            if let Some(board) = board.take() {
+                games.push(board);
+            }
+            if let Some(fen) = tag.strip_prefix("FEN \"") {
+                let fen = fen.trim_end_matches("\"]");
+                board = Some(formats::parse_fen(fen, true).ok_or(format!("invalid FEN {fen:?}"))?);
+            }
+            continue;
+        }
+        for token in line.split_whitespace() {
+            // Skip comments and variations, which may be nested.
+            let opens = token.matches(['{', '(']).count();
+            let closes = token.matches(['}', ')']).count();
+            depth += opens as i32;
+            if depth > 0 || opens > 0 || closes > 0 {
+                depth -= closes as i32;
+                continue;
+            }
+            if ["1-0", "0-1", "1/2-1/2", "*"].contains(&token) {
+                continue;
+            }
+            let san = token.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.');
+            if san.is_empty() || san.starts_with('$') {
+                continue;
+            }
+            let board = board.get_or_insert_with(Board::default);
+            let mv = parse_san(board, san).ok_or(format!("illegal move {san} in {board}"))?;
+            board.play_unchecked(mv);
+        }
+    }
+    games.extend(board);
+    Ok(games)
+}
+
+/// Parses a move in standard algebraic notation.
+pub fn parse_san(board: &Board, san: &str) -> Option<Move> {
+    let san = san.trim_end_matches(['+', '#', '!', '?']);
+    let stm = board.side_to_move();
+    let back_rank = Rank::First.relative_to(stm);
+    let castle = |file: Option<File>| {
+        Some(Move {
+            from: board.king(stm),
+            to: Square::new(file?, back_rank),
+            promotion: None,
+        })
+    };
+    match san {
+        "O-O" | "0-0" => {
+            return castle(board.castle_rights(stm).short).filter(|&mv| board.is_legal(mv))
+        }
+        "O-O-O" | "0-0-0" => {
+            return castle(board.castle_rights(stm).long).filter(|&mv| board.is_legal(mv))
+        }
+        _ => {}
+    }
+
+    let (san, promotion) = match san.split_once('=') {
+        Some((san, piece)) => (san, Some(piece.to_lowercase().parse().ok()?)),
+        None => (san, None),
+    };
+    let (piece, san) = match san.chars().next()? {
+        'N' | 'B' | 'R' | 'Q' | 'K' => (san[..1].to_lowercase().parse().ok()?, &san[1..]),
+        _ => (Piece::Pawn, san),
+    };
+    let to: Square = san.get(san.len().checked_sub(2)?..)?.parse().ok()?;
+    let hint = san[..san.len() - 2].replace('x', "");
+
+    let mut found = None;
+    for mv in legal_moves(board) {
+        let matches = mv.to == to
+            && mv.promotion == promotion
+            && board.piece_on(mv.from) == Some(piece)
+            && hint.chars().all(|c| mv.from.to_string().contains(c));
+        // A king moving onto its own rook is castling, which is written differently.
+        if matches && !board.colors(stm).has(mv.to) {
+            if found.is_some() {
+                return None;
+            }
+            found = Some(mv);
+        }
+    }
+    found
+}
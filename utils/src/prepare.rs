@@ -0,0 +1,174 @@
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+
+use marlinformat::Header;
+use structopt::StructOpt;
+
+use crate::dataset::{self, Dataset};
+use crate::interleave::interleave;
+use crate::progress::Progress;
+use crate::txt_to_data::{self, Format};
+use crate::{inputs, shuffle};
+
+/// How many records are copied into a shard at a time.
+const CHUNK_RECORDS: u64 = 1 << 20;
+
+/// Convert, globally shuffle and split raw data into equally sized shards in one go. Every
+/// stage writes its result to the work directory, so a rerun after an interruption resumes
+/// after the last finished stage.
+#[derive(StructOpt)]
+pub struct Options {
+    /// Input files, directories of them, or patterns with `*` and `?` wildcards.
+    #[structopt(required = true)]
+    inputs: Vec<PathBuf>,
+
+    /// Directory to write the shards to, as `shard-0000.bin` and so on.
+    #[structopt(short, long)]
+    output: PathBuf,
+
+    #[structopt(long, default_value = "1")]
+    shards: u64,
+
+    /// Convert the inputs from this text format first, as `txt-to-data --format` does.
+    /// Without it, the inputs must be data files.
+    #[structopt(long)]
+    text_format: Option<Format>,
+
+    /// Column separator for the legacy and viri formats.
+    #[structopt(long, default_value = " | ")]
+    separator: String,
+
+    /// Accept Shredder-FENs when converting, as `txt-to-data --frc` does.
+    #[structopt(long)]
+    frc: bool,
+
+    /// Directory for intermediate files, removed once every shard is written. Defaults to
+    /// `.prepare` inside the output directory.
+    #[structopt(long)]
+    work_dir: Option<PathBuf>,
+
+    #[structopt(long, default_value = "134217728")]
+    block_size: u64,
+    #[structopt(long, default_value = "256")]
+    group_size: u64,
+}
+
+pub fn run(options: Options) -> Result<()> {
+    if options.shards == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--shards must be at least 1",
+        ));
+    }
+    let work_dir = options
+        .work_dir
+        .clone()
+        .unwrap_or_else(|| options.output.join(".prepare"));
+    std::fs::create_dir_all(&options.output)?;
+    std::fs::create_dir_all(&work_dir)?;
+
+    let shards: Vec<_> = (0..options.shards)
+        .map(|index| options.output.join(format!("shard-{index:04}.bin")))
+        .collect();
+    if shards.iter().all(|shard| shard.exists()) {
+        eprintln!("All shards are already written.");
+        return Ok(());
+    }
+
+    let mut files = vec![];
+    for path in &options.inputs {
+        files.extend(inputs::expand(path)?);
+    }
+    if let Some(format) = options.text_format {
+        let converter =
+            txt_to_data::Options::with_header(format, options.separator.clone(), options.frc);
+        let mut bytes = 0;
+        for input in &files {
+            bytes += inputs::len(input)?;
+        }
+        let progress = Progress::new("convert", bytes);
+        for (index, input) in files.iter_mut().enumerate() {
+            let converted = work_dir.join(format!("converted-{index:04}.bin"));
+            stage(&converted, |tmp| {
+                txt_to_data::convert_files(std::slice::from_ref(input), tmp, &converter, &progress)
+            })?;
+            *input = converted;
+        }
+        progress.finish();
+    }
+
+    let interleaved = work_dir.join("interleaved.bin");
+    let shuffled = work_dir.join("shuffled.bin");
+    if !shuffled.exists() {
+        stage(&interleaved, |tmp| interleave_all(&files, tmp))?;
+    }
+    stage(&shuffled, |tmp| {
+        let dataset = Dataset::open(&interleaved)?;
+        shuffle::shuffle(dataset, tmp, options.block_size, options.group_size)
+    })?;
+
+    let dataset = Dataset::open(&shuffled)?;
+    let progress = Progress::new("split", dataset.len());
+    let count = options.shards;
+    for (index, shard) in shards.iter().enumerate() {
+        let index = index as u64;
+        let records = index * dataset.len() / count..(index + 1) * dataset.len() / count;
+        stage(shard, |tmp| {
+            let mut output = BufWriter::new(File::create(tmp)?);
+            if let Some(header) = dataset.header() {
+                let header = Header::new(records.end - records.start, header.flags());
+                output.write_all(bytemuck::bytes_of(&header))?;
+            }
+            let mut next = records.start;
+            while next < records.end {
+                let end = (next + CHUNK_RECORDS).min(records.end);
+                output.write_all(bytemuck::cast_slice(&dataset.read_chunk(next..end)?))?;
+                progress.advance(end - next);
+                next = end;
+            }
+            output.flush()
+        })?;
+    }
+    progress.finish();
+
+    std::fs::remove_dir_all(&work_dir)
+}
+
+/// Runs a stage writing `output` unless an earlier run already finished it. The stage writes
+/// to a temporary path that is renamed once it is done, so `output` is never left partial.
+fn stage(output: &Path, f: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    if output.exists() {
+        eprintln!("{} is already written.", output.display());
+        return Ok(());
+    }
+    let tmp = inputs::with_suffix(output, ".tmp");
+    f(&tmp)?;
+    std::fs::rename(tmp, output)
+}
+
+/// Interleaves data files into one, with a header if any of them has one.
+fn interleave_all(paths: &[PathBuf], output: &Path) -> Result<()> {
+    let mut files = vec![];
+    let mut headers = vec![];
+    for path in paths {
+        let file = File::open(path)?;
+        let (header, records) = dataset::detect_header(&file)?;
+        files.push((file, records));
+        headers.extend(header);
+    }
+    let total = files
+        .iter()
+        .map(|(_, records)| records.end - records.start)
+        .sum();
+
+    let mut into = File::create(output)?;
+    if !headers.is_empty() {
+        let flags = dataset::merge_flags(&headers, files.len())?;
+        into.write_all(bytemuck::bytes_of(&Header::new(total, flags)))?;
+    }
+    let progress = Progress::new("interleave", total);
+    interleave(&mut into, &mut files, None, &progress)?;
+    progress.finish();
+    Ok(())
+}
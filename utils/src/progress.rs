@@ -0,0 +1,117 @@
+//! Progress reporting for long-running subcommands, shared between worker threads.
+
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often the progress line is redrawn.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+
+pub struct Progress {
+    label: String,
+    total: u64,
+    positions: AtomicU64,
+    work: AtomicU64,
+    start: Instant,
+    last_redraw: Mutex<Option<Instant>>,
+    hidden: bool,
+}
+
+impl Progress {
+    /// Reports progress on stderr towards `total` units of work, which are positions
+    /// unless advanced with [`Progress::advance_work`]. A total of 0 means it is unknown,
+    /// as for input read from stdin.
+    pub fn new(label: impl Into<String>, total: u64) -> Self {
+        Progress {
+            label: label.into(),
+            total,
+            positions: AtomicU64::new(0),
+            work: AtomicU64::new(0),
+            start: Instant::now(),
+            last_redraw: Mutex::new(None),
+            hidden: false,
+        }
+    }
+
+    /// Tracks progress without printing anything.
+    pub fn hidden() -> Self {
+        Progress {
+            hidden: true,
+            ..Progress::new("", 0)
+        }
+    }
+
+    pub fn advance(&self, positions: u64) {
+        self.advance_work(positions, positions);
+    }
+
+    /// Records positions processed along with the work they took, for inputs such as text
+    /// files where the total is known in bytes rather than positions.
+    pub fn advance_work(&self, positions: u64, work: u64) {
+        self.positions.fetch_add(positions, Ordering::Relaxed);
+        let before = self.work.fetch_add(work, Ordering::Relaxed);
+        // Only look at the clock when the work done crosses a multiple of 2^16.
+        if self.hidden || before >> 16 == (before + work) >> 16 {
+            return;
+        }
+        if let Ok(mut last_redraw) = self.last_redraw.try_lock() {
+            let now = Instant::now();
+            if last_redraw.is_none_or(|last| now - last >= REDRAW_INTERVAL) {
+                *last_redraw = Some(now);
+                self.draw();
+            }
+        }
+    }
+
+    fn draw(&self) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let positions = self.positions.load(Ordering::Relaxed);
+        let rate = positions as f64 / elapsed.max(1e-9);
+        if self.total == 0 {
+            eprint!(
+                "\r\x1B[K{}: {positions:12} positions, {rate:10.0}/s",
+                self.label
+            );
+            let _ = std::io::stderr().flush();
+            return;
+        }
+        let work = self.work.load(Ordering::Relaxed).min(self.total);
+        let fraction = work as f64 / self.total as f64;
+        let eta = match work {
+            0 => "?".to_string(),
+            _ => format_duration(elapsed * (self.total - work) as f64 / work as f64),
+        };
+        eprint!(
+            "\r\x1B[K{}: {positions:12} positions ({:5.1}%), {rate:10.0}/s, ETA {eta}",
+            self.label,
+            fraction * 100.0,
+        );
+        let _ = std::io::stderr().flush();
+    }
+
+    /// Prints the final count and throughput.
+    pub fn finish(&self) {
+        if self.hidden {
+            return;
+        }
+        let elapsed = self.start.elapsed();
+        let positions = self.positions.load(Ordering::Relaxed);
+        eprintln!(
+            "\r\x1B[K{}: {positions} positions in {} ({:.0}/s)",
+            self.label,
+            format_duration(elapsed.as_secs_f64()),
+            positions as f64 / elapsed.as_secs_f64().max(1e-9),
+        );
+    }
+}
+
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Result, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc;
+
+use cozy_chess::GameStatus;
+use marlinformat::{Extra, Header, PackedBoard};
+use structopt::StructOpt;
+
+use crate::dataset::{self, Dataset, Subrange};
+use crate::progress::Progress;
+use crate::uci::{Engine, EngineOptions};
+
+/// How many records a worker takes from the queue at a time. Search times vary a lot between
+/// positions, so tasks are kept small to balance the load.
+const TASK_RECORDS: u64 = 64;
+
+/// Replace the evals of a dataset with the scores of a UCI engine. Records that fail to
+/// unpack, and positions with no legal moves, are copied unchanged. Rescored positions whose
+/// side to move is in check are marked so in the `extra` byte, unless the file holds sample
+/// weights there.
+#[derive(StructOpt)]
+pub struct Options {
+    dataset: PathBuf,
+
+    #[structopt(short, long)]
+    output: PathBuf,
+
+    #[structopt(flatten)]
+    engine: EngineOptions,
+
+    /// Number of engine processes searching at once. Defaults to the number of CPUs.
+    #[structopt(long)]
+    concurrency: Option<usize>,
+
+    #[structopt(flatten)]
+    range: Subrange,
+}
+
+pub fn run(options: Options) -> Result<()> {
+    options.engine.check_limits()?;
+    let dataset = Dataset::open(&options.dataset)?.subrange(&options.range);
+    let concurrency = options.concurrency.unwrap_or_else(dataset::default_workers);
+
+    // The engine's mate scores are only read as such from a file whose header says so, so one
+    // is written even for a headerless dataset.
+    let mut output = BufWriter::new(File::create(&options.output)?);
+    let flags = dataset.header().map_or(0, Header::flags);
+    let header = Header::new(dataset.len(), flags | Header::FLAG_MATE_SCORES);
+    output.write_all(bytemuck::bytes_of(&header))?;
+
+    let tasks = dataset.len().div_ceil(TASK_RECORDS);
+    let next = AtomicU64::new(0);
+    let stop = AtomicBool::new(false);
+    let progress = Progress::new("rescore-engine", dataset.len());
+    std::thread::scope(|scope| {
+        let (send, recv) = mpsc::channel();
+        for _ in 0..concurrency {
+            let send = send.clone();
+            let (dataset, engine, next, stop) = (&dataset, &options.engine, &next, &stop);
+            scope.spawn(move || {
+                if let Err(e) = work(dataset, engine, next, stop, &send) {
+                    let _ = send.send((tasks, Err(e)));
+                }
+            });
+        }
+        drop(send);
+
+        let result = write_in_order(recv, &mut output, &progress);
+        stop.store(true, Ordering::Relaxed);
+        result
+    })?;
+    output.flush()?;
+    progress.finish();
+
+    Ok(())
+}
+
+type Task = (u64, Result<Vec<PackedBoard>>);
+
+/// Takes tasks from the queue and sends their rescored records until the queue is empty.
+fn work(
+    dataset: &Dataset,
+    engine: &EngineOptions,
+    next: &AtomicU64,
+    stop: &AtomicBool,
+    send: &mpsc::Sender<Task>,
+) -> Result<()> {
+    let mut engine = Engine::start(engine)?;
+    let flags = dataset
+        .header()
+        .is_none_or(|header| header.flags() & Header::FLAG_WEIGHTS == 0);
+    let tasks = dataset.len().div_ceil(TASK_RECORDS);
+    while !stop.load(Ordering::Relaxed) {
+        let task = next.fetch_add(1, Ordering::Relaxed);
+        if task >= tasks {
+            break;
+        }
+        let start = task * TASK_RECORDS;
+        let end = (start + TASK_RECORDS).min(dataset.len());
+        let mut chunk = dataset.read_chunk(start..end)?;
+        for packed in &mut chunk {
+            rescore(&mut engine, packed, flags)?;
+        }
+        // The receiver only hangs up after an error, when the work is abandoned anyway.
+        let _ = send.send((task, Ok(chunk)));
+    }
+    Ok(())
+}
+
+/// Writes the tasks' records in order. Tasks finish out of order, so they are held back until
+/// every earlier one is written.
+fn write_in_order(
+    recv: mpsc::Receiver<Task>,
+    output: &mut impl Write,
+    progress: &Progress,
+) -> Result<()> {
+    let mut pending = BTreeMap::new();
+    let mut written = 0;
+    for (task, chunk) in recv {
+        pending.insert(task, chunk?);
+        while let Some(chunk) = pending.remove(&written) {
+            output.write_all(bytemuck::cast_slice(&chunk))?;
+            progress.advance(chunk.len() as u64);
+            written += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Rescores a record, setting its in check flag if `flags` says the `extra` byte holds flags.
+fn rescore(engine: &mut Engine, packed: &mut PackedBoard, flags: bool) -> Result<()> {
+    let (board, _, wdl, extra) = match packed.unpack() {
+        Some(unpacked) => unpacked,
+        None => return Ok(()),
+    };
+    if board.status() != GameStatus::Ongoing {
+        return Ok(());
+    }
+    if let Some(eval) = engine.search(&board, &[])?.eval {
+        let mut extra = Extra::new(extra);
+        if flags {
+            extra.set_in_check(!board.checkers().is_empty());
+        }
+        *packed = PackedBoard::pack(&board, eval.encode(), wdl, extra.bits());
+    }
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use marlinformat::{wdl_to_float, Eval, Header, PackedBoard};
+use structopt::StructOpt;
+
+use crate::dataset::{self, Dataset, Subrange};
+use crate::formats::bullet::{self, BulletBoard};
+use crate::formats::{self, legacy, viri};
+
+/// Convert a dataset, or a sample of it with `--limit`, to another format and back, and
+/// check nothing was lost.
+#[derive(StructOpt)]
+pub struct Options {
+    dataset: PathBuf,
+
+    /// Intermediate format: `text` (legacy text format), `viri` (viri text format) or `bullet`
+    /// (bulletformat).
+    #[structopt(long)]
+    via: Via,
+
+    /// Print up to this many records that did not survive the round trip.
+    #[structopt(long, default_value = "10")]
+    print: u64,
+
+    #[structopt(flatten)]
+    range: Subrange,
+}
+
+#[derive(Clone, Copy)]
+pub enum Via {
+    Text,
+    Viri,
+    Bullet,
+}
+
+impl FromStr for Via {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Via::Text),
+            "viri" => Ok(Via::Viri),
+            "bullet" => Ok(Via::Bullet),
+            _ => Err(format!(
+                "unknown format {s:?}, expected `text`, `viri` or `bullet`"
+            )),
+        }
+    }
+}
+
+impl Via {
+    /// Returns the record with everything the format cannot represent dropped. The text
+    /// formats saturate centipawns that could be mistaken for mates, which `mates` says the
+    /// file's evals close to the mate score are.
+    fn expected(self, packed: &PackedBoard, mates: bool) -> Option<PackedBoard> {
+        let (board, cp, wdl, extra) = packed.unpack()?;
+        let text_cp = Eval::decode(cp, mates).encode();
+        match self {
+            Via::Text => Some(PackedBoard::pack(&board, text_cp, wdl, 0)),
+            Via::Viri => Some(PackedBoard::pack(&board, text_cp, wdl, extra)),
+            Via::Bullet => {
+                let (board, cp, wdl) = bullet::relative_to_stm(&board, cp, wdl)?;
+                Some(PackedBoard::pack(&board, cp, wdl, 0))
+            }
+        }
+    }
+
+    fn roundtrip(self, packed: &PackedBoard, mates: bool) -> Option<PackedBoard> {
+        let (board, cp, wdl, extra) = packed.unpack()?;
+        let eval = Eval::decode(cp, mates);
+        let (board, cp, wdl, extra) = match self {
+            Via::Text => {
+                let line = legacy::format_line(&board, eval, wdl_to_float(wdl, false));
+                let (board, eval, wdl) = legacy::parse_line(&line)?;
+                let cp = eval.to_eval().encode();
+                (board, cp, formats::wdl_from_float(wdl), 0)
+            }
+            Via::Viri => {
+                let line = viri::format_line(&board, eval, wdl, extra);
+                let (board, eval, wdl, extra) = viri::parse_line(&line, legacy::SEPARATOR, true)?;
+                let cp = eval.to_eval().encode();
+                (board, cp, formats::wdl_from_float(wdl), extra)
+            }
+            Via::Bullet => {
+                let (board, cp, wdl) = BulletBoard::pack(&board, cp, wdl)?.unpack()?;
+                (board, cp, wdl, 0)
+            }
+        };
+        Some(PackedBoard::pack(&board, cp, wdl, extra))
+    }
+}
+
+pub fn run(options: Options) -> Result<()> {
+    let dataset = Dataset::open(&options.dataset)?.subrange(&options.range);
+    let flags = dataset.header().map_or(0, Header::flags);
+    let soft = flags & Header::FLAG_SOFT_WDL != 0;
+    let mates = flags & Header::FLAG_MATE_SCORES != 0;
+    // The checks compare results as a loss, draw or win.
+    dataset::check_hard_wdl(&options.dataset, soft, "the round trip check")?;
+
+    let mut checked = 0;
+    let mut invalid = 0;
+    let mut lossy = 0;
+    let mut board_mismatches = 0;
+    let mut label_mismatches = 0;
+    let mut printed = 0;
+    for packed in dataset.iter() {
+        let packed = packed?;
+        checked += 1;
+
+        let expected = match options.via.expected(&packed, mates) {
+            Some(expected) => expected,
+            None => {
+                invalid += 1;
+                continue;
+            }
+        };
+        if bytemuck::bytes_of(&expected) != bytemuck::bytes_of(&packed) {
+            lossy += 1;
+        }
+
+        let roundtripped = options.via.roundtrip(&packed, mates);
+        if roundtripped.is_some_and(|r| bytemuck::bytes_of(&r) == bytemuck::bytes_of(&expected)) {
+            continue;
+        }
+
+        let (board, cp, wdl, _) = expected.unpack().unwrap();
+        let unpacked = roundtripped.and_then(|r| r.unpack());
+        if unpacked.as_ref().is_none_or(|(b, ..)| *b != board) {
+            board_mismatches += 1;
+        } else {
+            label_mismatches += 1;
+        }
+        if printed < options.print {
+            printed += 1;
+            match unpacked {
+                Some((b, c, w, _)) => println!("{board} | {cp} | {wdl} -> {b} | {c} | {w}"),
+                None => println!("{board} | {cp} | {wdl} -> failed to convert"),
+            }
+        }
+    }
+
+    println!("checked:          {checked:12}");
+    println!("invalid:          {invalid:12}");
+    println!("lossy by design:  {lossy:12}");
+    println!("board mismatches: {board_mismatches:12}");
+    println!("label mismatches: {label_mismatches:12}");
+
+    if board_mismatches + label_mismatches > 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "some records did not survive the round trip",
+        ));
+    }
+    Ok(())
+}
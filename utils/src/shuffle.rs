@@ -1,13 +1,16 @@
 use std::fs::File;
-use std::io::{Read, Result, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::io::{Result, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use bytemuck::Zeroable;
-use marlinformat::PackedBoard;
+use marlinformat::Header;
 use rand::prelude::*;
 use structopt::StructOpt;
 
-use crate::interleave::interleave;
+use crate::dataset::{Dataset, Subrange};
+use crate::interleave::{interleave, whole_file};
+use crate::progress::Progress;
 
 #[derive(StructOpt)]
 /// Shuffle a dataset
@@ -26,93 +29,117 @@ pub struct Options {
     block_size: u64,
     #[structopt(long, default_value = "256")]
     group_size: u64,
+
+    #[structopt(flatten)]
+    range: Subrange,
 }
 
 pub fn run(options: Options) -> Result<()> {
     let output = options.output.unwrap_or_else(|| options.dataset.clone());
+    let dataset = Dataset::open(&options.dataset)?.subrange(&options.range);
+    shuffle(dataset, &output, options.block_size, options.group_size)
+}
+
+/// Shuffles a dataset into `output`, in memory if it has at most `block_size` records, and
+/// otherwise by shuffling blocks of that size and interleaving `group_size` of them at a time.
+pub fn shuffle(dataset: Dataset, output: &Path, block_size: u64, group_size: u64) -> Result<()> {
     let output_dir = output
         .parent()
         .expect("Could not get nominal parent directory of the oiutput file");
 
-    let mut dataset = File::open(options.dataset)?;
-    let positions = dataset.seek(SeekFrom::End(0))? / std::mem::size_of::<PackedBoard>() as u64;
-    dataset.rewind()?;
+    let positions = dataset.len();
+    let header = dataset
+        .header()
+        .map(|header| Header::new(positions, header.flags()));
 
-    if positions <= options.block_size {
+    if positions <= block_size {
         println!("in-memory shuffle");
-        let mut data = read(&mut dataset, positions)?;
+        let mut data = dataset.read_chunk(0..positions)?;
         drop(dataset);
         data.shuffle(&mut thread_rng());
         let mut target = tempfile::NamedTempFile::new_in(output_dir)?;
+        if let Some(header) = &header {
+            target.write_all(bytemuck::bytes_of(header))?;
+        }
         target.write_all(bytemuck::cast_slice(&data))?;
         target.persist(output)?;
         return Ok(());
     }
 
-    let block_count = (positions + options.block_size - 1) / options.block_size;
+    let block_count = (positions + block_size - 1) / block_size;
 
-    let (send, mut recv) = std::sync::mpsc::sync_channel(options.group_size as usize);
+    // Every record is written once when its block is shuffled, and once per merge level.
+    let mut passes = 1;
+    let mut files = block_count;
+    while files > 1 {
+        files = files.div_ceil(group_size);
+        passes += 1;
+    }
+    let progress = Arc::new(Progress::new("shuffle", positions * passes));
+
+    let (send, mut recv) = std::sync::mpsc::sync_channel(group_size as usize);
 
-    let mut remaining = positions;
-    let mut blocks_shuffled = 0;
+    let mut next = 0;
     std::thread::spawn({
         let output_dir = output_dir.to_owned();
+        let progress = progress.clone();
         move || loop {
-            if remaining == 0 {
+            if next == positions {
                 break;
             }
-            let count = remaining.min(options.block_size);
-            remaining -= count;
-            let mut data = read(&mut dataset, count).unwrap();
+            let count = (positions - next).min(block_size);
+            let mut data = dataset.read_chunk(next..next + count).unwrap();
+            next += count;
             data.shuffle(&mut thread_rng());
             let mut f = tempfile::tempfile_in(&output_dir).unwrap();
             f.write_all(bytemuck::cast_slice(&data)).unwrap();
             send.send(f).unwrap();
-            blocks_shuffled += 1;
-            println!("blocks: {blocks_shuffled}/{block_count}");
+            progress.advance(count);
         }
     });
 
     let mut items = block_count;
-    let mut level = 0;
     loop {
-        level += 1;
-        items = (items + options.group_size - 1) / options.group_size;
+        items = (items + group_size - 1) / group_size;
         if items == 1 {
             break;
         }
 
-        let (nsend, nrecv) = std::sync::mpsc::sync_channel(options.group_size as usize);
+        let (nsend, nrecv) = std::sync::mpsc::sync_channel(group_size as usize);
         let mut iter = recv.into_iter();
-        let mut progress = 0;
         std::thread::spawn({
             let output_dir = output_dir.to_owned();
+            let progress = progress.clone();
             move || loop {
-                let mut files: Vec<_> = (&mut iter).take(options.group_size as usize).collect();
+                let mut files: Vec<_> = (&mut iter)
+                    .take(group_size as usize)
+                    .map(with_records)
+                    .collect();
                 if files.is_empty() {
                     break;
                 }
                 let mut to = tempfile::tempfile_in(&output_dir).unwrap();
-                interleave(&mut to, &mut files, |_, _| {}).unwrap();
+                interleave(&mut to, &mut files, None, &progress).unwrap();
                 nsend.send(to).unwrap();
-                progress += 1;
-                println!("lvl. {level}: {progress}/{items}");
             }
         });
 
         recv = nrecv;
     }
 
-    let mut files: Vec<_> = recv.into_iter().collect();
+    let mut files: Vec<_> = recv.into_iter().map(with_records).collect();
     let mut target = tempfile::NamedTempFile::new_in(output_dir)?;
-    interleave(target.as_file_mut(), &mut files, |_, _| {})?;
+    if let Some(header) = &header {
+        target.write_all(bytemuck::bytes_of(header))?;
+    }
+    interleave(target.as_file_mut(), &mut files, None, &progress)?;
     target.persist(output)?;
+    progress.finish();
 
     Ok(())
 }
 
-fn read(dataset: &mut File, count: u64) -> Result<Vec<PackedBoard>> {
-    let mut boards = vec![PackedBoard::zeroed(); count as usize];
-    dataset.read_exact(bytemuck::cast_slice_mut(&mut boards))?;
-    Ok(boards)
+fn with_records(file: File) -> (File, Range<u64>) {
+    let records = whole_file(&file).unwrap();
+    (file, records)
 }
@@ -0,0 +1,143 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use bytemuck::Zeroable;
+use marlinformat::{Header, PackedBoard};
+use rayon::prelude::*;
+use structopt::StructOpt;
+
+use crate::dataset::{Dataset, Subrange};
+use crate::formats;
+use crate::progress::Progress;
+
+/// Sort a dataset by a key computed from each position, with an external merge sort.
+/// Records with equal keys keep their order, and records that fail to unpack go last.
+#[derive(StructOpt)]
+pub struct Options {
+    dataset: PathBuf,
+
+    #[structopt(short, long)]
+    output: PathBuf,
+
+    /// `pieces` (piece count), `phase` (0 for bare kings up to 24 for the starting material)
+    /// or `hash` (Zobrist hash, so that duplicate positions end up next to each other).
+    #[structopt(long, default_value = "phase")]
+    key: Key,
+
+    /// Number of records sorted in memory at a time.
+    #[structopt(long, default_value = "134217728")]
+    block_size: u64,
+
+    #[structopt(flatten)]
+    range: Subrange,
+}
+
+#[derive(Clone, Copy)]
+enum Key {
+    Pieces,
+    Phase,
+    Hash,
+}
+
+impl Key {
+    fn of(self, packed: &PackedBoard) -> u64 {
+        let board = match packed.unpack() {
+            Some((board, ..)) => board,
+            None => return u64::MAX,
+        };
+        match self {
+            Key::Pieces => board.occupied().len() as u64,
+            Key::Phase => formats::phase(&board),
+            // Leave u64::MAX for invalid records.
+            Key::Hash => board.hash().min(u64::MAX - 1),
+        }
+    }
+}
+
+impl FromStr for Key {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "pieces" => Ok(Key::Pieces),
+            "phase" => Ok(Key::Phase),
+            "hash" => Ok(Key::Hash),
+            _ => Err(format!(
+                "unknown key {s:?}, expected `pieces`, `phase` or `hash`"
+            )),
+        }
+    }
+}
+
+pub fn run(options: Options) -> Result<()> {
+    let dataset = Dataset::open(&options.dataset)?.subrange(&options.range);
+    let positions = dataset.len();
+    let output_dir = options
+        .output
+        .parent()
+        .expect("Could not get nominal parent directory of the output file");
+
+    // Every record is written once to its sorted block, and once more by the merge.
+    let progress = Progress::new("sort", positions * 2);
+    let mut blocks = vec![];
+    let mut next = 0;
+    while next < positions {
+        let count = (positions - next).min(options.block_size);
+        let mut keyed: Vec<_> = dataset
+            .read_chunk(next..next + count)?
+            .into_par_iter()
+            .map(|packed| (options.key.of(&packed), packed))
+            .collect();
+        next += count;
+        keyed.par_sort_by_key(|&(key, _)| key);
+        let mut block = BufWriter::new(tempfile::tempfile_in(output_dir)?);
+        for (_, packed) in &keyed {
+            block.write_all(bytemuck::bytes_of(packed))?;
+        }
+        let mut block = block.into_inner().map_err(|e| e.into_error())?;
+        block.seek(SeekFrom::Start(0))?;
+        blocks.push(BufReader::new(block));
+        progress.advance(count);
+    }
+
+    let mut target = tempfile::NamedTempFile::new_in(output_dir)?;
+    let mut output = BufWriter::new(target.as_file_mut());
+    if let Some(header) = dataset.header() {
+        let header = Header::new(positions, header.flags());
+        output.write_all(bytemuck::bytes_of(&header))?;
+    }
+
+    // Ties are broken by block, which keeps the sort stable.
+    let mut heads = vec![PackedBoard::zeroed(); blocks.len()];
+    let mut heap = BinaryHeap::new();
+    for (index, block) in blocks.iter_mut().enumerate() {
+        if read_record(block, &mut heads[index])? {
+            heap.push(Reverse((options.key.of(&heads[index]), index)));
+        }
+    }
+    while let Some(Reverse((_, index))) = heap.pop() {
+        output.write_all(bytemuck::bytes_of(&heads[index]))?;
+        if read_record(&mut blocks[index], &mut heads[index])? {
+            heap.push(Reverse((options.key.of(&heads[index]), index)));
+        }
+        progress.advance(1);
+    }
+    output.flush()?;
+    drop(output);
+    target.persist(&options.output)?;
+    progress.finish();
+
+    Ok(())
+}
+
+/// Reads the next record of a sorted block, or returns false at its end.
+fn read_record(block: &mut impl Read, packed: &mut PackedBoard) -> Result<bool> {
+    match block.read_exact(bytemuck::bytes_of_mut(packed)) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
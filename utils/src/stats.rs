@@ -0,0 +1,710 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, Result};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use cozy_chess::{BitBoard, Board, Color, Piece, Square};
+use marlinformat::{Eval, Header, PackedBoard};
+use serde::Serialize;
+use structopt::StructOpt;
+
+use crate::affinity::Affinity;
+use crate::dataset::{self, Dataset, Subrange};
+use crate::formats;
+use crate::games;
+use crate::interleave;
+use crate::progress::Progress;
+
+/// Print statistics about a dataset.
+#[derive(StructOpt)]
+pub struct Options {
+    dataset: PathBuf,
+
+    /// Number of workers, each reading its own range of the file. Defaults to the number of CPUs.
+    #[structopt(long)]
+    workers: Option<usize>,
+
+    /// Pin workers to CPUs: `cores` for one CPU each, or `numa` to spread them over the NUMA
+    /// nodes with each reading its range into its node's memory.
+    #[structopt(long, default_value = "none")]
+    affinity: Affinity,
+
+    /// Also report statistics per input bucket: `king` for the side to move's king square,
+    /// as used by the HalfKA/HalfKP feature sets, `material` for 8 buckets by piece count,
+    /// as used for output buckets, or `source` for the source file of datasets interleaved
+    /// with `--tag-sources`.
+    #[structopt(long)]
+    buckets: Option<Buckets>,
+
+    /// Also report the K most common positions, regardless of move counters, with their
+    /// counts. Takes two more passes over the dataset.
+    #[structopt(long)]
+    top_positions: Option<usize>,
+
+    /// Also report the calibration curve: the mean score of the positions in each eval
+    /// bucket, and the eval scale of the sigmoid that fits it best.
+    #[structopt(long)]
+    calibration: bool,
+
+    /// Write the calibration curve to this file as JSON.
+    #[structopt(long)]
+    calibration_json: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    range: Subrange,
+}
+
+#[derive(Clone, Copy)]
+pub enum Buckets {
+    King,
+    Material,
+    Source,
+}
+
+impl Buckets {
+    fn count(self) -> usize {
+        match self {
+            Buckets::King => Square::NUM,
+            Buckets::Material => 8,
+            Buckets::Source => 256,
+        }
+    }
+
+    fn index(self, board: &Board, extra: u8) -> usize {
+        match self {
+            Buckets::King => {
+                let stm = board.side_to_move();
+                let king = board.king(stm);
+                match stm {
+                    Color::White => king as usize,
+                    Color::Black => king.flip_rank() as usize,
+                }
+            }
+            Buckets::Material => ((board.occupied().len() as usize).saturating_sub(2) / 4).min(7),
+            Buckets::Source => extra as usize,
+        }
+    }
+
+    fn label(self, index: usize) -> String {
+        match self {
+            Buckets::King => Square::index(index).to_string(),
+            Buckets::Material => format!("{}-{} pieces", index * 4 + 2, index * 4 + 5),
+            Buckets::Source => format!("source {index}"),
+        }
+    }
+}
+
+impl FromStr for Buckets {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "king" => Ok(Buckets::King),
+            "material" => Ok(Buckets::Material),
+            "source" => Ok(Buckets::Source),
+            _ => Err(format!(
+                "unknown bucket scheme {s:?}, expected `king`, `material` or `source`"
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct BucketStats {
+    positions: u64,
+    wdl: [u64; 3],
+    eval_sum: i64,
+}
+
+/// The width of the eval buckets of the calibration curve, which cover evals from
+/// `-CALIBRATION_LIMIT` to `CALIBRATION_LIMIT`, with larger evals in the outermost buckets.
+const CALIBRATION_WIDTH: i16 = 50;
+const CALIBRATION_LIMIT: i16 = 1500;
+const CALIBRATION_BUCKETS: usize = (2 * CALIBRATION_LIMIT / CALIBRATION_WIDTH) as usize;
+
+#[derive(Serialize)]
+struct CalibrationBucket {
+    min_eval: i16,
+    max_eval: i16,
+    positions: u64,
+    mean_eval: f64,
+    // The mean result, counting a win as 1 and a draw as 0.5, and the fitted sigmoid's value
+    score: f64,
+    fitted: f64,
+}
+
+#[derive(Serialize)]
+pub struct Calibration {
+    scale: f64,
+    buckets: Vec<CalibrationBucket>,
+}
+
+impl Calibration {
+    fn print(&self) {
+        println!("calibration (best-fitting eval scale: {:.0}):", self.scale);
+        println!(
+            "  {:>11} {:>12} {:>10} {:>7} {:>7}",
+            "eval", "positions", "eval mean", "score", "fitted"
+        );
+        for bucket in &self.buckets {
+            println!(
+                "  {:>5}..{:<4} {:12} {:10.2} {:7.4} {:7.4}",
+                bucket.min_eval,
+                bucket.max_eval,
+                bucket.positions,
+                bucket.mean_eval,
+                bucket.score,
+                bucket.fitted
+            );
+        }
+    }
+}
+
+/// Evals at least this large are incongruent with a result in the other side's favour.
+const INCONGRUENCE_THRESHOLD: i16 = 400;
+
+pub struct Stats {
+    positions: u64,
+    invalid: u64,
+    wdl: [u64; 3],
+    eval_sum: i64,
+    eval_abs_sum: u64,
+    eval_min: i16,
+    eval_max: i16,
+    saturated: u64,
+    mates: u64,
+    incongruent: u64,
+    extra: [u64; 256],
+    buckets: Option<Buckets>,
+    // Whether evals close to the mate score are mates, see `Header::FLAG_MATE_SCORES`
+    mate_scores: bool,
+    // Whether results are soft, in which case they count as the nearest of a loss, draw or win
+    soft_wdl: bool,
+    bucket_stats: Vec<BucketStats>,
+    // The positions with centipawn evals in each bucket of the calibration curve
+    calibration: [BucketStats; CALIBRATION_BUCKETS],
+    // The previous valid record, and how many pairs of consecutive valid records are one move
+    // apart or have the same pieces on the same squares
+    previous: Option<Board>,
+    pairs: u64,
+    one_move_apart: u64,
+    same_placement: u64,
+}
+
+impl Stats {
+    /// Empty statistics of a dataset with the given header flags.
+    pub fn new(buckets: Option<Buckets>, flags: u16) -> Self {
+        Stats {
+            positions: 0,
+            invalid: 0,
+            wdl: [0; 3],
+            eval_sum: 0,
+            eval_abs_sum: 0,
+            eval_min: i16::MAX,
+            eval_max: i16::MIN,
+            saturated: 0,
+            mates: 0,
+            incongruent: 0,
+            extra: [0; 256],
+            buckets,
+            mate_scores: flags & Header::FLAG_MATE_SCORES != 0,
+            soft_wdl: flags & Header::FLAG_SOFT_WDL != 0,
+            bucket_stats: vec![BucketStats::default(); buckets.map_or(0, Buckets::count)],
+            calibration: [BucketStats::default(); CALIBRATION_BUCKETS],
+            previous: None,
+            pairs: 0,
+            one_move_apart: 0,
+            same_placement: 0,
+        }
+    }
+
+    pub fn add(&mut self, packed: &PackedBoard) {
+        self.positions += 1;
+        let (board, cp, wdl, extra) = match packed.unpack() {
+            Some(unpacked) => unpacked,
+            None => {
+                self.invalid += 1;
+                self.previous = None;
+                return;
+            }
+        };
+        let wdl = dataset::hard_wdl(wdl, self.soft_wdl);
+        self.wdl[wdl as usize] += 1;
+        self.eval_sum += cp as i64;
+        self.eval_abs_sum += cp.unsigned_abs() as u64;
+        self.eval_min = self.eval_min.min(cp);
+        self.eval_max = self.eval_max.max(cp);
+        let eval = Eval::decode(cp, self.mate_scores);
+        match eval {
+            Eval::Centipawns(cp) if cp.unsigned_abs() >= Eval::MAX_CENTIPAWNS as u16 => {
+                self.saturated += 1
+            }
+            Eval::Mate { .. } => self.mates += 1,
+            _ => {}
+        }
+        if let Eval::Centipawns(cp) = eval {
+            let clamped = cp.clamp(-CALIBRATION_LIMIT, CALIBRATION_LIMIT - 1);
+            let bucket =
+                &mut self.calibration[((clamped + CALIBRATION_LIMIT) / CALIBRATION_WIDTH) as usize];
+            bucket.positions += 1;
+            bucket.wdl[wdl as usize] += 1;
+            bucket.eval_sum += cp as i64;
+        }
+        let incongruent = match wdl {
+            0 => cp >= INCONGRUENCE_THRESHOLD,
+            2 => cp <= -INCONGRUENCE_THRESHOLD,
+            _ => false,
+        };
+        if incongruent {
+            self.incongruent += 1;
+        }
+        self.extra[extra as usize] += 1;
+        if let Some(buckets) = self.buckets {
+            let bucket = &mut self.bucket_stats[buckets.index(&board, extra)];
+            bucket.positions += 1;
+            bucket.wdl[wdl as usize] += 1;
+            bucket.eval_sum += cp as i64;
+        }
+        if let Some(previous) = &self.previous {
+            self.pairs += 1;
+            match changed_squares(previous, &board) {
+                0 => self.same_placement += 1,
+                // A move changes 2 squares, 3 for en passant and 4 for castling.
+                2..=4 if previous.side_to_move() != board.side_to_move() => {
+                    self.one_move_apart += 1
+                }
+                _ => {}
+            }
+        }
+        self.previous = Some(board);
+    }
+
+    pub fn merge(&mut self, other: &Stats) {
+        self.positions += other.positions;
+        self.invalid += other.invalid;
+        for (a, b) in self.wdl.iter_mut().zip(&other.wdl) {
+            *a += b;
+        }
+        self.eval_sum += other.eval_sum;
+        self.eval_abs_sum += other.eval_abs_sum;
+        self.eval_min = self.eval_min.min(other.eval_min);
+        self.eval_max = self.eval_max.max(other.eval_max);
+        self.saturated += other.saturated;
+        self.mates += other.mates;
+        self.incongruent += other.incongruent;
+        for (a, b) in self.extra.iter_mut().zip(&other.extra) {
+            *a += b;
+        }
+        for (a, b) in self.bucket_stats.iter_mut().zip(&other.bucket_stats) {
+            a.positions += b.positions;
+            for (a, b) in a.wdl.iter_mut().zip(&b.wdl) {
+                *a += b;
+            }
+            a.eval_sum += b.eval_sum;
+        }
+        for (a, b) in self.calibration.iter_mut().zip(&other.calibration) {
+            a.positions += b.positions;
+            for (a, b) in a.wdl.iter_mut().zip(&b.wdl) {
+                *a += b;
+            }
+            a.eval_sum += b.eval_sum;
+        }
+        // Pairs that straddle the two are lost, which is a handful per worker.
+        self.pairs += other.pairs;
+        self.one_move_apart += other.one_move_apart;
+        self.same_placement += other.same_placement;
+    }
+
+    pub fn positions(&self) -> u64 {
+        self.positions
+    }
+
+    fn fraction(&self, count: u64) -> f64 {
+        count as f64 / self.positions.max(1) as f64
+    }
+
+    pub fn invalid_fraction(&self) -> f64 {
+        self.fraction(self.invalid)
+    }
+
+    pub fn draw_fraction(&self) -> f64 {
+        self.fraction(self.wdl[1])
+    }
+
+    pub fn saturated_fraction(&self) -> f64 {
+        self.fraction(self.saturated)
+    }
+
+    pub fn incongruent_fraction(&self) -> f64 {
+        self.fraction(self.incongruent)
+    }
+
+    /// The calibration curve, with the eval scale whose sigmoid, `1 / (1 + exp(-eval /
+    /// scale))`, gives the buckets' results the greatest likelihood.
+    pub fn calibration(&self) -> Calibration {
+        let buckets = self
+            .calibration
+            .iter()
+            .filter(|bucket| bucket.positions > 0);
+        let log_likelihood = |scale: f64| {
+            buckets
+                .clone()
+                .map(|bucket| {
+                    let eval = bucket.eval_sum as f64 / bucket.positions as f64;
+                    let p = (1.0 / (1.0 + (-eval / scale).exp())).clamp(1e-9, 1.0 - 1e-9);
+                    let [losses, draws, wins] = bucket.wdl.map(|count| count as f64);
+                    (wins + draws / 2.0) * p.ln() + (losses + draws / 2.0) * (1.0 - p).ln()
+                })
+                .sum::<f64>()
+        };
+        let scale = (1..=2000)
+            .map(f64::from)
+            .max_by(|&a, &b| log_likelihood(a).total_cmp(&log_likelihood(b)))
+            .unwrap();
+
+        let buckets = self
+            .calibration
+            .iter()
+            .enumerate()
+            .map(|(index, bucket)| {
+                let min_eval = index as i16 * CALIBRATION_WIDTH - CALIBRATION_LIMIT;
+                let positions = bucket.positions.max(1) as f64;
+                let mean_eval = bucket.eval_sum as f64 / positions;
+                CalibrationBucket {
+                    min_eval,
+                    max_eval: min_eval + CALIBRATION_WIDTH,
+                    positions: bucket.positions,
+                    mean_eval,
+                    score: (bucket.wdl[2] as f64 + bucket.wdl[1] as f64 / 2.0) / positions,
+                    fitted: 1.0 / (1.0 + (-mean_eval / scale).exp()),
+                }
+            })
+            .collect();
+        Calibration { scale, buckets }
+    }
+
+    pub fn print(&self) {
+        let valid = self.positions - self.invalid;
+        let percent = |count: u64| count as f64 / valid.max(1) as f64 * 100.0;
+
+        println!("positions:       {:12}", self.positions);
+        println!("invalid:         {:12}", self.invalid);
+        println!(
+            "white wins:      {:12} ({:5.2}%)",
+            self.wdl[2],
+            percent(self.wdl[2])
+        );
+        println!(
+            "draws:           {:12} ({:5.2}%)",
+            self.wdl[1],
+            percent(self.wdl[1])
+        );
+        println!(
+            "black wins:      {:12} ({:5.2}%)",
+            self.wdl[0],
+            percent(self.wdl[0])
+        );
+        if valid > 0 {
+            println!("eval min:        {:12}", self.eval_min);
+            println!("eval max:        {:12}", self.eval_max);
+            println!(
+                "eval mean:       {:12.2}",
+                self.eval_sum as f64 / valid as f64
+            );
+            println!(
+                "eval mean |x|:   {:12.2}",
+                self.eval_abs_sum as f64 / valid as f64
+            );
+        }
+        println!("saturated evals: {:12}", self.saturated);
+        println!("mate scores:     {:12}", self.mates);
+        println!(
+            "incongruent:     {:12} ({:5.2}%, |eval| >= {INCONGRUENCE_THRESHOLD} against the result)",
+            self.incongruent,
+            percent(self.incongruent)
+        );
+        let pair_percent = |count: u64| count as f64 / self.pairs.max(1) as f64 * 100.0;
+        println!(
+            "one move apart:  {:12} ({:5.2}% of consecutive records)",
+            self.one_move_apart,
+            pair_percent(self.one_move_apart)
+        );
+        println!(
+            "same placement:  {:12} ({:5.2}% of consecutive records)",
+            self.same_placement,
+            pair_percent(self.same_placement)
+        );
+        println!("extra byte values:");
+        for (value, &count) in self.extra.iter().enumerate() {
+            if count > 0 {
+                println!(
+                    "  {value:3} (0x{value:02X}) {count:12} ({:5.2}%)",
+                    percent(count)
+                );
+            }
+        }
+        if let Some(buckets) = self.buckets {
+            println!("buckets:");
+            println!(
+                "  {:>12} {:>12} {:>7} {:>7} {:>7} {:>7} {:>10}",
+                "bucket", "positions", "share", "white", "draws", "black", "eval mean"
+            );
+            for (index, bucket) in self.bucket_stats.iter().enumerate() {
+                // Most of the 256 possible sources are unused.
+                if matches!(buckets, Buckets::Source) && bucket.positions == 0 {
+                    continue;
+                }
+                let bucket_percent =
+                    |count: u64| count as f64 / bucket.positions.max(1) as f64 * 100.0;
+                println!(
+                    "  {:>12} {:12} {:6.2}% {:6.2}% {:6.2}% {:6.2}% {:10.2}",
+                    buckets.label(index),
+                    bucket.positions,
+                    percent(bucket.positions),
+                    bucket_percent(bucket.wdl[2]),
+                    bucket_percent(bucket.wdl[1]),
+                    bucket_percent(bucket.wdl[0]),
+                    bucket.eval_sum as f64 / bucket.positions.max(1) as f64,
+                );
+            }
+        }
+    }
+}
+
+/// Statistics of the games of a dataset stored game by game, taken from the first and last
+/// position of each game that lies wholly within the dataset's range.
+#[derive(Default)]
+struct GameStats {
+    games: u64,
+    positions: u64,
+    // Games whose first and last positions are valid, and the plies between them
+    spanned: u64,
+    plies: u64,
+    results: [u64; 3],
+    lengths: [u64; games::LENGTH_BUCKETS.len()],
+}
+
+impl GameStats {
+    fn compute(dataset: &Dataset, path: &Path) -> Result<Self> {
+        let records = Dataset::open(path)?.len();
+        let starts = games::read_index(path, records)?;
+        let range = dataset.start()..dataset.start() + dataset.len();
+        let soft = dataset
+            .header()
+            .is_some_and(|header| header.flags() & Header::FLAG_SOFT_WDL != 0);
+        let mut stats = GameStats::default();
+        for game in games::ranges(&starts, records) {
+            if game.start < range.start || game.end > range.end {
+                continue;
+            }
+            let length = game.end - game.start;
+            stats.games += 1;
+            stats.positions += length;
+            stats.lengths[games::length_bucket(length)] += 1;
+            let first = dataset.read(game.start - range.start)?.unpack();
+            let last = dataset.read(game.end - 1 - range.start)?.unpack();
+            if let Some((.., wdl, _)) = first {
+                stats.results[dataset::hard_wdl(wdl, soft) as usize] += 1;
+            }
+            if let (Some((first, ..)), Some((last, ..))) = (first, last) {
+                stats.spanned += 1;
+                stats.plies += ply(&last).saturating_sub(ply(&first)) + 1;
+            }
+        }
+        Ok(stats)
+    }
+
+    fn print(&self, positions: &Stats) {
+        let percent = |count: u64| count as f64 / self.games.max(1) as f64 * 100.0;
+        let position_percent = |count: u64| positions.fraction(count) * 100.0;
+        println!("games:           {:12}", self.games);
+        println!(
+            "positions/game:  {:12.1}",
+            self.positions as f64 / self.games.max(1) as f64
+        );
+        println!(
+            "plies/game:      {:12.1} (from the first to the last position of each game)",
+            self.plies as f64 / self.spanned.max(1) as f64
+        );
+        println!("game results:             games  positions");
+        for (label, wdl) in [("white wins", 2), ("draws", 1), ("black wins", 0)] {
+            println!(
+                "  {label:<10} {:12} ({:5.2}%)   ({:5.2}%)",
+                self.results[wdl],
+                percent(self.results[wdl]),
+                position_percent(positions.wdl[wdl])
+            );
+        }
+        println!("positions per game:");
+        games::print_lengths(&self.lengths);
+    }
+}
+
+/// A Misra-Gries summary of position hashes, which keeps every position that makes up more
+/// than 1 / `capacity` of the positions it has seen, along with others.
+struct Summary {
+    capacity: usize,
+    counts: HashMap<u64, u64>,
+}
+
+impl Summary {
+    fn add(&mut self, hash: u64) {
+        if let Some(count) = self.counts.get_mut(&hash) {
+            *count += 1;
+        } else if self.counts.len() < self.capacity {
+            self.counts.insert(hash, 1);
+        } else {
+            // At most one in `capacity` positions gets here, so this is linear overall.
+            self.counts.retain(|_, count| {
+                *count -= 1;
+                *count > 0
+            });
+        }
+    }
+}
+
+/// The `k` most common positions of a dataset, most common first. A summary of each worker's
+/// range finds candidates, which include every position common enough to be among them in
+/// any range, and a second pass counts the candidates exactly.
+fn top_positions(dataset: &Dataset, workers: usize, k: usize) -> Result<Vec<(u64, Board)>> {
+    let capacity = (k * 16).max(4096);
+    let progress = Progress::new("top positions", dataset.len());
+    let summaries = dataset.par_chunks(
+        workers,
+        &progress,
+        || {
+            Ok(Summary {
+                capacity,
+                counts: HashMap::new(),
+            })
+        },
+        |summary, chunk| {
+            for packed in chunk.iter() {
+                if let Some((board, ..)) = packed.unpack() {
+                    summary.add(board.hash());
+                }
+            }
+            Ok(())
+        },
+    )?;
+    progress.finish();
+    let candidates: HashSet<u64> = summaries
+        .iter()
+        .flat_map(|summary| summary.counts.keys().copied())
+        .collect();
+
+    let progress = Progress::new("count positions", dataset.len());
+    let partials = dataset.par_chunks(
+        workers,
+        &progress,
+        || Ok(HashMap::new()),
+        |counts: &mut HashMap<u64, (u64, Board)>, chunk| {
+            for packed in chunk.iter() {
+                if let Some((board, ..)) = packed.unpack() {
+                    let hash = board.hash();
+                    if candidates.contains(&hash) {
+                        counts.entry(hash).or_insert((0, board)).0 += 1;
+                    }
+                }
+            }
+            Ok(())
+        },
+    )?;
+    progress.finish();
+    let mut counts = HashMap::new();
+    for partial in partials {
+        for (hash, (count, board)) in partial {
+            counts.entry(hash).or_insert((0, board)).0 += count;
+        }
+    }
+    let mut top: Vec<_> = counts.into_values().collect();
+    top.sort_by_key(|&(count, _)| Reverse(count));
+    top.truncate(k);
+    Ok(top)
+}
+
+/// The number of squares whose piece differs between two boards.
+fn changed_squares(a: &Board, b: &Board) -> u32 {
+    let mut changed = BitBoard::EMPTY;
+    for color in Color::ALL {
+        for piece in Piece::ALL {
+            changed |= a.colored_pieces(color, piece) ^ b.colored_pieces(color, piece);
+        }
+    }
+    changed.len()
+}
+
+/// The number of plies played before a position, from its move number.
+fn ply(board: &Board) -> u64 {
+    (board.fullmove_number() as u64 - 1) * 2 + (board.side_to_move() == Color::Black) as u64
+}
+
+pub fn run(options: Options) -> Result<()> {
+    let dataset = Dataset::open(&options.dataset)?
+        .subrange(&options.range)
+        .with_affinity(options.affinity);
+    let workers = options.workers.unwrap_or_else(dataset::default_workers);
+    let progress = Progress::new("stats", dataset.len());
+    let stats = compute(&dataset, workers, options.buckets, &progress)?;
+    progress.finish();
+    stats.print();
+    let sources = interleave::sources_path(&options.dataset);
+    if matches!(options.buckets, Some(Buckets::Source)) && sources.exists() {
+        println!("sources:");
+        for (index, source) in std::fs::read_to_string(sources)?.lines().enumerate() {
+            println!("  {:>12} {source}", format!("source {index}"));
+        }
+    }
+    // Datasets stored game by game also get statistics of their games.
+    if games::index_path(&options.dataset).exists() {
+        GameStats::compute(&dataset, &options.dataset)?.print(&stats);
+    }
+    if options.calibration || options.calibration_json.is_some() {
+        let calibration = stats.calibration();
+        calibration.print();
+        if let Some(path) = &options.calibration_json {
+            let json = serde_json::to_string_pretty(&calibration).map_err(Error::other)?;
+            std::fs::write(path, json)?;
+        }
+    }
+    if let Some(k) = options.top_positions {
+        let top = top_positions(&dataset, workers, k)?;
+        println!("most common positions:");
+        for (count, board) in top {
+            println!(
+                "  {count:12} ({:5.2}%) {}",
+                stats.fraction(count) * 100.0,
+                formats::fen(&board)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub fn compute(
+    dataset: &Dataset,
+    workers: usize,
+    buckets: Option<Buckets>,
+    progress: &Progress,
+) -> Result<Stats> {
+    let flags = dataset.header().map_or(0, Header::flags);
+    let partials = dataset.par_chunks(
+        workers,
+        progress,
+        || Ok(Stats::new(buckets, flags)),
+        |stats, chunk| {
+            for packed in chunk.iter() {
+                stats.add(packed);
+            }
+            Ok(())
+        },
+    )?;
+
+    let mut stats = Stats::new(buckets, flags);
+    for partial in &partials {
+        stats.merge(partial);
+    }
+    Ok(stats)
+}
@@ -0,0 +1,68 @@
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+use std::path::PathBuf;
+
+use marlinformat::Header;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use structopt::StructOpt;
+
+use crate::dataset::{self, Dataset, Subrange};
+use crate::progress::Progress;
+
+/// Keep each record independently with a given probability.
+#[derive(StructOpt)]
+pub struct Options {
+    dataset: PathBuf,
+
+    #[structopt(short, long)]
+    output: PathBuf,
+
+    /// Probability of keeping each record.
+    #[structopt(long)]
+    keep_prob: f64,
+
+    #[structopt(long, default_value = "0")]
+    seed: u64,
+
+    #[structopt(flatten)]
+    range: Subrange,
+}
+
+pub fn run(options: Options) -> Result<()> {
+    if !(0.0..=1.0).contains(&options.keep_prob) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--keep-prob must be between 0 and 1",
+        ));
+    }
+
+    let dataset = Dataset::open(&options.dataset)?.subrange(&options.range);
+    let mut output = BufWriter::new(File::create(&options.output)?);
+    // The record count is filled in once it is known.
+    let flags = dataset.header().map(Header::flags);
+    if let Some(flags) = flags {
+        output.write_all(bytemuck::bytes_of(&Header::new(0, flags)))?;
+    }
+    let mut rng = StdRng::seed_from_u64(options.seed);
+
+    let progress = Progress::new("thin", dataset.len());
+    let mut kept = 0;
+    for packed in dataset.iter() {
+        let packed = packed?;
+        if rng.gen_bool(options.keep_prob) {
+            output.write_all(bytemuck::bytes_of(&packed))?;
+            kept += 1;
+        }
+        progress.advance(1);
+    }
+    output.flush()?;
+    drop(output);
+    if let Some(flags) = flags {
+        dataset::set_header(&options.output, &Header::new(kept, flags))?;
+    }
+    progress.finish();
+    println!("kept {kept} of {} records.", dataset.len());
+
+    Ok(())
+}
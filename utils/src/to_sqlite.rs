@@ -0,0 +1,170 @@
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+
+use cozy_chess::{Board, Color, Piece};
+use marlinformat::Header;
+use rusqlite::{params, Connection};
+use structopt::StructOpt;
+
+use crate::dataset::{Dataset, Subrange};
+use crate::formats;
+use crate::progress::Progress;
+
+/// Write the positions of a dataset into an SQLite table, indexed by position hash and
+/// material signature, for ad-hoc SQL queries. `from-sqlite` reads them back. The header
+/// flags of the dataset are kept in the `marlinflow_flags` table.
+#[derive(StructOpt)]
+pub struct Options {
+    dataset: PathBuf,
+
+    /// The database, which is created if it doesn't exist. Positions are added to the table
+    /// if it already holds some.
+    #[structopt(short, long)]
+    output: PathBuf,
+
+    #[structopt(long, default_value = "positions")]
+    table: String,
+
+    #[structopt(flatten)]
+    range: Subrange,
+}
+
+/// Checks that a table name can be put in a statement as it is.
+pub fn check_table(table: &str) -> Result<()> {
+    let valid = table.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    match valid {
+        true => Ok(()),
+        false => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid table name {table:?}"),
+        )),
+    }
+}
+
+/// The table holding the header flags of the datasets in each table of positions, so that
+/// `from-sqlite` can write them back.
+const FLAGS_TABLE: &str = "marlinflow_flags";
+
+/// The header flags stored for a table of positions, if any.
+pub fn read_flags(db: &Connection, table: &str) -> Result<Option<u16>> {
+    let mut exists = db
+        .prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name = ?1")
+        .map_err(Error::other)?;
+    let mut rows = exists
+        .query(params![FLAGS_TABLE.to_string()])
+        .map_err(Error::other)?;
+    if rows.next().map_err(Error::other)?.is_none() {
+        return Ok(None);
+    }
+    let mut select = db
+        .prepare(&format!("SELECT flags FROM {FLAGS_TABLE} WHERE name = ?1"))
+        .map_err(Error::other)?;
+    let mut rows = select
+        .query(params![table.to_string()])
+        .map_err(Error::other)?;
+    match rows.next().map_err(Error::other)? {
+        Some(row) => Ok(Some(row.get::<i64>(0).map_err(Error::other)? as u16)),
+        None => Ok(None),
+    }
+}
+
+pub fn run(options: Options) -> Result<()> {
+    let table = &options.table;
+    check_table(table)?;
+    let dataset = Dataset::open(&options.dataset)?.subrange(&options.range);
+    let mut db = Connection::open(&options.output).map_err(Error::other)?;
+    // The database is written in one transaction, so a crash leaves it as it was anyway.
+    db.execute_batch(&format!(
+        "PRAGMA journal_mode = OFF;
+         PRAGMA synchronous = OFF;
+         CREATE TABLE IF NOT EXISTS {table} (
+             hash INTEGER NOT NULL,
+             fen TEXT NOT NULL,
+             eval INTEGER NOT NULL,
+             wdl INTEGER NOT NULL,
+             extra INTEGER NOT NULL,
+             material TEXT NOT NULL
+         );
+         CREATE TABLE IF NOT EXISTS {FLAGS_TABLE} (
+             name TEXT PRIMARY KEY,
+             flags INTEGER NOT NULL
+         );"
+    ))
+    .map_err(Error::other)?;
+    let flags = dataset.header().map_or(0, Header::flags);
+    let stored = read_flags(&db, table)?;
+    if let Some(stored) = stored.filter(|&stored| stored != flags) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{table} holds positions with header flags {stored:#x}, but the dataset has \
+                 {flags:#x}"
+            ),
+        ));
+    }
+
+    let progress = Progress::new("to-sqlite", dataset.len());
+    let transaction = db.transaction().map_err(Error::other)?;
+    if stored.is_none() {
+        transaction
+            .prepare(&format!("INSERT INTO {FLAGS_TABLE} VALUES (?1, ?2)"))
+            .map_err(Error::other)?
+            .execute(params![table.to_string(), flags as i64])
+            .map_err(Error::other)?;
+    }
+    {
+        let mut insert = transaction
+            .prepare(&format!(
+                "INSERT INTO {table} VALUES (?1, ?2, ?3, ?4, ?5, ?6)"
+            ))
+            .map_err(Error::other)?;
+        for packed in dataset.iter() {
+            if let Some((board, cp, wdl, extra)) = packed?.unpack() {
+                insert
+                    .execute(params![
+                        board.hash() as i64,
+                        formats::fen(&board),
+                        cp,
+                        wdl,
+                        extra,
+                        material_key(&board),
+                    ])
+                    .map_err(Error::other)?;
+            }
+            progress.advance(1);
+        }
+    }
+    // Indexing once at the end is faster than keeping the indexes up to date.
+    transaction
+        .execute_batch(&format!(
+            "CREATE INDEX IF NOT EXISTS {table}_hash ON {table} (hash);
+             CREATE INDEX IF NOT EXISTS {table}_material ON {table} (material);"
+        ))
+        .map_err(Error::other)?;
+    transaction.commit().map_err(Error::other)?;
+    progress.finish();
+
+    Ok(())
+}
+
+/// The material signature of a board, as in `KRPvKR`, with white's pieces first.
+pub fn material_key(board: &Board) -> String {
+    let side = |color| {
+        let mut side = String::new();
+        for (piece, c) in [
+            (Piece::King, 'K'),
+            (Piece::Queen, 'Q'),
+            (Piece::Rook, 'R'),
+            (Piece::Bishop, 'B'),
+            (Piece::Knight, 'N'),
+            (Piece::Pawn, 'P'),
+        ] {
+            for _ in 0..board.colored_pieces(color, piece).len() {
+                side.push(c);
+            }
+        }
+        side
+    };
+    format!("{}v{}", side(Color::White), side(Color::Black))
+}
@@ -0,0 +1,103 @@
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+use std::path::PathBuf;
+
+use marlinformat::{Eval, Header};
+use structopt::StructOpt;
+
+use crate::dataset::{self, Dataset, Subrange};
+use crate::progress::Progress;
+
+/// Rescale the evals of a dataset, to bring datasets from different sources onto one eval
+/// scale. Mate scores are left as they are.
+#[derive(StructOpt)]
+pub struct Options {
+    dataset: PathBuf,
+
+    #[structopt(short, long)]
+    output: PathBuf,
+
+    /// Multiply each centipawn eval by this, for example to convert an engine's internal
+    /// units to centipawns, or to match the sigmoid scale used in training.
+    #[structopt(long, default_value = "1")]
+    scale_eval: f64,
+
+    /// Subtract the mean centipawn eval of the dataset first, for evals that lean towards one
+    /// side. Takes an extra pass over the dataset.
+    #[structopt(long)]
+    recenter: bool,
+
+    #[structopt(flatten)]
+    range: Subrange,
+}
+
+pub fn run(options: Options) -> Result<()> {
+    if !options.scale_eval.is_finite() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--scale-eval must be finite",
+        ));
+    }
+
+    let dataset = Dataset::open(&options.dataset)?.subrange(&options.range);
+    let mate_scores = dataset
+        .header()
+        .is_some_and(|header| header.flags() & Header::FLAG_MATE_SCORES != 0);
+    let offset = match options.recenter {
+        true => mean_centipawns(&dataset, mate_scores)?,
+        false => 0.0,
+    };
+    let mut output = BufWriter::new(File::create(&options.output)?);
+    let header = dataset.header().copied();
+    if let Some(header) = header {
+        output.write_all(bytemuck::bytes_of(&header))?;
+    }
+
+    let progress = Progress::new("transform", dataset.len());
+    let mut saturated = 0;
+    for packed in dataset.iter() {
+        let mut packed = packed?;
+        if let Eval::Centipawns(cp) = Eval::decode(packed.eval(), mate_scores) {
+            let scaled = ((cp as f64 - offset) * options.scale_eval).round() as i64;
+            if scaled.abs() > Eval::MAX_CENTIPAWNS as i64 {
+                saturated += 1;
+            }
+            packed.set_eval(Eval::centipawns(scaled).encode());
+        }
+        output.write_all(bytemuck::bytes_of(&packed))?;
+        progress.advance(1);
+    }
+    output.flush()?;
+    drop(output);
+    if let Some(header) = header {
+        let records = dataset.len();
+        dataset::set_header(&options.output, &Header::new(records, header.flags()))?;
+    }
+    progress.finish();
+    if options.recenter {
+        println!("recentered evals by {:.1} centipawns.", -offset);
+    }
+    if saturated > 0 {
+        println!("saturated {saturated} evals to ±{}.", Eval::MAX_CENTIPAWNS);
+    }
+
+    Ok(())
+}
+
+/// The mean of the centipawn evals of the dataset, leaving out mate scores.
+fn mean_centipawns(dataset: &Dataset, mate_scores: bool) -> Result<f64> {
+    let progress = Progress::new("mean eval", dataset.len());
+    let (mut sum, mut count) = (0.0, 0_u64);
+    for packed in dataset.iter() {
+        if let Eval::Centipawns(cp) = Eval::decode(packed?.eval(), mate_scores) {
+            sum += cp as f64;
+            count += 1;
+        }
+        progress.advance(1);
+    }
+    progress.finish();
+    Ok(match count {
+        0 => 0.0,
+        _ => sum / count as f64,
+    })
+}
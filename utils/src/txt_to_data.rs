@@ -1,66 +1,496 @@
-use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Result, Write};
-use std::path::PathBuf;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use cozy_chess::Board;
-use marlinformat::PackedBoard;
+use marlinformat::{Eval, Header, PackedBoard, PackedBoardV2};
+use rayon::prelude::*;
 use structopt::StructOpt;
 
-/// Convert legacy text data format to marlinformat.
+use crate::dataset;
+use crate::formats::{self, TextEval, TextFormat};
+use crate::index;
+use crate::inputs;
+use crate::progress::Progress;
+use crate::uci;
+
+/// How many lines `--format auto` looks at to detect the format.
+const DETECT_LINES: usize = 16;
+
+/// How many lines are read into memory before being converted in parallel.
+const BLOCK_LINES: usize = 1 << 20;
+
+/// How many lines each parallel task converts.
+const TASK_LINES: usize = 1 << 12;
+
+/// Convert text data formats to marlinformat.
 #[derive(StructOpt)]
 pub struct Options {
-    #[structopt(short, long)]
-    output: PathBuf,
+    /// Output file, which every input is converted into, or `-` for stdout.
+    #[structopt(short, long, required_unless_one(&["suffix", "list-formats"]))]
+    output: Option<PathBuf>,
+
+    /// Convert every input to its own file instead, named after the input with its extension
+    /// replaced by this suffix.
+    #[structopt(long, conflicts_with("output"))]
+    suffix: Option<String>,
+
+    /// Input file, a directory or glob pattern of input files, or `-` for stdin.
+    #[structopt(required_unless("list-formats"))]
+    txt_file: Option<PathBuf>,
+
+    /// Input format: one of those `--list-formats` shows as readable, such as `legacy`,
+    /// `cudad`, `zurichess`, `viri` or `epd`, or `auto` to detect it from the first lines.
+    #[structopt(long, default_value = "legacy")]
+    format: Format,
+
+    /// List the text formats, including any registered by a fork, and exit.
+    #[structopt(long)]
+    list_formats: bool,
+
+    /// Column separator for the legacy and viri formats.
+    #[structopt(long, default_value = " | ")]
+    separator: String,
+
+    /// Also accept Shredder-FENs, which name the files of the castling rooks, for Chess960
+    /// and DFRC data. X-FENs are accepted either way.
+    #[structopt(long)]
+    frc: bool,
+
+    /// Start the output with a header recording the format version and record count, and
+    /// marking the file as holding mate scores, which evals written as `#N` or `#-N` are
+    /// converted to.
+    #[structopt(long)]
+    header: bool,
+
+    /// Write version 2 records, which carry the move played or the engine's best move. Each
+    /// line ends with the move in UCI notation, or `0000` for none, after `--separator`.
+    /// Implies `--header`.
+    #[structopt(long)]
+    v2: bool,
+
+    /// Keep fractional results, such as expected scores derived from the search score at
+    /// adjudication, as soft results instead of rounding them to a win, draw or loss. Implies
+    /// `--header`, which marks the file as holding soft results.
+    #[structopt(long)]
+    soft_wdl: bool,
+
+    /// What to do with lines that can't be parsed: `skip` them, warning about the first, `fail`
+    /// at the first one, or `log` each of them to `--error-log` and carry on.
+    #[structopt(long, default_value = "skip")]
+    on_error: OnError,
+
+    /// The file that `--on-error log` writes malformed lines to, each as
+    /// `<file>:<line number>: <line>`.
+    #[structopt(long, required_if("on-error", "log"))]
+    error_log: Option<PathBuf>,
+
+    /// Also write the sidecar index of each output file (see `index`).
+    #[structopt(long)]
+    index: bool,
+}
+
+/// What to do with lines that can't be parsed.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OnError {
+    Skip,
+    Fail,
+    Log,
+}
+
+impl FromStr for OnError {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(OnError::Skip),
+            "fail" => Ok(OnError::Fail),
+            "log" => Ok(OnError::Log),
+            _ => Err(format!("unknown policy {s}, expected skip, fail or log")),
+        }
+    }
+}
+
+/// Deals with the malformed lines of one input as an [`OnError`] policy says, for the
+/// subcommands that read text.
+pub struct Malformed {
+    on_error: OnError,
+    // The file that `OnError::Log` appends malformed lines to
+    log: Option<BufWriter<File>>,
+    count: u64,
+}
+
+impl Malformed {
+    /// Starts dealing with the malformed lines of an input. `error_log` must be given for
+    /// `OnError::Log`, and is appended to.
+    pub fn new(on_error: OnError, error_log: Option<&Path>) -> Result<Self> {
+        let log = match on_error {
+            OnError::Log => {
+                let path = error_log.unwrap();
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                Some(BufWriter::new(file))
+            }
+            _ => None,
+        };
+        Ok(Malformed {
+            on_error,
+            log,
+            count: 0,
+        })
+    }
+
+    /// Deals with line `number` of `input`, counting from 1, which couldn't be parsed.
+    pub fn report(&mut self, input: &Path, number: u64, line: &str) -> Result<()> {
+        let location = format!("{}:{number}", input.display());
+        match self.on_error {
+            OnError::Skip if self.count == 0 => {
+                eprintln!("Warning: skipping malformed lines, the first at {location}: {line}")
+            }
+            OnError::Skip => {}
+            OnError::Fail => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("malformed line at {location}: {line}"),
+                ))
+            }
+            OnError::Log => writeln!(self.log.as_mut().unwrap(), "{location}: {line}")?,
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    /// The number of malformed lines so far.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Flushes the error log, if there is one.
+    pub fn flush(&mut self) -> Result<()> {
+        match &mut self.log {
+            Some(log) => log.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    Auto,
+    Text(TextFormat),
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Format::Auto),
+            _ => s.parse().map(Format::Text),
+        }
+    }
+}
+
+impl Options {
+    /// Options for converting text into data files with a header, for use by other
+    /// subcommands through [`convert_files`].
+    pub fn with_header(format: Format, separator: String, frc: bool) -> Self {
+        Options {
+            output: None,
+            suffix: None,
+            txt_file: None,
+            format,
+            separator,
+            frc,
+            header: true,
+            v2: false,
+            soft_wdl: false,
+            on_error: OnError::Skip,
+            error_log: None,
+            index: false,
+            list_formats: false,
+        }
+    }
 
-    txt_file: PathBuf,
+    /// Whether the output starts with a header, which some options imply.
+    fn has_header(&self) -> bool {
+        self.header || self.v2 || self.soft_wdl
+    }
 }
 
 pub fn run(options: Options) -> Result<()> {
-    let input = BufReader::new(File::open(options.txt_file)?);
-    let mut output = BufWriter::new(File::create(options.output)?);
+    if options.list_formats {
+        formats::print_list();
+        return Ok(());
+    }
+    let inputs = inputs::expand(options.txt_file.as_ref().unwrap())?;
+    let mut bytes = 0;
+    for input in &inputs {
+        bytes += inputs::len(input)?;
+    }
+    let progress = Progress::new("txt-to-data", bytes);
+    // Each input appends its malformed lines to the log.
+    if let Some(error_log) = &options.error_log {
+        File::create(error_log)?;
+    }
+
+    match &options.suffix {
+        Some(suffix) => {
+            for input in &inputs {
+                let output = inputs::with_suffix(input, suffix);
+                convert_files(std::slice::from_ref(input), &output, &options, &progress)?;
+            }
+        }
+        None => convert_files(
+            &inputs,
+            options.output.as_ref().unwrap(),
+            &options,
+            &progress,
+        )?,
+    }
+    progress.finish();
+
+    Ok(())
+}
+
+/// Converts `inputs` into one data file. The output paths and input file of `options` are
+/// ignored.
+pub fn convert_files(
+    inputs: &[PathBuf],
+    output_path: &Path,
+    options: &Options,
+    progress: &Progress,
+) -> Result<()> {
+    let mut output = BufWriter::new(inputs::create(output_path)?);
+    // Centipawns are saturated short of the mate scores, so every eval reads as it was written.
+    let flags = match options.soft_wdl {
+        true => Header::FLAG_MATE_SCORES | Header::FLAG_SOFT_WDL,
+        false => Header::FLAG_MATE_SCORES,
+    };
+    let header = |records| match options.v2 {
+        true => Header::new_v2(records, flags),
+        false => Header::new(records, flags),
+    };
+    // The record count is filled in once it is known, unless writing to stdout.
+    let has_header = options.has_header();
+    if has_header {
+        output.write_all(bytemuck::bytes_of(&header(0)))?;
+    }
+    let mut records = index::Hashing::new(&mut output);
+    let mut positions = 0;
+    for input in inputs {
+        positions += convert_file(input, &mut records, options, progress)?;
+    }
+    let records = records.finish();
+    output.flush()?;
+    drop(output);
+    if has_header && !inputs::is_stdio(output_path) {
+        dataset::set_header(output_path, &header(positions))?;
+    }
+    if options.index {
+        let record_size = match options.v2 {
+            true => std::mem::size_of::<PackedBoardV2>(),
+            false => std::mem::size_of::<PackedBoard>(),
+        };
+        index::write_for(output_path, &records, record_size)?;
+    }
+    Ok(())
+}
+
+fn convert_file(
+    input: &Path,
+    output: &mut impl Write,
+    options: &Options,
+    progress: &Progress,
+) -> Result<u64> {
+    let mut lines = BufReader::new(inputs::open(input)?).lines();
+    let mut head = Vec::new();
+    let format = match options.format {
+        Format::Text(format) => format,
+        Format::Auto => {
+            let mut non_empty = 0;
+            for line in lines.by_ref() {
+                let line = line?;
+                if !line.trim().is_empty() {
+                    non_empty += 1;
+                }
+                head.push(line);
+                if non_empty == DETECT_LINES {
+                    break;
+                }
+            }
+            let sample: Vec<_> =
+                head.iter()
+                    .filter(|line| !line.trim().is_empty())
+                    .map(|line| match options.v2 {
+                        true => split_move(line, &options.separator)
+                            .map_or(line.as_str(), |(line, _)| line),
+                        false => line,
+                    })
+                    .collect();
+            let format =
+                TextFormat::detect(&sample, &options.separator, options.frc).map_err(|e| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("could not detect the format of {}: {e}", input.display()),
+                    )
+                })?;
+            eprintln!("Detected {format:?} format in {}.", input.display());
+            format
+        }
+    };
 
     let mut had_non_integer_cp = false;
     let mut had_out_of_range_cp = false;
+    let mut had_unmarked_mates = false;
+    let mut total = 0;
+    let mut malformed = Malformed::new(options.on_error, options.error_log.as_deref())?;
+    // The number of the first line of the block, counting from 1
+    let mut line_number = 1;
 
-    for line in input.lines() {
-        let line = line?;
-        let _ = (|| {
-            let (board, annotation) = line.split_once(" | ")?;
-            let (cp, wdl) = annotation.split_once(" | ")?;
-
-            let board: Board = board.parse().ok()?;
-            let cp: f32 = cp.parse().ok()?;
-            let wdl: f32 = wdl.parse().ok()?;
+    let mut block = head;
+    loop {
+        for line in lines.by_ref() {
+            block.push(line?);
+            if block.len() == BLOCK_LINES {
+                break;
+            }
+        }
+        if block.is_empty() {
+            break;
+        }
 
-            if !had_non_integer_cp && cp.floor() != cp {
-                println!("Warning: dataset contains non-integer centipawn values. These will be truncated.");
+        let converted: Vec<_> = block
+            .par_chunks(TASK_LINES)
+            .enumerate()
+            .map(|(task, lines)| {
+                let first_line = line_number + (task * TASK_LINES) as u64;
+                convert(lines, first_line, format, options)
+            })
+            .collect();
+        let bytes = block.iter().map(|line| line.len() as u64 + 1).sum();
+        let mut positions = 0;
+        for converted in converted {
+            if !had_non_integer_cp && converted.had_non_integer_cp {
+                eprintln!("Warning: dataset contains non-integer centipawn values. These will be truncated.");
                 had_non_integer_cp = true;
             }
+            if !had_out_of_range_cp && converted.had_out_of_range_cp {
+                eprintln!(
+                    "Warning: dataset contains centipawn values that would be read as mate scores. These will be saturated to ±{}.",
+                    Eval::MAX_CENTIPAWNS
+                );
+                had_out_of_range_cp = true;
+            }
+            if !had_unmarked_mates && converted.had_mates && !options.has_header() {
+                eprintln!("Warning: dataset contains mate scores, which only files with a header mark as mates. Use --header to keep them.");
+                had_unmarked_mates = true;
+            }
+            for (number, line) in &converted.malformed {
+                malformed.report(input, *number, line)?;
+            }
+            output.write_all(&converted.packed)?;
+            positions += converted.records;
+        }
+        progress.advance_work(positions, bytes);
+        total += positions;
+        line_number += block.len() as u64;
+        block.clear();
+    }
 
-            let cp = match (cp as i64).try_into() {
-                Ok(v) => v,
-                Err(_) => {
-                    if !had_out_of_range_cp {
-                        println!("Warning: dataset contains centipawn values outside the range representable by an i16. These will be saturated.");
-                        had_out_of_range_cp = true;
-                    }
-                    match cp.is_sign_positive() {
-                        true => i16::MAX,
-                        false => i16::MIN,
-                    }
-                },
-            };
+    malformed.flush()?;
+    if malformed.count() > 0 {
+        eprintln!(
+            "Skipped {} malformed lines in {}.",
+            malformed.count(),
+            input.display()
+        );
+    }
+    Ok(total)
+}
 
-            let wdl = match () {
-                _ if wdl < 0.25 => 0,
-                _ if wdl < 0.75 => 1,
-                _ => 2
+#[derive(Default)]
+struct Converted {
+    packed: Vec<u8>,
+    records: u64,
+    had_non_integer_cp: bool,
+    had_out_of_range_cp: bool,
+    had_mates: bool,
+    // The number and content of each line that couldn't be parsed
+    malformed: Vec<(u64, String)>,
+}
+
+/// Converts the lines, numbered from `first_line`. Blank lines are skipped.
+fn convert(lines: &[String], first_line: u64, format: TextFormat, options: &Options) -> Converted {
+    let mut converted = Converted::default();
+    for (number, full_line) in (first_line..).zip(lines) {
+        if full_line.trim().is_empty() {
+            continue;
+        }
+        let parsed = match options.v2 {
+            true => split_move(full_line, &options.separator),
+            false => Some((full_line.as_str(), None)),
+        }
+        .and_then(|(line, mv)| {
+            let parsed = format.parse_line(line, &options.separator, options.frc)?;
+            // The move must be legal, and standard castling is stored as the king taking its
+            // rook, as cozy-chess plays it.
+            let mv = match mv {
+                Some(mv) => Some(uci::from_uci(&parsed.0, mv)?),
+                None => None,
             };
+            Some((parsed, mv))
+        });
+        let ((board, eval, wdl, extra), mv) = match parsed {
+            Some(parsed) => parsed,
+            None => {
+                converted.malformed.push((number, full_line.clone()));
+                continue;
+            }
+        };
+
+        match eval {
+            TextEval::Centipawns(cp) => {
+                if cp.floor() != cp {
+                    converted.had_non_integer_cp = true;
+                }
+                // Anything beyond the centipawn range would be read back as a mate score.
+                if cp.abs() > Eval::MAX_CENTIPAWNS as f32 {
+                    converted.had_out_of_range_cp = true;
+                }
+            }
+            TextEval::Mate { .. } => converted.had_mates = true,
+        }
+        let cp = eval.to_eval().encode();
+
+        let wdl = match options.soft_wdl {
+            true => marlinformat::soft_wdl_from_float(wdl),
+            false => formats::wdl_from_float(wdl),
+        };
 
-            let packed = PackedBoard::pack(&board, cp, wdl, 0);
-            Some(output.write_all(bytemuck::bytes_of(&packed)))
-        })().transpose()?;
+        match options.v2 {
+            true => {
+                let packed = PackedBoardV2::pack(&board, cp, wdl, extra, mv);
+                converted
+                    .packed
+                    .extend_from_slice(bytemuck::bytes_of(&packed));
+            }
+            false => {
+                let packed = PackedBoard::pack(&board, cp, wdl, extra);
+                converted
+                    .packed
+                    .extend_from_slice(bytemuck::bytes_of(&packed));
+            }
+        }
+        converted.records += 1;
     }
+    converted
+}
 
-    Ok(())
+/// Splits the trailing move column off a line, which is `0000` for no move.
+fn split_move<'a>(line: &'a str, separator: &str) -> Option<(&'a str, Option<&'a str>)> {
+    let (line, mv) = line.rsplit_once(separator)?;
+    match mv.trim() {
+        "0000" => Some((line, None)),
+        mv => Some((line, Some(mv))),
+    }
 }
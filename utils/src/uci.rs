@@ -0,0 +1,259 @@
+//! Driving UCI engines as child processes, for the subcommands that search positions.
+
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::str::FromStr;
+
+use cozy_chess::{Board, Color, Move};
+use marlinformat::Eval;
+use structopt::StructOpt;
+
+use crate::formats;
+
+/// The engine to search with and the limits of each search.
+#[derive(StructOpt)]
+pub struct EngineOptions {
+    /// Path to a UCI engine.
+    #[structopt(long)]
+    pub engine: PathBuf,
+
+    /// UCI options to set, as `name=value`. Set `UCI_Chess960=true` for Chess960 data.
+    #[structopt(long, use_delimiter = true)]
+    pub engine_options: Vec<EngineOption>,
+
+    /// Stop each search after this many nodes.
+    #[structopt(long)]
+    pub nodes: Option<u64>,
+
+    /// Stop each search at this depth.
+    #[structopt(long)]
+    pub depth: Option<u32>,
+
+    /// Stop each search after this many milliseconds.
+    #[structopt(long)]
+    pub movetime: Option<u64>,
+}
+
+impl EngineOptions {
+    /// The `go` command searching within the limits.
+    fn go(&self) -> String {
+        let mut go = "go".to_string();
+        for (name, limit) in [
+            ("nodes", self.nodes),
+            ("depth", self.depth.map(u64::from)),
+            ("movetime", self.movetime),
+        ] {
+            if let Some(limit) = limit {
+                go += &format!(" {name} {limit}");
+            }
+        }
+        go
+    }
+
+    pub fn check_limits(&self) -> Result<()> {
+        match (self.nodes, self.depth, self.movetime) {
+            (None, None, None) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "give at least one of --nodes, --depth and --movetime",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct EngineOption {
+    name: String,
+    value: String,
+}
+
+impl FromStr for EngineOption {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((name, value)) => Ok(EngineOption {
+                name: name.trim().to_string(),
+                value: value.trim().to_string(),
+            }),
+            None => Err(format!(
+                "invalid engine option {s:?}, expected `name=value`"
+            )),
+        }
+    }
+}
+
+/// The outcome of a search.
+pub struct Search {
+    /// The last score reported, from white's point of view.
+    pub eval: Option<Eval>,
+    pub best_move: Option<Move>,
+}
+
+pub struct Engine {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    go: String,
+}
+
+impl Engine {
+    /// Starts an engine and sets its options.
+    pub fn start(options: &EngineOptions) -> Result<Self> {
+        let mut child = Command::new(&options.engine)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| with_engine(&options.engine, e))?;
+        let mut engine = Engine {
+            stdin: child.stdin.take().unwrap(),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+            child,
+            go: options.go(),
+        };
+        engine.send("uci")?;
+        engine.wait_for("uciok")?;
+        for option in &options.engine_options {
+            engine.send(&format!(
+                "setoption name {} value {}",
+                option.name, option.value
+            ))?;
+        }
+        engine.new_game()?;
+        Ok(engine)
+    }
+
+    /// Tells the engine that the next search is from a different game.
+    pub fn new_game(&mut self) -> Result<()> {
+        self.send("ucinewgame")?;
+        self.send("isready")?;
+        self.wait_for("readyok")
+    }
+
+    /// Searches a position reached by playing `moves` from `start`. Moves are given as cozy-chess
+    /// moves, where castling is the king capturing its own rook.
+    pub fn search(&mut self, start: &Board, moves: &[Move]) -> Result<Search> {
+        let mut board = start.clone();
+        let mut position = format!("position fen {}", formats::fen(start));
+        if !moves.is_empty() {
+            position += " moves";
+            for &mv in moves {
+                position += &format!(" {}", to_uci(&board, mv));
+                board.play_unchecked(mv);
+            }
+        }
+        self.send(&position)?;
+        self.send(&self.go.clone())?;
+
+        let mut eval = None;
+        loop {
+            let line = self.read_line()?;
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("info") => {
+                    let tokens: Vec<_> = tokens.collect();
+                    if let Some(score) = tokens.iter().position(|&token| token == "score") {
+                        eval = parse_score(&tokens[score + 1..], board.side_to_move()).or(eval);
+                    }
+                }
+                Some("bestmove") => {
+                    let best_move = tokens.next().and_then(|mv| from_uci(&board, mv));
+                    return Ok(Search { eval, best_move });
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn send(&mut self, command: &str) -> Result<()> {
+        writeln!(self.stdin, "{command}")?;
+        self.stdin.flush()
+    }
+
+    fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self.stdout.read_line(&mut line)? == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "the engine exited unexpectedly",
+            ));
+        }
+        Ok(line)
+    }
+
+    fn wait_for(&mut self, token: &str) -> Result<()> {
+        while self.read_line()?.trim() != token {}
+        Ok(())
+    }
+}
+
+impl Drop for Engine {
+    fn drop(&mut self) {
+        let _ = self.send("quit");
+        let _ = self.child.wait();
+    }
+}
+
+fn with_engine(path: &Path, e: Error) -> Error {
+    Error::new(e.kind(), format!("could not start {}: {e}", path.display()))
+}
+
+/// Parses the tokens after `score` in an `info` line, given the side to move.
+fn parse_score(tokens: &[&str], stm: Color) -> Option<Eval> {
+    let value: i64 = tokens.get(1)?.parse().ok()?;
+    let white = match stm {
+        Color::White => 1,
+        Color::Black => -1,
+    };
+    match tokens[0] {
+        "cp" => Some(Eval::centipawns(value * white)),
+        "mate" => {
+            // UCI counts mates in moves, so mating in n takes 2n - 1 plies.
+            let (mating, plies) = match value > 0 {
+                true => (stm, value * 2 - 1),
+                false => (!stm, -value * 2),
+            };
+            Some(Eval::Mate {
+                winner: mating,
+                plies: plies.clamp(0, u16::MAX as i64) as u16,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Formats a move in UCI notation, where standard chess castling is written as the king moving
+/// two squares.
+pub fn to_uci(board: &Board, mv: Move) -> String {
+    let castles = board.colors(board.side_to_move()).has(mv.to);
+    match castles && formats::has_standard_castling(board) {
+        true => {
+            let file = match mv.to.file() > mv.from.file() {
+                true => cozy_chess::File::G,
+                false => cozy_chess::File::C,
+            };
+            Move {
+                from: mv.from,
+                to: cozy_chess::Square::new(file, mv.from.rank()),
+                promotion: None,
+            }
+            .to_string()
+        }
+        false => mv.to_string(),
+    }
+}
+
+/// Parses a move in UCI notation into a legal move of `board`.
+pub fn from_uci(board: &Board, mv: &str) -> Option<Move> {
+    let mut mv: Move = mv.parse().ok()?;
+    let king = board.king(board.side_to_move());
+    let two_files = (mv.from.file() as i32 - mv.to.file() as i32).abs() == 2;
+    if mv.from == king && two_files && formats::has_standard_castling(board) {
+        let rook = match mv.to.file() > mv.from.file() {
+            true => cozy_chess::File::H,
+            false => cozy_chess::File::A,
+        };
+        mv.to = cozy_chess::Square::new(rook, mv.from.rank());
+    }
+    board.is_legal(mv).then_some(mv)
+}